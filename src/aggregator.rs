@@ -1,19 +1,27 @@
 use crate::errors::*;
+use crate::expression::{as_f64, Expression};
 use crate::query::*;
 use crate::record::*;
 
-use flexbuffers::{Reader, VectorReader};
 use indexmap::{IndexMap, IndexSet};
-use std::collections::{BTreeMap, HashMap, HashSet};
+use std::cell::{Cell, RefCell};
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashSet;
+use std::hash::{Hash, Hasher};
 use std::marker::PhantomData;
+use std::rc::Rc;
 
 pub(crate) fn create_aggregator<'ret, 'de: 'ret>(
     headers: &Vec<String>,
     projections: Vec<Projection>,
     group_by: Option<GroupBy>,
+    having: Option<Having>,
     order_by: Option<OrderBy>,
+    distinct: bool,
+    options: &QueryOptions,
 ) -> ApiResult<Box<dyn Aggregator<'de> + 'ret>> {
-    let result = base_aggregator(headers, &projections, group_by)
+    let result = base_aggregator(headers, &projections, group_by, having, distinct, options)
         .map(|a| apply_order_by(a, headers, &projections, order_by))?;
 
     Ok(result?)
@@ -25,42 +33,66 @@ fn base_aggregator<'de>(
     headers: &Vec<String>,
     projections: &Vec<Projection>,
     group_by: Option<GroupBy>,
+    having: Option<Having>,
+    distinct: bool,
+    options: &QueryOptions,
 ) -> ApiResult<AnyBaseAggregator<'de>> {
     if projections.is_empty() {
         return invalid_data_ae!("empty projections");
     }
 
-    let mut count = None;
-    let mut columns_set: ColumnSet = Default::default();
+    let mut functions = Vec::new();
+    let mut columns = Vec::new();
 
-    validate_projections(&headers, projections, &mut count, &mut columns_set)?;
+    validate_projections(&headers, projections, &mut functions, &mut columns)?;
 
     if let Some(group_by) = group_by {
-        return group_by_aggregator(group_by, projections, headers);
+        let aggregator = group_by_aggregator(group_by, projections, headers, having, options)?;
+        if distinct {
+            return Ok(distinct_grouped(aggregator));
+        }
+        return Ok(aggregator);
     }
 
-    if count.is_some() {
-        return count_aggregator(columns_set);
+    if having.is_some() {
+        return invalid_data_ae!("having is supported with group by only");
     }
 
-    Ok(AnyBaseAggregator::Columns(ColumnsAggregator::new(
-        columns_set,
-    )))
+    if projections
+        .iter()
+        .any(|p| matches!(p.ptype, ProjectionType::Grouping(_)))
+    {
+        return invalid_data_ae!("grouping is supported with group by only");
+    }
+
+    if !functions.is_empty() {
+        return function_aggregator(columns, functions, options);
+    }
+
+    if distinct {
+        return distinct_aggregator(projections);
+    }
+
+    Ok(AnyBaseAggregator::Columns(ColumnsAggregator::new(columns)))
 }
 
 fn validate_projections(
     headers: &Vec<String>,
     projections: &Vec<Projection>,
-    has_count: &mut Option<usize>,
-    columns_set: &mut ColumnSet,
+    functions: &mut Vec<ProjectionType>,
+    columns: &mut Vec<Expression>,
 ) -> ApiResult<()> {
-    for (idx, p) in projections.iter().enumerate() {
+    let mut columns_set: ColumnSet = Default::default();
+    for p in projections.iter() {
         match p.ptype {
-            ProjectionType::Count => {
-                if has_count.is_some() {
-                    return invalid_data_ae!("duplicate count()");
+            ProjectionType::Count
+            | ProjectionType::CountDistinct(_)
+            | ProjectionType::ApproxCountDistinct(_)
+            | ProjectionType::ApproxPercentile(_, _) => {
+                if functions.contains(&p.ptype) {
+                    return invalid_data_ae!("duplicate {}", p.name);
                 }
-                *has_count = Some(idx);
+                functions.push(p.ptype.clone());
             }
             ProjectionType::Column(column_idx) => {
                 if headers.len() - 1 < column_idx {
@@ -72,9 +104,15 @@ fn validate_projections(
                 }
 
                 if !columns_set.insert(column_idx) {
-                    return invalid_data_ae!("duplicate column \"{}\" projection", headers[idx]);
+                    return invalid_data_ae!(
+                        "duplicate column \"{}\" projection",
+                        headers[column_idx]
+                    );
                 }
+                columns.push(Expression::Column(column_idx));
             }
+            ProjectionType::Expression(ref expr) => columns.push(expr.clone()),
+            ProjectionType::Grouping(_) => (),
         }
     }
     Ok(())
@@ -84,111 +122,264 @@ fn group_by_aggregator<'de>(
     group_by: GroupBy,
     projections: &Vec<Projection>,
     headers: &Vec<String>,
+    mut having: Option<Having>,
+    options: &QueryOptions,
 ) -> ApiResult<AnyBaseAggregator<'de>> {
-    let (column_id, signature) = match group_by {
-        GroupBy::ProjectionId(pid) => validate_group_by_projection(pid, projections)?,
-        GroupBy::Column(c) => validate_group_by_column(c, projections, headers)?,
+    let key = match group_by {
+        GroupBy::Sets { columns, sets } => {
+            return grouping_sets_aggregator(columns, sets, projections, headers, having, options)
+        }
+        group_by => group_by_key(group_by, projections, headers)?,
     };
+    let mut signature = group_by_signature(&key, projections, headers, options)?;
+
+    // Aggregates used by HAVING only are kept after projections and aren't shown
+    let len = signature.len();
+    if let Some(having) = having.as_mut() {
+        if having.bind_count(len) {
+            signature.push(GroupBySigType::Fun(FunctionAggregatorType::Count));
+        }
+    }
 
     Ok(AnyBaseAggregator::GroupBy(GroupByAggregator::new(
-        column_id, signature,
+        vec![key],
+        signature,
+        len,
+        having,
     )))
 }
 
-fn validate_group_by_column(
-    column: String,
+fn group_by_key(
+    group_by: GroupBy,
     projections: &Vec<Projection>,
     headers: &Vec<String>,
-) -> ApiResult<(usize, Vec<GroupBySigType>)> {
-    let mut signature = Vec::new();
-    let mut column_id = None;
+) -> ApiResult<Expression> {
+    match group_by {
+        GroupBy::ProjectionId(pid) => group_by_projection_key(pid, projections),
+        GroupBy::Column(c) => group_by_column_key(c, projections, headers),
+        GroupBy::Expression(e) => Ok(e),
+        GroupBy::Sets { .. } => invalid_data_ae!("grouping sets can't be nested"),
+    }
+}
 
-    for p in projections.iter() {
-        match p.ptype {
-            ProjectionType::Column(c) => {
-                if p.name != column {
-                    if c > headers.len() - 1 {
-                        return invalid_data_ae!("out of bounds");
-                    }
-                    let h = &headers[c];
-                    if !column.eq(h) {
-                        return invalid_data_ae!(
-                            "can't combine column projection {} with group by {}",
-                            h,
-                            column
-                        );
-                    }
-                }
+// Every grouping set is aggregated by its own group by aggregator in the same scan
+fn grouping_sets_aggregator<'de>(
+    columns: Vec<GroupBy>,
+    sets: Vec<Vec<usize>>,
+    projections: &Vec<Projection>,
+    headers: &Vec<String>,
+    having: Option<Having>,
+    options: &QueryOptions,
+) -> ApiResult<AnyBaseAggregator<'de>> {
+    let mut keys = Vec::with_capacity(columns.len());
+    for column in columns {
+        keys.push(group_by_key(column, projections, headers)?);
+    }
+
+    let mut aggregators = Vec::with_capacity(sets.len());
+    for set in sets.iter() {
+        let mut signature = grouping_set_signature(&keys, set, projections, headers, options)?;
+        let len = signature.len();
+        let mut having = having.clone();
+        if let Some(having) = having.as_mut() {
+            if having.bind_count(len) {
+                signature.push(GroupBySigType::Fun(FunctionAggregatorType::Count));
+            }
+        }
+        let key = set.iter().map(|c| keys[*c].clone()).collect();
+        aggregators.push(GroupByAggregator::new(key, signature, len, having));
+    }
+    Ok(AnyBaseAggregator::GroupingSets(
+        GroupingSetsAggregator::new(aggregators),
+    ))
+}
 
-                column_id = Some(c);
-                signature.push(GroupBySigType::GroupByProjection);
+// Grouping columns which aren't in the set are NULL in its rows
+fn grouping_set_signature(
+    keys: &[Expression],
+    set: &[usize],
+    projections: &Vec<Projection>,
+    headers: &Vec<String>,
+    options: &QueryOptions,
+) -> ApiResult<Vec<GroupBySigType>> {
+    let mut signature = Vec::with_capacity(projections.len());
+    for p in projections.iter() {
+        let expr = match &p.ptype {
+            ProjectionType::Column(c) => Expression::Column(*c),
+            ProjectionType::Expression(e) => e.clone(),
+            ProjectionType::Grouping(args) => {
+                let value = grouping_value(args, keys, set, projections, headers)?;
+                signature.push(GroupBySigType::Constant(value));
+                continue;
             }
-            ProjectionType::Count => {
-                signature.push(GroupBySigType::Fun(FunctionAggregatorType::Count))
+            ptype => {
+                signature.push(GroupBySigType::Fun(FunctionAggregatorType::new(
+                    ptype, options,
+                )?));
+                continue;
             }
+        };
+        guard!(let Some(column) = keys.iter().position(|k| *k == expr) else {
+            return invalid_data_ae!("can't combine projection {} with group by", p.name);
+        });
+        signature.push(match set.iter().position(|c| *c == column) {
+            Some(idx) => GroupBySigType::GroupByProjection(idx),
+            None => GroupBySigType::Constant(ValueRef::Null),
+        });
+    }
+    Ok(signature)
+}
+
+// The first argument is the highest bit, bit is set if column isn't in grouping set
+fn grouping_value(
+    args: &Vec<GroupBy>,
+    keys: &[Expression],
+    set: &[usize],
+    projections: &Vec<Projection>,
+    headers: &Vec<String>,
+) -> ApiResult<ValueRef<'static>> {
+    if args.len() > 63 {
+        return invalid_data_ae!("too many grouping arguments: {}", args.len());
+    }
+    let mut result = 0u64;
+    for arg in args.iter() {
+        let expr = group_by_key(arg.clone(), projections, headers)?;
+        guard!(let Some(column) = keys.iter().position(|k| *k == expr) else {
+            return invalid_data_ae!("grouping argument {:?} is not in group by", arg);
+        });
+        result = (result << 1) | (!set.contains(&column) as u64);
+    }
+    Ok(ValueRef::UInteger(result))
+}
+
+// `SELECT DISTINCT` is grouping by all projected columns
+fn distinct_aggregator<'de>(projections: &Vec<Projection>) -> ApiResult<AnyBaseAggregator<'de>> {
+    let mut key = Vec::with_capacity(projections.len());
+    let mut signature = Vec::with_capacity(projections.len());
+    for p in projections.iter() {
+        let expr = match &p.ptype {
+            ProjectionType::Column(c) => Expression::Column(*c),
+            ProjectionType::Expression(e) => e.clone(),
+            _ => return invalid_data_ae!("distinct projection {} is not a column", p.name),
+        };
+        signature.push(GroupBySigType::GroupByProjection(key.len()));
+        key.push(expr);
+    }
+    let len = signature.len();
+    Ok(AnyBaseAggregator::GroupBy(GroupByAggregator::new(
+        key, signature, len, None,
+    )))
+}
+
+// Grouped rows are distinct only if group key is projected, otherwise
+// rows are collected and duplicates are dropped, as it's done for grouping sets
+fn distinct_grouped<'de>(aggregator: AnyBaseAggregator<'de>) -> AnyBaseAggregator<'de> {
+    match aggregator {
+        AnyBaseAggregator::GroupBy(a) if a.projects_key() => AnyBaseAggregator::GroupBy(a),
+        AnyBaseAggregator::GroupBy(a) => {
+            AnyBaseAggregator::GroupingSets(GroupingSetsAggregator::new(vec![a]).with_distinct())
         }
+        AnyBaseAggregator::GroupingSets(a) => AnyBaseAggregator::GroupingSets(a.with_distinct()),
+        a => a,
     }
+}
 
-    guard!(let Some(column_id) = column_id else {
+// Projection alias is checked before table column name
+fn group_by_column_key(
+    column: String,
+    projections: &Vec<Projection>,
+    headers: &Vec<String>,
+) -> ApiResult<Expression> {
+    for p in projections.iter().filter(|p| p.name == column) {
+        match &p.ptype {
+            ProjectionType::Column(c) => return Ok(Expression::Column(*c)),
+            ProjectionType::Expression(e) => return Ok(e.clone()),
+            _ => (),
+        }
+    }
+    guard!(let Some(column_id) = headers.iter().position(|h| *h == column) else {
         return invalid_data_ae!("Column for group_by not detected");
     });
-
-    Ok((column_id, signature))
+    Ok(Expression::Column(column_id))
 }
 
-fn validate_group_by_projection(
+fn group_by_projection_key(
     projection_id: usize,
     projections: &Vec<Projection>,
-) -> ApiResult<(usize, Vec<GroupBySigType>)> {
-    let mut signature = Vec::new();
-    let mut column_id = None;
-
-    if projections.len() < projection_id {
+) -> ApiResult<Expression> {
+    if projection_id == 0 || projections.len() < projection_id {
         return invalid_data_ae!(
             "can't group_by on {}, there is only {} projections",
             projection_id,
             projections.len()
         );
     }
+    match &projections[projection_id - 1].ptype {
+        ProjectionType::Column(c) => Ok(Expression::Column(*c)),
+        ProjectionType::Expression(e) => Ok(e.clone()),
+        _ => invalid_data_ae!("Can't group by on not column"),
+    }
+}
 
-    for (i, p) in projections.iter().enumerate() {
-        if i == projection_id - 1 {
-            match p.ptype {
-                ProjectionType::Column(c) => {
-                    column_id = Some(c);
-                    signature.push(GroupBySigType::GroupByProjection);
-                }
-                _ => return invalid_data_ae!("Can't group by on not column"),
+// Every projection is either the group key or aggregate function
+fn group_by_signature(
+    key: &Expression,
+    projections: &Vec<Projection>,
+    headers: &Vec<String>,
+    options: &QueryOptions,
+) -> ApiResult<Vec<GroupBySigType>> {
+    let mut signature = Vec::new();
+    let mut key_projected = false;
+
+    for p in projections.iter() {
+        let expr = match &p.ptype {
+            ProjectionType::Column(c) => Expression::Column(*c),
+            ProjectionType::Expression(e) => e.clone(),
+            ProjectionType::Grouping(args) => {
+                let keys = std::slice::from_ref(key);
+                let value = grouping_value(args, keys, &[0], projections, headers)?;
+                signature.push(GroupBySigType::Constant(value));
+                continue;
             }
-        } else {
-            match p.ptype {
-                ProjectionType::Count => {
-                    signature.push(GroupBySigType::Fun(FunctionAggregatorType::Count))
-                }
-                _ => {
-                    return invalid_data_ae!(
-                        "Another column in projection which should be grouped by"
-                    )
-                }
+            ptype => {
+                signature.push(GroupBySigType::Fun(FunctionAggregatorType::new(
+                    ptype, options,
+                )?));
+                continue;
             }
+        };
+        if expr != *key {
+            return invalid_data_ae!("can't combine projection {} with group by", p.name);
         }
+        key_projected = true;
+        signature.push(GroupBySigType::GroupByProjection(0));
     }
 
-    guard!(let Some(column_id) = column_id else {
+    if !key_projected {
         return invalid_data_ae!("Column for group_by not detected");
-    });
+    }
 
-    Ok((column_id, signature))
+    Ok(signature)
 }
 
-fn count_aggregator<'de>(columns_set: ColumnSet) -> ApiResult<AnyBaseAggregator<'de>> {
-    if !columns_set.is_empty() {
+fn function_aggregator<'de>(
+    columns: Vec<Expression>,
+    functions: Vec<ProjectionType>,
+    options: &QueryOptions,
+) -> ApiResult<AnyBaseAggregator<'de>> {
+    if !columns.is_empty() {
         return invalid_data_ae!(
             "there is count() projection combined with another ones, but no group_by set"
         );
     }
-    Ok(AnyBaseAggregator::Function(FunctionAggregator::new(
-        FunctionAggregatorType::Count,
+    let mut aggregators = Vec::with_capacity(functions.len());
+    for function in functions.iter() {
+        aggregators.push(FunctionAggregator::new(FunctionAggregatorType::new(
+            function, options,
+        )?));
+    }
+    Ok(AnyBaseAggregator::Function(FunctionsAggregator::new(
+        aggregators,
     )))
 }
 
@@ -248,14 +439,15 @@ fn validate_order_type(
 }
 
 pub(crate) trait Aggregator<'de> {
-    fn aggregate(&mut self, value: &flexbuffers::VectorReader<'de>) -> ApiResult<()>;
+    fn aggregate(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<()>;
     fn iter(&self) -> BoxedRecordIterator<'_, 'de>;
 }
 
 enum AnyBaseAggregator<'de> {
-    Function(FunctionAggregator<'de>),
+    Function(FunctionsAggregator<'de>),
     Columns(ColumnsAggregator<'de>),
     GroupBy(GroupByAggregator<'de>),
+    GroupingSets(GroupingSetsAggregator<'de>),
 }
 
 impl<'de> AnyBaseAggregator<'de> {
@@ -267,6 +459,7 @@ impl<'de> AnyBaseAggregator<'de> {
             Self::Function(a) => Box::new(a),
             Self::Columns(a) => Box::new(a),
             Self::GroupBy(a) => Box::new(a),
+            Self::GroupingSets(a) => Box::new(a),
         }
     }
 }
@@ -274,14 +467,36 @@ impl<'de> AnyBaseAggregator<'de> {
 #[derive(Copy, Clone)]
 enum FunctionAggregatorType {
     Count,
+    CountDistinct(usize, DistinctMode),
+    ApproxPercentile(usize, f64),
+}
+
+impl FunctionAggregatorType {
+    fn new(ptype: &ProjectionType, options: &QueryOptions) -> ApiResult<Self> {
+        match ptype {
+            ProjectionType::Count => Ok(Self::Count),
+            ProjectionType::CountDistinct(c) => Ok(Self::CountDistinct(*c, options.distinct)),
+            ProjectionType::ApproxCountDistinct(c) => {
+                Ok(Self::CountDistinct(*c, DistinctMode::Approx))
+            }
+            ProjectionType::ApproxPercentile(c, p) => Ok(Self::ApproxPercentile(*c, *p)),
+            ProjectionType::Column(_)
+            | ProjectionType::Expression(_)
+            | ProjectionType::Grouping(_) => invalid_data_ae!("column is not aggregate function"),
+        }
+    }
 }
 
-enum FunctionAggregatorInner {
+enum FunctionAggregatorInner<'de> {
     Count(u64),
+    // Every distinct value is kept, strings of stored records are borrowed, computed ones are owned
+    CountDistinct(usize, HashSet<ValueOrdRef<'de>>),
+    ApproxCountDistinct(usize, HyperLogLog),
+    ApproxPercentile(usize, f64, TDigest),
 }
 
 struct FunctionAggregator<'de> {
-    inner: FunctionAggregatorInner,
+    inner: FunctionAggregatorInner<'de>,
     _phatom: PhantomData<&'de ()>,
 }
 
@@ -289,6 +504,15 @@ impl<'de> FunctionAggregator<'de> {
     fn new(fun: FunctionAggregatorType) -> Self {
         let inner = match fun {
             FunctionAggregatorType::Count => FunctionAggregatorInner::Count(0),
+            FunctionAggregatorType::CountDistinct(c, DistinctMode::Exact) => {
+                FunctionAggregatorInner::CountDistinct(c, HashSet::new())
+            }
+            FunctionAggregatorType::CountDistinct(c, DistinctMode::Approx) => {
+                FunctionAggregatorInner::ApproxCountDistinct(c, HyperLogLog::new())
+            }
+            FunctionAggregatorType::ApproxPercentile(c, p) => {
+                FunctionAggregatorInner::ApproxPercentile(c, p, TDigest::new())
+            }
         };
         Self {
             inner,
@@ -297,16 +521,42 @@ impl<'de> FunctionAggregator<'de> {
     }
 
     fn value(&self) -> ValueRef<'de> {
-        match self.inner {
-            FunctionAggregatorInner::Count(c) => ValueRef::UInteger(c),
+        match &self.inner {
+            FunctionAggregatorInner::Count(c) => ValueRef::UInteger(*c),
+            FunctionAggregatorInner::CountDistinct(_, set) => ValueRef::UInteger(set.len() as u64),
+            FunctionAggregatorInner::ApproxCountDistinct(_, hll) => {
+                ValueRef::UInteger(hll.estimate())
+            }
+            FunctionAggregatorInner::ApproxPercentile(_, p, digest) => match digest.quantile(*p) {
+                Some(v) => ValueRef::Float(v),
+                None => ValueRef::Null,
+            },
         }
     }
 }
 
 impl<'de> Aggregator<'de> for FunctionAggregator<'de> {
-    fn aggregate(&mut self, _: &VectorReader<'de>) -> ApiResult<()> {
+    fn aggregate(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<()> {
         match &mut self.inner {
             FunctionAggregatorInner::Count(c) => *c += 1,
+            FunctionAggregatorInner::CountDistinct(c, set) => {
+                let v = value.value_at(*c)?;
+                if !v.is_null() {
+                    set.insert(v.ord_ref());
+                }
+            }
+            FunctionAggregatorInner::ApproxCountDistinct(c, hll) => {
+                let v = value.value_at(*c)?;
+                if !v.is_null() {
+                    hll.insert(&v.ord_ref());
+                }
+            }
+            FunctionAggregatorInner::ApproxPercentile(c, _, digest) => {
+                let v = value.value_at(*c)?;
+                if !v.is_null() {
+                    digest.insert(as_f64(&v)?);
+                }
+            }
         }
         Ok(())
     }
@@ -316,23 +566,221 @@ impl<'de> Aggregator<'de> for FunctionAggregator<'de> {
     }
 }
 
+// Aggregates without group by, they give one row even for empty input
+struct FunctionsAggregator<'de> {
+    functions: Vec<FunctionAggregator<'de>>,
+}
+
+impl<'de> FunctionsAggregator<'de> {
+    fn new(functions: Vec<FunctionAggregator<'de>>) -> Self {
+        Self { functions }
+    }
+}
+
+impl<'de> Aggregator<'de> for FunctionsAggregator<'de> {
+    fn aggregate(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<()> {
+        for function in self.functions.iter_mut() {
+            function.aggregate(value)?;
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> BoxedRecordIterator<'_, 'de> {
+        let row: Vec<ValueRef<'de>> = self.functions.iter().map(|f| f.value()).collect();
+        Box::new(OneRecordIterator::new(row))
+    }
+}
+
+// Precision of approximate distinct count, 2^12 registers take 4K per aggregate,
+// standard error is 1.04 / sqrt(4096), about 1.625%
+const HLL_PRECISION: u32 = 12;
+
+// Plain HyperLogLog estimate of distinct count in fixed memory, with linear counting
+// for small cardinalities, but without HLL++ bias correction and sparse registers
+struct HyperLogLog {
+    registers: Vec<u8>,
+    // Estimate is kept until register is changed, as it scans all of them
+    estimate: Cell<Option<u64>>,
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        Self {
+            registers: vec![0; 1 << HLL_PRECISION],
+            estimate: Cell::new(None),
+        }
+    }
+
+    fn insert(&mut self, value: &ValueOrdRef) {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        let hash = hasher.finish();
+        let idx = (hash >> (64 - HLL_PRECISION)) as usize;
+        // Position of the first set bit in the rest of hash, bounded by its length
+        let rest = (hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        if self.registers[idx] < rank {
+            self.registers[idx] = rank;
+            self.estimate.set(None);
+        }
+    }
+
+    fn estimate(&self) -> u64 {
+        if let Some(estimate) = self.estimate.get() {
+            return estimate;
+        }
+        let estimate = self.compute_estimate();
+        self.estimate.set(Some(estimate));
+        estimate
+    }
+
+    fn compute_estimate(&self) -> u64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let mut sum = 0.0;
+        let mut zeros = 0;
+        for r in self.registers.iter() {
+            sum += 1.0 / (1u64 << r) as f64;
+            if *r == 0 {
+                zeros += 1;
+            }
+        }
+        let estimate = alpha * m * m / sum;
+        // Linear counting is more accurate for small cardinalities
+        if estimate <= 2.5 * m && zeros > 0 {
+            return (m * (m / zeros as f64).ln()).round() as u64;
+        }
+        estimate.round() as u64
+    }
+}
+
+// Compression of t-digest, centroids count doesn't exceed it
+const TDIGEST_COMPRESSION: f64 = 100.0;
+const TDIGEST_BUFFER: usize = 256;
+
+// Merging t-digest estimate of quantiles in fixed memory: values are buffered, then merged
+// to centroids which are small near the tails and large near the median
+struct TDigest {
+    // Mean and weight of centroid, sorted by mean
+    centroids: Vec<(f64, f64)>,
+    buffer: Vec<f64>,
+    // Centroids merged with buffer for quantiles, kept until the next insert
+    merged_cache: RefCell<Option<Rc<Vec<(f64, f64)>>>>,
+    min: f64,
+    max: f64,
+}
+
+impl TDigest {
+    fn new() -> Self {
+        Self {
+            centroids: Vec::new(),
+            buffer: Vec::with_capacity(TDIGEST_BUFFER),
+            merged_cache: RefCell::new(None),
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+        }
+    }
+
+    fn insert(&mut self, value: f64) {
+        if value.is_nan() {
+            return;
+        }
+        *self.merged_cache.get_mut() = None;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push(value);
+        if self.buffer.len() >= TDIGEST_BUFFER {
+            self.centroids = self.merged();
+            self.buffer.clear();
+        }
+    }
+
+    fn merged(&self) -> Vec<(f64, f64)> {
+        let mut all = self.centroids.clone();
+        all.extend(self.buffer.iter().map(|v| (*v, 1.0)));
+        all.sort_by(|a, b| a.0.partial_cmp(&b.0).unwrap_or(Ordering::Equal));
+        let total: f64 = all.iter().map(|c| c.1).sum();
+
+        let mut result = Vec::with_capacity(all.len());
+        let mut iter = all.into_iter();
+        guard!(let Some(mut cur) = iter.next() else { return result });
+        let mut weight_before = 0.0;
+        for next in iter {
+            let q_left = weight_before / total;
+            let q_right = (weight_before + cur.1 + next.1) / total;
+            if tdigest_scale(q_right) - tdigest_scale(q_left) <= 1.0 {
+                cur.0 += (next.0 - cur.0) * next.1 / (cur.1 + next.1);
+                cur.1 += next.1;
+            } else {
+                weight_before += cur.1;
+                result.push(cur);
+                cur = next;
+            }
+        }
+        result.push(cur);
+        result
+    }
+
+    // Value is interpolated between centers of neighbour centroids, or min and max at the tails
+    fn quantile(&self, q: f64) -> Option<f64> {
+        let cached = self.merged_cache.borrow().clone();
+        let centroids = cached.unwrap_or_else(|| {
+            let merged = Rc::new(self.merged());
+            *self.merged_cache.borrow_mut() = Some(merged.clone());
+            merged
+        });
+        let total: f64 = centroids.iter().map(|c| c.1).sum();
+        if centroids.is_empty() {
+            return None;
+        }
+        let target = q * total;
+        let (first_mean, first_weight) = centroids[0];
+        if target <= first_weight / 2.0 {
+            return Some(self.min + (first_mean - self.min) * target / (first_weight / 2.0));
+        }
+        let mut weight_before = 0.0;
+        for pair in centroids.windows(2) {
+            let (left, right) = (pair[0], pair[1]);
+            let left_center = weight_before + left.1 / 2.0;
+            let right_center = weight_before + left.1 + right.1 / 2.0;
+            if target <= right_center {
+                let fraction = (target - left_center) / (right_center - left_center);
+                return Some(left.0 + (right.0 - left.0) * fraction);
+            }
+            weight_before += left.1;
+        }
+        let (last_mean, last_weight) = centroids[centroids.len() - 1];
+        let fraction = (target - (total - last_weight / 2.0)) / (last_weight / 2.0);
+        Some(last_mean + (self.max - last_mean) * fraction.min(1.0))
+    }
+}
+
+// Centroid spans at most 1 of scale, which is steep near the tails
+fn tdigest_scale(q: f64) -> f64 {
+    TDIGEST_COMPRESSION / (2.0 * std::f64::consts::PI) * (2.0 * q - 1.0).min(1.0).asin()
+}
+
 trait OrderByCompatibleAggregator<'de>: Aggregator<'de> {
-    fn aggregate_with_idx(&mut self, value: &VectorReader<'de>) -> ApiResult<usize>;
+    fn aggregate_with_idx(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<usize>;
     fn record_random_iter(&self) -> Box<dyn RecordRandomIterator<'de> + '_>;
     fn query_order_by_value(&self, idx: usize, proj_idx: usize) -> ApiResult<ValueRef<'de>>;
 }
 
 trait RecordRandomIterator<'de> {
     fn at(&mut self, idx: usize) -> ApiResult<&dyn RecordRef<'de>>;
+    // `false` if record is filtered out by HAVING
+    fn is_visible(&self, _: usize) -> ApiResult<bool> {
+        Ok(true)
+    }
 }
 
 struct ColumnsAggregator<'de> {
-    columns: ColumnSet,
-    aggregated: Vec<Vec<Reader<'de>>>,
+    columns: Vec<Expression>,
+    aggregated: Vec<Vec<ValueRef<'de>>>,
 }
 
 impl<'de> ColumnsAggregator<'de> {
-    fn new(columns: ColumnSet) -> Self {
+    fn new(columns: Vec<Expression>) -> Self {
         Self {
             columns,
             aggregated: Vec::new(),
@@ -341,11 +789,10 @@ impl<'de> ColumnsAggregator<'de> {
 }
 
 impl<'de> Aggregator<'de> for ColumnsAggregator<'de> {
-    fn aggregate(&mut self, value: &VectorReader<'de>) -> ApiResult<()> {
+    fn aggregate(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<()> {
         let mut record = Vec::with_capacity(self.columns.len());
-        for col_idx in self.columns.iter() {
-            let reader = value.index(*col_idx)?;
-            record.push(reader);
+        for column in self.columns.iter() {
+            record.push(column.eval(value)?);
         }
         self.aggregated.push(record);
         Ok(())
@@ -357,7 +804,7 @@ impl<'de> Aggregator<'de> for ColumnsAggregator<'de> {
 }
 
 impl<'de> OrderByCompatibleAggregator<'de> for ColumnsAggregator<'de> {
-    fn aggregate_with_idx(&mut self, value: &VectorReader<'de>) -> ApiResult<usize> {
+    fn aggregate_with_idx(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<usize> {
         self.aggregate(value)?;
         if self.aggregated.is_empty() {
             return invalid_data_ae!("nothing aggregated");
@@ -377,11 +824,11 @@ impl<'de> OrderByCompatibleAggregator<'de> for ColumnsAggregator<'de> {
 }
 
 struct ColumnsRecordRandomIterator<'a, 'de: 'a> {
-    aggregated: &'a Vec<Vec<Reader<'de>>>,
+    aggregated: &'a Vec<Vec<ValueRef<'de>>>,
 }
 
 impl<'a, 'de: 'a> ColumnsRecordRandomIterator<'a, 'de> {
-    fn new(aggregated: &'a Vec<Vec<Reader<'de>>>) -> Self {
+    fn new(aggregated: &'a Vec<Vec<ValueRef<'de>>>) -> Self {
         Self { aggregated }
     }
 }
@@ -396,11 +843,11 @@ impl<'a, 'de: 'a> RecordRandomIterator<'de> for ColumnsRecordRandomIterator<'a,
 }
 
 struct ColumnsRecordIterator<'a, 'de: 'a> {
-    iter: std::slice::Iter<'a, Vec<Reader<'de>>>,
+    iter: std::slice::Iter<'a, Vec<ValueRef<'de>>>,
 }
 
 impl<'a, 'de: 'a> ColumnsRecordIterator<'a, 'de> {
-    fn new(iter: std::slice::Iter<'a, Vec<Reader<'de>>>) -> Self {
+    fn new(iter: std::slice::Iter<'a, Vec<ValueRef<'de>>>) -> Self {
         Self { iter }
     }
 }
@@ -414,70 +861,124 @@ impl<'a, 'de: 'a> RecordIterator<'de> for ColumnsRecordIterator<'a, 'de> {
     }
 }
 
-impl<'de> RecordRef<'de> for Vec<Reader<'de>> {
+impl<'de> RecordRef<'de> for Vec<ValueRef<'de>> {
     fn len(&self) -> usize {
         self.len()
     }
     fn value_at(&self, idx: usize) -> ApiResult<ValueRef<'de>> {
-        guard!(let Some(reader) = self.get(idx) else {
+        guard!(let Some(value) = self.get(idx) else {
             return invalid_data_ae!("out of bounds");
         });
-        let result = ValueRef::from_reader(reader)?;
-        Ok(result)
+        Ok(value.clone())
+    }
+}
+
+// Key of group or distinct row, single column keys are kept without allocation
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum GroupKey<'de> {
+    One(ValueOrdRef<'de>),
+    Many(Vec<ValueOrdRef<'de>>),
+}
+
+impl<'de> GroupKey<'de> {
+    fn from_record(record: &dyn RecordRef<'de>, exprs: &[Expression]) -> ApiResult<Self> {
+        if let [expr] = exprs {
+            return Ok(Self::One(expr.eval(record)?.ord_ref()));
+        }
+        let mut key = Vec::with_capacity(exprs.len());
+        for expr in exprs.iter() {
+            key.push(expr.eval(record)?.ord_ref());
+        }
+        Ok(Self::Many(key))
+    }
+
+    fn value_at(&self, idx: usize) -> ApiResult<ValueRef<'de>> {
+        let value = match self {
+            Self::One(v) if idx == 0 => v,
+            Self::Many(key) if idx < key.len() => &key[idx],
+            _ => return invalid_data_ae!("out of bounds"),
+        };
+        Ok(value.as_value_ref())
     }
 }
 
 struct GroupByAggregator<'de> {
-    column_id: usize,
+    key: Vec<Expression>,
     signature: Vec<GroupBySigType>,
-    aggregated: IndexMap<ValueOrdRef<'de>, Vec<GroupByItem<'de>>>,
+    // Count of projections, hidden aggregates of HAVING follow them in signature
+    len: usize,
+    having: Option<Having>,
+    aggregated: IndexMap<GroupKey<'de>, Vec<GroupByItem<'de>>>,
 }
 
 enum GroupBySigType {
     Fun(FunctionAggregatorType),
-    GroupByProjection,
+    // Index of column in group key
+    GroupByProjection(usize),
+    // NULL of column which isn't in grouping set or value of `GROUPING()`
+    Constant(ValueRef<'static>),
 }
 
 impl GroupBySigType {
     fn as_new_item<'de>(&self) -> GroupByItem<'de> {
-        match *self {
-            Self::Fun(ft) => GroupByItem::Fun(FunctionAggregator::new(ft)),
-            Self::GroupByProjection => GroupByItem::GroupByProjection,
+        match self {
+            Self::Fun(ft) => GroupByItem::Fun(FunctionAggregator::new(*ft)),
+            Self::GroupByProjection(idx) => GroupByItem::GroupByProjection(*idx),
+            Self::Constant(v) => GroupByItem::Constant(v.clone()),
         }
     }
 }
 
 enum GroupByItem<'de> {
     Fun(FunctionAggregator<'de>),
-    GroupByProjection,
+    GroupByProjection(usize),
+    Constant(ValueRef<'static>),
 }
 
 impl<'de> GroupByAggregator<'de> {
-    fn new(column_id: usize, signature: Vec<GroupBySigType>) -> Self {
+    fn new(
+        key: Vec<Expression>,
+        signature: Vec<GroupBySigType>,
+        len: usize,
+        having: Option<Having>,
+    ) -> Self {
         Self {
-            column_id,
+            key,
             signature,
+            len,
+            having,
             aggregated: Default::default(),
         }
     }
+
+    fn projects_key(&self) -> bool {
+        (0..self.key.len()).all(|idx| {
+            self.signature[..self.len]
+                .iter()
+                .any(|s| matches!(s, GroupBySigType::GroupByProjection(p) if *p == idx))
+        })
+    }
 }
 
 impl<'de> Aggregator<'de> for GroupByAggregator<'de> {
-    fn aggregate(&mut self, value: &VectorReader<'de>) -> ApiResult<()> {
+    fn aggregate(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<()> {
         let _ = self.aggregate_with_idx(value)?;
         Ok(())
     }
 
     fn iter(&self) -> BoxedRecordIterator<'_, 'de> {
-        Box::new(GroupByIterator::new(self.aggregated.iter()))
+        Box::new(GroupByIterator::new(
+            self.aggregated.iter(),
+            self.len,
+            self.having.as_ref(),
+        ))
     }
 }
 
 impl<'de> OrderByCompatibleAggregator<'de> for GroupByAggregator<'de> {
-    fn aggregate_with_idx(&mut self, value: &VectorReader<'de>) -> ApiResult<usize> {
-        let reader = value.index(self.column_id)?;
-        let key = ValueRef::from_reader(&reader)?;
-        let entry = self.aggregated.entry(key.ord_ref());
+    fn aggregate_with_idx(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<usize> {
+        let key = GroupKey::from_record(value, &self.key)?;
+        let entry = self.aggregated.entry(key);
         let idx = entry.index();
         let sig = &self.signature;
         let v = entry.or_insert_with(|| sig.iter().map(|s| s.as_new_item()).collect());
@@ -491,51 +992,83 @@ impl<'de> OrderByCompatibleAggregator<'de> for GroupByAggregator<'de> {
     }
 
     fn record_random_iter(&self) -> Box<dyn RecordRandomIterator<'de> + '_> {
-        Box::new(GroupByRandomIterator::new(&self.aggregated))
+        Box::new(GroupByRandomIterator::new(
+            &self.aggregated,
+            self.len,
+            self.having.as_ref(),
+        ))
     }
 
     fn query_order_by_value(&self, idx: usize, proj_idx: usize) -> ApiResult<ValueRef<'de>> {
-        GroupByRandomIterator::new(&self.aggregated)
+        GroupByRandomIterator::new(&self.aggregated, self.len, None)
             .at(idx)?
             .value_at(proj_idx)
     }
 }
 
 struct GroupByRandomIterator<'a, 'de: 'a> {
-    aggregated: &'a IndexMap<ValueOrdRef<'de>, Vec<GroupByItem<'de>>>,
-    cur_item: Option<(&'a ValueOrdRef<'de>, &'a Vec<GroupByItem<'de>>)>,
+    aggregated: &'a IndexMap<GroupKey<'de>, Vec<GroupByItem<'de>>>,
+    len: usize,
+    having: Option<&'a Having>,
+    cur_item: Option<GroupByRecord<'a, 'de>>,
 }
 
 impl<'a, 'de: 'a> GroupByRandomIterator<'a, 'de> {
-    fn new(aggregated: &'a IndexMap<ValueOrdRef<'de>, Vec<GroupByItem<'de>>>) -> Self {
+    fn new(
+        aggregated: &'a IndexMap<GroupKey<'de>, Vec<GroupByItem<'de>>>,
+        len: usize,
+        having: Option<&'a Having>,
+    ) -> Self {
         Self {
             aggregated,
+            len,
+            having,
             cur_item: None,
         }
     }
+
+    fn record(&self, idx: usize) -> ApiResult<GroupByRecord<'a, 'de>> {
+        guard!(let Some((key, items)) = self.aggregated.get_index(idx) else {
+            return invalid_data_ae!("out of bounds");
+        });
+        Ok(GroupByRecord {
+            key,
+            items,
+            len: self.len,
+        })
+    }
 }
 
 impl<'a, 'de: 'a> RecordRandomIterator<'de> for GroupByRandomIterator<'a, 'de> {
     fn at(&mut self, idx: usize) -> ApiResult<&dyn RecordRef<'de>> {
-        self.cur_item = self.aggregated.get_index(idx);
+        self.cur_item = Some(self.record(idx)?);
         guard!(let Some(cur) = self.cur_item.as_ref() else {
             return invalid_data_ae!("out of bounds");
         });
         Ok(cur)
     }
+
+    fn is_visible(&self, idx: usize) -> ApiResult<bool> {
+        guard!(let Some(having) = self.having else { return Ok(true) });
+        having_matches(having, &self.record(idx)?)
+    }
 }
 
-type GroupByInnerIter<'a, 'de> = indexmap::map::Iter<'a, ValueOrdRef<'de>, Vec<GroupByItem<'de>>>;
+type GroupByInnerIter<'a, 'de> = indexmap::map::Iter<'a, GroupKey<'de>, Vec<GroupByItem<'de>>>;
 
 struct GroupByIterator<'a, 'de: 'a> {
     iter: GroupByInnerIter<'a, 'de>,
-    cur_item: Option<(&'a ValueOrdRef<'de>, &'a Vec<GroupByItem<'de>>)>,
+    len: usize,
+    having: Option<&'a Having>,
+    cur_item: Option<GroupByRecord<'a, 'de>>,
 }
 
 impl<'a, 'de> GroupByIterator<'a, 'de> {
-    fn new(iter: GroupByInnerIter<'a, 'de>) -> Self {
+    fn new(iter: GroupByInnerIter<'a, 'de>, len: usize, having: Option<&'a Having>) -> Self {
         Self {
             iter,
+            len,
+            having,
             cur_item: None,
         }
     }
@@ -543,36 +1076,187 @@ impl<'a, 'de> GroupByIterator<'a, 'de> {
 
 impl<'a, 'de> RecordIterator<'de> for GroupByIterator<'a, 'de> {
     fn next(&mut self) -> ApiResult<Option<RecordIteratorItem<'_, 'de>>> {
-        guard!(let Some(next) = self.iter.next() else { return Ok(None); });
-        self.cur_item = Some(next);
+        loop {
+            guard!(let Some((key, items)) = self.iter.next() else { return Ok(None); });
+            let record = GroupByRecord {
+                key,
+                items,
+                len: self.len,
+            };
+            if let Some(having) = self.having {
+                if !having_matches(having, &record)? {
+                    continue;
+                }
+            }
+            self.cur_item = Some(record);
+            break;
+        }
         guard!(let Some(cur) = self.cur_item.as_ref() else { return Ok(None); });
         Ok(Some(cur))
     }
 }
 
-impl<'de> RecordRef<'de> for (&ValueOrdRef<'de>, &Vec<GroupByItem<'de>>) {
+struct GroupByRecord<'a, 'de: 'a> {
+    key: &'a GroupKey<'de>,
+    items: &'a Vec<GroupByItem<'de>>,
+    len: usize,
+}
+
+impl<'a, 'de: 'a> RecordRef<'de> for GroupByRecord<'a, 'de> {
     fn len(&self) -> usize {
-        self.1.len()
+        self.len
     }
 
+    // Hidden aggregates after `len` are readable too, HAVING is evaluated on them
     fn value_at(&self, idx: usize) -> ApiResult<ValueRef<'de>> {
-        if idx > self.1.len() - 1 {
+        guard!(let Some(item) = self.items.get(idx) else {
             return invalid_data_ae!("out of bounds");
-        }
-        let result = match &self.1[idx] {
+        });
+        let result = match item {
             GroupByItem::Fun(f) => f.value(),
-            GroupByItem::GroupByProjection => self.0.as_value_ref(),
+            GroupByItem::GroupByProjection(idx) => self.key.value_at(*idx)?,
+            GroupByItem::Constant(v) => v.clone(),
         };
         Ok(result)
     }
 }
 
+struct GroupingSetsAggregator<'de> {
+    sets: Vec<GroupByAggregator<'de>>,
+    order_by: Option<(usize, bool)>,
+    distinct: bool,
+}
+
+impl<'de> GroupingSetsAggregator<'de> {
+    fn new(sets: Vec<GroupByAggregator<'de>>) -> Self {
+        Self {
+            sets,
+            order_by: None,
+            distinct: false,
+        }
+    }
+
+    // Sets could give equal rows, e.g. NULL group of column and row where it's rolled up
+    fn with_distinct(mut self) -> Self {
+        self.distinct = true;
+        self
+    }
+
+    // Rows of all sets are sorted together when they are requested
+    fn with_order_by(mut self, proj_idx: usize, asc: bool) -> Self {
+        self.order_by = Some((proj_idx, asc));
+        self
+    }
+
+    fn rows(&self) -> ApiResult<Vec<Vec<ValueRef<'de>>>> {
+        let mut rows = Vec::new();
+        let mut seen = HashSet::new();
+        for set in self.sets.iter() {
+            let mut iter = set.iter();
+            while let Some(record) = iter.next()? {
+                let mut row = Vec::with_capacity(record.len());
+                for idx in 0..record.len() {
+                    row.push(record.value_at(idx)?);
+                }
+                if self.distinct {
+                    let key: Vec<ValueOrdRef> = row.iter().map(|v| v.clone().ord_ref()).collect();
+                    if !seen.insert(key) {
+                        continue;
+                    }
+                }
+                rows.push(row);
+            }
+        }
+        if let Some((idx, asc)) = self.order_by {
+            rows.sort_by(|a, b| {
+                let result = a[idx].clone().ord_ref().cmp(&b[idx].clone().ord_ref());
+                if asc {
+                    result
+                } else {
+                    result.reverse()
+                }
+            });
+        }
+        Ok(rows)
+    }
+}
+
+impl<'de> Aggregator<'de> for GroupingSetsAggregator<'de> {
+    fn aggregate(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<()> {
+        for set in self.sets.iter_mut() {
+            set.aggregate(value)?;
+        }
+        Ok(())
+    }
+
+    fn iter(&self) -> BoxedRecordIterator<'_, 'de> {
+        Box::new(OwnedRecordIterator::new(self.rows().map(Rc::new)))
+    }
+}
+
+fn having_matches<'de>(having: &Having, record: &dyn RecordRef<'de>) -> ApiResult<bool> {
+    Ok(eval_having(having, record)? == Some(true))
+}
+
+// Three-valued logic of SQL, comparison with NULL is unknown (`None`)
+fn eval_having<'de>(having: &Having, record: &dyn RecordRef<'de>) -> ApiResult<Option<bool>> {
+    let result = match having {
+        Having::Compare(left, op, right) => {
+            let left = having_operand_value(left, record)?;
+            let right = having_operand_value(right, record)?;
+            match (&left, &right) {
+                (ValueOrdRef::Null, _) | (_, ValueOrdRef::Null) => None,
+                _ => Some(op.matches(&left, &right)),
+            }
+        }
+        Having::IsNull(o) => Some(matches!(
+            having_operand_value(o, record)?,
+            ValueOrdRef::Null
+        )),
+        Having::IsNotNull(o) => Some(!matches!(
+            having_operand_value(o, record)?,
+            ValueOrdRef::Null
+        )),
+        Having::And(left, right) => {
+            match (eval_having(left, record)?, eval_having(right, record)?) {
+                (Some(false), _) | (_, Some(false)) => Some(false),
+                (Some(true), Some(true)) => Some(true),
+                _ => None,
+            }
+        }
+        Having::Or(left, right) => {
+            match (eval_having(left, record)?, eval_having(right, record)?) {
+                (Some(true), _) | (_, Some(true)) => Some(true),
+                (Some(false), Some(false)) => Some(false),
+                _ => None,
+            }
+        }
+        Having::Not(h) => eval_having(h, record)?.map(|v| !v),
+    };
+    Ok(result)
+}
+
+fn having_operand_value<'a, 'de: 'a>(
+    operand: &'a HavingOperand,
+    record: &dyn RecordRef<'de>,
+) -> ApiResult<ValueOrdRef<'a>> {
+    let result = match operand {
+        HavingOperand::Projection(idx) => record.value_at(*idx)?.ord_ref(),
+        HavingOperand::Value(v) => v.as_ord_ref(),
+        HavingOperand::Count => return invalid_data_ae!("having count() is not bound"),
+    };
+    Ok(result)
+}
+
 struct OrderByAggregator<'de, T: OrderByCompatibleAggregator<'de>> {
     inner: T,
     order_by_type: OrderByAggType,
     asc: bool,
-    rec_values: HashMap<usize, ValueOrdRef<'de>>,
-    ordered_recs: BTreeMap<ValueOrdRef<'de>, HashSet<usize>>,
+    // Records in order of aggregation, key of ORDER BY column is taken from input row,
+    // keys of projections are computed once all rows are aggregated
+    keys: IndexMap<usize, Option<ValueOrdRef<'de>>>,
+    // Sorted records, computed by the first `iter`, reset once more input is aggregated
+    ordered: RefCell<Option<Rc<Vec<usize>>>>,
 }
 
 fn boxed_order_by_aggregator<'ret, 'de: 'ret>(
@@ -591,6 +1275,12 @@ fn boxed_order_by_aggregator<'ret, 'de: 'ret>(
             }
             _ => Ok(OrderByAggregator::new(a, order_by_type, asc)),
         },
+        AnyBaseAggregator::GroupingSets(a) => match order_by_type {
+            OrderByAggType::ColumnId(_) => {
+                invalid_data_ae!("order_by column is not in group_by projection")
+            }
+            OrderByAggType::ProjectionId(idx) => Ok(Box::new(a.with_order_by(idx, asc))),
+        },
     }
 }
 
@@ -600,136 +1290,134 @@ impl<'de, T: OrderByCompatibleAggregator<'de>> OrderByAggregator<'de, T> {
             inner,
             order_by_type,
             asc,
-            rec_values: HashMap::new(),
-            ordered_recs: BTreeMap::new(),
+            keys: IndexMap::new(),
+            ordered: RefCell::new(None),
         })
     }
 
-    fn key_value_for(
-        &self,
-        value: &VectorReader<'de>,
-        last_idx: usize,
-    ) -> ApiResult<ValueOrdRef<'de>> {
-        let result = match self.order_by_type {
-            OrderByAggType::ColumnId(c) => {
-                let reader = value.index(c)?;
-                ValueRef::from_reader(&reader)?.ord_ref()
-            }
-            OrderByAggType::ProjectionId(p) => {
-                self.inner.query_order_by_value(last_idx, p)?.ord_ref()
-            }
-        };
-        Ok(result)
-    }
-
-    fn check_need_insert(
-        &mut self,
-        key_value: &ValueOrdRef<'de>,
-        last_idx: usize,
-    ) -> ApiResult<bool> {
-        let entry = self.rec_values.entry(last_idx.clone());
-        use std::collections::hash_map::Entry;
-
-        let result = match entry {
-            Entry::Occupied(ref entry) if entry.get().eq(&key_value) => false,
-            Entry::Occupied(mut entry) => {
-                match self.ordered_recs.entry(entry.get().clone()) {
-                    std::collections::btree_map::Entry::Occupied(mut old_entry) => {
-                        let old_set = old_entry.get_mut();
-                        if old_set.len() == 1 {
-                            old_entry.remove();
-                        } else {
-                            old_set.remove(&last_idx);
-                        }
-                    }
-                    _ => return invalid_data_ae!("query consistency corruption"),
+    // Sort is stable, records with equal keys are kept in order of aggregation
+    fn ordered_ids(&self) -> ApiResult<Vec<usize>> {
+        let mut keyed = Vec::with_capacity(self.keys.len());
+        for (idx, key) in self.keys.iter() {
+            let key = match (key, &self.order_by_type) {
+                (Some(key), _) => key.clone(),
+                (None, OrderByAggType::ProjectionId(p)) => {
+                    self.inner.query_order_by_value(*idx, *p)?.ord_ref()
                 }
-                entry.insert(key_value.clone());
-                true
-            }
-            Entry::Vacant(entry) => {
-                entry.insert(key_value.clone());
-                true
+                (None, OrderByAggType::ColumnId(_)) => {
+                    return invalid_data_ae!("query consistency corruption")
+                }
+            };
+            keyed.push((key, *idx));
+        }
+        keyed.sort_by(|a, b| {
+            let result = a.0.cmp(&b.0);
+            if self.asc {
+                result
+            } else {
+                result.reverse()
             }
-        };
-        Ok(result)
+        });
+        Ok(keyed.into_iter().map(|(_, idx)| idx).collect())
     }
 }
 
 impl<'de, T: OrderByCompatibleAggregator<'de>> Aggregator<'de> for OrderByAggregator<'de, T> {
-    fn aggregate(&mut self, value: &VectorReader<'de>) -> ApiResult<()> {
+    fn aggregate(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<()> {
         let last_idx = self.inner.aggregate_with_idx(value)?;
-        let key_value = self.key_value_for(value, last_idx)?;
-
-        if self.check_need_insert(&key_value, last_idx.clone())? {
-            if !self
-                .ordered_recs
-                .entry(key_value)
-                .or_insert_with(|| HashSet::new())
-                .insert(last_idx)
-            {
-                return invalid_data_ae!("query consistency corruption");
-            }
-        }
+        let key = match self.order_by_type {
+            OrderByAggType::ColumnId(c) => Some(value.value_at(c)?.ord_ref()),
+            OrderByAggType::ProjectionId(_) => None,
+        };
+        self.keys.insert(last_idx, key);
+        *self.ordered.get_mut() = None;
         Ok(())
     }
 
     fn iter(&self) -> BoxedRecordIterator<'_, 'de> {
-        if self.asc {
-            Box::new(OrderByIterator::new(
-                self.ordered_recs.iter(),
-                self.inner.record_random_iter(),
-            ))
-        } else {
-            Box::new(OrderByIterator::new(
-                self.ordered_recs.iter().rev(),
-                self.inner.record_random_iter(),
-            ))
-        }
+        let cached = self.ordered.borrow().clone();
+        let ids = match cached {
+            Some(ids) => Ok(ids),
+            None => self.ordered_ids().map(|ids| {
+                let ids = Rc::new(ids);
+                *self.ordered.borrow_mut() = Some(ids.clone());
+                ids
+            }),
+        };
+        Box::new(OrderByIterator::new(ids, self.inner.record_random_iter()))
     }
 }
 
-type OrderedIdsIterItem<'a, 'de> = (&'a ValueOrdRef<'de>, &'a HashSet<usize>);
-
-struct OrderByIterator<'a, 'de: 'a, I>
-where
-    I: Iterator<Item = OrderedIdsIterItem<'a, 'de>>,
-{
-    inner_iter: I,
+// Error of ordering is returned by the first `next`
+struct OrderByIterator<'a, 'de: 'a> {
+    error: Option<ApiError>,
+    ids: Rc<Vec<usize>>,
+    pos: usize,
     rec_rand_iter: Box<dyn RecordRandomIterator<'de> + 'a>,
-    rec_ids_iter: Option<std::collections::hash_set::Iter<'a, usize>>,
 }
 
-impl<'a, 'de: 'a, I> OrderByIterator<'a, 'de, I>
-where
-    I: Iterator<Item = OrderedIdsIterItem<'a, 'de>>,
-{
-    fn new(inner_iter: I, rec_rand_iter: Box<dyn RecordRandomIterator<'de> + 'a>) -> Self {
+impl<'a, 'de: 'a> OrderByIterator<'a, 'de> {
+    fn new(
+        ids: ApiResult<Rc<Vec<usize>>>,
+        rec_rand_iter: Box<dyn RecordRandomIterator<'de> + 'a>,
+    ) -> Self {
+        let (ids, error) = match ids {
+            Ok(ids) => (ids, None),
+            Err(e) => (Rc::new(Vec::new()), Some(e)),
+        };
         Self {
-            inner_iter,
+            error,
+            ids,
+            pos: 0,
             rec_rand_iter,
-            rec_ids_iter: None,
         }
     }
 }
 
-impl<'a, 'de: 'a, I> RecordIterator<'de> for OrderByIterator<'a, 'de, I>
-where
-    I: Iterator<Item = OrderedIdsIterItem<'a, 'de>>,
-{
+impl<'a, 'de: 'a> RecordIterator<'de> for OrderByIterator<'a, 'de> {
     fn next(&mut self) -> ApiResult<Option<RecordIteratorItem<'_, 'de>>> {
-        loop {
-            if let Some(rec_ids_iter) = self.rec_ids_iter.as_mut() {
-                if let Some(idx) = rec_ids_iter.next() {
-                    return Ok(Some(self.rec_rand_iter.at(*idx)?));
-                }
-            }
-            if let Some((_, ids_set)) = self.inner_iter.next() {
-                self.rec_ids_iter = Some(ids_set.iter());
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        while let Some(idx) = self.ids.get(self.pos).cloned() {
+            self.pos += 1;
+            if !self.rec_rand_iter.is_visible(idx)? {
                 continue;
             }
-            return Ok(None);
+            return Ok(Some(self.rec_rand_iter.at(idx)?));
         }
+        Ok(None)
+    }
+}
+
+#[test]
+fn test_tdigest() {
+    let mut digest = TDigest::new();
+    assert_eq!(digest.quantile(0.5), None);
+    for v in [100.0, 5.0, 25.0, 10.0].iter() {
+        digest.insert(*v);
+    }
+    assert_eq!(digest.quantile(0.0), Some(5.0));
+    assert_eq!(digest.quantile(0.25), Some(7.5));
+    assert_eq!(digest.quantile(0.5), Some(17.5));
+    assert_eq!(digest.quantile(1.0), Some(100.0));
+    // Cached centroids are merged again after insert
+    digest.insert(1000.0);
+    assert_eq!(digest.quantile(1.0), Some(1000.0));
+
+    let mut digest = TDigest::new();
+    for v in 0..100_000 {
+        digest.insert(((v * 7919) % 100_000) as f64);
+    }
+    assert!(digest.centroids.len() <= TDIGEST_COMPRESSION as usize + 1);
+    for q in [0.01, 0.5, 0.9, 0.99].iter() {
+        let estimate = digest.quantile(*q).unwrap();
+        assert!(
+            (estimate - q * 100_000.0).abs() < 1000.0,
+            "{} {}",
+            q,
+            estimate
+        );
     }
 }
 
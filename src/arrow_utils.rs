@@ -0,0 +1,304 @@
+use crate::errors::*;
+use crate::export::RowWriter;
+use crate::record::*;
+
+use arrow::array::{
+    ArrayRef, BooleanBuilder, Float64Builder, Int64Builder, StringBuilder, UInt64Builder,
+};
+use arrow::datatypes::{DataType, Field, Schema, SchemaRef};
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use std::cell::RefCell;
+use std::io::Write;
+use std::rc::Rc;
+use std::sync::Arc;
+
+// Rows count of one record batch
+const ARROW_BATCH_ROWS: usize = 8192;
+
+// Column type derived from values of the first batch, widened to fit every one of them
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ArrowColumnType {
+    Null,
+    UInt,
+    Int,
+    Float,
+    Bool,
+    Utf8,
+}
+
+impl ArrowColumnType {
+    pub fn of(value: &ValueRef) -> Self {
+        match value {
+            ValueRef::Null => Self::Null,
+            ValueRef::UInteger(_) => Self::UInt,
+            ValueRef::Integer(_) => Self::Int,
+            ValueRef::Float(_) => Self::Float,
+            ValueRef::Bool(_) => Self::Bool,
+            ValueRef::Str(_) | ValueRef::OwnedStr(_) | ValueRef::Json(_) => Self::Utf8,
+        }
+    }
+
+    pub fn merge(self, other: Self) -> Self {
+        match (self, other) {
+            (t1, t2) if t1 == t2 => t1,
+            (Self::Null, t) | (t, Self::Null) => t,
+            (Self::UInt, Self::Int) | (Self::Int, Self::UInt) => Self::Int,
+            (Self::UInt, Self::Float)
+            | (Self::Float, Self::UInt)
+            | (Self::Int, Self::Float)
+            | (Self::Float, Self::Int) => Self::Float,
+            _ => Self::Utf8,
+        }
+    }
+
+    pub fn data_type(&self) -> DataType {
+        match self {
+            Self::UInt => DataType::UInt64,
+            Self::Int => DataType::Int64,
+            Self::Float => DataType::Float64,
+            Self::Bool => DataType::Boolean,
+            // Column of NULLs only is kept as nullable strings
+            Self::Null | Self::Utf8 => DataType::Utf8,
+        }
+    }
+}
+
+enum ColumnBuilder {
+    UInt(UInt64Builder),
+    Int(Int64Builder),
+    Float(Float64Builder),
+    Bool(BooleanBuilder),
+    Utf8(StringBuilder),
+}
+
+impl ColumnBuilder {
+    fn new(column_type: ArrowColumnType) -> Self {
+        match column_type {
+            ArrowColumnType::UInt => Self::UInt(UInt64Builder::new(ARROW_BATCH_ROWS)),
+            ArrowColumnType::Int => Self::Int(Int64Builder::new(ARROW_BATCH_ROWS)),
+            ArrowColumnType::Float => Self::Float(Float64Builder::new(ARROW_BATCH_ROWS)),
+            ArrowColumnType::Bool => Self::Bool(BooleanBuilder::new(ARROW_BATCH_ROWS)),
+            ArrowColumnType::Null | ArrowColumnType::Utf8 => {
+                Self::Utf8(StringBuilder::new(ARROW_BATCH_ROWS))
+            }
+        }
+    }
+
+    fn append(&mut self, value: &ValueRef) -> ApiResult<()> {
+        match (self, value) {
+            (Self::UInt(b), ValueRef::Null) => b.append_null()?,
+            (Self::Int(b), ValueRef::Null) => b.append_null()?,
+            (Self::Float(b), ValueRef::Null) => b.append_null()?,
+            (Self::Bool(b), ValueRef::Null) => b.append_null()?,
+            (Self::Utf8(b), ValueRef::Null) => b.append_null()?,
+            (Self::UInt(b), ValueRef::UInteger(v)) => b.append_value(*v)?,
+            (Self::UInt(b), ValueRef::Integer(v)) if *v >= 0 => b.append_value(*v as u64)?,
+            (Self::Int(b), ValueRef::Integer(v)) => b.append_value(*v)?,
+            (Self::Int(b), ValueRef::UInteger(v)) if *v <= i64::MAX as u64 => {
+                b.append_value(*v as i64)?
+            }
+            (Self::Float(b), ValueRef::Float(v)) => b.append_value(*v)?,
+            (Self::Float(b), ValueRef::Integer(v)) => b.append_value(*v as f64)?,
+            (Self::Float(b), ValueRef::UInteger(v)) => b.append_value(*v as f64)?,
+            (Self::Bool(b), ValueRef::Bool(v)) => b.append_value(*v)?,
+            (Self::Utf8(b), ValueRef::Str(s)) => b.append_value(s)?,
+            (Self::Utf8(b), value) => b.append_value(&value.to_string())?,
+            (_, value) => {
+                return invalid_data_ae!(
+                    "value {} doesn't fit arrow column typed by the first {} rows",
+                    value,
+                    ARROW_BATCH_ROWS
+                )
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> ArrayRef {
+        match self {
+            Self::UInt(b) => Arc::new(b.finish()),
+            Self::Int(b) => Arc::new(b.finish()),
+            Self::Float(b) => Arc::new(b.finish()),
+            Self::Bool(b) => Arc::new(b.finish()),
+            Self::Utf8(b) => Arc::new(b.finish()),
+        }
+    }
+}
+
+// Collects rows into record batches of `ARROW_BATCH_ROWS`, rows of the first batch are kept
+// until it's full to derive column types, then schema is fixed by `start`
+pub struct BatchBuilder {
+    headers: Vec<String>,
+    types: Vec<ArrowColumnType>,
+    schema: Option<SchemaRef>,
+    pending: Vec<Vec<ValueRef<'static>>>,
+    builders: Vec<ColumnBuilder>,
+    rows: usize,
+}
+
+impl BatchBuilder {
+    pub fn new(headers: Vec<String>) -> Self {
+        let types = vec![ArrowColumnType::Null; headers.len()];
+        Self {
+            headers,
+            types,
+            schema: None,
+            pending: Vec::new(),
+            builders: Vec::new(),
+            rows: 0,
+        }
+    }
+
+    pub fn schema(&self) -> Schema {
+        let fields = self
+            .headers
+            .iter()
+            .zip(self.types.iter())
+            .map(|(name, t)| Field::new(name, t.data_type(), true))
+            .collect();
+        Schema::new(fields)
+    }
+
+    // Fixes schema by types of rows seen so far, pending rows are moved into builders
+    pub fn start(&mut self) -> ApiResult<SchemaRef> {
+        if let Some(schema) = self.schema.as_ref() {
+            return Ok(schema.clone());
+        }
+        let schema = Arc::new(self.schema());
+        self.schema = Some(schema.clone());
+        self.builders = self.types.iter().map(|t| ColumnBuilder::new(*t)).collect();
+        for record in std::mem::take(&mut self.pending) {
+            self.append_to_builders(&record)?;
+        }
+        Ok(schema)
+    }
+
+    // Returns batch once it's full
+    pub fn append(&mut self, record: &dyn RecordRef) -> ApiResult<Option<RecordBatch>> {
+        if self.schema.is_some() {
+            self.append_to_builders(record)?;
+        } else {
+            let mut values = Vec::with_capacity(record.len());
+            for (idx, column_type) in self.types.iter_mut().enumerate().take(record.len()) {
+                let value = record.value_at(idx)?;
+                *column_type = column_type.merge(ArrowColumnType::of(&value));
+                values.push(value.into_owned());
+            }
+            self.pending.push(values);
+            if self.pending.len() < ARROW_BATCH_ROWS {
+                return Ok(None);
+            }
+            self.start()?;
+        }
+        if self.rows >= ARROW_BATCH_ROWS {
+            return self.finish_batch();
+        }
+        Ok(None)
+    }
+
+    fn append_to_builders(&mut self, record: &dyn RecordRef) -> ApiResult<()> {
+        for (idx, builder) in self.builders.iter_mut().enumerate() {
+            // Short rows of NDJSON import are padded with NULLs
+            let value = if idx < record.len() {
+                record.value_at(idx)?
+            } else {
+                ValueRef::Null
+            };
+            builder.append(&value)?;
+        }
+        self.rows += 1;
+        Ok(())
+    }
+
+    // Result shorter than one batch is typed by all its rows
+    pub fn finish_batch(&mut self) -> ApiResult<Option<RecordBatch>> {
+        let schema = self.start()?;
+        if self.rows == 0 {
+            return Ok(None);
+        }
+        let columns = self.builders.iter_mut().map(|b| b.finish()).collect();
+        self.rows = 0;
+        Ok(Some(RecordBatch::try_new(schema, columns)?))
+    }
+}
+
+// IPC output is collected here and moved to export chunks after every message
+#[derive(Clone, Default)]
+struct SharedBuf(Rc<RefCell<Vec<u8>>>);
+
+impl SharedBuf {
+    fn take_into(&self, out: &mut Vec<u8>) {
+        out.append(&mut self.0.borrow_mut());
+    }
+}
+
+impl Write for SharedBuf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+// Writes Arrow IPC stream: schema message, then record batch for every `ARROW_BATCH_ROWS` rows,
+// schema is written along with the first batch
+pub struct ArrowWriter {
+    batches: BatchBuilder,
+    buf: SharedBuf,
+    writer: Option<StreamWriter<SharedBuf>>,
+}
+
+impl ArrowWriter {
+    pub fn new(headers: Vec<String>) -> Self {
+        Self {
+            batches: BatchBuilder::new(headers),
+            buf: SharedBuf::default(),
+            writer: None,
+        }
+    }
+
+    pub fn schema(&self) -> Schema {
+        self.batches.schema()
+    }
+
+    fn start_writer(&mut self, schema: &Schema) -> ApiResult<&mut StreamWriter<SharedBuf>> {
+        if self.writer.is_none() {
+            self.writer = Some(StreamWriter::try_new(self.buf.clone(), schema)?);
+        }
+        guard!(let Some(writer) = self.writer.as_mut() else {
+            return invalid_data_ae!("arrow schema is not written");
+        });
+        Ok(writer)
+    }
+
+    fn write_batch(&mut self, batch: Option<RecordBatch>, out: &mut Vec<u8>) -> ApiResult<()> {
+        guard!(let Some(batch) = batch else { return Ok(()) });
+        self.start_writer(&batch.schema())?.write(&batch)?;
+        self.buf.take_into(out);
+        Ok(())
+    }
+}
+
+impl RowWriter for ArrowWriter {
+    fn write_headers(&mut self, _: &mut Vec<u8>) -> ApiResult<()> {
+        Ok(())
+    }
+
+    fn write_row(&mut self, record: &dyn RecordRef, out: &mut Vec<u8>) -> ApiResult<()> {
+        let batch = self.batches.append(record)?;
+        self.write_batch(batch, out)
+    }
+
+    fn finish(&mut self, out: &mut Vec<u8>) -> ApiResult<()> {
+        let batch = self.batches.finish_batch()?;
+        self.write_batch(batch, out)?;
+        // Empty result has schema only
+        let schema = self.batches.start()?;
+        self.start_writer(&schema)?.finish()?;
+        self.buf.take_into(out);
+        Ok(())
+    }
+}
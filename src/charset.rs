@@ -0,0 +1,176 @@
+use crate::compression::BytesStream;
+use crate::errors::*;
+
+use bytes::Bytes;
+use encoding_rs::{CoderResult, Decoder, DecoderResult, Encoding, UTF_8};
+use futures::future;
+use futures::stream::{self, StreamExt, TryStreamExt};
+use tokio::io::Result;
+
+// Input charset of import, `encoding` is `None` if not set by client,
+// in this case input is treated as UTF-8 unless BOM says otherwise
+#[derive(Debug, Copy, Clone)]
+pub struct Charset {
+    pub encoding: Option<&'static Encoding>,
+    // Reject malformed input instead of replacing it with U+FFFD
+    pub strict: bool,
+}
+
+impl Charset {
+    pub fn new(label: Option<&str>, strict: bool) -> ApiResult<Self> {
+        let encoding = match label {
+            Some(label) => match Encoding::for_label(label.trim().as_bytes()) {
+                Some(encoding) => Some(encoding),
+                None => return invalid_data_ae!("unknown encoding: {}", label),
+            },
+            None => None,
+        };
+        Ok(Self { encoding, strict })
+    }
+}
+
+impl Default for Charset {
+    fn default() -> Self {
+        Self {
+            encoding: None,
+            strict: false,
+        }
+    }
+}
+
+struct TranscodeState {
+    stream: BytesStream,
+    decoder: Decoder,
+    strict: bool,
+    // Count of input bytes consumed, for error reporting
+    offset: u64,
+    finished: bool,
+}
+
+impl TranscodeState {
+    fn decode(&mut self, src: &[u8], last: bool) -> Result<Bytes> {
+        let mut dst = String::new();
+        let mut read_total = 0;
+        loop {
+            let input = &src[read_total..];
+            if self.strict {
+                let needed = self
+                    .decoder
+                    .max_utf8_buffer_length_without_replacement(input.len());
+                guard!(let Some(needed) = needed else { return invalid_data_e!("too big chunk") });
+                dst.reserve(needed);
+                let (result, read) = self
+                    .decoder
+                    .decode_to_string_without_replacement(input, &mut dst, last);
+                read_total += read;
+                match result {
+                    DecoderResult::InputEmpty => break,
+                    DecoderResult::OutputFull => continue,
+                    DecoderResult::Malformed(bad, after) => {
+                        // Malformed bytes could start in previous chunk
+                        let offset = (self.offset + read_total as u64)
+                            .saturating_sub(bad as u64 + after as u64);
+                        return invalid_data_e!(
+                            "invalid {} input at byte offset {}",
+                            self.decoder.encoding().name(),
+                            offset
+                        );
+                    }
+                }
+            } else {
+                let needed = self.decoder.max_utf8_buffer_length(input.len());
+                guard!(let Some(needed) = needed else { return invalid_data_e!("too big chunk") });
+                dst.reserve(needed);
+                let (result, read, _) = self.decoder.decode_to_string(input, &mut dst, last);
+                read_total += read;
+                match result {
+                    CoderResult::InputEmpty => break,
+                    CoderResult::OutputFull => continue,
+                }
+            }
+        }
+        self.offset += src.len() as u64;
+        Ok(Bytes::from(dst))
+    }
+}
+
+// UTF-8 BOM is the longest one
+const MAX_BOM_LEN: usize = 3;
+
+// Transcodes stream into UTF-8 chunk by chunk, BOM has priority over requested encoding.
+// Plain UTF-8 input without BOM is passed as is, unless strict mode is requested
+pub async fn transcode_stream(charset: Charset, stream: BytesStream) -> BytesStream {
+    transcode_stream_bom(charset, stream).await.0
+}
+
+// Same as `transcode_stream`, also returns encoding and length of BOM removed from input
+pub async fn transcode_stream_bom(
+    charset: Charset,
+    stream: BytesStream,
+) -> (BytesStream, Option<(&'static Encoding, usize)>) {
+    let mut stream = stream;
+    // BOM could be split between chunks, so chunks are peeked until it's complete
+    let mut head = Vec::with_capacity(MAX_BOM_LEN);
+    let mut peeked = Vec::new();
+    while head.len() < MAX_BOM_LEN {
+        match stream.next().await {
+            Some(Ok(bytes)) => {
+                let len = bytes.len().min(MAX_BOM_LEN - head.len());
+                head.extend_from_slice(&bytes[..len]);
+                peeked.push(Ok(bytes));
+            }
+            Some(Err(e)) => {
+                peeked.push(Err(e));
+                break;
+            }
+            None => break,
+        }
+    }
+    if peeked.is_empty() {
+        return (Box::pin(stream::empty()), None);
+    }
+
+    let bom = Encoding::for_bom(&head);
+
+    // Put peeked chunks back in front of the stream
+    let stream: BytesStream = Box::pin(stream::iter(peeked).chain(stream));
+
+    if charset.encoding.is_none() && !charset.strict && bom.is_none() {
+        return (stream, None);
+    }
+
+    let state = TranscodeState {
+        stream,
+        // Decoder sniffs BOM and removes it from output
+        decoder: charset.encoding.unwrap_or(UTF_8).new_decoder(),
+        strict: charset.strict,
+        offset: 0,
+        finished: false,
+    };
+
+    let transcoded = stream::unfold(state, |mut state| async move {
+        if state.finished {
+            return None;
+        }
+        let (bytes, last) = match state.stream.next().await {
+            Some(Ok(bytes)) => (bytes, false),
+            Some(Err(e)) => {
+                state.finished = true;
+                return Some((Err(e), state));
+            }
+            None => {
+                state.finished = true;
+                (Bytes::new(), true)
+            }
+        };
+        let result = state.decode(bytes.as_ref(), last);
+        if result.is_err() {
+            state.finished = true;
+        }
+        Some((result, state))
+    });
+
+    // Chunk could be consumed by decoder completely, e.g. BOM or part of multibyte sequence
+    let transcoded = transcoded.try_filter(|bytes| future::ready(!bytes.is_empty()));
+    (Box::pin(transcoded), bom)
+}
@@ -0,0 +1,125 @@
+use crate::misc_utils::*;
+
+use async_compression::tokio_02::bufread::{GzipDecoder, ZstdDecoder};
+use bytes::Bytes;
+use futures::stream::{self, Stream, StreamExt};
+use rocket::http::Status;
+use rocket::request;
+use std::pin::Pin;
+use tokio::io::Result;
+
+pub type BytesStream = Pin<Box<dyn Stream<Item = Result<Bytes>> + Send>>;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ContentEncoding {
+    Identity,
+    Gzip,
+    Zstd,
+}
+
+impl ContentEncoding {
+    pub fn from_header(value: &str) -> Option<Self> {
+        match value.trim().to_lowercase().as_str() {
+            "identity" => Some(Self::Identity),
+            "gzip" | "x-gzip" => Some(Self::Gzip),
+            "zstd" => Some(Self::Zstd),
+            _ => None,
+        }
+    }
+
+    pub fn from_magic(data: &[u8]) -> Self {
+        if data.starts_with(GZIP_MAGIC) {
+            Self::Gzip
+        } else if data.starts_with(ZSTD_MAGIC) {
+            Self::Zstd
+        } else {
+            Self::Identity
+        }
+    }
+}
+
+// `Content-Encoding` of request body, `None` if header is absent,
+// in this case encoding is detected by magic bytes of the first chunk
+#[derive(Debug, Copy, Clone)]
+pub struct RequestEncoding(pub Option<ContentEncoding>);
+
+#[rocket::async_trait]
+impl<'a, 'r> request::FromRequest<'a, 'r> for RequestEncoding {
+    type Error = ();
+
+    async fn from_request(req: &'a request::Request<'r>) -> request::Outcome<Self, ()> {
+        guard!(let Some(value) = req.headers().get_one("Content-Encoding") else {
+            return request::Outcome::Success(Self(None));
+        });
+        match ContentEncoding::from_header(value) {
+            Some(encoding) => request::Outcome::Success(Self(Some(encoding))),
+            None => request::Outcome::Failure((Status::UnsupportedMediaType, ())),
+        }
+    }
+}
+
+// Zstd magic is the longest one
+const MAX_MAGIC_LEN: usize = 4;
+
+// Encoding of body, detected by magic bytes if header is absent
+pub async fn detect_encoding<S>(
+    encoding: Option<ContentEncoding>,
+    stream: S,
+) -> (ContentEncoding, BytesStream)
+where
+    S: Stream<Item = Result<Bytes>> + Send + 'static,
+{
+    let mut stream = Box::pin(stream);
+    if let Some(encoding) = encoding {
+        return (encoding, stream);
+    }
+
+    // Magic could be split between chunks, so chunks are peeked until it's complete
+    let mut head = Vec::with_capacity(MAX_MAGIC_LEN);
+    let mut peeked = Vec::new();
+    while head.len() < MAX_MAGIC_LEN {
+        match stream.next().await {
+            Some(Ok(bytes)) => {
+                let len = bytes.len().min(MAX_MAGIC_LEN - head.len());
+                head.extend_from_slice(&bytes[..len]);
+                peeked.push(Ok(bytes));
+            }
+            Some(Err(e)) => {
+                peeked.push(Err(e));
+                break;
+            }
+            None => break,
+        }
+    }
+    if peeked.is_empty() {
+        return (ContentEncoding::Identity, Box::pin(stream::empty()));
+    }
+
+    // Put peeked chunks back in front of the stream
+    let encoding = ContentEncoding::from_magic(&head);
+    (encoding, Box::pin(stream::iter(peeked).chain(stream)))
+}
+
+// Wraps body stream into streaming decompressor, so only current chunks are kept in memory
+pub async fn decode_stream<S>(encoding: Option<ContentEncoding>, stream: S) -> BytesStream
+where
+    S: Stream<Item = Result<Bytes>> + Send + 'static,
+{
+    let (encoding, stream) = detect_encoding(encoding, stream).await;
+    match encoding {
+        ContentEncoding::Identity => Box::pin(stream),
+        ContentEncoding::Gzip => {
+            let mut decoder = GzipDecoder::new(tokio::io::stream_reader(stream));
+            decoder.multiple_members(true);
+            Box::pin(into_bytes_stream(decoder))
+        }
+        ContentEncoding::Zstd => {
+            let mut decoder = ZstdDecoder::new(tokio::io::stream_reader(stream));
+            decoder.multiple_members(true);
+            Box::pin(into_bytes_stream(decoder))
+        }
+    }
+}
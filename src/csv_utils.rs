@@ -1,6 +1,73 @@
+use crate::errors::*;
+use crate::record::record_key;
+
 use bytes::BufMut;
+use flexbuffers::Reader;
+use json::JsonValue;
+use std::borrow::Cow;
 use tokio::io::Result;
 
+// Which CSV fields are stored as NULL, it's saved in catalog along with table
+#[derive(Debug, Clone, PartialEq)]
+pub struct NullValues {
+    tokens: Vec<String>,
+    empty_as_null: bool,
+}
+
+impl Default for NullValues {
+    fn default() -> Self {
+        Self {
+            tokens: Vec::new(),
+            empty_as_null: true,
+        }
+    }
+}
+
+impl NullValues {
+    // `tokens` is comma separated list of NULL markers, e.g. `NA,\N,null`
+    pub fn new(tokens: Option<&str>, empty_as_null: bool) -> Self {
+        let tokens = tokens
+            .map(|t| t.split(',').map(|t| t.to_owned()).collect())
+            .unwrap_or_default();
+        Self {
+            tokens,
+            empty_as_null,
+        }
+    }
+
+    #[inline]
+    pub fn is_null(&self, value: &str) -> bool {
+        if value.is_empty() {
+            return self.empty_as_null;
+        }
+        self.tokens.iter().any(|t| t == value)
+    }
+
+    pub fn to_json(&self) -> JsonValue {
+        let mut result = JsonValue::new_object();
+        result["null_tokens"] = self
+            .tokens
+            .iter()
+            .map(|t| JsonValue::from(t.as_str()))
+            .collect::<Vec<JsonValue>>()
+            .into();
+        result["empty_as_null"] = self.empty_as_null.into();
+        result
+    }
+
+    pub fn from_json(value: &JsonValue) -> Self {
+        let tokens = value["null_tokens"]
+            .members()
+            .filter_map(|t| t.as_str())
+            .map(|t| t.to_owned())
+            .collect();
+        Self {
+            tokens,
+            empty_as_null: value["empty_as_null"].as_bool().unwrap_or(true),
+        }
+    }
+}
+
 pub struct CSVImportReader {
     cur_bytes: bytes::Bytes,
     reader: CSVReader,
@@ -18,6 +85,15 @@ impl CSVImportReader {
         Ok(result)
     }
 
+    // Reader for chunk of whole records without headers
+    pub fn from_records_chunk(data: bytes::Bytes) -> Self {
+        Self {
+            cur_bytes: data,
+            reader: CSVReader::new(),
+            headers: Vec::new(),
+        }
+    }
+
     #[inline]
     pub fn headers(&self) -> &Vec<String> {
         &self.headers
@@ -67,6 +143,133 @@ impl CSVImportReader {
     }
 }
 
+// Encoded records of batch, with keys of upsert key column if it's requested
+pub struct CSVBatch {
+    pub records: Vec<Vec<u8>>,
+    pub keys: Vec<String>,
+}
+
+// Encodes batch of whole records, batches are independent, so they could be parsed in parallel
+pub fn parse_csv_batch(
+    data: bytes::Bytes,
+    nulls: &NullValues,
+    headers_count: usize,
+    key_idx: Option<usize>,
+) -> Result<CSVBatch> {
+    let mut reader = CSVImportReader::from_records_chunk(data);
+    let mut iter = reader.parse_records();
+    let mut result = CSVBatch {
+        records: Vec::new(),
+        keys: Vec::new(),
+    };
+    while let Some(value) = iter.next() {
+        let record = value?;
+        if headers_count != record.len() {
+            return invalid_data_e!(
+                "headers and fields count mismatch, expected {}, got {}",
+                headers_count,
+                record.len()
+            );
+        }
+        let data = record.as_flexbuffer(nulls);
+        if let Some(key_idx) = key_idx {
+            // Key is taken from encoded record, the same way as on index rebuild
+            let key = Reader::get_root(data.as_slice())
+                .map_err(ApiError::from)
+                .and_then(|reader| record_key(&reader.as_vector(), key_idx))
+                .map_err(|e| e.0)?;
+            result.keys.push(key);
+        }
+        result.records.push(data);
+    }
+    Ok(result)
+}
+
+// Splits input chunks at unquoted new lines, so every batch contains whole records only.
+// Lone `\r` ends record too, as it does for CSV parser
+pub struct CSVBatchSplitter {
+    cur_bytes: bytes::BytesMut,
+    in_quotes: bool,
+    // Count of bytes already scanned for quotes and new lines
+    scanned: usize,
+    // Position after first unquoted new line, it ends headers
+    first_boundary: Option<usize>,
+    // Position after last unquoted new line
+    boundary: Option<usize>,
+}
+
+impl CSVBatchSplitter {
+    pub fn new() -> Self {
+        Self {
+            cur_bytes: bytes::BytesMut::new(),
+            in_quotes: false,
+            scanned: 0,
+            first_boundary: None,
+            boundary: None,
+        }
+    }
+
+    pub fn add_chunk(&mut self, data: bytes::Bytes) {
+        self.cur_bytes.put(data);
+        self.scan();
+    }
+
+    // Takes first record, used for headers
+    pub fn take_line(&mut self) -> Option<bytes::Bytes> {
+        let pos = self.first_boundary?;
+        let line = self.cur_bytes.split_to(pos).freeze();
+        // Rest is scanned once more to find its first new line
+        self.in_quotes = false;
+        self.scanned = 0;
+        self.first_boundary = None;
+        self.boundary = None;
+        self.scan();
+        Some(line)
+    }
+
+    // Takes all whole records if there are at least `min_size` bytes of them
+    pub fn next_batch(&mut self, min_size: usize) -> Option<bytes::Bytes> {
+        match self.boundary {
+            Some(boundary) if boundary >= min_size => {
+                self.first_boundary = None;
+                self.boundary = None;
+                self.scanned -= boundary;
+                Some(self.cur_bytes.split_to(boundary).freeze())
+            }
+            _ => None,
+        }
+    }
+
+    // Takes the rest of input, last record could be not terminated by new line
+    pub fn finish(&mut self) -> Option<bytes::Bytes> {
+        if self.cur_bytes.is_empty() {
+            return None;
+        }
+        if self.cur_bytes.last() != Some(&b'\n') {
+            self.cur_bytes.put_u8(b'\n');
+        }
+        self.first_boundary = None;
+        self.boundary = None;
+        self.scanned = 0;
+        Some(self.cur_bytes.split().freeze())
+    }
+
+    fn scan(&mut self) {
+        for (i, b) in self.cur_bytes[self.scanned..].iter().enumerate() {
+            match b {
+                b'"' => self.in_quotes = !self.in_quotes,
+                b'\n' | b'\r' if !self.in_quotes => {
+                    let boundary = self.scanned + i + 1;
+                    self.first_boundary.get_or_insert(boundary);
+                    self.boundary = Some(boundary);
+                }
+                _ => (),
+            }
+        }
+        self.scanned = self.cur_bytes.len();
+    }
+}
+
 const S_OUT_BYTES_COUNT: usize = 8192;
 const S_OUT_MAX_FIELDS_COUNT: usize = 100;
 
@@ -166,33 +369,54 @@ impl<'a> CSVRecord<'a> {
         self.reader.nend
     }
 
-    pub fn as_flexbuffer(&self) -> Vec<u8> {
+    pub fn fields(&self) -> impl Iterator<Item = Cow<'a, str>> {
+        let reader = self.reader;
+        let mut prev_pos = 0;
+        reader.out_fields.iter().take(reader.nend).map(move |pos| {
+            let slice: &'a [u8] = &reader.out[prev_pos..*pos];
+            prev_pos = *pos;
+            String::from_utf8_lossy(slice)
+        })
+    }
+
+    pub fn as_flexbuffer(&self, nulls: &NullValues) -> Vec<u8> {
         let mut builder = flexbuffers::Builder::default();
         let mut vec = builder.start_vector();
-        let mut prev_pos = 0;
-        // println!(" --- RECORD ---");
-        self.reader
-            .out_fields
-            .iter()
-            .take(self.reader.nend)
-            .for_each(|pos| {
-                let slice: &'a [u8] = &self.reader.out[prev_pos..*pos];
-                let value = String::from_utf8_lossy(slice);
-                prev_pos = *pos;
-                // println!("{}", value);
-                if value.len() == 0 {
-                    vec.push(());
-                } else if let Ok(num) = value.parse::<u64>() {
-                    vec.push(num);
-                } else if let Ok(num) = value.parse::<i64>() {
-                    vec.push(num);
-                } else if let Ok(num) = value.parse::<f64>() {
-                    vec.push(num);
-                } else {
-                    vec.push(value.as_ref());
-                }
+        self.fields()
+            .for_each(|value| match FieldValue::parse(value.as_ref(), nulls) {
+                FieldValue::Null => vec.push(()),
+                FieldValue::UInt(num) => vec.push(num),
+                FieldValue::Int(num) => vec.push(num),
+                FieldValue::Float(num) => vec.push(num),
+                FieldValue::Str(s) => vec.push(s),
             });
         vec.end_vector();
         builder.take_buffer()
     }
 }
+
+// CSV field as it's stored, numbers are detected by parsing
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum FieldValue<'a> {
+    Null,
+    UInt(u64),
+    Int(i64),
+    Float(f64),
+    Str(&'a str),
+}
+
+impl<'a> FieldValue<'a> {
+    pub fn parse(value: &'a str, nulls: &NullValues) -> Self {
+        if nulls.is_null(value) {
+            Self::Null
+        } else if let Ok(num) = value.parse::<u64>() {
+            Self::UInt(num)
+        } else if let Ok(num) = value.parse::<i64>() {
+            Self::Int(num)
+        } else if let Ok(num) = value.parse::<f64>() {
+            Self::Float(num)
+        } else {
+            Self::Str(value)
+        }
+    }
+}
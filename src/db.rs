@@ -42,6 +42,7 @@ type AsyncMutex<T> = tokio::sync::Mutex<T>;
 pub struct DB {
     env: Arc<DBEnv>,
     write_mutex: AsyncMutex<MutableDB>,
+    writer_handle: tokio::runtime::Handle,
 }
 
 impl DB {
@@ -53,9 +54,12 @@ impl DB {
             return other_e!("wrong path for db: {}", path);
         });
         let env = Arc::new(DBEnv::new(path_str)?);
+        let mutable_db = MutableDB::new(env.clone())?;
+        let writer_handle = mutable_db.runtime.handle().clone();
         Ok(Self {
-            env: env.clone(),
-            write_mutex: AsyncMutex::new(MutableDB::new(env)?),
+            env,
+            write_mutex: AsyncMutex::new(mutable_db),
+            writer_handle,
         })
     }
 
@@ -63,6 +67,15 @@ impl DB {
         self.write_mutex.lock().await
     }
 
+    // Detached background mutation on `db_writer` runtime,
+    // it should acquire `mutation` lock by itself
+    pub fn spawn_job<F>(&self, f: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        self.writer_handle.spawn(f);
+    }
+
     pub fn table(&self, table: String) -> Table {
         Table::new(self.env.clone(), table)
     }
@@ -128,20 +141,43 @@ impl MutableTable {
 pub struct WriteTransaction {
     txn: Transaction,
     last_idx: usize,
+    key_index: Option<MDBX_dbi>,
 }
 
 impl WriteTransaction {
     fn new(txn: Transaction) -> Self {
-        Self { txn, last_idx: 0 }
+        Self {
+            txn,
+            last_idx: 0,
+            key_index: None,
+        }
     }
 
     pub fn drop(&mut self) -> ApiResult<()> {
         unsafe {
             mdbx_check(mdbx_drop(self.txn.txn, self.txn.dbi, 0))?;
         }
+        self.drop_key_index()
+    }
+
+    // Continues appending after the last record, for imports into existing table
+    pub fn seek_end(&mut self) -> ApiResult<()> {
+        let cursor = self.txn.cursor(MDBX_cursor_op::MDBX_LAST)?;
+        self.last_idx = match cursor.idx()? {
+            Some(idx) => idx + 1,
+            None => 0,
+        };
         Ok(())
     }
 
+    pub fn headers(&self) -> ApiResult<Option<Vec<String>>> {
+        let cursor = self.txn.cursor(MDBX_cursor_op::MDBX_FIRST)?;
+        match cursor.idx()? {
+            Some(0) => Ok(Some(flex_to_string_vec(cursor.data()?))),
+            _ => Ok(None),
+        }
+    }
+
     pub fn commit(&mut self) -> ApiResult<()> {
         unsafe {
             mdbx_check(mdbx_txn_commit(self.txn.txn))?;
@@ -150,22 +186,120 @@ impl WriteTransaction {
         Ok(())
     }
 
-    pub fn append(&mut self, data: Vec<u8>) -> ApiResult<()> {
+    // Commits written records and begins new transaction for the rest of import,
+    // opened sub dbs stay valid
+    pub fn commit_and_continue(&mut self) -> ApiResult<()> {
+        self.commit()?;
         unsafe {
-            let key_str = self.last_idx.to_string();
-            let mut key = into_val(key_str.as_bytes());
-            let mut value = into_val(data.as_ref());
-            mdbx_check(mdbx_put(
-                self.txn.txn,
-                self.txn.dbi,
-                &mut key,
-                &mut value,
-                MDBX_APPEND,
+            mdbx_check(mdbx_txn_begin(
+                self.txn.env,
+                ptr::null_mut(),
+                0,
+                &mut self.txn.txn,
             ))?;
         }
+        Ok(())
+    }
+
+    // Index of the last appended record
+    pub fn last_idx(&self) -> Option<usize> {
+        self.last_idx.checked_sub(1)
+    }
+
+    pub fn append(&mut self, data: Vec<u8>) -> ApiResult<()> {
+        self.put(self.last_idx, data, MDBX_APPEND)?;
         self.last_idx += 1;
         Ok(())
     }
+
+    // Leaves first slot empty, for imports where headers are known only after all records
+    pub fn reserve_headers(&mut self) {
+        if self.last_idx == 0 {
+            self.last_idx = 1;
+        }
+    }
+
+    pub fn put_headers(&mut self, data: Vec<u8>) -> ApiResult<()> {
+        self.put(0, data, 0)
+    }
+
+    // Table metadata is written in the same transaction as table data
+    pub fn put_meta(&mut self, data: &[u8]) -> ApiResult<()> {
+        guard!(let Some(catalog) = self.txn.open_catalog(MDBX_CREATE)? else {
+            return other_e!("can't create catalog");
+        });
+        self.txn.put(catalog, self.txn.name.as_bytes(), data, 0)
+    }
+
+    pub fn meta(&self) -> ApiResult<Option<Vec<u8>>> {
+        self.txn.meta()
+    }
+
+    // Opens key -> row index of table, returns `true` if index is just created
+    pub fn open_key_index(&mut self) -> ApiResult<bool> {
+        let name = self.key_index_name();
+        let created = self.txn.open_dbi(&name, 0)?.is_none();
+        self.key_index = self.txn.open_dbi(&name, MDBX_CREATE)?;
+        Ok(created)
+    }
+
+    pub fn drop_key_index(&mut self) -> ApiResult<()> {
+        self.key_index = None;
+        if let Some(dbi) = self.txn.open_dbi(&self.key_index_name(), 0)? {
+            unsafe {
+                mdbx_check(mdbx_drop(self.txn.txn, dbi, 1))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Fills key index from records already stored in table
+    pub fn build_key_index<F>(&mut self, key_fn: F) -> ApiResult<()>
+    where
+        F: Fn(&VectorReader) -> ApiResult<String>,
+    {
+        guard!(let Some(index) = self.key_index else { return other_e!("key index is not open") });
+        // First record is skipped by `next`, it's headers
+        let mut cursor = self.txn.cursor(MDBX_cursor_op::MDBX_FIRST)?;
+        while let Some(record) = cursor.next()? {
+            guard!(let Some(idx) = cursor.idx()? else { break });
+            let key = key_fn(&record)?;
+            self.txn
+                .put(index, key.as_bytes(), idx.to_string().as_bytes(), 0)?;
+        }
+        Ok(())
+    }
+
+    // Replaces record with the same key in place or appends new one
+    pub fn upsert(&mut self, key: &str, data: Vec<u8>) -> ApiResult<()> {
+        guard!(let Some(index) = self.key_index else { return other_e!("key index is not open") });
+        let existing = match self.txn.get(index, key.as_bytes())? {
+            Some(idx) => match String::from_utf8_lossy(idx).parse::<usize>() {
+                Ok(idx) => Some(idx),
+                Err(_) => return invalid_data_ae!("broken key index for {}", key),
+            },
+            None => None,
+        };
+        match existing {
+            Some(idx) => self.put(idx, data, 0),
+            None => {
+                let idx = self.last_idx;
+                self.append(data)?;
+                self.txn
+                    .put(index, key.as_bytes(), idx.to_string().as_bytes(), 0)
+            }
+        }
+    }
+
+    fn key_index_name(&self) -> String {
+        format!("{}{}", KEY_INDEX_PREFIX, self.txn.name)
+    }
+
+    fn put(&mut self, idx: usize, data: Vec<u8>, flags: c_uint) -> ApiResult<()> {
+        let key_str = idx.to_string();
+        self.txn
+            .put(self.txn.dbi, key_str.as_bytes(), data.as_ref(), flags)
+    }
 }
 
 pub struct Table {
@@ -197,6 +331,10 @@ impl<'de> ReadTransaction<'de> {
         }
     }
 
+    pub fn meta(&self) -> ApiResult<Option<Vec<u8>>> {
+        self.txn.meta()
+    }
+
     pub fn cursor_on_start<'ret>(&'de self) -> ApiResult<Cursor<'de>> {
         let mut cursor: *mut MDBX_cursor = ptr::null_mut();
         unsafe { mdbx_check(mdbx_cursor_open(self.txn.txn, self.txn.dbi, &mut cursor))? }
@@ -241,6 +379,17 @@ impl<'de> Cursor<'de> {
         Ok(true)
     }
 
+    fn idx(&self) -> ApiResult<Option<usize>> {
+        if self.key.iov_base.is_null() {
+            return Ok(None);
+        }
+        let key = unsafe { from_val(&self.key) };
+        match String::from_utf8_lossy(key).parse::<usize>() {
+            Ok(idx) => Ok(Some(idx)),
+            Err(e) => invalid_data_ae!("wrong record key: {}", e),
+        }
+    }
+
     pub fn next(&mut self) -> ApiResult<Option<VectorReader<'de>>> {
         if !self.call_get(MDBX_cursor_op::MDBX_NEXT)? {
             return Ok(None);
@@ -270,6 +419,23 @@ impl<'de> Cursor<'de> {
     }
 }
 
+// Row interface of stored tables, materialized results of subqueries implement it too
+pub trait RowCursor<'de> {
+    // Current record, the first one is headers
+    fn data(&self) -> ApiResult<VectorReader<'de>>;
+    fn next(&mut self) -> ApiResult<Option<VectorReader<'de>>>;
+}
+
+impl<'de> RowCursor<'de> for Cursor<'de> {
+    fn data(&self) -> ApiResult<VectorReader<'de>> {
+        Cursor::data(self)
+    }
+
+    fn next(&mut self) -> ApiResult<Option<VectorReader<'de>>> {
+        Cursor::next(self)
+    }
+}
+
 impl Drop for Cursor<'_> {
     fn drop(&mut self) {
         unsafe {
@@ -279,8 +445,10 @@ impl Drop for Cursor<'_> {
 }
 
 pub struct Transaction {
+    env: *mut MDBX_env,
     txn: *mut MDBX_txn,
     dbi: MDBX_dbi,
+    name: String,
 }
 
 unsafe impl Send for Transaction {}
@@ -293,9 +461,13 @@ impl Transaction {
         dbi_flags: c_uint,
         column: String,
     ) -> ApiResult<Self> {
+        if column == CATALOG_NAME || column.starts_with(KEY_INDEX_PREFIX) {
+            return invalid_data_ae!("table name is reserved: {}", column);
+        }
         let mut txn: *mut MDBX_txn = ptr::null_mut();
         let mut dbi: MDBX_dbi = 0;
-        let column = CString::new(column)?;
+        let name = column;
+        let column = CString::new(name.as_str())?;
         unsafe {
             mdbx_check(mdbx_txn_begin(env, ptr::null_mut(), txn_flags, &mut txn))?;
             mdbx_check(mdbx_dbi_open_ex(
@@ -307,7 +479,66 @@ impl Transaction {
                 None,
             ))?;
         };
-        Ok(Self { txn, dbi })
+        Ok(Self {
+            env,
+            txn,
+            dbi,
+            name,
+        })
+    }
+
+    fn meta(&self) -> ApiResult<Option<Vec<u8>>> {
+        guard!(let Some(catalog) = self.open_catalog(0)? else { return Ok(None) });
+        let data = self.get(catalog, self.name.as_bytes())?;
+        Ok(data.map(|d| d.to_vec()))
+    }
+
+    // Catalog keeps metadata of tables, it's keyed by table name, so default comparator is used
+    fn open_catalog(&self, dbi_flags: c_uint) -> ApiResult<Option<MDBX_dbi>> {
+        self.open_dbi(CATALOG_NAME, dbi_flags)
+    }
+
+    // Opens auxiliary sub db with default comparator, `None` if it doesn't exist
+    fn open_dbi(&self, name: &str, dbi_flags: c_uint) -> ApiResult<Option<MDBX_dbi>> {
+        let mut dbi: MDBX_dbi = 0;
+        let column = CString::new(name)?;
+        unsafe {
+            let err_code =
+                mdbx_dbi_open_ex(self.txn, column.as_ptr(), dbi_flags, &mut dbi, None, None);
+            if err_code == MDBX_NOTFOUND {
+                return Ok(None);
+            }
+            mdbx_check(err_code)?;
+        }
+        Ok(Some(dbi))
+    }
+
+    fn get(&self, dbi: MDBX_dbi, key: &[u8]) -> ApiResult<Option<&[u8]>> {
+        unsafe {
+            let mut key = into_val(key);
+            let mut data = into_val(&[]);
+            let err_code = mdbx_get(self.txn, dbi, &mut key, &mut data);
+            if err_code == MDBX_NOTFOUND {
+                return Ok(None);
+            }
+            mdbx_check(err_code)?;
+            Ok(Some(from_val_ptr(&data)))
+        }
+    }
+
+    fn put(&self, dbi: MDBX_dbi, key: &[u8], data: &[u8], flags: c_uint) -> ApiResult<()> {
+        unsafe {
+            let mut key = into_val(key);
+            let mut value = into_val(data);
+            mdbx_check(mdbx_put(self.txn, dbi, &mut key, &mut value, flags))?;
+        }
+        Ok(())
+    }
+
+    fn cursor<'de>(&'de self, op: MDBX_cursor_op) -> ApiResult<Cursor<'de>> {
+        let mut cursor: *mut MDBX_cursor = ptr::null_mut();
+        unsafe { mdbx_check(mdbx_cursor_open(self.txn, self.dbi, &mut cursor))? }
+        Cursor::new(cursor, op)
     }
 }
 
@@ -348,6 +579,8 @@ unsafe impl Send for DBEnv {}
 unsafe impl Sync for DBEnv {}
 
 const MAX_TABLES: MDBX_dbi = 1024;
+const CATALOG_NAME: &str = "__catalog";
+const KEY_INDEX_PREFIX: &str = "__key_index:";
 const OPEN_FLAGS: c_uint = MDBX_COALESCE
     | MDBX_LIFORECLAIM
     | MDBX_NOMETASYNC
@@ -16,6 +16,30 @@ macro_rules! invalid_data_ae {
     ($($arg:tt)*) => { Err(ApiError(invalid_data!($($arg)*))) };
 }
 
+#[macro_export]
+macro_rules! payload_too_large {
+    ($($arg:tt)*) => {{
+        let res = std::fmt::format(std::format_args!($($arg)*));
+        std::io::Error::new(std::io::ErrorKind::Other, $crate::errors::PayloadTooLarge(res))
+    }}
+}
+
+#[macro_export]
+macro_rules! not_found_ae {
+    ($($arg:tt)*) => {{
+        let res = std::fmt::format(std::format_args!($($arg)*));
+        Err(ApiError(std::io::Error::new(std::io::ErrorKind::NotFound, res)))
+    }}
+}
+
+#[macro_export]
+macro_rules! forbidden_ae {
+    ($($arg:tt)*) => {{
+        let res = std::fmt::format(std::format_args!($($arg)*));
+        Err(ApiError(std::io::Error::new(std::io::ErrorKind::PermissionDenied, res)))
+    }}
+}
+
 #[macro_export]
 macro_rules! other_e {
     ($($arg:tt)*) => {{
@@ -35,18 +59,44 @@ use tokio::io;
 #[derive(Debug)]
 pub struct ApiError(pub io::Error);
 
+// Marker error for request bodies exceeding configured limits, mapped to 413
+#[derive(Debug)]
+pub struct PayloadTooLarge(pub String);
+
+impl std::fmt::Display for PayloadTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for PayloadTooLarge {}
+
+impl ApiError {
+    fn status(&self) -> Status {
+        if let Some(inner) = self.0.get_ref() {
+            if inner.is::<PayloadTooLarge>() {
+                return Status::PayloadTooLarge;
+            }
+        }
+        match self.0.kind() {
+            ErrorKind::InvalidData => Status::BadRequest,
+            ErrorKind::NotFound => Status::NotFound,
+            ErrorKind::PermissionDenied => Status::Forbidden,
+            _ => Status::InternalServerError,
+        }
+    }
+}
+
 pub type ApiResult<T> = Result<T, ApiError>;
 
 impl<'r> Responder<'r, 'static> for ApiError {
     fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
         let descr = format!("Error: {:?}", self.0.to_string());
+        let status = self.status();
         Response::build()
             .header(ContentType::Plain)
             .sized_body(descr.len(), Cursor::new(descr))
-            .status(match self.0.kind() {
-                ErrorKind::InvalidData => Status::BadRequest,
-                _ => Status::InternalServerError,
-            })
+            .status(status)
             .ok()
     }
 }
@@ -93,6 +143,18 @@ impl<T> std::convert::From<std::sync::PoisonError<T>> for ApiError {
     }
 }
 
+impl std::convert::From<arrow::error::ArrowError> for ApiError {
+    fn from(e: arrow::error::ArrowError) -> Self {
+        Self(invalid_data!("{}", e))
+    }
+}
+
+impl std::convert::From<parquet::errors::ParquetError> for ApiError {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        Self(invalid_data!("{}", e))
+    }
+}
+
 impl std::convert::From<tokio::task::JoinError> for ApiError {
     fn from(e: tokio::task::JoinError) -> Self {
         Self(invalid_data!("{}", e))
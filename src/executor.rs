@@ -0,0 +1,210 @@
+use crate::db::*;
+use crate::errors::*;
+use crate::join::HashJoin;
+use crate::mem_table::MemTable;
+use crate::query::*;
+use crate::query_processor::QueryProcessor;
+use crate::record::*;
+use crate::set_operation::*;
+
+use sqlparser::ast;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+// Stored tables which are read by query, by name
+pub trait TableCursors {
+    fn cursor<'a>(&'a self, table: &str) -> ApiResult<Box<dyn RowCursor<'a> + 'a>>;
+}
+
+// Read transactions of tables from `Query::get_stored_tables`
+pub type ReadTransactions<'de> = HashMap<String, ReadTransaction<'de>>;
+
+impl TableCursors for ReadTransactions<'_> {
+    fn cursor<'a>(&'a self, table: &str) -> ApiResult<Box<dyn RowCursor<'a> + 'a>> {
+        guard!(let Some(txn) = self.get(table) else {
+            return invalid_data_ae!("table {} isn't opened", table);
+        });
+        let txn: &'a ReadTransaction<'a> = txn;
+        Ok(Box::new(txn.cursor_on_start()?))
+    }
+}
+
+impl TableCursors for HashMap<String, MemTable> {
+    fn cursor<'a>(&'a self, table: &str) -> ApiResult<Box<dyn RowCursor<'a> + 'a>> {
+        guard!(let Some(mem_table) = self.get(table) else {
+            return not_found_ae!("table {} not found", table);
+        });
+        Ok(Box::new(mem_table.cursor()))
+    }
+}
+
+// Materialized CTEs visible in query and its subqueries
+type Ctes = HashMap<String, Rc<MemTable>>;
+
+// CTEs, subqueries in FROM and `IN (SELECT ...)` are materialized in memory before query
+// is processed, then processor with result is passed to `f`
+pub fn execute_query<R>(
+    tables: &dyn TableCursors,
+    query: Query,
+    options: QueryOptions,
+    f: impl FnOnce(&QueryProcessor) -> ApiResult<R>,
+) -> ApiResult<R> {
+    execute(tables, query, options, &Ctes::new(), f)
+}
+
+fn execute<R>(
+    tables: &dyn TableCursors,
+    mut query: Query,
+    options: QueryOptions,
+    ctes: &Ctes,
+    f: impl FnOnce(&QueryProcessor) -> ApiResult<R>,
+) -> ApiResult<R> {
+    // CTE is materialized only if it's read by query body or by other materialized CTE,
+    // names of taken CTEs are returned by `get_stored_tables` as stored ones
+    let query_ctes = query.take_ctes();
+    let mut used = query.get_stored_tables()?;
+    let mut used_ctes = Vec::with_capacity(query_ctes.len());
+    for cte in query_ctes.into_iter().rev() {
+        if used.contains(&cte.name) {
+            used.extend(cte.query.get_stored_tables()?);
+            used_ctes.push(cte);
+        }
+    }
+    let mut ctes = ctes.clone();
+    for cte in used_ctes.into_iter().rev() {
+        let table = materialize(tables, cte.query, &cte.columns, options, &ctes)?;
+        ctes.insert(cte.name, Rc::new(table));
+    }
+
+    if let Some(operation) = query.get_set_operation() {
+        let mut result = ResultSink::new(query.get_set_operation_result()?, options);
+        stream_set_operation(tables, operation, options, &ctes, &mut result)?;
+        guard!(let Some(processor) = result.processor.as_ref() else {
+            return invalid_data_ae!("set operation has no result");
+        });
+        return f(processor);
+    }
+
+    query.bind_in_subqueries(&mut |subquery| {
+        let table = materialize(tables, subquery, &Vec::new(), options, &ctes)?;
+        in_list_values(&table)
+    })?;
+
+    let table_refs = query.get_table_refs()?;
+    let mut mem_tables = Vec::with_capacity(table_refs.len());
+    for table in table_refs.iter() {
+        mem_tables.push(match &table.subquery {
+            Some(subquery) => Some(Rc::new(materialize(
+                tables,
+                subquery.query.clone(),
+                &subquery.columns,
+                options,
+                &ctes,
+            )?)),
+            None => ctes.get(&table.name).cloned(),
+        });
+    }
+
+    let mut cursors: Vec<Box<dyn RowCursor<'_> + '_>> = Vec::with_capacity(table_refs.len());
+    for (table, mem_table) in table_refs.iter().zip(mem_tables.iter()) {
+        cursors.push(match mem_table {
+            Some(mem_table) => Box::new(mem_table.cursor()),
+            None => tables.cursor(&table.name)?,
+        });
+    }
+
+    let join = HashJoin::from_cursors(&query, &mut cursors)?;
+    let mut processor = QueryProcessor::with_options(query, join.headers().clone(), options)?;
+    join.process(cursors[0].as_mut(), &mut processor)?;
+    f(&processor)
+}
+
+// Rows of query are passed to `sink`, set operation without ORDER BY and LIMIT isn't buffered
+fn stream_rows(
+    tables: &dyn TableCursors,
+    query: Query,
+    options: QueryOptions,
+    ctes: &Ctes,
+    sink: &mut dyn RowSink,
+) -> ApiResult<bool> {
+    if !query.has_order_by_or_limit() {
+        if let Some(operation) = query.get_set_operation() {
+            return stream_set_operation(tables, operation, options, ctes, sink);
+        }
+    }
+    execute(tables, query, options, ctes, |processor| {
+        sink.headers(processor.headers())?;
+        let mut iter = processor.iter();
+        while let Some(record) = iter.next()? {
+            if !sink.row(owned_row(record)?)? {
+                return Ok(false);
+            }
+        }
+        Ok(true)
+    })
+}
+
+fn stream_set_operation(
+    tables: &dyn TableCursors,
+    operation: SetOperation,
+    options: QueryOptions,
+    ctes: &Ctes,
+    out: &mut dyn RowSink,
+) -> ApiResult<bool> {
+    let mut sink = SetOperationSink::new(operation.op, operation.all, out);
+    if operation.op == SetOperator::Union {
+        if !stream_rows(tables, operation.left, options, ctes, &mut sink)? {
+            return Ok(false);
+        }
+        return stream_rows(tables, operation.right, options, ctes, &mut sink);
+    }
+    // Right branch is hashed, then rows of left one are probed
+    stream_rows(tables, operation.right, options, ctes, &mut sink)?;
+    sink.start_probe();
+    stream_rows(tables, operation.left, options, ctes, &mut sink)
+}
+
+fn materialize(
+    tables: &dyn TableCursors,
+    query: Query,
+    columns: &Vec<String>,
+    options: QueryOptions,
+    ctes: &Ctes,
+) -> ApiResult<MemTable> {
+    let mut result = execute(tables, query, options, ctes, |processor| {
+        MemTable::from_processor(processor)
+    })?;
+    if !columns.is_empty() {
+        result.rename_columns(columns)?;
+    }
+    Ok(result)
+}
+
+// Values of one column subquery as SQL literals
+fn in_list_values(table: &MemTable) -> ApiResult<Vec<ast::Expr>> {
+    let headers = table.headers()?;
+    if headers.len() != 1 {
+        return invalid_data_ae!(
+            "subquery in IN should return one column, got {}",
+            headers.len()
+        );
+    }
+    let mut result = Vec::with_capacity(table.len());
+    let mut cursor = table.cursor();
+    while let Some(record) = cursor.next()? {
+        let value = match record.value_at(0)? {
+            ValueRef::UInteger(v) => ast::Value::Number(v.to_string()),
+            ValueRef::Integer(v) => ast::Value::Number(v.to_string()),
+            ValueRef::Float(v) if v.is_finite() => ast::Value::Number(v.to_string()),
+            // SQL literal can't be NaN or infinity
+            ValueRef::Float(v) => {
+                return invalid_data_ae!("subquery in IN returned non-finite value {}", v)
+            }
+            ValueRef::Bool(v) => ast::Value::Boolean(v),
+            ValueRef::Null => ast::Value::Null,
+            v => ast::Value::SingleQuotedString(v.to_string()),
+        };
+        result.push(ast::Expr::Value(value));
+    }
+    Ok(result)
+}
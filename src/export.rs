@@ -0,0 +1,398 @@
+use crate::arrow_utils::*;
+use crate::compression::BytesStream;
+use crate::db::*;
+use crate::errors::*;
+use crate::executor::*;
+use crate::misc_utils::*;
+use crate::parquet_utils::*;
+use crate::query::*;
+use crate::record::*;
+
+use bytes::Bytes;
+use flexbuffers::VectorReader;
+use futures::executor::block_on;
+use futures::stream::{self, StreamExt};
+use rocket::http::ContentType;
+use rocket::request;
+use rocket::response::{self, Responder, Response};
+use rocket::Request;
+use std::io::Write;
+use tokio::io::Result;
+use tokio::sync::mpsc;
+
+// Rows are sent to client in chunks of about this size
+pub(crate) const EXPORT_CHUNK_SIZE: usize = 64 * 1024;
+// Count of chunks buffered between cursor and client
+const EXPORT_CHANNEL_SIZE: usize = 4;
+
+#[derive(FromForm, Default)]
+pub struct ExportOptions {
+    // `csv` (default), `ndjson`, `arrow` or `parquet`
+    pub format: Option<String>,
+    // Query to export instead of whole table
+    pub sql: Option<String>,
+    // `exact` (default) or `approx` count of distinct values
+    pub distinct: Option<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ExportFormat {
+    CSV,
+    NDJSON,
+    // Arrow IPC stream
+    Arrow,
+    Parquet,
+}
+
+const ARROW_STREAM_SUBTYPE: &str = "vnd.apache.arrow.stream";
+
+impl ExportFormat {
+    pub fn from_options(options: &ExportOptions) -> ApiResult<Self> {
+        match options.format.as_deref() {
+            None | Some("csv") => Ok(Self::CSV),
+            Some("ndjson") => Ok(Self::NDJSON),
+            Some("arrow") => Ok(Self::Arrow),
+            Some("parquet") => Ok(Self::Parquet),
+            Some(format) => invalid_data_ae!("unknown export format: {}", format),
+        }
+    }
+
+    fn content_type(&self) -> ContentType {
+        match self {
+            Self::CSV => ContentType::CSV,
+            Self::NDJSON => ContentType::new("application", "x-ndjson"),
+            Self::Arrow => ContentType::new("application", ARROW_STREAM_SUBTYPE),
+            Self::Parquet => ContentType::new("application", "vnd.apache.parquet"),
+        }
+    }
+
+    fn extension(&self) -> &'static str {
+        match self {
+            Self::CSV => "csv",
+            Self::NDJSON => "ndjson",
+            Self::Arrow => "arrows",
+            Self::Parquet => "parquet",
+        }
+    }
+
+    pub fn writer(&self, headers: Vec<String>) -> Box<dyn RowWriter> {
+        match self {
+            Self::CSV => Box::new(CSVWriter { headers }),
+            Self::NDJSON => Box::new(NDJSONWriter {
+                keys: headers
+                    .iter()
+                    .map(|h| json::stringify(h.as_str()))
+                    .collect(),
+            }),
+            Self::Arrow => Box::new(ArrowWriter::new(headers)),
+            Self::Parquet => Box::new(ParquetWriter::new(headers)),
+        }
+    }
+}
+
+pub trait RowWriter {
+    // Typed formats derive column types from the first rows, their headers are written with them
+    fn write_headers(&mut self, out: &mut Vec<u8>) -> ApiResult<()>;
+    fn write_row(&mut self, record: &dyn RecordRef, out: &mut Vec<u8>) -> ApiResult<()>;
+    fn finish(&mut self, _: &mut Vec<u8>) -> ApiResult<()> {
+        Ok(())
+    }
+    // Output written aside and sent after `finish`, chunk by chunk, while it returns `true`
+    fn write_tail(&mut self, _: &mut Vec<u8>) -> ApiResult<bool> {
+        Ok(false)
+    }
+}
+
+struct CSVWriter {
+    headers: Vec<String>,
+}
+
+impl RowWriter for CSVWriter {
+    fn write_headers(&mut self, out: &mut Vec<u8>) -> ApiResult<()> {
+        for (idx, header) in self.headers.iter().enumerate() {
+            if idx > 0 {
+                out.push(b',');
+            }
+            write_csv_str(header, out);
+        }
+        out.push(b'\n');
+        Ok(())
+    }
+
+    fn write_row(&mut self, record: &dyn RecordRef, out: &mut Vec<u8>) -> ApiResult<()> {
+        for idx in 0..record.len() {
+            if idx > 0 {
+                out.push(b',');
+            }
+            write_csv_value(&record.value_at(idx)?, out)?;
+        }
+        out.push(b'\n');
+        Ok(())
+    }
+}
+
+// NULL is written as empty field, strings are quoted only if needed
+pub fn write_csv_value(value: &ValueRef, out: &mut Vec<u8>) -> ApiResult<()> {
+    match value {
+        ValueRef::Null => (),
+        ValueRef::Str(s) => write_csv_str(s, out),
+        ValueRef::OwnedStr(s) | ValueRef::Json(s) => write_csv_str(s, out),
+        value => write!(out, "{}", value)?,
+    }
+    Ok(())
+}
+
+fn write_csv_str(value: &str, out: &mut Vec<u8>) {
+    let needs_quotes = value
+        .bytes()
+        .any(|b| b == b',' || b == b'"' || b == b'\n' || b == b'\r');
+    if !needs_quotes {
+        out.extend_from_slice(value.as_bytes());
+        return;
+    }
+    out.push(b'"');
+    for b in value.bytes() {
+        if b == b'"' {
+            out.push(b'"');
+        }
+        out.push(b);
+    }
+    out.push(b'"');
+}
+
+struct NDJSONWriter {
+    // Headers already rendered as json strings
+    keys: Vec<String>,
+}
+
+impl RowWriter for NDJSONWriter {
+    fn write_headers(&mut self, _: &mut Vec<u8>) -> ApiResult<()> {
+        Ok(())
+    }
+
+    fn write_row(&mut self, record: &dyn RecordRef, out: &mut Vec<u8>) -> ApiResult<()> {
+        out.push(b'{');
+        for (idx, key) in self.keys.iter().enumerate().take(record.len()) {
+            if idx > 0 {
+                out.push(b',');
+            }
+            out.extend_from_slice(key.as_bytes());
+            out.push(b':');
+            write_json_value(&record.value_at(idx)?, out)?;
+        }
+        out.extend_from_slice(b"}\n");
+        Ok(())
+    }
+}
+
+pub fn write_json_value(value: &ValueRef, out: &mut Vec<u8>) -> ApiResult<()> {
+    match value {
+        ValueRef::Null => out.extend_from_slice(b"null"),
+        // JSON has no NaN and infinities
+        ValueRef::Float(f) if !f.is_finite() => out.extend_from_slice(b"null"),
+        ValueRef::Str(s) => out.extend_from_slice(json::stringify(*s).as_bytes()),
+        ValueRef::OwnedStr(s) => out.extend_from_slice(json::stringify(s.as_ref()).as_bytes()),
+        // Nested values are kept as they were imported
+        ValueRef::Json(s) => out.extend_from_slice(s.as_bytes()),
+        value => write!(out, "{}", value)?,
+    }
+    Ok(())
+}
+
+// Stored row, records imported before new column appeared are shorter than headers
+struct TableRecord<'de> {
+    record: VectorReader<'de>,
+    len: usize,
+}
+
+impl<'de> RecordRef<'de> for TableRecord<'de> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn value_at(&self, idx: usize) -> ApiResult<ValueRef<'de>> {
+        ValueRef::from_reader(&column_reader(&self.record, idx))
+    }
+}
+
+// Collects written rows and sends them by chunks, blocks while client is behind
+struct ChunkSender {
+    tx: mpsc::Sender<Result<Bytes>>,
+    buf: Vec<u8>,
+}
+
+impl ChunkSender {
+    fn send(&mut self, chunk: Result<Bytes>) -> ApiResult<()> {
+        if block_on(self.tx.send(chunk)).is_err() {
+            return other_e!("export is cancelled by client");
+        }
+        Ok(())
+    }
+
+    // Sends what is written so far, even nothing, to tell that export is started
+    fn start(&mut self) -> ApiResult<()> {
+        let chunk = Bytes::from(std::mem::take(&mut self.buf));
+        self.send(Ok(chunk))
+    }
+
+    fn flush(&mut self, force: bool) -> ApiResult<()> {
+        if self.buf.len() >= EXPORT_CHUNK_SIZE || (force && !self.buf.is_empty()) {
+            let chunk = Bytes::from(std::mem::take(&mut self.buf));
+            self.send(Ok(chunk))?;
+        }
+        Ok(())
+    }
+}
+
+fn export_table(
+    db: &DBHandle,
+    table: String,
+    format: ExportFormat,
+    sender: &mut ChunkSender,
+) -> ApiResult<()> {
+    let table = db.table(table);
+    let txn = table.open_transaction()?;
+    let mut cursor = txn.cursor_on_start()?;
+    let headers = flex_to_string_vec(cursor.data()?);
+    let len = headers.len();
+    let mut writer = format.writer(headers);
+    writer.write_headers(&mut sender.buf)?;
+    sender.start()?;
+    while let Some(record) = cursor.next()? {
+        writer.write_row(&TableRecord { record, len }, &mut sender.buf)?;
+        sender.flush(false)?;
+    }
+    writer.finish(&mut sender.buf)?;
+    while writer.write_tail(&mut sender.buf)? {
+        sender.flush(false)?;
+    }
+    sender.flush(true)
+}
+
+fn export_query(
+    db: &DBHandle,
+    sql: &str,
+    options: QueryOptions,
+    table: Option<&str>,
+    format: ExportFormat,
+    sender: &mut ChunkSender,
+) -> ApiResult<()> {
+    let query = Query::from_query_str(sql)?;
+    let stored_tables = query.get_stored_tables()?;
+    if let Some(table) = table {
+        if !stored_tables.iter().any(|name| name == table) {
+            return invalid_data_ae!("query doesn't read from exported table {}", table);
+        }
+    }
+    let tables = stored_tables
+        .into_iter()
+        .map(|name| (name.clone(), db.table(name)))
+        .collect::<Vec<_>>();
+    let txns = tables
+        .iter()
+        .map(|(name, t)| t.open_transaction().map(|txn| (name.clone(), txn)))
+        .collect::<ApiResult<ReadTransactions>>()?;
+    execute_query(&txns, query, options, |processor| {
+        let mut writer = format.writer(processor.headers().clone());
+        writer.write_headers(&mut sender.buf)?;
+        sender.start()?;
+        let mut iter = processor.iter();
+        while let Some(record) = iter.next()? {
+            writer.write_row(record, &mut sender.buf)?;
+            sender.flush(false)?;
+        }
+        writer.finish(&mut sender.buf)?;
+        while writer.write_tail(&mut sender.buf)? {
+            sender.flush(false)?;
+        }
+        sender.flush(true)
+    })
+}
+
+pub enum ExportSource {
+    Table(String),
+    // Query of `/export/<table>` should read from the table, it's `None` for `/query`
+    Query(String, QueryOptions, Option<String>),
+}
+
+// `/query` result is streamed as Arrow IPC if client accepts it, otherwise request is forwarded
+pub struct ArrowAccepted;
+
+#[rocket::async_trait]
+impl<'a, 'r> request::FromRequest<'a, 'r> for ArrowAccepted {
+    type Error = ();
+
+    async fn from_request(req: &'a request::Request<'r>) -> request::Outcome<Self, ()> {
+        match req.accept() {
+            Some(accept)
+                if accept.media_types().any(|mt| {
+                    mt.top().as_str().eq_ignore_ascii_case("application")
+                        && mt.sub().as_str().eq_ignore_ascii_case(ARROW_STREAM_SUBTYPE)
+                }) =>
+            {
+                request::Outcome::Success(Self)
+            }
+            _ => request::Outcome::Forward(()),
+        }
+    }
+}
+
+pub struct ExportResponse {
+    format: ExportFormat,
+    filename: String,
+    stream: BytesStream,
+}
+
+impl<'r> Responder<'r, 'static> for ExportResponse {
+    fn respond_to(self, _: &'r Request<'_>) -> response::Result<'static> {
+        let disposition = format!("attachment; filename=\"{}\"", self.filename);
+        Response::build()
+            .header(self.format.content_type())
+            .raw_header("Content-Disposition", disposition)
+            .streamed_body(tokio::io::stream_reader(self.stream))
+            .ok()
+    }
+}
+
+// Rows are read and written by blocking pool, as cursor can't leave its thread,
+// errors before the first chunk are returned as response status, later ones break the body
+pub async fn run_export(
+    db: DBHandle,
+    source: ExportSource,
+    format: ExportFormat,
+) -> ApiResult<ExportResponse> {
+    let name = match &source {
+        ExportSource::Table(table) | ExportSource::Query(_, _, Some(table)) => table.as_str(),
+        ExportSource::Query(_, _, None) => "query",
+    };
+    let filename = format!("{}.{}", name, format.extension());
+    let (tx, mut rx) = mpsc::channel(EXPORT_CHANNEL_SIZE);
+
+    tokio::task::spawn_blocking(move || {
+        let mut sender = ChunkSender {
+            tx,
+            buf: Vec::new(),
+        };
+        let result = match source {
+            ExportSource::Query(sql, options, table) => {
+                export_query(&db, &sql, options, table.as_deref(), format, &mut sender)
+            }
+            ExportSource::Table(table) => export_table(&db, table, format, &mut sender),
+        };
+        if let Err(e) = result {
+            let _ = sender.send(Err(e.0));
+        }
+    });
+
+    let stream: BytesStream = match rx.recv().await {
+        Some(Ok(first)) if first.is_empty() => Box::pin(rx),
+        Some(Ok(first)) => Box::pin(stream::iter(Some(Ok(first))).chain(rx)),
+        Some(Err(e)) => return Err(ApiError(e)),
+        None => Box::pin(stream::empty()),
+    };
+    Ok(ExportResponse {
+        format,
+        filename,
+        stream,
+    })
+}
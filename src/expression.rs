@@ -0,0 +1,562 @@
+use crate::errors::*;
+use crate::pattern::{Pattern, PatternSpec};
+use crate::query::{compound_name, BinaryOpType};
+use crate::record::*;
+
+use sqlparser::ast;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+// Scalar expression over columns of one record, used by projections, filters and group keys
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Expression {
+    Column(usize),
+    Literal(ValueOrdRef<'static>),
+    Arithmetic(Box<Expression>, ArithmeticOp, Box<Expression>),
+    Compare(Box<Expression>, BinaryOpType, Box<Expression>),
+    Pattern(Box<Expression>, Pattern),
+    And(Box<Expression>, Box<Expression>),
+    Or(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Negate(Box<Expression>),
+    IsNull(Box<Expression>),
+    IsNotNull(Box<Expression>),
+    // `IN (...)` with literal values, result of `IN (SELECT ...)` is bound as such list
+    InList {
+        expr: Box<Expression>,
+        values: Arc<HashSet<ValueOrdRef<'static>>>,
+        has_null: bool,
+        negated: bool,
+    },
+    Case {
+        operand: Option<Box<Expression>>,
+        conditions: Vec<(Expression, Expression)>,
+        else_result: Option<Box<Expression>>,
+    },
+    Cast(Box<Expression>, CastType),
+    Function(ScalarFunction, Vec<Expression>),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum ArithmeticOp {
+    Plus,
+    Minus,
+    Multiply,
+    Divide,
+    Modulus,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum CastType {
+    Integer,
+    Float,
+    String,
+    Bool,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum ScalarFunction {
+    Lower,
+    Upper,
+    Substr,
+    Length,
+    Trim,
+    Concat,
+    Coalesce,
+}
+
+impl ScalarFunction {
+    fn from_name(name: &str) -> Option<Self> {
+        let result = match name.to_lowercase().as_str() {
+            "lower" => Self::Lower,
+            "upper" => Self::Upper,
+            "substr" | "substring" => Self::Substr,
+            "length" | "char_length" => Self::Length,
+            "trim" => Self::Trim,
+            "concat" => Self::Concat,
+            "coalesce" => Self::Coalesce,
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn check_args(&self, count: usize) -> bool {
+        match self {
+            Self::Lower | Self::Upper | Self::Length | Self::Trim => count == 1,
+            Self::Substr => count == 2 || count == 3,
+            Self::Concat | Self::Coalesce => count > 0,
+        }
+    }
+}
+
+impl Expression {
+    pub(crate) fn parse(expr: &ast::Expr, headers: &Vec<String>) -> ApiResult<Self> {
+        let result = match expr {
+            ast::Expr::Identifier(i) => Self::Column(column_idx(&i.value, headers)?),
+            ast::Expr::CompoundIdentifier(vi) => {
+                Self::Column(column_idx(&compound_name(vi, headers)?, headers)?)
+            }
+            ast::Expr::Value(v) => Self::Literal(parse_literal(v)?),
+            ast::Expr::Nested(e) => Self::parse(e, headers)?,
+            ast::Expr::BinaryOp { left, op, right } => {
+                Self::parse_binary_op(left, op, right, headers)?
+            }
+            ast::Expr::UnaryOp { op, expr } => {
+                let expr = Box::new(Self::parse(expr, headers)?);
+                match op {
+                    ast::UnaryOperator::Not => Self::Not(expr),
+                    ast::UnaryOperator::Minus => Self::Negate(expr),
+                    ast::UnaryOperator::Plus => *expr,
+                }
+            }
+            ast::Expr::IsNull(e) => Self::IsNull(Box::new(Self::parse(e, headers)?)),
+            ast::Expr::IsNotNull(e) => Self::IsNotNull(Box::new(Self::parse(e, headers)?)),
+            ast::Expr::InList {
+                expr,
+                list,
+                negated,
+            } => {
+                let mut values = HashSet::with_capacity(list.len());
+                let mut has_null = false;
+                for e in list.iter() {
+                    match Self::parse(e, headers)? {
+                        Self::Literal(ValueOrdRef::Null) => has_null = true,
+                        Self::Literal(v) => {
+                            values.insert(v);
+                        }
+                        _ => return invalid_data_ae!("IN list should contain only values: {}", e),
+                    }
+                }
+                Self::InList {
+                    expr: Box::new(Self::parse(expr, headers)?),
+                    values: Arc::new(values),
+                    has_null,
+                    negated: *negated,
+                }
+            }
+            ast::Expr::Case {
+                operand,
+                conditions,
+                results,
+                else_result,
+            } => {
+                let mut parsed = Vec::with_capacity(conditions.len());
+                for (condition, result) in conditions.iter().zip(results.iter()) {
+                    parsed.push((
+                        Self::parse(condition, headers)?,
+                        Self::parse(result, headers)?,
+                    ));
+                }
+                Self::Case {
+                    operand: match operand {
+                        Some(e) => Some(Box::new(Self::parse(e, headers)?)),
+                        None => None,
+                    },
+                    conditions: parsed,
+                    else_result: match else_result {
+                        Some(e) => Some(Box::new(Self::parse(e, headers)?)),
+                        None => None,
+                    },
+                }
+            }
+            ast::Expr::Cast { expr, data_type } => Self::Cast(
+                Box::new(Self::parse(expr, headers)?),
+                parse_cast_type(data_type)?,
+            ),
+            ast::Expr::Function(f) => {
+                let name = match f.name.0.as_slice() {
+                    [name] => name.value.as_str(),
+                    _ => return invalid_data_ae!("unsupported function: {}", f.name),
+                };
+                guard!(let Some(function) = ScalarFunction::from_name(name) else {
+                    return invalid_data_ae!("unsupported function: {}", f.name);
+                });
+                if f.distinct || f.over.is_some() || !function.check_args(f.args.len()) {
+                    return invalid_data_ae!("wrong arguments of function: {}", expr);
+                }
+                let mut args = Vec::with_capacity(f.args.len());
+                for arg in f.args.iter() {
+                    args.push(Self::parse(arg, headers)?);
+                }
+                Self::Function(function, args)
+            }
+            _ => return invalid_data_ae!("unsupported expression: {}", expr),
+        };
+        Ok(result)
+    }
+
+    fn parse_binary_op(
+        left: &ast::Expr,
+        op: &ast::BinaryOperator,
+        right: &ast::Expr,
+        headers: &Vec<String>,
+    ) -> ApiResult<Self> {
+        let compare = match op {
+            ast::BinaryOperator::Eq => Some(BinaryOpType::Eq),
+            ast::BinaryOperator::NotEq => Some(BinaryOpType::NotEq),
+            ast::BinaryOperator::Lt => Some(BinaryOpType::Lt),
+            ast::BinaryOperator::LtEq => Some(BinaryOpType::LtEq),
+            ast::BinaryOperator::Gt => Some(BinaryOpType::Gt),
+            ast::BinaryOperator::GtEq => Some(BinaryOpType::GtEq),
+            _ => None,
+        };
+        if let Some(spec) = PatternSpec::parse(op, right)? {
+            return Ok(Self::Pattern(
+                Box::new(Self::parse(left, headers)?),
+                spec.compile()?,
+            ));
+        }
+        if let Some(compare) = compare {
+            // Unknown identifier on the right side is a string, like in simple WHERE
+            let right = match right {
+                ast::Expr::Identifier(i) if !headers.contains(&i.value) => {
+                    Self::Literal(ValueOrdRef::OwnedStr(i.value.as_str().into()))
+                }
+                right => Self::parse(right, headers)?,
+            };
+            return Ok(Self::Compare(
+                Box::new(Self::parse(left, headers)?),
+                compare,
+                Box::new(right),
+            ));
+        }
+
+        let left = Box::new(Self::parse(left, headers)?);
+        let right = Box::new(Self::parse(right, headers)?);
+        let result = match op {
+            ast::BinaryOperator::And => Self::And(left, right),
+            ast::BinaryOperator::Or => Self::Or(left, right),
+            ast::BinaryOperator::Plus => Self::Arithmetic(left, ArithmeticOp::Plus, right),
+            ast::BinaryOperator::Minus => Self::Arithmetic(left, ArithmeticOp::Minus, right),
+            ast::BinaryOperator::Multiply => Self::Arithmetic(left, ArithmeticOp::Multiply, right),
+            ast::BinaryOperator::Divide => Self::Arithmetic(left, ArithmeticOp::Divide, right),
+            ast::BinaryOperator::Modulus => Self::Arithmetic(left, ArithmeticOp::Modulus, right),
+            _ => return invalid_data_ae!("unsupported operator: {:?}", op),
+        };
+        Ok(result)
+    }
+
+    pub(crate) fn eval<'de>(&self, record: &dyn RecordRef<'de>) -> ApiResult<ValueRef<'de>> {
+        let result = match self {
+            Self::Column(idx) => record.value_at(*idx)?,
+            Self::Literal(v) => v.as_value_ref(),
+            Self::Arithmetic(left, op, right) => {
+                eval_arithmetic(left.eval(record)?, *op, right.eval(record)?)?
+            }
+            Self::Compare(left, op, right) => {
+                let left = left.eval(record)?;
+                let right = right.eval(record)?;
+                if left.is_null() || right.is_null() {
+                    return Ok(ValueRef::Null);
+                }
+                ValueRef::Bool(op.matches(&left.ord_ref(), &right.ord_ref()))
+            }
+            // Three-valued logic, NULL is unknown
+            Self::And(left, right) => match (left.eval_bool(record)?, right.eval_bool(record)?) {
+                (Some(false), _) | (_, Some(false)) => ValueRef::Bool(false),
+                (Some(true), Some(true)) => ValueRef::Bool(true),
+                _ => ValueRef::Null,
+            },
+            Self::Or(left, right) => match (left.eval_bool(record)?, right.eval_bool(record)?) {
+                (Some(true), _) | (_, Some(true)) => ValueRef::Bool(true),
+                (Some(false), Some(false)) => ValueRef::Bool(false),
+                _ => ValueRef::Null,
+            },
+            Self::Not(e) => match e.eval_bool(record)? {
+                Some(v) => ValueRef::Bool(!v),
+                None => ValueRef::Null,
+            },
+            Self::Negate(e) => match e.eval(record)? {
+                ValueRef::Null => ValueRef::Null,
+                ValueRef::Float(v) => ValueRef::Float(-v),
+                v => eval_arithmetic(ValueRef::UInteger(0), ArithmeticOp::Minus, v)?,
+            },
+            Self::Pattern(e, pattern) => match pattern.matches(&e.eval(record)?) {
+                Some(v) => ValueRef::Bool(v),
+                None => ValueRef::Null,
+            },
+            Self::IsNull(e) => ValueRef::Bool(e.eval(record)?.is_null()),
+            Self::IsNotNull(e) => ValueRef::Bool(!e.eval(record)?.is_null()),
+            // NULL if value isn't found, but list has NULL
+            Self::InList {
+                expr,
+                values,
+                has_null,
+                negated,
+            } => match expr.eval(record)? {
+                ValueRef::Null => ValueRef::Null,
+                v if values.contains(&v.ord_ref()) => ValueRef::Bool(!negated),
+                _ if *has_null => ValueRef::Null,
+                _ => ValueRef::Bool(*negated),
+            },
+            Self::Case {
+                operand,
+                conditions,
+                else_result,
+            } => {
+                let operand = match operand {
+                    Some(e) => Some(e.eval(record)?),
+                    None => None,
+                };
+                for (condition, result) in conditions.iter() {
+                    let matched = match &operand {
+                        Some(ValueRef::Null) => false,
+                        Some(v) => {
+                            let c = condition.eval(record)?;
+                            !c.is_null() && v.clone().ord_ref() == c.ord_ref()
+                        }
+                        None => condition.eval_bool(record)? == Some(true),
+                    };
+                    if matched {
+                        return result.eval(record);
+                    }
+                }
+                match else_result {
+                    Some(e) => e.eval(record)?,
+                    None => ValueRef::Null,
+                }
+            }
+            Self::Cast(e, cast_type) => eval_cast(e.eval(record)?, *cast_type)?,
+            Self::Function(function, args) => eval_function(*function, args, record)?,
+        };
+        Ok(result)
+    }
+
+    // `None` for NULL, non-boolean values are errors
+    pub(crate) fn eval_bool(&self, record: &dyn RecordRef) -> ApiResult<Option<bool>> {
+        match self.eval(record)? {
+            ValueRef::Bool(v) => Ok(Some(v)),
+            ValueRef::Null => Ok(None),
+            v => invalid_data_ae!("expected boolean, got {}", v),
+        }
+    }
+}
+
+fn column_idx(name: &str, headers: &Vec<String>) -> ApiResult<usize> {
+    guard!(let Some(idx) = headers.iter().position(|h| h == name) else {
+        return invalid_data_ae!("can't find column with name {}", name);
+    });
+    Ok(idx)
+}
+
+fn parse_literal(value: &ast::Value) -> ApiResult<ValueOrdRef<'static>> {
+    let result = match value {
+        ast::Value::Number(num_str) => ValueOrdRef::Number(parse_number(num_str)?),
+        ast::Value::SingleQuotedString(s) => ValueOrdRef::OwnedStr(s.as_str().into()),
+        ast::Value::Boolean(b) => ValueOrdRef::Bool(*b),
+        ast::Value::Null => ValueOrdRef::Null,
+        _ => return invalid_data_ae!("unsupported literal: {}", value),
+    };
+    Ok(result)
+}
+
+fn parse_cast_type(data_type: &ast::DataType) -> ApiResult<CastType> {
+    let result = match data_type {
+        ast::DataType::SmallInt | ast::DataType::Int | ast::DataType::BigInt => CastType::Integer,
+        ast::DataType::Float(_)
+        | ast::DataType::Real
+        | ast::DataType::Double
+        | ast::DataType::Decimal(_, _) => CastType::Float,
+        ast::DataType::Char(_)
+        | ast::DataType::Varchar(_)
+        | ast::DataType::Text
+        | ast::DataType::Clob(_) => CastType::String,
+        ast::DataType::Boolean => CastType::Bool,
+        _ => return invalid_data_ae!("unsupported cast type: {}", data_type),
+    };
+    Ok(result)
+}
+
+// Integers are computed in i128 and narrowed back, so mixed signs don't overflow,
+// results which don't fit in 64 bits become floats
+pub(crate) fn eval_arithmetic<'de>(
+    left: ValueRef<'de>,
+    op: ArithmeticOp,
+    right: ValueRef<'de>,
+) -> ApiResult<ValueRef<'de>> {
+    let result = match (&left, &right) {
+        (ValueRef::Null, _) | (_, ValueRef::Null) => ValueRef::Null,
+        (ValueRef::Float(_), _) | (_, ValueRef::Float(_)) => {
+            ValueRef::Float(eval_float(as_f64(&left)?, op, as_f64(&right)?))
+        }
+        _ => {
+            let (l, r) = (as_i128(&left)?, as_i128(&right)?);
+            if r == 0 && (op == ArithmeticOp::Divide || op == ArithmeticOp::Modulus) {
+                return invalid_data_ae!("division by zero");
+            }
+            let result = match op {
+                ArithmeticOp::Plus => l.checked_add(r),
+                ArithmeticOp::Minus => l.checked_sub(r),
+                ArithmeticOp::Multiply => l.checked_mul(r),
+                ArithmeticOp::Divide => l.checked_div(r),
+                ArithmeticOp::Modulus => l.checked_rem(r),
+            };
+            match result {
+                Some(v) if v >= 0 && v <= u64::MAX as i128 => ValueRef::UInteger(v as u64),
+                Some(v) if v < 0 && v >= i64::MIN as i128 => ValueRef::Integer(v as i64),
+                Some(v) => ValueRef::Float(v as f64),
+                None => ValueRef::Float(eval_float(l as f64, op, r as f64)),
+            }
+        }
+    };
+    Ok(result)
+}
+
+fn eval_float(l: f64, op: ArithmeticOp, r: f64) -> f64 {
+    match op {
+        ArithmeticOp::Plus => l + r,
+        ArithmeticOp::Minus => l - r,
+        ArithmeticOp::Multiply => l * r,
+        ArithmeticOp::Divide => l / r,
+        ArithmeticOp::Modulus => l % r,
+    }
+}
+
+fn as_i128(value: &ValueRef) -> ApiResult<i128> {
+    match *value {
+        ValueRef::UInteger(v) => Ok(v as i128),
+        ValueRef::Integer(v) => Ok(v as i128),
+        _ => invalid_data_ae!("not a number: {}", value),
+    }
+}
+
+pub(crate) fn as_f64(value: &ValueRef) -> ApiResult<f64> {
+    match *value {
+        ValueRef::UInteger(v) => Ok(v as f64),
+        ValueRef::Integer(v) => Ok(v as f64),
+        ValueRef::Float(v) => Ok(v),
+        _ => invalid_data_ae!("not a number: {}", value),
+    }
+}
+
+fn eval_cast<'de>(value: ValueRef<'de>, cast_type: CastType) -> ApiResult<ValueRef<'de>> {
+    let result = match (cast_type, value) {
+        (_, ValueRef::Null) => ValueRef::Null,
+        (CastType::Integer, v @ ValueRef::UInteger(_))
+        | (CastType::Integer, v @ ValueRef::Integer(_)) => v,
+        (CastType::Integer, ValueRef::Float(v)) => {
+            if !v.is_finite() || v.trunc() < i64::MIN as f64 || v.trunc() > u64::MAX as f64 {
+                return invalid_data_ae!("can't cast {} to integer", v);
+            }
+            if v < 0.0 {
+                ValueRef::Integer(v.trunc() as i64)
+            } else {
+                ValueRef::UInteger(v.trunc() as u64)
+            }
+        }
+        (CastType::Integer, ValueRef::Bool(v)) => ValueRef::UInteger(v as u64),
+        (CastType::Integer, v) => {
+            let s = v.to_string();
+            let s = s.trim();
+            if let Ok(num) = s.parse::<u64>() {
+                ValueRef::UInteger(num)
+            } else if let Ok(num) = s.parse::<i64>() {
+                ValueRef::Integer(num)
+            } else {
+                return invalid_data_ae!("can't cast {} to integer", s);
+            }
+        }
+        (CastType::Float, ValueRef::Bool(v)) => ValueRef::Float(if v { 1.0 } else { 0.0 }),
+        (CastType::Float, v @ ValueRef::UInteger(_))
+        | (CastType::Float, v @ ValueRef::Integer(_))
+        | (CastType::Float, v @ ValueRef::Float(_)) => ValueRef::Float(as_f64(&v)?),
+        (CastType::Float, v) => match v.to_string().trim().parse::<f64>() {
+            Ok(num) => ValueRef::Float(num),
+            Err(_) => return invalid_data_ae!("can't cast {} to float", v),
+        },
+        (CastType::String, v @ ValueRef::Str(_))
+        | (CastType::String, v @ ValueRef::OwnedStr(_)) => v,
+        (CastType::String, v) => ValueRef::OwnedStr(v.to_string().into()),
+        (CastType::Bool, v @ ValueRef::Bool(_)) => v,
+        (CastType::Bool, ValueRef::UInteger(v)) => ValueRef::Bool(v != 0),
+        (CastType::Bool, ValueRef::Integer(v)) => ValueRef::Bool(v != 0),
+        (CastType::Bool, ValueRef::Float(v)) => ValueRef::Bool(v != 0.0),
+        (CastType::Bool, v) => match v.to_string().trim().to_lowercase().as_str() {
+            "true" | "t" | "yes" | "y" | "1" => ValueRef::Bool(true),
+            "false" | "f" | "no" | "n" | "0" => ValueRef::Bool(false),
+            _ => return invalid_data_ae!("can't cast {} to boolean", v),
+        },
+    };
+    Ok(result)
+}
+
+fn eval_function<'de>(
+    function: ScalarFunction,
+    args: &Vec<Expression>,
+    record: &dyn RecordRef<'de>,
+) -> ApiResult<ValueRef<'de>> {
+    if function == ScalarFunction::Coalesce {
+        for arg in args.iter() {
+            let value = arg.eval(record)?;
+            if !value.is_null() {
+                return Ok(value);
+            }
+        }
+        return Ok(ValueRef::Null);
+    }
+    // NULL arguments are skipped by concat, other functions return NULL for them
+    if function == ScalarFunction::Concat {
+        let mut result = String::new();
+        for arg in args.iter() {
+            let value = arg.eval(record)?;
+            if !value.is_null() {
+                result.push_str(&value.to_string());
+            }
+        }
+        return Ok(owned_str(result));
+    }
+
+    let value = args[0].eval(record)?;
+    if value.is_null() {
+        return Ok(ValueRef::Null);
+    }
+    let result = match function {
+        ScalarFunction::Lower => owned_str(value.to_string().to_lowercase()),
+        ScalarFunction::Upper => owned_str(value.to_string().to_uppercase()),
+        ScalarFunction::Length => ValueRef::UInteger(value.to_string().chars().count() as u64),
+        ScalarFunction::Trim => match value {
+            ValueRef::Str(s) => ValueRef::Str(s.trim()),
+            v => owned_str(v.to_string().trim().to_owned()),
+        },
+        ScalarFunction::Substr => {
+            let start = args[1].eval(record)?;
+            let len = match args.get(2) {
+                Some(arg) => Some(arg.eval(record)?),
+                None => None,
+            };
+            if start.is_null() || len.as_ref().map_or(false, |l| l.is_null()) {
+                return Ok(ValueRef::Null);
+            }
+            // Positions are 1-based and counted in chars, start before 1 shortens result
+            let start = as_i128(&start)?;
+            let end = match len {
+                Some(len) => {
+                    let len = as_i128(&len)?;
+                    if len < 0 {
+                        return invalid_data_ae!("negative substring length");
+                    }
+                    Some(start + len)
+                }
+                None => None,
+            };
+            let s = value.to_string();
+            let result = s
+                .chars()
+                .enumerate()
+                .filter(|(idx, _)| {
+                    let pos = *idx as i128 + 1;
+                    pos >= start && end.map_or(true, |end| pos < end)
+                })
+                .map(|(_, c)| c)
+                .collect::<String>();
+            owned_str(result)
+        }
+        ScalarFunction::Concat | ScalarFunction::Coalesce => ValueRef::Null,
+    };
+    Ok(result)
+}
+
+#[inline]
+fn owned_str<'de>(s: String) -> ValueRef<'de> {
+    ValueRef::OwnedStr(Arc::from(s))
+}
@@ -1,7 +1,7 @@
 use crate::errors::*;
+use crate::pattern::Pattern;
 use crate::query::*;
-use crate::record::ValueRef;
-use flexbuffers::VectorReader;
+use crate::record::{RecordRef, ValueRef};
 
 pub(crate) fn create_filter(
     limit: Option<usize>,
@@ -10,10 +10,10 @@ pub(crate) fn create_filter(
     let result: Box<dyn Filter> = match (limit, selection) {
         (Some(l), Some(s)) => Box::new(CompositeFilter::new(vec![
             Box::new(LimitFilter::new(l)),
-            Box::new(SelectionFilter::new(s)),
+            Box::new(SelectionFilter::new(s)?),
         ])),
         (Some(l), None) => Box::new(LimitFilter::new(l)),
-        (None, Some(s)) => Box::new(SelectionFilter::new(s)),
+        (None, Some(s)) => Box::new(SelectionFilter::new(s)?),
         _ => return Ok(None),
     };
     Ok(Some(result))
@@ -26,7 +26,7 @@ pub(crate) enum FilterRes {
 }
 
 pub(crate) trait Filter {
-    fn filter(&mut self, record: &flexbuffers::VectorReader) -> ApiResult<FilterRes>;
+    fn filter(&mut self, record: &dyn RecordRef) -> ApiResult<FilterRes>;
 }
 
 struct LimitFilter {
@@ -44,7 +44,7 @@ impl LimitFilter {
 }
 
 impl Filter for LimitFilter {
-    fn filter(&mut self, _: &VectorReader) -> ApiResult<FilterRes> {
+    fn filter(&mut self, _: &dyn RecordRef) -> ApiResult<FilterRes> {
         self.cur_count += 1;
         if self.cur_count > self.limit {
             return Ok(FilterRes::NeedStop);
@@ -55,36 +55,69 @@ impl Filter for LimitFilter {
 
 struct SelectionFilter {
     selection: Selection,
+    pattern: Option<Pattern>,
 }
 
 impl SelectionFilter {
-    fn new(selection: Selection) -> Self {
-        Self { selection }
+    // Pattern is compiled once per query
+    fn new(selection: Selection) -> ApiResult<Self> {
+        let pattern = match &selection {
+            Selection::Pattern(_, spec) => Some(spec.clone().compile()?),
+            _ => None,
+        };
+        Ok(Self { selection, pattern })
     }
 }
 
 impl Filter for SelectionFilter {
-    fn filter(&mut self, value_reader: &VectorReader) -> ApiResult<FilterRes> {
+    fn filter(&mut self, value_reader: &dyn RecordRef) -> ApiResult<FilterRes> {
         let result = match &self.selection {
             Selection::BinaryOp(col_id, op, value) => {
-                let reader = value_reader.index(*col_id)?;
-                let rv = ValueRef::from_reader(&reader)?;
+                let rv = value_reader.value_at(*col_id)?;
                 let v = value.as_ord_ref();
-                match op {
-                    BinaryOpType::Eq => {
-                        if rv.ord_ref().eq(&v) {
-                            FilterRes::NeedProcess
-                        } else {
-                            FilterRes::NeedPass
-                        }
-                    }
+                if op.matches(&rv.ord_ref(), &v) {
+                    FilterRes::NeedProcess
+                } else {
+                    FilterRes::NeedPass
+                }
+            }
+            Selection::IsNull(col_id) => {
+                if is_null(value_reader, *col_id)? {
+                    FilterRes::NeedProcess
+                } else {
+                    FilterRes::NeedPass
+                }
+            }
+            Selection::IsNotNull(col_id) => {
+                if is_null(value_reader, *col_id)? {
+                    FilterRes::NeedPass
+                } else {
+                    FilterRes::NeedProcess
                 }
             }
+            Selection::Pattern(col_id, _) => {
+                guard!(let Some(pattern) = self.pattern.as_ref() else {
+                    return invalid_data_ae!("pattern is not compiled");
+                });
+                match pattern.matches(&value_reader.value_at(*col_id)?) {
+                    Some(true) => FilterRes::NeedProcess,
+                    _ => FilterRes::NeedPass,
+                }
+            }
+            // NULL result is not true, so row is skipped
+            Selection::Expression(expr) => match expr.eval_bool(value_reader)? {
+                Some(true) => FilterRes::NeedProcess,
+                _ => FilterRes::NeedPass,
+            },
         };
         Ok(result)
     }
 }
 
+fn is_null(value_reader: &dyn RecordRef, col_id: usize) -> ApiResult<bool> {
+    Ok(matches!(value_reader.value_at(col_id)?, ValueRef::Null))
+}
+
 struct CompositeFilter {
     inner: Vec<Box<dyn Filter>>,
 }
@@ -96,7 +129,7 @@ impl CompositeFilter {
 }
 
 impl Filter for CompositeFilter {
-    fn filter(&mut self, value: &VectorReader) -> ApiResult<FilterRes> {
+    fn filter(&mut self, value: &dyn RecordRef) -> ApiResult<FilterRes> {
         for f in &mut self.inner {
             match f.filter(value)? {
                 FilterRes::NeedStop => return Ok(FilterRes::NeedStop),
@@ -0,0 +1,220 @@
+use crate::errors::*;
+use crate::pattern::{is_word_part, skipped_end};
+
+use sqlparser::ast;
+use std::borrow::Cow;
+
+const GROUPING_SETS_MARKER: &str = "__grouping_sets";
+const GROUPING_SET_MARKER: &str = "__grouping_set";
+
+// CUBE of 12 columns is already 4096 grouping sets
+const MAX_CUBE_COLUMNS: usize = 12;
+const MAX_GROUPING_SETS: usize = 4096;
+
+// Columns of all grouping sets are kept once, sets refer to them by index
+pub(crate) struct GroupingSets {
+    pub(crate) columns: Vec<ast::Expr>,
+    pub(crate) sets: Vec<Vec<usize>>,
+}
+
+// SQL parser knows neither GROUPING SETS nor tuples, so they are rewritten before parsing
+// to marker functions: `GROUPING SETS ((a, b), ())` is `__grouping_sets(__grouping_set(a, b), __grouping_set())`
+pub(crate) fn rewrite_grouping_sets(sql: &str) -> ApiResult<Cow<str>> {
+    let bytes = sql.as_bytes();
+    let mut result = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if let Some(end) = skipped_end(bytes, i) {
+            i = end;
+            continue;
+        }
+        if !bytes[i].is_ascii_alphabetic() && bytes[i] != b'_' {
+            i += 1;
+            continue;
+        }
+
+        let end = word_end(bytes, i);
+        if !sql[i..end].eq_ignore_ascii_case("GROUPING") {
+            i = end;
+            continue;
+        }
+        let sets_start = whitespace_end(bytes, end);
+        let sets_end = word_end(bytes, sets_start);
+        let open = whitespace_end(bytes, sets_end);
+        if !sql[sets_start..sets_end].eq_ignore_ascii_case("SETS") || bytes.get(open) != Some(&b'(')
+        {
+            i = end;
+            continue;
+        }
+
+        result.push_str(&sql[copied..i]);
+        result.push_str(GROUPING_SETS_MARKER);
+        result.push('(');
+        copied = open + 1;
+        let mut depth = 1;
+        i = open + 1;
+        while i < bytes.len() && depth > 0 {
+            if let Some(end) = skipped_end(bytes, i) {
+                i = end;
+                continue;
+            }
+            match bytes[i] {
+                b'(' => {
+                    if depth == 1 {
+                        result.push_str(&sql[copied..i]);
+                        result.push_str(GROUPING_SET_MARKER);
+                        result.push('(');
+                        copied = i + 1;
+                    }
+                    depth += 1;
+                }
+                b')' => depth -= 1,
+                _ => (),
+            }
+            i += 1;
+        }
+    }
+
+    if copied == 0 {
+        return Ok(Cow::Borrowed(sql));
+    }
+    result.push_str(&sql[copied..]);
+    Ok(Cow::Owned(result))
+}
+
+fn word_end(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|b| !is_word_part(*b))
+        .map_or(bytes.len(), |p| start + p)
+}
+
+fn whitespace_end(bytes: &[u8], start: usize) -> usize {
+    bytes[start..]
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .map_or(bytes.len(), |p| start + p)
+}
+
+#[derive(Copy, Clone)]
+enum GroupingKind {
+    Rollup,
+    Cube,
+    Sets,
+}
+
+fn grouping_kind(expr: &ast::Expr) -> Option<(GroupingKind, &Vec<ast::Expr>)> {
+    guard!(let ast::Expr::Function(f) = expr else { return None });
+    let kind = match f.name.to_string().to_lowercase().as_str() {
+        "rollup" => GroupingKind::Rollup,
+        "cube" => GroupingKind::Cube,
+        GROUPING_SETS_MARKER => GroupingKind::Sets,
+        _ => return None,
+    };
+    Some((kind, &f.args))
+}
+
+// `None` if GROUP BY has no ROLLUP, CUBE or GROUPING SETS. Sets of GROUP BY items are combined
+// as cross product: `GROUP BY a, ROLLUP(b)` is `GROUPING SETS ((a, b), (a))`
+pub(crate) fn expand_grouping_sets(items: &[ast::Expr]) -> ApiResult<Option<GroupingSets>> {
+    if !items.iter().any(|e| grouping_kind(e).is_some()) {
+        return Ok(None);
+    }
+    let mut columns = Vec::new();
+    let mut sets = vec![Vec::new()];
+    for item in items.iter() {
+        let item_sets = item_sets(item, &mut columns)?;
+        // Size of product is checked before it's allocated
+        let count = sets.len().checked_mul(item_sets.len());
+        guard!(let Some(count) = count.filter(|c| *c <= MAX_GROUPING_SETS) else {
+            return invalid_data_ae!("more than {} grouping sets", MAX_GROUPING_SETS);
+        });
+        let mut product = Vec::with_capacity(count);
+        for set in sets.iter() {
+            for item_set in item_sets.iter() {
+                let mut combined: Vec<usize> = set.clone();
+                for c in item_set.iter() {
+                    if !combined.contains(c) {
+                        combined.push(*c);
+                    }
+                }
+                product.push(combined);
+            }
+        }
+        sets = product;
+    }
+    Ok(Some(GroupingSets { columns, sets }))
+}
+
+// `ROLLUP(a, b)` is `(a, b), (a), ()`, `CUBE(a, b)` is `(a, b), (a), (b), ()`
+fn item_sets(item: &ast::Expr, columns: &mut Vec<ast::Expr>) -> ApiResult<Vec<Vec<usize>>> {
+    guard!(let Some((kind, args)) = grouping_kind(item) else {
+        return Ok(vec![vec![grouping_column(item, columns)]]);
+    });
+    let result: Vec<Vec<usize>> = match kind {
+        GroupingKind::Rollup => {
+            let key = grouping_columns(args, columns);
+            (0..=key.len()).rev().map(|n| key[..n].to_vec()).collect()
+        }
+        GroupingKind::Cube => {
+            if args.len() > MAX_CUBE_COLUMNS {
+                return invalid_data_ae!("CUBE of more than {} columns", MAX_CUBE_COLUMNS);
+            }
+            let key = grouping_columns(args, columns);
+            (0..1usize << key.len())
+                .rev()
+                .map(|mask| {
+                    key.iter()
+                        .enumerate()
+                        .filter(|(idx, _)| mask & (1 << (key.len() - 1 - idx)) != 0)
+                        .map(|(_, c)| *c)
+                        .collect()
+                })
+                .collect()
+        }
+        GroupingKind::Sets => args
+            .iter()
+            .map(|arg| match arg {
+                ast::Expr::Function(f) if f.name.to_string() == GROUPING_SET_MARKER => {
+                    grouping_columns(&f.args, columns)
+                }
+                arg => vec![grouping_column(arg, columns)],
+            })
+            .collect(),
+    };
+    Ok(result)
+}
+
+fn grouping_columns(exprs: &Vec<ast::Expr>, columns: &mut Vec<ast::Expr>) -> Vec<usize> {
+    exprs.iter().map(|e| grouping_column(e, columns)).collect()
+}
+
+fn grouping_column(expr: &ast::Expr, columns: &mut Vec<ast::Expr>) -> usize {
+    if let Some(idx) = columns.iter().position(|c| c == expr) {
+        return idx;
+    }
+    columns.push(expr.clone());
+    columns.len() - 1
+}
+
+#[test]
+fn test_rewrite_grouping_sets() {
+    let sql = "SELECT a, grouping(a) FROM t GROUP BY ROLLUP(a, b)";
+    assert_eq!(rewrite_grouping_sets(sql).unwrap(), sql);
+
+    let sql = "SELECT 'grouping sets (' FROM t GROUP BY grouping  sets ((a, lower(b)), a, ())";
+    assert_eq!(
+        rewrite_grouping_sets(sql).unwrap(),
+        "SELECT 'grouping sets (' FROM t GROUP BY __grouping_sets(__grouping_set(a, lower(b)), a, __grouping_set())"
+    );
+
+    // Comments are skipped, inside of grouping sets too
+    let sql = "SELECT a /* grouping sets (a) */ FROM t -- grouping sets (a)\nGROUP BY a";
+    assert_eq!(rewrite_grouping_sets(sql).unwrap(), sql);
+    let sql = "SELECT a FROM t GROUP BY GROUPING SETS (/* ( */ (a), ())";
+    assert_eq!(
+        rewrite_grouping_sets(sql).unwrap(),
+        "SELECT a FROM t GROUP BY __grouping_sets(/* ( */ __grouping_set(a), __grouping_set())"
+    );
+}
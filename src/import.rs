@@ -0,0 +1,826 @@
+use crate::charset::*;
+use crate::compression::*;
+use crate::csv_utils::*;
+use crate::db::*;
+use crate::errors::*;
+use crate::jobs::*;
+use crate::misc_utils::*;
+use crate::multipart::*;
+use crate::ndjson_utils::*;
+use crate::parquet_utils::*;
+use crate::preview::*;
+use crate::record::record_key;
+use crate::settings::*;
+
+use bytes::Bytes;
+use encoding_rs::UTF_8;
+use futures::stream::{self, Stream, StreamExt};
+use json::JsonValue;
+use rocket::http::{ContentType, Status};
+use rocket::request;
+use std::borrow::BorrowMut;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::mpsc;
+
+#[derive(FromForm, Default)]
+pub struct ImportOptions {
+    // Run import in background and return job id immediately
+    #[form(field = "async")]
+    pub is_async: Option<bool>,
+    // Server side path or glob, relative to import dir
+    pub path: Option<String>,
+    // Input charset label, e.g. `windows-1252`, UTF-8 by default
+    pub encoding: Option<String>,
+    // Reject malformed input instead of replacing it with U+FFFD
+    pub strict: Option<bool>,
+    // Comma separated CSV values stored as NULL, e.g. `NA,\N`
+    pub nulls: Option<String>,
+    // Empty CSV fields are NULLs by default, otherwise they are stored as empty strings
+    pub empty_as_null: Option<bool>,
+    // `replace` (default) or `upsert`
+    pub mode: Option<String>,
+    // Key column for upsert mode
+    pub key: Option<String>,
+    // Commit and write checkpoint every N rows, so interrupted import could be resumed
+    pub commit_every: Option<u64>,
+    // Count of rows read by preview
+    pub rows: Option<usize>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ImportMode {
+    // Table is erased before import
+    Replace,
+    // Rows with existing key are replaced in place, new ones are appended
+    Upsert(String),
+}
+
+impl ImportMode {
+    pub fn from_options(options: &ImportOptions) -> ApiResult<Self> {
+        match (options.mode.as_deref(), options.key.as_ref()) {
+            (None, _) | (Some("replace"), _) => Ok(Self::Replace),
+            (Some("upsert"), Some(key)) => Ok(Self::Upsert(key.clone())),
+            (Some("upsert"), None) => invalid_data_ae!("key column is required for upsert"),
+            (Some(mode), _) => invalid_data_ae!("unknown import mode: {}", mode),
+        }
+    }
+}
+
+pub enum ImportSource {
+    Body {
+        // Request body, limited by `limited_bytes_stream`
+        body: BytesStream,
+        // Limit of decompressed body
+        limit: u64,
+        encoding: RequestEncoding,
+        format: ImportFormat,
+        range: ContentRange,
+        // File part of browser form is imported instead of whole body
+        multipart: MultipartBoundary,
+    },
+    // Server side CSV files, imported one after another
+    Files(Vec<PathBuf>),
+}
+
+// Everything import needs from request, so it could be moved into background job
+pub struct ImportRequest {
+    pub table: String,
+    pub options: ImportOptions,
+    pub source: ImportSource,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum ImportFormat {
+    CSV,
+    NDJSON,
+    Parquet,
+}
+
+const NDJSON_SUBTYPES: &[&str] = &["x-ndjson", "ndjson", "x-jsonlines", "jsonl"];
+const PARQUET_SUBTYPES: &[&str] = &["vnd.apache.parquet", "x-parquet"];
+
+impl ImportFormat {
+    pub fn from_content_type(ct: &ContentType) -> Option<Self> {
+        if !ct.top().as_str().eq_ignore_ascii_case("application") {
+            return None;
+        }
+        let sub = ct.sub().as_str();
+        if NDJSON_SUBTYPES.iter().any(|s| sub.eq_ignore_ascii_case(s)) {
+            Some(Self::NDJSON)
+        } else if PARQUET_SUBTYPES.iter().any(|s| sub.eq_ignore_ascii_case(s)) {
+            Some(Self::Parquet)
+        } else {
+            None
+        }
+    }
+
+    // Browsers send Parquet files as `application/octet-stream`, so extension is checked too
+    fn from_part(headers: &PartHeaders) -> Option<Self> {
+        let by_type = headers
+            .content_type
+            .as_deref()
+            .and_then(ContentType::parse_flexible)
+            .and_then(|ct| Self::from_content_type(&ct));
+        by_type.or_else(|| match headers.filename.as_deref() {
+            Some(name) if name.to_ascii_lowercase().ends_with(".parquet") => Some(Self::Parquet),
+            _ => None,
+        })
+    }
+}
+
+// Start of `Content-Range: bytes <start>-<end>/<total>`, body continues import from checkpoint
+#[derive(Debug, Copy, Clone)]
+pub struct ContentRange(pub Option<u64>);
+
+impl ContentRange {
+    pub fn parse_start(value: &str) -> Option<u64> {
+        let value = value.trim();
+        if !value.starts_with("bytes ") || !value.contains('-') {
+            return None;
+        }
+        let start = value["bytes ".len()..].split('-').next()?;
+        start.trim().parse::<u64>().ok()
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> request::FromRequest<'a, 'r> for ContentRange {
+    type Error = ();
+
+    async fn from_request(req: &'a request::Request<'r>) -> request::Outcome<Self, ()> {
+        guard!(let Some(value) = req.headers().get_one("Content-Range") else {
+            return request::Outcome::Success(Self(None));
+        });
+        match Self::parse_start(value) {
+            Some(start) => request::Outcome::Success(Self(Some(start))),
+            None => request::Outcome::Failure((Status::BadRequest, ())),
+        }
+    }
+}
+
+// Import format is detected by `Content-Type`, CSV is default
+#[rocket::async_trait]
+impl<'a, 'r> request::FromRequest<'a, 'r> for ImportFormat {
+    type Error = ();
+
+    async fn from_request(req: &'a request::Request<'r>) -> request::Outcome<ImportFormat, ()> {
+        let format = req
+            .content_type()
+            .and_then(ImportFormat::from_content_type)
+            .unwrap_or(ImportFormat::CSV);
+        request::Outcome::Success(format)
+    }
+}
+
+pub async fn run_import(
+    db: DBHandle,
+    jobs: JobsHandle,
+    request: ImportRequest,
+) -> ApiResult<String> {
+    let start = Instant::now();
+    let progress = Arc::new(ImportProgress::new(request.table.clone()));
+    let charset = Charset::new(
+        request.options.encoding.as_deref(),
+        request.options.strict.unwrap_or(false),
+    )?;
+    let mode = ImportMode::from_options(&request.options)?;
+    if let (ImportMode::Upsert(_), ImportSource::Body { format, .. }) = (&mode, &request.source) {
+        if *format != ImportFormat::CSV {
+            return invalid_data_ae!("upsert is supported for CSV import only");
+        }
+    }
+    // Range from the start is a normal import
+    let resume_from = match &request.source {
+        ImportSource::Body { range, .. } => range.0.filter(|&start| start > 0),
+        ImportSource::Files(_) => None,
+    };
+    if let ImportSource::Body {
+        multipart: MultipartBoundary(Some(_)),
+        ..
+    } = &request.source
+    {
+        // Offset of checkpoint is counted in file, not in form
+        if resume_from.is_some() {
+            return invalid_data_ae!("resume is not supported for multipart body");
+        }
+    }
+    let commit_every = request.options.commit_every;
+    if commit_every.is_some() || resume_from.is_some() {
+        guard!(let ImportSource::Body { format: ImportFormat::CSV, .. } = &request.source else {
+            return invalid_data_ae!("checkpoints are supported for CSV request body only");
+        });
+    }
+    if commit_every == Some(0) {
+        return invalid_data_ae!("commit_every should be positive");
+    }
+    // Offset of checkpoint is counted in file as sent, transcoded input has different offsets
+    if (commit_every.is_some() || resume_from.is_some()) && request.options.encoding.is_some() {
+        return invalid_data_ae!("checkpoints are not supported with encoding");
+    }
+    // `None` if not set, so upsert keeps NULL semantics of table
+    let nulls = match (&request.options.nulls, request.options.empty_as_null) {
+        (None, None) => None,
+        (nulls, empty_as_null) => Some(NullValues::new(
+            nulls.as_deref(),
+            empty_as_null.unwrap_or(true),
+        )),
+    };
+    let options = ImportTableOptions {
+        charset,
+        nulls,
+        mode,
+        commit_every,
+        resume_from,
+    };
+
+    if request.options.is_async.unwrap_or(false) {
+        // Body is received before job id is returned, so client doesn't wait for processing.
+        // Import continues on `db_writer` runtime, progress is polled with `GET /jobs/<id>`
+        let (request, spooled) = spool_body(request).await?;
+        let id = jobs.register(progress.clone())?;
+        let job_db = db.clone();
+        let job_progress = progress.clone();
+        db.spawn_job(async move {
+            let result = import_table(job_db, request, options, job_progress.clone()).await;
+            let _ = job_progress.finish(&result);
+            // Spooled body is kept until import is done
+            drop(spooled);
+        });
+        return Ok(progress.to_json(id)?.dump());
+    }
+
+    let result = import_table(db, request, options, progress.clone()).await;
+    progress.finish(&result)?;
+
+    Ok(format!(
+        "{} records sucessfully imported, duration: {:?}",
+        result?,
+        start.elapsed()
+    ))
+}
+
+// Raw body is written to temp file as is, it's decompressed and parsed later by import itself
+async fn spool_body(mut request: ImportRequest) -> ApiResult<(ImportRequest, Option<TempFile>)> {
+    let temp = match &mut request.source {
+        ImportSource::Body { body, .. } => {
+            let temp = TempFile::new("body");
+            spool_to_file(body, &temp.path).await?;
+            let file = tokio::fs::File::open(&temp.path).await?;
+            *body = Box::pin(into_bytes_stream(file));
+            Some(temp)
+        }
+        ImportSource::Files(_) => None,
+    };
+    Ok((request, temp))
+}
+
+// Dry run of CSV import, shows how body would be imported without opening write transaction
+pub async fn run_preview(request: ImportRequest) -> ApiResult<String> {
+    let charset = Charset::new(
+        request.options.encoding.as_deref(),
+        request.options.strict.unwrap_or(false),
+    )?;
+    let nulls = NullValues::new(
+        request.options.nulls.as_deref(),
+        request.options.empty_as_null.unwrap_or(true),
+    );
+    let rows = request.options.rows.unwrap_or(DEFAULT_PREVIEW_ROWS);
+    guard!(let ImportSource::Body { body, limit, encoding, format, multipart, .. } = request.source else {
+        return invalid_data_ae!("preview is supported for request body only");
+    });
+    let (format, mut bs, _) =
+        body_stream(body, limit, encoding, &multipart, format, charset, false).await?;
+    if format != ImportFormat::CSV {
+        return invalid_data_ae!("preview is supported for CSV import only");
+    }
+
+    let preview = preview_csv(&mut bs, &nulls, rows).await?;
+    Ok(preview.to_json(&request.table).dump())
+}
+
+// Input of import from request body, file part of multipart form is decompressed on its own,
+// as file could be compressed regardless of `Content-Encoding` of the whole body,
+// format of file part is detected by its own headers
+// Checkpoint offset is counted in file as sent, so with `checkpoints` only UTF-8 BOM is
+// allowed to be removed from input, its length is returned to be added to offset
+async fn body_stream(
+    bs: BytesStream,
+    limit: u64,
+    encoding: RequestEncoding,
+    multipart: &MultipartBoundary,
+    mut format: ImportFormat,
+    charset: Charset,
+    checkpoints: bool,
+) -> ApiResult<(ImportFormat, BytesStream, usize)> {
+    let (mut detected, bs) = detect_encoding(encoding.0, bs).await;
+    let mut bs = decode_stream(Some(detected), bs).await;
+    if let Some(boundary) = multipart.0.as_ref() {
+        let (headers, part) = file_part(boundary, bs).await?;
+        format = ImportFormat::from_part(&headers).unwrap_or(format);
+        let (part_encoding, part) = detect_encoding(None, part).await;
+        detected = part_encoding;
+        bs = decode_stream(Some(detected), part).await;
+    }
+    if checkpoints && detected != ContentEncoding::Identity {
+        return invalid_data_ae!("checkpoints are not supported for compressed input");
+    }
+    // Decompressed body is limited too
+    let bs: BytesStream = Box::pin(limit_stream(bs, limit));
+    // Parquet is binary, charset of its strings is always UTF-8
+    if format == ImportFormat::Parquet {
+        return Ok((format, bs, 0));
+    }
+    match transcode_stream_bom(charset, bs).await {
+        (bs, Some((bom, len))) if bom == UTF_8 => Ok((format, bs, len)),
+        (_, Some((bom, _))) if checkpoints => {
+            invalid_data_ae!("checkpoints are not supported for {} input", bom.name())
+        }
+        (bs, _) => Ok((format, bs, 0)),
+    }
+}
+
+struct ImportTableOptions {
+    charset: Charset,
+    nulls: Option<NullValues>,
+    mode: ImportMode,
+    commit_every: Option<u64>,
+    resume_from: Option<u64>,
+}
+
+async fn import_table(
+    db: DBHandle,
+    request: ImportRequest,
+    options: ImportTableOptions,
+    progress: Arc<ImportProgress>,
+) -> ApiResult<usize> {
+    // Open write transaction, only one write txn is possible at the moment,
+    // so we should await in case if second write txn exist and acquire lock
+    let mut write_lock = db.mutation().await;
+    let mutable_db = write_lock.borrow_mut();
+
+    mutable_db
+        .mutable_table(
+            request.table.clone(),
+            async move |mut txn| -> ApiResult<usize> {
+                let mut table = CSVTable::open(&mut txn, &options)?;
+
+                let records_imported = match request.source {
+                    ImportSource::Body {
+                        body,
+                        limit,
+                        encoding,
+                        format,
+                        multipart,
+                        ..
+                    } => {
+                        // Transform request Body into stream of bytes, decompressing it on the fly
+                        let bs = progress_bytes_stream(body, progress.clone());
+                        let checkpoints =
+                            options.commit_every.is_some() || options.resume_from.is_some();
+                        let (format, mut bs, bom_len) = body_stream(
+                            Box::pin(bs),
+                            limit,
+                            encoding,
+                            &multipart,
+                            format,
+                            options.charset,
+                            checkpoints,
+                        )
+                        .await?;
+
+                        match format {
+                            ImportFormat::CSV => {
+                                // Offset is counted in file, including its BOM
+                                table.offset += bom_len as u64;
+                                import_csv_part(&mut txn, &mut bs, &mut table, &progress).await?
+                            }
+                            ImportFormat::NDJSON => {
+                                // JSON has its own nulls, empty strings are kept as is
+                                table.nulls = NullValues::new(None, false);
+                                import_ndjson(&mut txn, &mut bs, &progress).await?
+                            }
+                            ImportFormat::Parquet => {
+                                // File part of form isn't known to be Parquet before it's read
+                                if let ImportMode::Upsert(_) = options.mode {
+                                    return invalid_data_ae!(
+                                        "upsert is supported for CSV import only"
+                                    );
+                                }
+                                if options.commit_every.is_some() {
+                                    return invalid_data_ae!(
+                                        "checkpoints are supported for CSV request body only"
+                                    );
+                                }
+                                table.nulls = NullValues::new(None, false);
+                                import_parquet(&mut txn, &mut bs, &progress).await?
+                            }
+                        }
+                    }
+                    ImportSource::Files(files) => {
+                        import_csv_files(&mut txn, files, options.charset, &mut table, &progress)
+                            .await?
+                    }
+                };
+
+                // Keep NULL semantics and key in catalog, so it's known how table was imported,
+                // checkpoint is removed as import is complete
+                txn.put_meta(table.meta().dump().as_bytes())?;
+
+                txn.commit()?;
+                Ok(records_imported)
+            },
+        )
+        .await
+}
+
+// Target table of CSV import, shared by all CSV inputs of one import
+struct CSVTable {
+    nulls: NullValues,
+    // Table headers, `None` if table is empty, then they are written by the first input
+    headers: Option<Vec<String>>,
+    // Upsert key column and its index, resolved once headers are known
+    key: Option<String>,
+    key_idx: Option<usize>,
+    last_key: Option<String>,
+    // Input is continued from checkpoint, so it has no headers
+    resumed: bool,
+    // Bytes of input written to table, including headers
+    offset: u64,
+    commit_every: Option<u64>,
+    uncommitted: u64,
+}
+
+impl CSVTable {
+    fn new(
+        nulls: NullValues,
+        headers: Option<Vec<String>>,
+        key: Option<String>,
+        options: &ImportTableOptions,
+    ) -> Self {
+        Self {
+            nulls,
+            headers,
+            key,
+            key_idx: None,
+            last_key: None,
+            resumed: options.resume_from.is_some(),
+            offset: options.resume_from.unwrap_or(0),
+            commit_every: options.commit_every,
+            uncommitted: 0,
+        }
+    }
+
+    fn open(txn: &mut WriteTransaction, options: &ImportTableOptions) -> ApiResult<Self> {
+        let meta = table_meta(txn.meta()?)?;
+
+        if let Some(offset) = options.resume_from {
+            guard!(let Some(expected) = meta["checkpoint"]["offset"].as_u64() else {
+                return invalid_data_ae!("no checkpoint to resume import from");
+            });
+            if offset != expected {
+                return invalid_data_ae!(
+                    "wrong resume offset {}, checkpoint is at {}",
+                    offset,
+                    expected
+                );
+            }
+        }
+
+        let key = match &options.mode {
+            ImportMode::Upsert(key) => Some(key.clone()),
+            ImportMode::Replace => None,
+        };
+        if key.is_none() && options.resume_from.is_none() {
+            // Erase table if there is previous import
+            txn.drop()?;
+            let nulls = options.nulls.clone().unwrap_or_default();
+            return Ok(Self::new(nulls, None, None, options));
+        }
+
+        let nulls = match (&options.nulls, meta["nulls"].is_null()) {
+            (Some(nulls), _) => nulls.clone(),
+            (None, false) => NullValues::from_json(&meta["nulls"]),
+            (None, true) => NullValues::default(),
+        };
+        let mut result = Self::new(nulls, txn.headers()?, key.clone(), options);
+        txn.seek_end()?;
+
+        guard!(let Some(key) = key else {
+            // Appended rows are not indexed, so index is stale
+            txn.drop_key_index()?;
+            return Ok(result);
+        });
+
+        // Index is rebuilt if table was imported with other key or without it
+        if meta["key"].as_str() != Some(key.as_str()) {
+            txn.drop_key_index()?;
+        }
+        if txn.open_key_index()? {
+            if let Some(headers) = result.headers.clone() {
+                let key_idx = result.resolve_key(&headers)?;
+                txn.build_key_index(|record| record_key(record, key_idx))?;
+            }
+        }
+        Ok(result)
+    }
+
+    fn meta(&self) -> JsonValue {
+        let mut meta = JsonValue::new_object();
+        meta["nulls"] = self.nulls.to_json();
+        if let Some(key) = self.key.as_ref() {
+            meta["key"] = key.as_str().into();
+        }
+        meta
+    }
+
+    fn checkpoint_meta(&self, txn: &WriteTransaction) -> JsonValue {
+        let mut checkpoint = JsonValue::new_object();
+        checkpoint["offset"] = self.offset.into();
+        if let Some(idx) = txn.last_idx() {
+            checkpoint["last_row"] = idx.into();
+        }
+        if let Some(key) = self.last_key.as_ref() {
+            checkpoint["last_key"] = key.as_str().into();
+        }
+        let mut meta = self.meta();
+        meta["checkpoint"] = checkpoint;
+        meta
+    }
+
+    fn resolve_key(&mut self, headers: &Vec<String>) -> ApiResult<usize> {
+        guard!(let Some(key) = self.key.as_ref() else { return other_e!("no key column") });
+        guard!(let Some(idx) = headers.iter().position(|h| h == key) else {
+            return invalid_data_ae!("key column not found: {}", key);
+        });
+        self.key_idx = Some(idx);
+        Ok(idx)
+    }
+
+    // Headers are written for the first input only, following inputs should have the same headers
+    fn check_headers(
+        &mut self,
+        txn: &mut WriteTransaction,
+        headers: &Vec<String>,
+    ) -> ApiResult<()> {
+        match self.headers.as_ref() {
+            Some(expected) if expected != headers => {
+                return invalid_data_ae!(
+                    "headers mismatch, expected {:?}, got {:?}",
+                    expected,
+                    headers
+                );
+            }
+            Some(_) => (),
+            None => {
+                // Write headers
+                txn.append(string_vec_to_flex(headers))?;
+                self.headers = Some(headers.clone());
+            }
+        }
+        if self.key.is_some() {
+            self.resolve_key(headers)?;
+        }
+        Ok(())
+    }
+
+    // Record is upserted if it has key, otherwise it's appended
+    fn write(
+        &mut self,
+        txn: &mut WriteTransaction,
+        data: Vec<u8>,
+        key: Option<String>,
+    ) -> ApiResult<()> {
+        guard!(let Some(key) = key else { return txn.append(data) });
+        txn.upsert(&key, data)?;
+        self.last_key = Some(key);
+        Ok(())
+    }
+
+    // Commits at batch boundary once `commit_every` rows are written,
+    // checkpoint is committed along with rows
+    fn add_batch(&mut self, txn: &mut WriteTransaction, rows: u64, bytes: u64) -> ApiResult<()> {
+        self.offset += bytes;
+        self.uncommitted += rows;
+        guard!(let Some(commit_every) = self.commit_every else { return Ok(()) });
+        if self.uncommitted < commit_every {
+            return Ok(());
+        }
+        let meta = self.checkpoint_meta(txn);
+        txn.put_meta(meta.dump().as_bytes())?;
+        txn.commit_and_continue()?;
+        self.uncommitted = 0;
+        Ok(())
+    }
+}
+
+fn table_meta(meta: Option<Vec<u8>>) -> ApiResult<JsonValue> {
+    guard!(let Some(meta) = meta else { return Ok(JsonValue::Null) });
+    let meta = String::from_utf8_lossy(&meta);
+    match json::parse(&meta) {
+        Ok(meta) => Ok(meta),
+        Err(e) => invalid_data_ae!("broken table meta: {}", e),
+    }
+}
+
+// Checkpoint of unfinished import, to resume it with `Content-Range`
+pub fn import_checkpoint(db: &DBHandle, table: String) -> ApiResult<String> {
+    let table = db.table(table);
+    let txn = table.open_transaction()?;
+    let meta = table_meta(txn.meta()?)?;
+    if meta["checkpoint"].is_null() {
+        return not_found_ae!("no checkpoint");
+    }
+    Ok(meta["checkpoint"].dump())
+}
+
+async fn import_csv_files(
+    txn: &mut WriteTransaction,
+    files: Vec<PathBuf>,
+    charset: Charset,
+    table: &mut CSVTable,
+    progress: &Arc<ImportProgress>,
+) -> ApiResult<usize> {
+    let mut records_imported: usize = 0;
+    for path in files.iter() {
+        let file = tokio::fs::File::open(path).await?;
+        let bs = progress_bytes_stream(into_bytes_stream(file), progress.clone());
+        // Compressed files are detected by magic bytes
+        let bs = decode_stream(None, bs).await;
+        let mut bs = transcode_stream(charset, bs).await;
+        records_imported += import_csv_part(txn, &mut bs, table, progress)
+            .await
+            .map_err(|e| ApiError(invalid_data!("{:?}: {}", path, e.0)))?;
+    }
+    Ok(records_imported)
+}
+
+// Imports one of csv inputs into table
+async fn import_csv_part(
+    txn: &mut WriteTransaction,
+    bs: &mut BytesStream,
+    table: &mut CSVTable,
+    progress: &ImportProgress,
+) -> ApiResult<usize> {
+    let mut splitter = CSVBatchSplitter::new();
+
+    let headers = if table.resumed {
+        // Resumed input starts right after checkpoint, headers are taken from table
+        table.resumed = false;
+        guard!(let Some(headers) = table.headers.clone() else {
+            return invalid_data_ae!("no headers in table to resume import");
+        });
+        headers
+    } else {
+        // Headers are parsed first, the rest is split into batches of whole records
+        let headers_line = loop {
+            if let Some(line) = splitter.take_line() {
+                break line;
+            }
+            guard!(let Some(bytes) = bs.next().await else {
+                guard!(let Some(line) = splitter.finish() else {
+                    return invalid_data_ae!("empty body");
+                });
+                break line;
+            });
+            splitter.add_chunk(bytes?);
+        };
+        table.offset += headers_line.len() as u64;
+        CSVImportReader::from_first_chunk(headers_line)?
+            .headers()
+            .clone()
+    };
+    table.check_headers(txn, &headers)?;
+
+    // Batches are parsed and encoded by blocking pool,
+    // `buffered` keeps them in input order, so rows are appended in the same order
+    let nulls = Arc::new(table.nulls.clone());
+    let headers_count = headers.len();
+    let key_idx = table.key_idx;
+    let mut batches = csv_batches(bs, splitter)
+        .map(move |batch| {
+            let nulls = nulls.clone();
+            async move {
+                let batch = batch?;
+                let len = batch.len();
+                let records = tokio::task::spawn_blocking(move || {
+                    parse_csv_batch(batch, &nulls, headers_count, key_idx)
+                })
+                .await??;
+                ApiResult::Ok((records, len))
+            }
+        })
+        .buffered(PARSE_WORKERS_COUNT);
+
+    let mut records_imported: usize = 0;
+    while let Some(batch) = batches.next().await {
+        let (batch, len) = batch?;
+        let count = batch.records.len();
+        let mut keys = batch.keys.into_iter();
+        for data in batch.records {
+            table.write(txn, data, keys.next())?;
+        }
+        table.add_batch(txn, count as u64, len as u64)?;
+        records_imported += count;
+        progress.add_rows(count as u64);
+    }
+
+    Ok(records_imported)
+}
+
+const PARSE_WORKERS_COUNT: usize = 4;
+const MIN_BATCH_SIZE: usize = 1024 * 1024;
+
+fn csv_batches<'a>(
+    bs: &'a mut BytesStream,
+    splitter: CSVBatchSplitter,
+) -> impl Stream<Item = ApiResult<Bytes>> + 'a {
+    stream::unfold(Some((bs, splitter)), |state| async move {
+        let (bs, mut splitter) = state?;
+        loop {
+            if let Some(batch) = splitter.next_batch(MIN_BATCH_SIZE) {
+                return Some((Ok(batch), Some((bs, splitter))));
+            }
+            match bs.next().await {
+                Some(Ok(bytes)) => splitter.add_chunk(bytes),
+                Some(Err(e)) => return Some((Err(e.into()), None)),
+                None => return splitter.finish().map(|batch| (Ok(batch), None)),
+            }
+        }
+    })
+}
+
+pub async fn import_ndjson(
+    txn: &mut WriteTransaction,
+    bs: &mut BytesStream,
+    progress: &ImportProgress,
+) -> ApiResult<usize> {
+    // Headers are union of keys of all records, so they are known only in the end
+    txn.reserve_headers();
+
+    let mut reader = NDJSONImportReader::new();
+    let mut records_imported: usize = 0;
+
+    loop {
+        while let Some(record) = reader.next_record()? {
+            txn.append(record)?;
+            records_imported += 1;
+            progress.add_rows(1);
+        }
+
+        guard!(let Some(bytes) = bs.next().await else { break });
+        reader.add_chunk(bytes?);
+    }
+
+    if let Some(record) = reader.finish()? {
+        txn.append(record)?;
+        records_imported += 1;
+        progress.add_rows(1);
+    }
+
+    if records_imported == 0 {
+        return invalid_data_ae!("empty body");
+    }
+
+    txn.put_headers(string_vec_to_flex(&reader.headers()))?;
+    Ok(records_imported)
+}
+
+// Parquet is read from temp file, as its metadata is stored in the end.
+// Rows are decoded by blocking pool, transaction is written here
+pub async fn import_parquet(
+    txn: &mut WriteTransaction,
+    bs: &mut BytesStream,
+    progress: &ImportProgress,
+) -> ApiResult<usize> {
+    let temp = TempFile::new("parquet");
+    if spool_to_file(bs, &temp.path).await? == 0 {
+        return invalid_data_ae!("empty body");
+    }
+
+    let (tx, mut rx) = mpsc::channel(PARQUET_CHANNEL_SIZE);
+    let path = temp.path.clone();
+    tokio::task::spawn_blocking(move || decode_parquet(path, tx));
+
+    let mut records_imported: usize = 0;
+    while let Some(batch) = rx.recv().await {
+        match batch? {
+            ParquetBatch::Headers(headers) => {
+                txn.reserve_headers();
+                txn.put_headers(string_vec_to_flex(&headers))?;
+            }
+            ParquetBatch::Records(records) => {
+                let count = records.len();
+                for record in records {
+                    txn.append(record)?;
+                }
+                records_imported += count;
+                progress.add_rows(count as u64);
+            }
+        }
+    }
+    Ok(records_imported)
+}
+
+const PARQUET_CHANNEL_SIZE: usize = 4;
@@ -0,0 +1,189 @@
+use crate::errors::*;
+
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use indexmap::IndexMap;
+use json::JsonValue;
+use rocket::{request, State};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::io::Result;
+
+// Finished jobs are kept for polling, but not forever
+pub const MAX_FINISHED_JOBS: usize = 1024;
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum JobStatus {
+    Running,
+    Done,
+    Failed,
+}
+
+impl JobStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Running => "running",
+            Self::Done => "done",
+            Self::Failed => "failed",
+        }
+    }
+}
+
+struct JobState {
+    status: JobStatus,
+    errors: Vec<String>,
+    duration: Option<std::time::Duration>,
+}
+
+pub struct ImportProgress {
+    table: String,
+    started: Instant,
+    bytes_read: AtomicU64,
+    rows_imported: AtomicU64,
+    state: Mutex<JobState>,
+}
+
+impl ImportProgress {
+    pub fn new(table: String) -> Self {
+        Self {
+            table,
+            started: Instant::now(),
+            bytes_read: AtomicU64::new(0),
+            rows_imported: AtomicU64::new(0),
+            state: Mutex::new(JobState {
+                status: JobStatus::Running,
+                errors: Vec::new(),
+                duration: None,
+            }),
+        }
+    }
+
+    #[inline]
+    pub fn add_bytes(&self, count: u64) {
+        self.bytes_read.fetch_add(count, Ordering::Relaxed);
+    }
+
+    #[inline]
+    pub fn add_rows(&self, count: u64) {
+        self.rows_imported.fetch_add(count, Ordering::Relaxed);
+    }
+
+    pub fn rows_imported(&self) -> u64 {
+        self.rows_imported.load(Ordering::Relaxed)
+    }
+
+    pub fn status(&self) -> ApiResult<JobStatus> {
+        Ok(self.state.lock()?.status)
+    }
+
+    pub fn finish<T>(&self, result: &ApiResult<T>) -> ApiResult<()> {
+        let mut state = self.state.lock()?;
+        state.duration = Some(self.started.elapsed());
+        match result {
+            Ok(_) => state.status = JobStatus::Done,
+            Err(e) => {
+                state.status = JobStatus::Failed;
+                state.errors.push(e.0.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn to_json(&self, id: u64) -> ApiResult<JsonValue> {
+        let state = self.state.lock()?;
+        let mut result = JsonValue::new_object();
+        result["id"] = id.into();
+        result["table"] = self.table.as_str().into();
+        result["status"] = state.status.as_str().into();
+        result["bytes_read"] = self.bytes_read.load(Ordering::Relaxed).into();
+        result["rows_imported"] = self.rows_imported().into();
+        result["errors"] = state
+            .errors
+            .iter()
+            .map(|e| JsonValue::from(e.as_str()))
+            .collect::<Vec<JsonValue>>()
+            .into();
+        let duration = state.duration.unwrap_or_else(|| self.started.elapsed());
+        result["duration"] = format!("{:?}", duration).into();
+        Ok(result)
+    }
+}
+
+// Counts raw body bytes read from client
+pub fn progress_bytes_stream<S>(
+    stream: S,
+    progress: Arc<ImportProgress>,
+) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    stream.map(move |chunk| {
+        if let Ok(bytes) = chunk.as_ref() {
+            progress.add_bytes(bytes.len() as u64);
+        }
+        chunk
+    })
+}
+
+pub struct Jobs {
+    next_id: AtomicU64,
+    jobs: Mutex<IndexMap<u64, Arc<ImportProgress>>>,
+}
+
+impl Jobs {
+    pub fn new() -> Self {
+        Self {
+            next_id: AtomicU64::new(1),
+            jobs: Mutex::new(IndexMap::new()),
+        }
+    }
+
+    pub fn register(&self, progress: Arc<ImportProgress>) -> ApiResult<u64> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let mut jobs = self.jobs.lock()?;
+        let mut finished = Vec::new();
+        for (idx, (_, job)) in jobs.iter().enumerate() {
+            if job.status()? != JobStatus::Running {
+                finished.push(idx);
+            }
+        }
+        // Running jobs are never removed, the oldest finished ones are removed first
+        if finished.len() >= MAX_FINISHED_JOBS {
+            let excess = finished.len() + 1 - MAX_FINISHED_JOBS;
+            for idx in finished[..excess].iter().rev() {
+                jobs.shift_remove_index(*idx);
+            }
+        }
+        jobs.insert(id, progress);
+        Ok(id)
+    }
+
+    pub fn get(&self, id: u64) -> ApiResult<Arc<ImportProgress>> {
+        guard!(let Some(job) = self.jobs.lock()?.get(&id).cloned() else {
+            return not_found_ae!("no job with id {}", id);
+        });
+        Ok(job)
+    }
+}
+
+#[derive(Clone)]
+pub struct JobsHandle(pub Arc<Jobs>);
+
+impl std::ops::Deref for JobsHandle {
+    type Target = Jobs;
+
+    fn deref(&self) -> &Jobs {
+        &*self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> request::FromRequest<'a, 'r> for JobsHandle {
+    type Error = ();
+
+    async fn from_request(req: &'a request::Request<'r>) -> request::Outcome<JobsHandle, ()> {
+        let jobs = try_outcome!(req.guard::<State<JobsHandle>>().await);
+        request::Outcome::Success(jobs.clone())
+    }
+}
@@ -0,0 +1,250 @@
+use crate::db::RowCursor;
+use crate::errors::*;
+use crate::misc_utils::flex_to_string_vec;
+use crate::query::*;
+use crate::query_processor::QueryProcessor;
+use crate::record::*;
+
+use flexbuffers::VectorReader;
+use std::collections::HashMap;
+
+type JoinKey<'de> = Vec<ValueOrdRef<'de>>;
+
+struct JoinStep {
+    kind: JoinKind,
+    // Columns of joined row before this table
+    probe_columns: Vec<usize>,
+    // Columns of joined table record
+    build_columns: Vec<usize>,
+}
+
+// Rows of joined tables are hashed by join key, rows of the first table are streamed
+// and probed against them, joined rows go to query processor
+pub struct HashJoin<'de> {
+    headers: Vec<String>,
+    // First column of each table in joined row
+    offsets: Vec<usize>,
+    steps: Vec<JoinStep>,
+    // Records stay valid while read transactions are open
+    hashed: Vec<HashMap<JoinKey<'de>, Vec<VectorReader<'de>>>>,
+}
+
+impl<'de> HashJoin<'de> {
+    pub fn new(query: &Query, tables_headers: Vec<Vec<String>>) -> ApiResult<Self> {
+        let tables = query.get_table_refs()?;
+        let joins = query.get_joins()?;
+        if tables.len() != tables_headers.len() {
+            return invalid_data_ae!(
+                "expected headers of {} tables, got {}",
+                tables.len(),
+                tables_headers.len()
+            );
+        }
+
+        let headers = joined_headers(&tables, &tables_headers);
+        let mut offsets = Vec::with_capacity(tables_headers.len());
+        let mut offset = 0;
+        for h in tables_headers.iter() {
+            offsets.push(offset);
+            offset += h.len();
+        }
+
+        let mut steps = Vec::with_capacity(joins.len());
+        for (idx, join) in joins.iter().enumerate() {
+            let start = offsets[idx + 1];
+            let end = start + tables_headers[idx + 1].len();
+            let mut step = JoinStep {
+                kind: join.kind,
+                probe_columns: Vec::new(),
+                build_columns: Vec::new(),
+            };
+            for (left, right) in parse_join_on(&join.on, &headers)? {
+                let (probe, build) = match (left, right) {
+                    (l, r) if l < start && r >= start && r < end => (l, r),
+                    (l, r) if r < start && l >= start && l < end => (r, l),
+                    _ => {
+                        return invalid_data_ae!(
+                            "join of {} should compare its columns with previous tables",
+                            join.table.qualifier()
+                        )
+                    }
+                };
+                step.probe_columns.push(probe);
+                step.build_columns.push(build - start);
+            }
+            steps.push(step);
+        }
+
+        Ok(Self {
+            headers,
+            offsets,
+            hashed: steps.iter().map(|_| HashMap::new()).collect(),
+            steps,
+        })
+    }
+
+    // Headers are read from the first record of each cursor, then joined tables are hashed
+    pub fn from_cursors(
+        query: &Query,
+        cursors: &mut [Box<dyn RowCursor<'de> + '_>],
+    ) -> ApiResult<Self> {
+        let mut tables_headers = Vec::with_capacity(cursors.len());
+        for cursor in cursors.iter() {
+            tables_headers.push(flex_to_string_vec(cursor.data()?));
+        }
+        let mut result = Self::new(query, tables_headers)?;
+        for (table, cursor) in cursors.iter_mut().enumerate().skip(1) {
+            while let Some(record) = cursor.next()? {
+                result.add_build_row(table, record)?;
+            }
+        }
+        Ok(result)
+    }
+
+    pub fn headers(&self) -> &Vec<String> {
+        &self.headers
+    }
+
+    // Tables are counted from the first one, which isn't hashed
+    pub fn add_build_row(&mut self, table: usize, record: VectorReader<'de>) -> ApiResult<()> {
+        if table == 0 || table > self.steps.len() {
+            return invalid_data_ae!("table {} isn't joined", table);
+        }
+        let step = &self.steps[table - 1];
+        // NULL never equals to anything, so such rows are never matched
+        guard!(let Some(key) = join_key(&record, &step.build_columns)? else { return Ok(()) });
+        self.hashed[table - 1]
+            .entry(key)
+            .or_insert_with(Vec::new)
+            .push(record);
+        Ok(())
+    }
+
+    // Rows of the first table are read after joined tables are hashed
+    pub fn process(
+        &self,
+        cursor: &mut dyn RowCursor<'de>,
+        processor: &mut QueryProcessor<'_, 'de>,
+    ) -> ApiResult<()> {
+        while let Some(record) = cursor.next()? {
+            if !self.probe(record, processor)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // `false` if processor doesn't need more rows
+    pub fn probe(
+        &self,
+        record: VectorReader<'de>,
+        processor: &mut QueryProcessor<'_, 'de>,
+    ) -> ApiResult<bool> {
+        if self.steps.is_empty() {
+            return processor.process_record(&record);
+        }
+        let mut parts = Vec::with_capacity(self.offsets.len());
+        parts.push(Some(record));
+        self.probe_step(0, &mut parts, processor)
+    }
+
+    fn probe_step(
+        &self,
+        idx: usize,
+        parts: &mut Vec<Option<VectorReader<'de>>>,
+        processor: &mut QueryProcessor<'_, 'de>,
+    ) -> ApiResult<bool> {
+        guard!(let Some(step) = self.steps.get(idx) else {
+            return processor.process_record(&self.joined(parts));
+        });
+        let key = join_key(&self.joined(parts), &step.probe_columns)?;
+        match key.and_then(|k| self.hashed[idx].get(&k)) {
+            Some(records) => {
+                for record in records.iter() {
+                    parts.push(Some(record.clone()));
+                    let proceed = self.probe_step(idx + 1, parts, processor)?;
+                    parts.pop();
+                    if !proceed {
+                        return Ok(false);
+                    }
+                }
+                Ok(true)
+            }
+            None if step.kind == JoinKind::Left => {
+                parts.push(None);
+                let proceed = self.probe_step(idx + 1, parts, processor)?;
+                parts.pop();
+                Ok(proceed)
+            }
+            None => Ok(true),
+        }
+    }
+
+    fn joined<'a>(&'a self, parts: &'a [Option<VectorReader<'de>>]) -> JoinedRecord<'a, 'de> {
+        JoinedRecord {
+            parts,
+            offsets: &self.offsets,
+            len: self.headers.len(),
+        }
+    }
+}
+
+// Columns with the same name in several tables are qualified, like `donors.Donor ID`
+fn joined_headers(tables: &[TableRef], tables_headers: &[Vec<String>]) -> Vec<String> {
+    let mut counts = HashMap::<&String, usize>::new();
+    for h in tables_headers.iter().flatten() {
+        *counts.entry(h).or_insert(0) += 1;
+    }
+    let mut result = Vec::with_capacity(counts.len());
+    for (table, headers) in tables.iter().zip(tables_headers.iter()) {
+        for h in headers.iter() {
+            if counts[h] > 1 {
+                result.push(format!("{}.{}", table.qualifier(), h));
+            } else {
+                result.push(h.clone());
+            }
+        }
+    }
+    result
+}
+
+fn join_key<'de>(
+    record: &dyn RecordRef<'de>,
+    columns: &[usize],
+) -> ApiResult<Option<JoinKey<'de>>> {
+    let mut key = Vec::with_capacity(columns.len());
+    for c in columns.iter() {
+        let value = record.value_at(*c)?;
+        if value.is_null() {
+            return Ok(None);
+        }
+        key.push(value.ord_ref());
+    }
+    Ok(Some(key))
+}
+
+// Records of joined tables one after another, missing record of LEFT JOIN is NULLs
+struct JoinedRecord<'a, 'de> {
+    parts: &'a [Option<VectorReader<'de>>],
+    offsets: &'a [usize],
+    len: usize,
+}
+
+impl<'a, 'de> RecordRef<'de> for JoinedRecord<'a, 'de> {
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    fn value_at(&self, idx: usize) -> ApiResult<ValueRef<'de>> {
+        if idx >= self.len {
+            return invalid_data_ae!("out of bounds");
+        }
+        guard!(let Some(part) = self.offsets.iter().rposition(|o| *o <= idx) else {
+            return invalid_data_ae!("out of bounds");
+        });
+        match self.parts.get(part) {
+            Some(Some(record)) => record.value_at(idx - self.offsets[part]),
+            _ => Ok(ValueRef::Null),
+        }
+    }
+}
@@ -6,12 +6,31 @@ pub mod errors;
 pub mod misc_utils;
 
 mod aggregator;
+pub mod arrow_utils;
+pub mod charset;
+pub mod compression;
 pub mod csv_utils;
 pub mod db;
+pub mod executor;
+pub mod export;
+mod expression;
 mod filter;
+mod grouping_sets;
+pub mod import;
+pub mod jobs;
+pub mod join;
+pub mod mem_table;
+pub mod multipart;
+pub mod ndjson_utils;
+pub mod parquet_utils;
+mod pattern;
+pub mod preview;
 pub mod query;
 pub mod query_processor;
 pub mod record;
+pub mod set_operation;
+pub mod settings;
+mod window;
 
 #[macro_use]
 extern crate guard;
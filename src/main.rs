@@ -6,21 +6,45 @@ mod errors;
 mod misc_utils;
 
 mod aggregator;
+mod arrow_utils;
+mod charset;
+mod compression;
 mod csv_utils;
 mod db;
+mod executor;
+mod export;
+mod expression;
 mod filter;
+mod grouping_sets;
+mod import;
+mod jobs;
+mod join;
 mod logger;
+mod mem_table;
+mod multipart;
+mod ndjson_utils;
+mod parquet_utils;
+mod pattern;
+mod preview;
 mod query;
 mod query_processor;
 mod record;
+mod set_operation;
+mod settings;
+mod window;
 
-use csv_utils::*;
+use compression::*;
 use db::*;
 use errors::*;
+use executor::*;
+use export::*;
+use import::*;
+use jobs::*;
 use misc_utils::*;
+use multipart::*;
 use query::*;
-use query_processor::*;
 use record::*;
+use settings::*;
 
 #[macro_use]
 extern crate guard;
@@ -36,16 +60,14 @@ use mimalloc::MiMalloc;
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
 
-use futures::stream::StreamExt;
 use logger::SyncLogger;
-use rocket::{config::LoggingLevel, data::ToByteUnit, request::Form, Data};
+use rocket::{config::LoggingLevel, request::Form, Data};
 use rocket_contrib::serve::StaticFiles;
 use sloggers::{
     terminal::{Destination, TerminalLoggerBuilder},
     types::Severity,
     Build,
 };
-use std::borrow::BorrowMut;
 use std::fmt::Write;
 use std::sync::Arc;
 use std::time::Instant;
@@ -53,106 +75,172 @@ use std::time::Instant;
 //`import` is async endpoint,
 // we need to await body chunks and also unique writer lock
 // without locking
-#[post("/import/<table>", data = "<data>")]
+#[post("/import/<table>?<options..>", data = "<data>")]
 async fn import(
     /*log: SyncLogger,*/ db: DBHandle,
+    settings: SettingsHandle,
+    jobs: JobsHandle,
+    api_key: ApiKey,
     table: String,
+    options: Option<Form<ImportOptions>>,
+    encoding: RequestEncoding,
+    format: ImportFormat,
+    range: ContentRange,
+    multipart: MultipartBoundary,
     data: Data,
 ) -> ApiResult<String> {
-    let start = Instant::now();
-    // Open write transaction, only one write txn is possible at the moment,
-    // so we should await in case if second write txn exist and acquire lock
-    let mut write_lock = db.mutation().await;
-    let mutable_db = write_lock.borrow_mut();
-
-    mutable_db
-        .mutable_table(table.clone(), async move |mut txn| -> ApiResult<String> {
-            // Erase table if there is previous import
-            txn.drop()?;
-
-            // Transform request Body into stream of bytes
-            let mut bs = into_bytes_stream(data.open(16.megabytes()));
-            guard!(let Some(bytes) = bs.next().await else { return invalid_data_ae!("empty body") });
-
-            // Construct csv reader object with first chunk of data and try parse headers
-            let mut reader = CSVImportReader::from_first_chunk(bytes?)?;
-            // debug!(log, "headers parsed: {:?}", reader.headers());
-
-            let mut records_imported: usize = 0;
-
-            // Write headers
-            let headers = reader.headers();
-            txn.append(string_vec_to_flex(headers))?;
-
-            let headers_count = headers.len();
-            loop {
-                // Start parsing csv records
-                let mut iter = reader.parse_records();
-                while let Some(value) = iter.next() {
-                    let record = value?;
-                    if headers_count != record.len() {
-                        return invalid_data_ae!(
-                    "headers and fields count mismatch, expected {}, got {}",
-                    headers_count,
-                    record.len()
-                );
-                    }
-                    // Convert parsed record to flexbuffers and append to table
-                    txn.append(record.as_flexbuffer())?;
-                    records_imported += 1;
-                }
-
-                // Await for next chunk and add to cvs reader
-                guard!(let Some(bytes) = bs.next().await else { break });
-                reader.add_chunk(bytes?);
-            }
-
-            txn.commit()?;
-
-            Ok(format!(
-                "{} records sucessfully imported, duration: {:?}",
-                records_imported,
-                start.elapsed()
-            ))
-        })
-        .await
+    let limit = settings.import_limit(&table, &api_key);
+    let request = ImportRequest {
+        source: ImportSource::Body {
+            body: Box::pin(limited_bytes_stream(data, limit)),
+            limit,
+            encoding,
+            format,
+            range,
+            multipart,
+        },
+        table,
+        options: options.map(|o| o.into_inner()).unwrap_or_default(),
+    };
+    run_import(db, jobs, request).await
+}
+
+// Admin endpoint to import CSV files already placed on server, in one transaction
+#[post("/import/<table>/from_path?<options..>")]
+async fn import_from_path(
+    db: DBHandle,
+    settings: SettingsHandle,
+    jobs: JobsHandle,
+    api_key: ApiKey,
+    table: String,
+    options: Option<Form<ImportOptions>>,
+) -> ApiResult<String> {
+    settings.check_admin(&api_key)?;
+    let options = options.map(|o| o.into_inner()).unwrap_or_default();
+    guard!(let Some(path) = options.path.as_ref() else {
+        return invalid_data_ae!("path is empty");
+    });
+    let request = ImportRequest {
+        source: ImportSource::Files(settings.import_files(path)?),
+        table,
+        options,
+    };
+    run_import(db, jobs, request).await
+}
+
+// Shows headers, column types, NULLs and errors of first rows, nothing is imported
+#[post("/import/<table>/preview?<options..>", data = "<data>")]
+async fn preview(
+    settings: SettingsHandle,
+    api_key: ApiKey,
+    table: String,
+    options: Option<Form<ImportOptions>>,
+    encoding: RequestEncoding,
+    format: ImportFormat,
+    multipart: MultipartBoundary,
+    data: Data,
+) -> ApiResult<String> {
+    let limit = settings.import_limit(&table, &api_key);
+    let request = ImportRequest {
+        source: ImportSource::Body {
+            body: Box::pin(limited_bytes_stream(data, limit)),
+            limit,
+            encoding,
+            format,
+            range: ContentRange(None),
+            multipart,
+        },
+        table,
+        options: options.map(|o| o.into_inner()).unwrap_or_default(),
+    };
+    run_preview(request).await
+}
+
+#[get("/import/<table>/checkpoint")]
+fn checkpoint(db: DBHandle, table: String) -> ApiResult<String> {
+    import_checkpoint(&db, table)
+}
+
+#[get("/jobs/<id>")]
+fn job(jobs: JobsHandle, id: u64) -> ApiResult<String> {
+    Ok(jobs.get(id)?.to_json(id)?.dump())
+}
+
+// Streams whole table or query result in `format`, `csv` by default
+#[get("/export/<table>?<options..>")]
+async fn export(
+    db: DBHandle,
+    table: String,
+    options: Option<Form<ExportOptions>>,
+) -> ApiResult<ExportResponse> {
+    let options = options.map(|o| o.into_inner()).unwrap_or_default();
+    let format = ExportFormat::from_options(&options)?;
+    let query_options = QueryOptions {
+        distinct: DistinctMode::from_option(options.distinct.as_deref())?,
+    };
+    let source = match options.sql {
+        Some(sql) => ExportSource::Query(sql, query_options, Some(table)),
+        None => ExportSource::Table(table),
+    };
+    run_export(db, source, format).await
 }
 
 #[derive(FromForm)]
 struct SQLQueryString {
     sql: String,
+    // `exact` (default) or `approx` count of distinct values
+    distinct: Option<String>,
+}
+
+impl SQLQueryString {
+    fn options(&self) -> ApiResult<QueryOptions> {
+        Ok(QueryOptions {
+            distinct: DistinctMode::from_option(self.distinct.as_deref())?,
+        })
+    }
+}
+
+// Typed query result for `Accept: application/vnd.apache.arrow.stream`,
+// record batches are sent while result is iterated
+#[get("/query?<sql..>", rank = 1)]
+async fn query_arrow(
+    db: DBHandle,
+    _accept: ArrowAccepted,
+    sql: Option<Form<SQLQueryString>>,
+) -> ApiResult<ExportResponse> {
+    guard!(let Some(sql) = sql else { return invalid_data_ae!("query is empty"); });
+    let options = sql.options()?;
+    let source = ExportSource::Query(sql.into_inner().sql, options, None);
+    run_export(db, source, ExportFormat::Arrow).await
 }
 
 //`query` is sync endpoint, as there are no locks on critical path
-#[get("/query?<sql..>")]
+#[get("/query?<sql..>", rank = 2)]
 fn query(db: DBHandle, sql: Option<Form<SQLQueryString>>) -> ApiResult<String> {
     let start = Instant::now();
     guard!(let Some(sql) = sql else { return invalid_data_ae!("query is empty"); });
     // Parsing query string
     let query = Query::from_query_str(sql.sql.as_str())?;
-    // Get table reference from query
-    let table_ref = query.get_table_name()?;
-    // Open read transaction
-    let table = db.table(table_ref.name);
-    let txn = table.open_transaction()?;
-    // And acquire cursor
-    let mut cursor = txn.cursor_on_start()?;
-    // Getting headers from first record
-    let headers = flex_to_string_vec(cursor.data()?);
-    // Initialize query processor
-    let mut processor = QueryProcessor::new(query, headers)?;
-    while let Some(rec) = cursor.next()? {
-        if !processor.process_record(&rec)? {
-            break;
+    // Open read transactions of tables used by query and its subqueries
+    let tables = query
+        .get_stored_tables()?
+        .into_iter()
+        .map(|name| (name.clone(), db.table(name)))
+        .collect::<Vec<_>>();
+    let txns = tables
+        .iter()
+        .map(|(name, t)| t.open_transaction().map(|txn| (name.clone(), txn)))
+        .collect::<ApiResult<ReadTransactions>>()?;
+    // Process query and iterate over results
+    let mut result = execute_query(&txns, query, sql.options()?, |processor| {
+        let mut result = String::new();
+        writeln!(result, "{}", processor.headers_csv())?;
+        let mut iter = processor.iter();
+        while let Some(record) = iter.next()? {
+            writeln!(result, "{}", record.to_csv()?)?;
         }
-    }
-    // Iterate over results
-    let mut result = String::new();
-    writeln!(result, "{}", processor.headers_csv())?;
-    let mut iter = processor.iter();
-    while let Some(record) = iter.next()? {
-        writeln!(result, "{}", record.to_csv()?)?;
-    }
+        Ok(result)
+    })?;
     writeln!(result, "duration: {:?}", start.elapsed())?;
     Ok(result)
 }
@@ -173,13 +261,31 @@ async fn run() -> ApiResult<()> {
 
     let logger = SyncLogger(Arc::new(logger));
 
+    let settings = SettingsHandle(Arc::new(Settings::from_config(config)?));
+
     let db = db::DB::new("~/.db_test").unwrap();
     let db = DBHandle(Arc::new(db));
 
+    let jobs = JobsHandle(Arc::new(Jobs::new()));
+
     rocket
-        .mount("/", routes![import, query])
+        .mount(
+            "/",
+            routes![
+                import,
+                import_from_path,
+                preview,
+                checkpoint,
+                job,
+                export,
+                query_arrow,
+                query
+            ],
+        )
         .mount("/test", StaticFiles::from("static"))
         .manage(logger)
+        .manage(settings)
+        .manage(jobs)
         .manage(db)
         .launch()
         .await?;
@@ -0,0 +1,119 @@
+use crate::db::RowCursor;
+use crate::errors::*;
+use crate::misc_utils::{flex_to_string_vec, string_vec_to_flex};
+use crate::ndjson_utils::push_json_value;
+use crate::query_processor::QueryProcessor;
+use crate::record::*;
+
+use flexbuffers::{Reader, VectorReader};
+
+// Materialized result of CTE or subquery, rows are flexbuffers like in stored table
+// and the first one is headers
+pub struct MemTable {
+    rows: Vec<Vec<u8>>,
+}
+
+impl MemTable {
+    pub fn new(headers: &Vec<String>) -> Self {
+        Self {
+            rows: vec![string_vec_to_flex(headers)],
+        }
+    }
+
+    pub fn from_processor(processor: &QueryProcessor) -> ApiResult<Self> {
+        let mut result = Self::new(processor.headers());
+        let mut iter = processor.iter();
+        while let Some(record) = iter.next()? {
+            result.push(record)?;
+        }
+        Ok(result)
+    }
+
+    pub fn push(&mut self, record: &dyn RecordRef) -> ApiResult<()> {
+        self.rows.push(record_to_flex(record)?);
+        Ok(())
+    }
+
+    // Row already serialized as flexbuffer vector, e.g. by import
+    pub fn push_flex(&mut self, data: Vec<u8>) {
+        self.rows.push(data);
+    }
+
+    pub fn headers(&self) -> ApiResult<Vec<String>> {
+        Ok(flex_to_string_vec(
+            Reader::get_root(&self.rows[0])?.as_vector(),
+        ))
+    }
+
+    // Column aliases of `WITH name (a, b) AS` or of subquery alias
+    pub fn rename_columns(&mut self, columns: &Vec<String>) -> ApiResult<()> {
+        let headers = self.headers()?;
+        if columns.len() != headers.len() {
+            return invalid_data_ae!(
+                "expected {} column aliases, got {}",
+                headers.len(),
+                columns.len()
+            );
+        }
+        self.rows[0] = string_vec_to_flex(columns);
+        Ok(())
+    }
+
+    // Rows without headers
+    pub fn len(&self) -> usize {
+        self.rows.len() - 1
+    }
+
+    pub fn cursor(&self) -> MemCursor<'_> {
+        MemCursor {
+            rows: &self.rows,
+            idx: 0,
+        }
+    }
+}
+
+pub struct MemCursor<'de> {
+    rows: &'de [Vec<u8>],
+    idx: usize,
+}
+
+impl<'de> RowCursor<'de> for MemCursor<'de> {
+    fn data(&self) -> ApiResult<VectorReader<'de>> {
+        let rows: &'de [Vec<u8>] = self.rows;
+        guard!(let Some(row) = rows.get(self.idx) else {
+            return invalid_data_ae!("NULL data");
+        });
+        Ok(Reader::get_root(row.as_slice())?.as_vector())
+    }
+
+    fn next(&mut self) -> ApiResult<Option<VectorReader<'de>>> {
+        if self.idx + 1 >= self.rows.len() {
+            return Ok(None);
+        }
+        self.idx += 1;
+        Ok(Some(self.data()?))
+    }
+}
+
+fn record_to_flex(record: &dyn RecordRef) -> ApiResult<Vec<u8>> {
+    let mut builder = flexbuffers::Builder::default();
+    let mut vec = builder.start_vector();
+    for idx in 0..record.len() {
+        match record.value_at(idx)? {
+            ValueRef::UInteger(v) => vec.push(v),
+            ValueRef::Integer(v) => vec.push(v),
+            ValueRef::Float(v) => vec.push(v),
+            ValueRef::Bool(v) => vec.push(v),
+            ValueRef::Str(s) => vec.push(s),
+            ValueRef::OwnedStr(s) => vec.push(s.as_ref()),
+            // Nested values are stored back as maps and vectors
+            ValueRef::Json(s) => match json::parse(&s) {
+                Ok(value) => push_json_value(&mut vec, &value),
+                Err(e) => return invalid_data_ae!("wrong nested value {}: {}", s, e),
+            },
+            ValueRef::Null => vec.push(()),
+        }
+    }
+    vec.end_vector();
+    Ok(builder.take_buffer())
+}
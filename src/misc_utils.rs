@@ -23,6 +23,7 @@ pub fn expand_tilde<P: AsRef<Path>>(path_user_input: P) -> Option<PathBuf> {
 use bytes::Bytes;
 use flexbuffers::VectorReader;
 use futures::stream::{Stream, TryStreamExt};
+use std::sync::atomic::{AtomicUsize, Ordering};
 use tokio::io::{AsyncRead, Result};
 use tokio_util::codec;
 
@@ -44,3 +45,30 @@ pub(crate) fn string_vec_to_flex(data: &Vec<String>) -> Vec<u8> {
 pub(crate) fn flex_to_string_vec(value: VectorReader) -> Vec<String> {
     value.iter().map(|r| r.as_str().to_owned()).collect()
 }
+
+static TEMP_FILES_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+// File in system temp dir, removed on drop, already opened handles keep working
+pub struct TempFile {
+    pub path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(extension: &str) -> Self {
+        let name = format!(
+            "test_db-{}-{}.{}",
+            std::process::id(),
+            TEMP_FILES_COUNT.fetch_add(1, Ordering::Relaxed),
+            extension
+        );
+        Self {
+            path: std::env::temp_dir().join(name),
+        }
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::compression::BytesStream;
+use crate::errors::*;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::stream::{self, StreamExt};
+use rocket::http::Status;
+use rocket::request;
+use tokio::io::Result;
+
+const MAX_PART_HEADERS_SIZE: usize = 8 * 1024;
+
+// Boundary of `multipart/form-data` body, `None` if body is plain file
+#[derive(Debug, Clone)]
+pub struct MultipartBoundary(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'a, 'r> request::FromRequest<'a, 'r> for MultipartBoundary {
+    type Error = ();
+
+    async fn from_request(req: &'a request::Request<'r>) -> request::Outcome<Self, ()> {
+        match req.content_type() {
+            Some(ct)
+                if ct.top().as_str().eq_ignore_ascii_case("multipart")
+                    && ct.sub().as_str().eq_ignore_ascii_case("form-data") =>
+            {
+                let boundary = ct.params().find(|(key, _)| {
+                    let key: &str = key.as_ref();
+                    key.eq_ignore_ascii_case("boundary")
+                });
+                match boundary {
+                    Some((_, boundary)) if !boundary.is_empty() => {
+                        request::Outcome::Success(Self(Some(boundary.to_owned())))
+                    }
+                    _ => request::Outcome::Failure((Status::BadRequest, ())),
+                }
+            }
+            _ => request::Outcome::Success(Self(None)),
+        }
+    }
+}
+
+// Headers of form part which matter for import
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct PartHeaders {
+    pub name: Option<String>,
+    pub filename: Option<String>,
+    pub content_type: Option<String>,
+}
+
+impl PartHeaders {
+    pub fn parse(data: &[u8]) -> Self {
+        let mut result = Self::default();
+        for line in String::from_utf8_lossy(data).split("\r\n") {
+            let mut parts = line.splitn(2, ':');
+            let (name, value) = match (parts.next(), parts.next()) {
+                (Some(name), Some(value)) => (name.trim(), value.trim()),
+                _ => continue,
+            };
+            if name.eq_ignore_ascii_case("Content-Type") {
+                result.content_type = Some(value.to_owned());
+            } else if name.eq_ignore_ascii_case("Content-Disposition") {
+                // e.g. `form-data; name="file"; filename="Donors.csv"`
+                for param in value.split(';').skip(1) {
+                    let mut kv = param.splitn(2, '=');
+                    let (key, value) = match (kv.next(), kv.next()) {
+                        (Some(key), Some(value)) => (key.trim(), value.trim().trim_matches('"')),
+                        _ => continue,
+                    };
+                    if key.eq_ignore_ascii_case("name") {
+                        result.name = Some(value.to_owned());
+                    } else if key.eq_ignore_ascii_case("filename") {
+                        result.filename = Some(value.to_owned());
+                    }
+                }
+            }
+        }
+        result
+    }
+}
+
+struct MultipartReader {
+    stream: BytesStream,
+    buf: BytesMut,
+    // `\r\n--<boundary>`, body of part ends right before it
+    delimiter: Vec<u8>,
+    eof: bool,
+}
+
+impl MultipartReader {
+    fn new(boundary: &str, stream: BytesStream) -> Self {
+        let mut buf = BytesMut::new();
+        // So the first boundary could be found with the same delimiter as others
+        buf.put_slice(b"\r\n");
+        Self {
+            stream,
+            buf,
+            delimiter: format!("\r\n--{}", boundary).into_bytes(),
+            eof: false,
+        }
+    }
+
+    async fn fill(&mut self) -> Result<bool> {
+        if self.eof {
+            return Ok(false);
+        }
+        match self.stream.next().await {
+            Some(bytes) => {
+                self.buf.put(bytes?);
+                Ok(true)
+            }
+            None => {
+                self.eof = true;
+                Ok(false)
+            }
+        }
+    }
+
+    // Skips everything up to the next delimiter and the line after it,
+    // returns `false` if it was the closing one
+    async fn next_part(&mut self) -> Result<bool> {
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                self.buf.advance(pos + self.delimiter.len());
+                break;
+            }
+            // Keep tail, it could be the start of delimiter
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                self.buf.advance(self.buf.len() - keep);
+            }
+            if !self.fill().await? {
+                return invalid_data_e!("unterminated multipart body");
+            }
+        }
+        loop {
+            if self.buf.len() >= 2 {
+                if self.buf.starts_with(b"--") {
+                    return Ok(false);
+                }
+                if let Some(pos) = find(&self.buf, b"\r\n") {
+                    self.buf.advance(pos + 2);
+                    return Ok(true);
+                }
+            }
+            if !self.fill().await? {
+                return invalid_data_e!("unterminated multipart body");
+            }
+        }
+    }
+
+    async fn part_headers(&mut self) -> Result<PartHeaders> {
+        loop {
+            // Part without headers starts with empty line
+            if self.buf.starts_with(b"\r\n") {
+                self.buf.advance(2);
+                return Ok(PartHeaders::default());
+            }
+            if let Some(pos) = find(&self.buf, b"\r\n\r\n") {
+                let headers = PartHeaders::parse(&self.buf[..pos]);
+                self.buf.advance(pos + 4);
+                return Ok(headers);
+            }
+            if self.buf.len() > MAX_PART_HEADERS_SIZE {
+                return invalid_data_e!("too big multipart headers");
+            }
+            if !self.fill().await? {
+                return invalid_data_e!("unterminated multipart body");
+            }
+        }
+    }
+
+    // Next chunk of current part body, `None` once delimiter is reached
+    async fn next_chunk(&mut self) -> Option<Result<Bytes>> {
+        loop {
+            if let Some(pos) = find(&self.buf, &self.delimiter) {
+                if pos == 0 {
+                    return None;
+                }
+                return Some(Ok(self.buf.split_to(pos).freeze()));
+            }
+            let keep = self.delimiter.len() - 1;
+            if self.buf.len() > keep {
+                let len = self.buf.len() - keep;
+                return Some(Ok(self.buf.split_to(len).freeze()));
+            }
+            match self.fill().await {
+                Ok(true) => continue,
+                Ok(false) => return Some(invalid_data_e!("unterminated multipart body")),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|w| w == needle)
+}
+
+// Streams body of the first file part of form, other fields are skipped,
+// the rest of body after file part is not read
+pub async fn file_part(
+    boundary: &str,
+    stream: BytesStream,
+) -> ApiResult<(PartHeaders, BytesStream)> {
+    let mut reader = MultipartReader::new(boundary, stream);
+    loop {
+        if !reader.next_part().await? {
+            return invalid_data_ae!("no file in multipart body");
+        }
+        let headers = reader.part_headers().await?;
+        if headers.filename.is_some() {
+            let part = stream::unfold(Some(reader), |reader| async move {
+                let mut reader = reader?;
+                match reader.next_chunk().await {
+                    Some(Ok(bytes)) => Some((Ok(bytes), Some(reader))),
+                    Some(Err(e)) => Some((Err(e), None)),
+                    None => None,
+                }
+            });
+            return Ok((headers, Box::pin(part)));
+        }
+    }
+}
@@ -0,0 +1,157 @@
+use bytes::BufMut;
+use flexbuffers::{MapBuilder, VectorBuilder};
+use indexmap::IndexSet;
+use json::JsonValue;
+use tokio::io::Result;
+
+pub struct NDJSONImportReader {
+    cur_bytes: bytes::BytesMut,
+    // Bytes of `cur_bytes` already checked for new line, so long lines aren't rescanned
+    scanned: usize,
+    // Union of top level keys of all records seen so far, in order of appearance
+    columns: IndexSet<String>,
+    line_no: usize,
+}
+
+impl NDJSONImportReader {
+    pub fn new() -> Self {
+        Self {
+            cur_bytes: bytes::BytesMut::new(),
+            scanned: 0,
+            columns: IndexSet::new(),
+            line_no: 0,
+        }
+    }
+
+    pub fn headers(&self) -> Vec<String> {
+        self.columns.iter().cloned().collect()
+    }
+
+    pub fn add_chunk(&mut self, data: bytes::Bytes) {
+        self.cur_bytes.put(data);
+    }
+
+    // Parses next complete line, records are encoded with columns known at the moment,
+    // so records before new key appearance are shorter and missing values are read as nulls
+    pub fn next_record(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            let new_line = self.cur_bytes[self.scanned..]
+                .iter()
+                .position(|b| *b == b'\n');
+            guard!(let Some(pos) = new_line.map(|p| self.scanned + p) else {
+                self.scanned = self.cur_bytes.len();
+                return Ok(None);
+            });
+            self.scanned = 0;
+            let line = self.cur_bytes.split_to(pos + 1);
+            if let Some(record) = self.parse_line(&line[..pos])? {
+                return Ok(Some(record));
+            }
+        }
+    }
+
+    // Parses last line in case if input is not terminated by new line
+    pub fn finish(&mut self) -> Result<Option<Vec<u8>>> {
+        let line = self.cur_bytes.split();
+        self.scanned = 0;
+        self.parse_line(&line[..])
+    }
+
+    fn parse_line(&mut self, line: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.line_no += 1;
+        let line = match std::str::from_utf8(line) {
+            Ok(line) => line.trim(),
+            Err(e) => return invalid_data_e!("line {}: {}", self.line_no, e),
+        };
+        if line.is_empty() {
+            return Ok(None);
+        }
+        let value = match json::parse(line) {
+            Ok(value) => value,
+            Err(e) => return invalid_data_e!("line {}: {}", self.line_no, e),
+        };
+        guard!(let JsonValue::Object(object) = value else {
+            return invalid_data_e!("line {}: expected json object", self.line_no);
+        });
+
+        let mut values: Vec<Option<&JsonValue>> = vec![None; self.columns.len()];
+        for (key, value) in object.iter() {
+            let (idx, _) = self.columns.insert_full(key.to_owned());
+            if idx >= values.len() {
+                values.resize(idx + 1, None);
+            }
+            values[idx] = Some(value);
+        }
+
+        let mut builder = flexbuffers::Builder::default();
+        let mut vec = builder.start_vector();
+        for value in values.iter() {
+            match value {
+                Some(value) => push_json_value(&mut vec, value),
+                None => vec.push(()),
+            }
+        }
+        vec.end_vector();
+        Ok(Some(builder.take_buffer()))
+    }
+}
+
+pub(crate) fn push_json_value(vec: &mut VectorBuilder, value: &JsonValue) {
+    match value {
+        JsonValue::Null => vec.push(()),
+        JsonValue::Boolean(b) => vec.push(*b),
+        JsonValue::Short(s) => vec.push(s.as_str()),
+        JsonValue::String(s) => vec.push(s.as_str()),
+        JsonValue::Number(_) => {
+            if let Some(num) = value.as_u64() {
+                vec.push(num);
+            } else if let Some(num) = value.as_i64() {
+                vec.push(num);
+            } else {
+                vec.push(value.as_f64().unwrap_or(std::f64::NAN));
+            }
+        }
+        JsonValue::Array(array) => {
+            let mut inner = vec.start_vector();
+            array.iter().for_each(|v| push_json_value(&mut inner, v));
+            inner.end_vector();
+        }
+        JsonValue::Object(object) => {
+            let mut inner = vec.start_map();
+            object
+                .iter()
+                .for_each(|(k, v)| push_json_map_value(&mut inner, k, v));
+            inner.end_map();
+        }
+    }
+}
+
+fn push_json_map_value(map: &mut MapBuilder, key: &str, value: &JsonValue) {
+    match value {
+        JsonValue::Null => map.push(key, ()),
+        JsonValue::Boolean(b) => map.push(key, *b),
+        JsonValue::Short(s) => map.push(key, s.as_str()),
+        JsonValue::String(s) => map.push(key, s.as_str()),
+        JsonValue::Number(_) => {
+            if let Some(num) = value.as_u64() {
+                map.push(key, num);
+            } else if let Some(num) = value.as_i64() {
+                map.push(key, num);
+            } else {
+                map.push(key, value.as_f64().unwrap_or(std::f64::NAN));
+            }
+        }
+        JsonValue::Array(array) => {
+            let mut inner = map.start_vector(key);
+            array.iter().for_each(|v| push_json_value(&mut inner, v));
+            inner.end_vector();
+        }
+        JsonValue::Object(object) => {
+            let mut inner = map.start_map(key);
+            object
+                .iter()
+                .for_each(|(k, v)| push_json_map_value(&mut inner, k, v));
+            inner.end_map();
+        }
+    }
+}
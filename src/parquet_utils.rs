@@ -0,0 +1,301 @@
+use crate::arrow_utils::BatchBuilder;
+use crate::compression::BytesStream;
+use crate::errors::*;
+use crate::export::{RowWriter, EXPORT_CHUNK_SIZE};
+use crate::misc_utils::*;
+use crate::ndjson_utils::push_json_value;
+use crate::record::*;
+
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use flexbuffers::VectorBuilder;
+use futures::executor::block_on;
+use futures::stream::StreamExt;
+use json::JsonValue;
+use parquet::arrow::arrow_writer::ArrowWriter as ParquetArrowWriter;
+use parquet::file::reader::{FileReader, SerializedFileReader};
+use parquet::record::{Field, Row};
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+// Parquet footer is at the end of file, so body is written to disk before reading
+pub async fn spool_to_file(bs: &mut BytesStream, path: &Path) -> ApiResult<u64> {
+    let mut file = tokio::fs::File::create(path).await?;
+    let mut size = 0;
+    while let Some(bytes) = bs.next().await {
+        let bytes = bytes?;
+        size += bytes.len() as u64;
+        file.write_all(bytes.as_ref()).await?;
+    }
+    file.flush().await?;
+    Ok(size)
+}
+
+pub struct ParquetImportReader {
+    reader: SerializedFileReader<File>,
+    headers: Vec<String>,
+}
+
+impl ParquetImportReader {
+    pub fn open(path: &Path) -> ApiResult<Self> {
+        let reader = SerializedFileReader::new(File::open(path)?)?;
+        let headers = reader
+            .metadata()
+            .file_metadata()
+            .schema()
+            .get_fields()
+            .iter()
+            .map(|f| f.name().to_owned())
+            .collect();
+        Ok(Self { reader, headers })
+    }
+
+    #[inline]
+    pub fn headers(&self) -> &Vec<String> {
+        &self.headers
+    }
+
+    // Rows are encoded in order of top level columns
+    pub fn records(&self) -> ApiResult<impl Iterator<Item = Vec<u8>> + '_> {
+        let rows = self.reader.get_row_iter(None)?;
+        Ok(rows.map(|row| row_to_flexbuffer(&row)))
+    }
+}
+
+// Decoded parquet input, headers come first
+pub enum ParquetBatch {
+    Headers(Vec<String>),
+    Records(Vec<Vec<u8>>),
+}
+
+const PARQUET_BATCH_SIZE: usize = 1024;
+
+fn send_batch(
+    tx: &mut mpsc::Sender<ApiResult<ParquetBatch>>,
+    batch: ApiResult<ParquetBatch>,
+) -> ApiResult<()> {
+    if block_on(tx.send(batch)).is_err() {
+        return other_e!("import is cancelled");
+    }
+    Ok(())
+}
+
+fn send_records(path: &Path, tx: &mut mpsc::Sender<ApiResult<ParquetBatch>>) -> ApiResult<()> {
+    let reader = ParquetImportReader::open(path)?;
+    send_batch(tx, Ok(ParquetBatch::Headers(reader.headers().clone())))?;
+    let mut batch = Vec::with_capacity(PARQUET_BATCH_SIZE);
+    for record in reader.records()? {
+        batch.push(record);
+        if batch.len() >= PARQUET_BATCH_SIZE {
+            send_batch(tx, Ok(ParquetBatch::Records(std::mem::take(&mut batch))))?;
+        }
+    }
+    if !batch.is_empty() {
+        send_batch(tx, Ok(ParquetBatch::Records(batch)))?;
+    }
+    Ok(())
+}
+
+// Decodes file by blocking pool and sends records by batches, blocks while import is behind
+pub fn decode_parquet(path: PathBuf, mut tx: mpsc::Sender<ApiResult<ParquetBatch>>) {
+    if let Err(e) = send_records(&path, &mut tx) {
+        let _ = send_batch(&mut tx, Err(e));
+    }
+}
+
+pub fn row_to_flexbuffer(row: &Row) -> Vec<u8> {
+    let mut builder = flexbuffers::Builder::default();
+    let mut vec = builder.start_vector();
+    for (_, field) in row.get_column_iter() {
+        push_field(&mut vec, field);
+    }
+    vec.end_vector();
+    builder.take_buffer()
+}
+
+// Integers keep their sign, dates and timestamps are stored as ISO strings,
+// nested groups, lists and maps are stored like nested JSON of NDJSON import
+fn push_field(vec: &mut VectorBuilder, field: &Field) {
+    match field {
+        Field::Null => vec.push(()),
+        Field::Bool(v) => vec.push(*v),
+        Field::Byte(v) => vec.push(*v as i64),
+        Field::Short(v) => vec.push(*v as i64),
+        Field::Int(v) => vec.push(*v as i64),
+        Field::Long(v) => vec.push(*v),
+        Field::UByte(v) => vec.push(*v as u64),
+        Field::UShort(v) => vec.push(*v as u64),
+        Field::UInt(v) => vec.push(*v as u64),
+        Field::ULong(v) => vec.push(*v),
+        Field::Float(v) => vec.push(*v as f64),
+        Field::Double(v) => vec.push(*v),
+        Field::Decimal(_) => {
+            let value = field.to_string();
+            match value.parse::<f64>() {
+                Ok(num) => vec.push(num),
+                Err(_) => vec.push(value.as_str()),
+            }
+        }
+        Field::Str(s) => vec.push(s.as_str()),
+        Field::Bytes(b) => vec.push(&*String::from_utf8_lossy(b.data())),
+        Field::Date(days) => vec.push(format_date(*days as i64).as_str()),
+        Field::TimestampMillis(ms) => vec.push(format_timestamp(*ms as i64 * 1000).as_str()),
+        Field::TimestampMicros(us) => vec.push(format_timestamp(*us as i64).as_str()),
+        Field::Group(_) | Field::ListInternal(_) | Field::MapInternal(_) => {
+            push_json_value(vec, &field_to_json(field))
+        }
+    }
+}
+
+fn field_to_json(field: &Field) -> JsonValue {
+    match field {
+        Field::Null => JsonValue::Null,
+        Field::Bool(v) => (*v).into(),
+        Field::Byte(v) => (*v).into(),
+        Field::Short(v) => (*v).into(),
+        Field::Int(v) => (*v).into(),
+        Field::Long(v) => (*v).into(),
+        Field::UByte(v) => (*v).into(),
+        Field::UShort(v) => (*v).into(),
+        Field::UInt(v) => (*v).into(),
+        Field::ULong(v) => (*v).into(),
+        Field::Float(v) => (*v).into(),
+        Field::Double(v) => (*v).into(),
+        Field::Str(s) => s.as_str().into(),
+        Field::Bytes(b) => String::from_utf8_lossy(b.data()).into_owned().into(),
+        Field::Date(days) => format_date(*days as i64).into(),
+        Field::TimestampMillis(ms) => format_timestamp(*ms as i64 * 1000).into(),
+        Field::TimestampMicros(us) => format_timestamp(*us as i64).into(),
+        Field::Decimal(_) => field.to_string().into(),
+        Field::Group(row) => {
+            let mut object = JsonValue::new_object();
+            for (name, value) in row.get_column_iter() {
+                object[name.as_str()] = field_to_json(value);
+            }
+            object
+        }
+        Field::ListInternal(list) => list
+            .elements()
+            .iter()
+            .map(field_to_json)
+            .collect::<Vec<JsonValue>>()
+            .into(),
+        Field::MapInternal(map) => {
+            let mut object = JsonValue::new_object();
+            for (key, value) in map.entries() {
+                let key = match key {
+                    Field::Str(s) => s.clone(),
+                    key => key.to_string(),
+                };
+                object[key.as_str()] = field_to_json(value);
+            }
+            object
+        }
+    }
+}
+
+// Days since Unix epoch to `YYYY-MM-DD`, proleptic Gregorian calendar
+pub fn format_date(days: i64) -> String {
+    let z = days + 719_468;
+    let era = if z >= 0 { z } else { z - 146_096 } / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+// Microseconds since Unix epoch to `YYYY-MM-DD HH:MM:SS.ffffff`, UTC
+pub fn format_timestamp(micros: i64) -> String {
+    const MICROS_PER_DAY: i64 = 86_400_000_000;
+    let days = micros.div_euclid(MICROS_PER_DAY);
+    let rem = micros.rem_euclid(MICROS_PER_DAY);
+    let secs = rem / 1_000_000;
+    format!(
+        "{} {:02}:{:02}:{:02}.{:06}",
+        format_date(days),
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+        rem % 1_000_000
+    )
+}
+
+// Parquet file is written to temp file, as footer is known only in the end,
+// then it's sent by `write_tail`
+pub struct ParquetWriter {
+    batches: BatchBuilder,
+    temp: Option<TempFile>,
+    writer: Option<ParquetArrowWriter<File>>,
+    output: Option<File>,
+}
+
+impl ParquetWriter {
+    pub fn new(headers: Vec<String>) -> Self {
+        Self {
+            batches: BatchBuilder::new(headers),
+            temp: None,
+            writer: None,
+            output: None,
+        }
+    }
+
+    fn start_writer(&mut self, schema: SchemaRef) -> ApiResult<&mut ParquetArrowWriter<File>> {
+        if self.writer.is_none() {
+            let temp = TempFile::new("parquet");
+            let file = File::create(&temp.path)?;
+            self.output = Some(File::open(&temp.path)?);
+            self.writer = Some(ParquetArrowWriter::try_new(file, schema, None)?);
+            self.temp = Some(temp);
+        }
+        guard!(let Some(writer) = self.writer.as_mut() else {
+            return invalid_data_ae!("parquet schema is not written");
+        });
+        Ok(writer)
+    }
+
+    fn write_batch(&mut self, batch: Option<RecordBatch>) -> ApiResult<()> {
+        guard!(let Some(batch) = batch else { return Ok(()) });
+        self.start_writer(batch.schema())?.write(&batch)?;
+        Ok(())
+    }
+}
+
+impl RowWriter for ParquetWriter {
+    fn write_headers(&mut self, _: &mut Vec<u8>) -> ApiResult<()> {
+        Ok(())
+    }
+
+    fn write_row(&mut self, record: &dyn RecordRef, _: &mut Vec<u8>) -> ApiResult<()> {
+        let batch = self.batches.append(record)?;
+        self.write_batch(batch)
+    }
+
+    fn finish(&mut self, _: &mut Vec<u8>) -> ApiResult<()> {
+        let batch = self.batches.finish_batch()?;
+        self.write_batch(batch)?;
+        // Empty result has schema only
+        let schema = self.batches.start()?;
+        self.start_writer(schema)?;
+        if let Some(mut writer) = self.writer.take() {
+            writer.close()?;
+        }
+        Ok(())
+    }
+
+    fn write_tail(&mut self, out: &mut Vec<u8>) -> ApiResult<bool> {
+        guard!(let Some(output) = self.output.as_mut() else { return Ok(false) });
+        let read = output.take(EXPORT_CHUNK_SIZE as u64).read_to_end(out)?;
+        if read == 0 {
+            self.output = None;
+            self.temp = None;
+        }
+        Ok(read > 0)
+    }
+}
@@ -0,0 +1,240 @@
+use crate::errors::*;
+use crate::record::ValueRef;
+
+use regex::Regex;
+use sqlparser::ast;
+use std::borrow::Cow;
+
+const ILIKE_MARKER: &str = "__ilike";
+const REGEXP_MARKER: &str = "__regexp";
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum PatternOp {
+    // `%` is any string, `_` is any char, `\` escapes them
+    Like,
+    ILike,
+    // Regular expression is matched anywhere in value, like `~` of Postgres
+    Regexp,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct PatternSpec {
+    pub(crate) op: PatternOp,
+    pub(crate) negated: bool,
+    pub(crate) pattern: String,
+}
+
+impl PatternSpec {
+    // `None` if operator isn't LIKE, pattern should be string literal
+    pub(crate) fn parse(op: &ast::BinaryOperator, right: &ast::Expr) -> ApiResult<Option<Self>> {
+        let negated = match op {
+            ast::BinaryOperator::Like => false,
+            ast::BinaryOperator::NotLike => true,
+            _ => return Ok(None),
+        };
+        let (op, pattern) = match right {
+            ast::Expr::Value(ast::Value::SingleQuotedString(s)) => (PatternOp::Like, s),
+            ast::Expr::Function(f) => match (f.name.to_string().as_str(), f.args.as_slice()) {
+                (ILIKE_MARKER, [ast::Expr::Value(ast::Value::SingleQuotedString(s))]) => {
+                    (PatternOp::ILike, s)
+                }
+                (REGEXP_MARKER, [ast::Expr::Value(ast::Value::SingleQuotedString(s))]) => {
+                    (PatternOp::Regexp, s)
+                }
+                _ => return invalid_data_ae!("pattern should be a string literal: {}", right),
+            },
+            _ => return invalid_data_ae!("pattern should be a string literal: {}", right),
+        };
+        Ok(Some(Self {
+            op,
+            negated,
+            pattern: pattern.clone(),
+        }))
+    }
+
+    pub(crate) fn compile(self) -> ApiResult<Pattern> {
+        let source = match self.op {
+            PatternOp::Like => like_to_regex(&self.pattern, false)?,
+            PatternOp::ILike => like_to_regex(&self.pattern, true)?,
+            PatternOp::Regexp => self.pattern.clone(),
+        };
+        let regex = Regex::new(&source)
+            .map_err(|e| ApiError(invalid_data!("wrong pattern {}: {}", self.pattern, e)))?;
+        Ok(Pattern { spec: self, regex })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct Pattern {
+    spec: PatternSpec,
+    regex: Regex,
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.spec == other.spec
+    }
+}
+
+impl Pattern {
+    // `None` for NULL, strings are matched in place, other values by their text
+    pub(crate) fn matches(&self, value: &ValueRef) -> Option<bool> {
+        let matched = match value {
+            ValueRef::Null => return None,
+            ValueRef::Str(s) => self.regex.is_match(s),
+            ValueRef::OwnedStr(s) | ValueRef::Json(s) => self.regex.is_match(s),
+            v => self.regex.is_match(&v.to_string()),
+        };
+        Some(matched != self.spec.negated)
+    }
+}
+
+fn like_to_regex(pattern: &str, case_insensitive: bool) -> ApiResult<String> {
+    let mut result = String::with_capacity(pattern.len() + 8);
+    result.push_str(if case_insensitive { "(?is)^" } else { "(?s)^" });
+    let mut chars = pattern.chars();
+    while let Some(ch) = chars.next() {
+        match ch {
+            '%' => result.push_str(".*"),
+            '_' => result.push('.'),
+            '\\' => {
+                guard!(let Some(escaped) = chars.next() else {
+                    return invalid_data_ae!("pattern ends with escape character: {}", pattern);
+                });
+                result.push_str(&regex::escape(escaped.encode_utf8(&mut [0; 4])));
+            }
+            ch => result.push_str(&regex::escape(ch.encode_utf8(&mut [0; 4]))),
+        }
+    }
+    result.push('$');
+    Ok(result)
+}
+
+// SQL parser knows only LIKE, so ILIKE, REGEXP and `~` are rewritten before parsing
+// to LIKE with marker function around pattern: `a ILIKE 'x%'` is `a LIKE __ilike('x%')`
+pub(crate) fn rewrite_pattern_operators(sql: &str) -> ApiResult<Cow<str>> {
+    let bytes = sql.as_bytes();
+    let mut result = String::new();
+    let mut copied = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        if let Some(end) = skipped_end(bytes, i) {
+            i = end;
+            continue;
+        }
+        let (op_end, marker) = match bytes[i] {
+            b'~' => (i + 1, REGEXP_MARKER),
+            b if b.is_ascii_alphabetic() || b == b'_' => {
+                let end = bytes[i..]
+                    .iter()
+                    .position(|b| !is_word_part(*b))
+                    .map_or(bytes.len(), |p| i + p);
+                let word = &sql[i..end];
+                if word.eq_ignore_ascii_case("ILIKE") {
+                    (end, ILIKE_MARKER)
+                } else if word.eq_ignore_ascii_case("REGEXP") {
+                    (end, REGEXP_MARKER)
+                } else {
+                    i = end;
+                    continue;
+                }
+            }
+            _ => {
+                i += 1;
+                continue;
+            }
+        };
+
+        let literal_start = bytes[op_end..]
+            .iter()
+            .position(|b| !b.is_ascii_whitespace())
+            .map_or(bytes.len(), |p| op_end + p);
+        if bytes.get(literal_start) != Some(&b'\'') {
+            return invalid_data_ae!("pattern should be a string literal: {}", &sql[i..]);
+        }
+        let literal_end = quoted_end(bytes, literal_start, b'\'');
+
+        result.push_str(&sql[copied..i]);
+        if i > 0 && !bytes[i - 1].is_ascii_whitespace() {
+            result.push(' ');
+        }
+        result.push_str("LIKE ");
+        result.push_str(marker);
+        result.push('(');
+        result.push_str(&sql[literal_start..literal_end]);
+        result.push(')');
+        copied = literal_end;
+        i = literal_end;
+    }
+
+    if copied == 0 {
+        return Ok(Cow::Borrowed(sql));
+    }
+    result.push_str(&sql[copied..]);
+    Ok(Cow::Owned(result))
+}
+
+pub(crate) fn is_word_part(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_' || b == b'$' || b == b'#' || b == b'@'
+}
+
+// End of quoted identifier, string or comment at `start`, they are skipped by SQL rewriters.
+// Unterminated ones end with SQL
+pub(crate) fn skipped_end(bytes: &[u8], start: usize) -> Option<usize> {
+    let end = match bytes[start] {
+        q @ b'\'' | q @ b'"' | q @ b'`' => quoted_end(bytes, start, q),
+        b'-' if bytes.get(start + 1) == Some(&b'-') => bytes[start..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(bytes.len(), |p| start + p),
+        b'/' if bytes.get(start + 1) == Some(&b'*') => bytes[start + 2..]
+            .windows(2)
+            .position(|w| w == b"*/")
+            .map_or(bytes.len(), |p| start + 2 + p + 2),
+        _ => return None,
+    };
+    Some(end)
+}
+
+// Index after closing quote, doubled quote is escaped one
+fn quoted_end(bytes: &[u8], start: usize, quote: u8) -> usize {
+    let mut i = start + 1;
+    while i < bytes.len() {
+        if bytes[i] == quote {
+            if bytes.get(i + 1) == Some(&quote) {
+                i += 2;
+                continue;
+            }
+            return i + 1;
+        }
+        i += 1;
+    }
+    bytes.len()
+}
+
+#[test]
+fn test_rewrite_pattern_operators() {
+    let sql = "SELECT a FROM t WHERE a LIKE 'x%'";
+    assert_eq!(rewrite_pattern_operators(sql).unwrap(), sql);
+
+    let sql = "SELECT \"ilike\" FROM t WHERE a ILIKE 'it''s%' AND b NOT REGEXP '^~' OR c~'d'";
+    assert_eq!(
+        rewrite_pattern_operators(sql).unwrap(),
+        "SELECT \"ilike\" FROM t WHERE a LIKE __ilike('it''s%') AND b NOT LIKE __regexp('^~') OR c LIKE __regexp('d')"
+    );
+
+    assert!(rewrite_pattern_operators("SELECT a FROM t WHERE a ~ b").is_err());
+
+    // Operators in comments are kept as is
+    let sql = "SELECT a /* ~ ILIKE */ FROM t /* REGEXP ~*/ WHERE a ILIKE 'x' -- ~";
+    assert_eq!(
+        rewrite_pattern_operators(sql).unwrap(),
+        "SELECT a /* ~ ILIKE */ FROM t /* REGEXP ~*/ WHERE a LIKE __ilike('x') -- ~"
+    );
+    let sql = "SELECT a FROM t /* ~ ";
+    assert_eq!(rewrite_pattern_operators(sql).unwrap(), sql);
+
+    assert_eq!(like_to_regex("a_b%\\%.", false).unwrap(), "(?s)^a.b.*%\\.$");
+    assert_eq!(like_to_regex("a\\\\", false).unwrap(), "(?s)^a\\\\$");
+    assert!(like_to_regex("a\\", false).is_err());
+}
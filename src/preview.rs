@@ -0,0 +1,209 @@
+use crate::compression::BytesStream;
+use crate::csv_utils::*;
+use crate::errors::*;
+
+use futures::stream::StreamExt;
+use json::JsonValue;
+
+pub const DEFAULT_PREVIEW_ROWS: usize = 100;
+const SAMPLES_COUNT: usize = 5;
+
+// Column type as it would be stored by import, widened by every non-null value
+#[derive(Debug, Copy, Clone, PartialEq, PartialOrd)]
+pub enum ColumnType {
+    Null,
+    UInt,
+    Int,
+    Float,
+    String,
+}
+
+impl ColumnType {
+    fn of(value: &FieldValue) -> Self {
+        match value {
+            FieldValue::Null => Self::Null,
+            FieldValue::UInt(_) => Self::UInt,
+            FieldValue::Int(_) => Self::Int,
+            FieldValue::Float(_) => Self::Float,
+            FieldValue::Str(_) => Self::String,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Null => "null",
+            Self::UInt => "uint",
+            Self::Int => "int",
+            Self::Float => "float",
+            Self::String => "string",
+        }
+    }
+}
+
+pub struct ColumnPreview {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub nulls: usize,
+    pub samples: Vec<String>,
+}
+
+// What import would make of the first rows of input, nothing is written
+pub struct ImportPreview {
+    pub headers: Vec<String>,
+    pub rows: usize,
+    pub columns: Vec<ColumnPreview>,
+    // Row number (1-based, headers excluded) and error
+    pub errors: Vec<(usize, String)>,
+}
+
+impl ImportPreview {
+    fn new(headers: Vec<String>) -> Self {
+        let columns = headers
+            .iter()
+            .map(|name| ColumnPreview {
+                name: name.clone(),
+                column_type: ColumnType::Null,
+                nulls: 0,
+                samples: Vec::new(),
+            })
+            .collect();
+        Self {
+            headers,
+            rows: 0,
+            columns,
+            errors: Vec::new(),
+        }
+    }
+
+    fn add_record(&mut self, record: &CSVRecord, nulls: &NullValues) {
+        self.rows += 1;
+        if record.len() != self.headers.len() {
+            // Import would fail on this row, preview goes on to show the rest
+            self.errors.push((
+                self.rows,
+                format!(
+                    "headers and fields count mismatch, expected {}, got {}",
+                    self.headers.len(),
+                    record.len()
+                ),
+            ));
+            return;
+        }
+        for (column, field) in self.columns.iter_mut().zip(record.fields()) {
+            let value = FieldValue::parse(field.as_ref(), nulls);
+            let value_type = ColumnType::of(&value);
+            if value_type == ColumnType::Null {
+                column.nulls += 1;
+                continue;
+            }
+            if value_type > column.column_type {
+                column.column_type = value_type;
+            }
+            if column.samples.len() < SAMPLES_COUNT {
+                column.samples.push(field.to_string());
+            }
+        }
+    }
+
+    pub fn to_json(&self, table: &str) -> JsonValue {
+        let mut result = JsonValue::new_object();
+        result["table"] = table.into();
+        result["headers"] = self
+            .headers
+            .iter()
+            .map(|h| JsonValue::from(h.as_str()))
+            .collect::<Vec<JsonValue>>()
+            .into();
+        result["rows"] = self.rows.into();
+        result["columns"] = self
+            .columns
+            .iter()
+            .map(|c| {
+                let mut column = JsonValue::new_object();
+                column["name"] = c.name.as_str().into();
+                column["type"] = c.column_type.as_str().into();
+                column["nulls"] = c.nulls.into();
+                column["samples"] = c
+                    .samples
+                    .iter()
+                    .map(|s| JsonValue::from(s.as_str()))
+                    .collect::<Vec<JsonValue>>()
+                    .into();
+                column
+            })
+            .collect::<Vec<JsonValue>>()
+            .into();
+        result["errors"] = self
+            .errors
+            .iter()
+            .map(|(row, error)| {
+                let mut e = JsonValue::new_object();
+                e["row"] = (*row).into();
+                e["error"] = error.as_str().into();
+                e
+            })
+            .collect::<Vec<JsonValue>>()
+            .into();
+        result
+    }
+}
+
+// Reads headers and up to `rows` records the same way import does, the rest of input is ignored
+pub async fn preview_csv(
+    bs: &mut BytesStream,
+    nulls: &NullValues,
+    rows: usize,
+) -> ApiResult<ImportPreview> {
+    let mut splitter = CSVBatchSplitter::new();
+
+    let headers_line = loop {
+        if let Some(line) = splitter.take_line() {
+            break line;
+        }
+        guard!(let Some(bytes) = bs.next().await else {
+            guard!(let Some(line) = splitter.finish() else {
+                return invalid_data_ae!("empty body");
+            });
+            break line;
+        });
+        splitter.add_chunk(bytes?);
+    };
+    let headers = CSVImportReader::from_first_chunk(headers_line)?
+        .headers()
+        .clone();
+    let mut preview = ImportPreview::new(headers);
+
+    let mut finished = false;
+    while preview.rows < rows && !finished {
+        // Every batch holds whole records, so it's parsed on its own
+        let batch = match splitter.next_batch(1) {
+            Some(batch) => batch,
+            None => match bs.next().await {
+                Some(bytes) => {
+                    splitter.add_chunk(bytes?);
+                    continue;
+                }
+                None => {
+                    finished = true;
+                    guard!(let Some(batch) = splitter.finish() else { break });
+                    batch
+                }
+            },
+        };
+
+        let mut reader = CSVImportReader::from_records_chunk(batch);
+        let mut iter = reader.parse_records();
+        while preview.rows < rows {
+            guard!(let Some(record) = iter.next() else { break });
+            match record {
+                Ok(record) => preview.add_record(&record, nulls),
+                Err(e) => {
+                    // Parser can't continue after broken record
+                    preview.errors.push((preview.rows + 1, e.to_string()));
+                    return Ok(preview);
+                }
+            }
+        }
+    }
+    Ok(preview)
+}
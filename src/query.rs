@@ -1,7 +1,11 @@
 use crate::errors::*;
+use crate::expression::Expression;
+use crate::grouping_sets::{expand_grouping_sets, rewrite_grouping_sets};
+use crate::pattern::{rewrite_pattern_operators, PatternSpec};
 use crate::record::{parse_number, ValueOrdRef};
 use json::number::Number;
 use sqlparser::{ast, dialect::Dialect, parser::Parser};
+use std::cmp::Ordering;
 
 #[derive(Clone)]
 pub(crate) struct Projection {
@@ -9,10 +13,18 @@ pub(crate) struct Projection {
     pub(crate) ptype: ProjectionType,
 }
 
-#[derive(Clone)]
+#[derive(Clone, PartialEq)]
 pub(crate) enum ProjectionType {
     Column(usize),
     Count,
+    // `count(DISTINCT column)`, NULLs are not counted
+    CountDistinct(usize),
+    // Sketches of fixed size, `approx_median` is percentile 0.5
+    ApproxCountDistinct(usize),
+    ApproxPercentile(usize, f64),
+    Expression(Expression),
+    // `GROUPING(a, b)`, bit of column is set in rows where it's aggregated
+    Grouping(Vec<GroupBy>),
 }
 
 impl Projection {
@@ -67,11 +79,8 @@ impl Projection {
         }
     }
 
-    fn count(name: String) -> Self {
-        Self {
-            name,
-            ptype: ProjectionType::Count,
-        }
+    fn function(name: String, ptype: ProjectionType) -> Self {
+        Self { name, ptype }
     }
 
     fn add_projection_from_expr(
@@ -100,21 +109,36 @@ impl Projection {
             }
             ast::Expr::CompoundIdentifier(c) => {
                 projections.push(Self::projection_from_column_name(
-                    &last_ident(c)?.value,
+                    &compound_name(c, headers)?,
                     alias,
                     headers,
                 )?);
             }
-            ast::Expr::Function(f) => {
-                if f.name.0.len() != 1 || f.name.0.first().unwrap().value.to_lowercase() != "count"
-                {
-                    return invalid_data_ae!("unsupported function projection: {:?}", f);
+            ast::Expr::Function(f) if is_count_function(f) => {
+                let (ptype, name) = parse_count_function(f, headers)?;
+                projections.push(Self::function(alias.cloned().unwrap_or(name), ptype));
+            }
+            ast::Expr::Function(f) if is_approx_function(f) => {
+                let ptype = parse_approx_function(f, headers)?;
+                let name = alias.cloned().unwrap_or_else(|| expr.to_string());
+                projections.push(Self::function(name, ptype));
+            }
+            ast::Expr::Function(f) if is_grouping_function(f) => {
+                if f.args.is_empty() || f.distinct || f.over.is_some() {
+                    return invalid_data_ae!("wrong arguments of function: {}", expr);
                 }
-                projections.push(Self::count(
-                    alias.cloned().unwrap_or_else(|| "count(*)".to_owned()),
-                ));
+                let mut args = Vec::with_capacity(f.args.len());
+                for arg in f.args.iter() {
+                    args.push(group_by_item(arg, headers)?);
+                }
+                let name = alias.cloned().unwrap_or_else(|| expr.to_string());
+                projections.push(Self::function(name, ProjectionType::Grouping(args)));
+            }
+            _ => {
+                let ptype = ProjectionType::Expression(Expression::parse(expr, headers)?);
+                let name = alias.cloned().unwrap_or_else(|| expr.to_string());
+                projections.push(Self { name, ptype });
             }
-            _ => return invalid_data_ae!("unsupported expression in projection: {:?}", expr),
         };
         Ok(())
     }
@@ -133,7 +157,36 @@ impl Projection {
     }
 }
 
-#[derive(Debug)]
+// Count of distinct values is kept exactly in hash set or estimated in fixed memory
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub enum DistinctMode {
+    Exact,
+    Approx,
+}
+
+impl Default for DistinctMode {
+    fn default() -> Self {
+        Self::Exact
+    }
+}
+
+impl DistinctMode {
+    pub fn from_option(value: Option<&str>) -> ApiResult<Self> {
+        match value {
+            None | Some("exact") => Ok(Self::Exact),
+            Some("approx") => Ok(Self::Approx),
+            Some(mode) => invalid_data_ae!("unknown distinct mode: {}", mode),
+        }
+    }
+}
+
+// Options of query which aren't part of SQL, passed in query string
+#[derive(Debug, Default, Copy, Clone)]
+pub struct QueryOptions {
+    pub distinct: DistinctMode,
+}
+
+#[derive(Debug, Clone)]
 pub struct Query(Box<ast::Query>);
 
 impl Query {
@@ -145,7 +198,9 @@ impl Query {
 
     fn parse_query(query_sql: &str) -> ApiResult<Box<ast::Query>> {
         let dialect = TestDialect {};
-        let ast = Parser::parse_sql(&dialect, query_sql)?;
+        let query_sql = rewrite_pattern_operators(query_sql)?;
+        let query_sql = rewrite_grouping_sets(&query_sql)?;
+        let ast = Parser::parse_sql(&dialect, &query_sql)?;
         if ast.len() != 1 {
             return invalid_data_ae!("expected just 1 sql query");
         }
@@ -155,9 +210,10 @@ impl Query {
         Ok(query)
     }
 
-    fn validate_query(query: &Box<ast::Query>) -> ApiResult<()> {
-        if !query.ctes.is_empty() {
-            return invalid_data_ae!("no ctes supported");
+    // CTEs and subqueries are validated along with query
+    fn validate_query(query: &ast::Query) -> ApiResult<()> {
+        for cte in query.ctes.iter() {
+            Self::validate_query(&cte.query)?;
         }
 
         if query.offset.is_some() {
@@ -168,16 +224,63 @@ impl Query {
             return invalid_data_ae!("fetch not supported");
         }
 
-        guard!(let ast::SetExpr::Select(select) = &query.body
-            else { return invalid_data_ae!("only ordinal select supported"); });
+        Self::validate_set_expr(&query.body)
+    }
 
+    // Branches of UNION, INTERSECT and EXCEPT are validated as separate queries
+    fn validate_set_expr(body: &ast::SetExpr) -> ApiResult<()> {
+        match body {
+            ast::SetExpr::Select(select) => Self::validate_select(select),
+            ast::SetExpr::Query(query) => Self::validate_query(query),
+            ast::SetExpr::SetOperation { left, right, .. } => {
+                Self::validate_set_expr(left)?;
+                Self::validate_set_expr(right)
+            }
+            _ => invalid_data_ae!("only ordinal select supported"),
+        }
+    }
+
+    fn validate_select(select: &ast::Select) -> ApiResult<()> {
         if select.from.len() != 1 {
             return invalid_data_ae!("only one from supported");
         }
 
+        Self::validate_relation(&select.from[0].relation)?;
+        for join in select.from[0].joins.iter() {
+            match &join.join_operator {
+                ast::JoinOperator::Inner(ast::JoinConstraint::On(_))
+                | ast::JoinOperator::LeftOuter(ast::JoinConstraint::On(_)) => (),
+                op => return invalid_data_ae!("only INNER and LEFT JOIN ON supported: {:?}", op),
+            }
+            Self::validate_relation(&join.relation)?;
+        }
+
+        if let Some(selection) = &select.selection {
+            let mut subqueries = Vec::new();
+            collect_in_subqueries(selection, &mut subqueries)?;
+            for subquery in subqueries {
+                Self::validate_query(subquery)?;
+            }
+        }
+
         Ok(())
     }
 
+    fn validate_relation(relation: &ast::TableFactor) -> ApiResult<()> {
+        match relation {
+            ast::TableFactor::Table { .. } => Ok(()),
+            ast::TableFactor::Derived {
+                lateral: false,
+                subquery,
+                alias: Some(_),
+            } => Self::validate_query(subquery),
+            ast::TableFactor::Derived { alias: None, .. } => {
+                invalid_data_ae!("subquery in from should have alias")
+            }
+            _ => invalid_data_ae!("unsupported table source: {}", relation),
+        }
+    }
+
     pub(crate) fn select(&self) -> ApiResult<&Box<ast::Select>> {
         match &self.0.body {
             ast::SetExpr::Select(select) => Ok(select),
@@ -193,55 +296,159 @@ impl Query {
             _ => return invalid_data_ae!("no suitable table source"),
         };
 
-        let (name_parts, alias) = match &from.relation {
-            ast::TableFactor::Table {
-                name,
-                alias,
-                args: _,
-                with_hints: _,
-            } => (name, alias),
-            _ => return invalid_data_ae!("no suitable table source"),
-        };
+        table_ref(&from.relation)
+    }
 
-        let name = match name_parts.0.last() {
-            Some(ident) => &ident.value,
-            _ => return invalid_data_ae!("no suitable table source"),
-        };
+    // The first table and joined ones in order of joins
+    pub fn get_table_refs(&self) -> ApiResult<Vec<TableRef>> {
+        let mut result = vec![self.get_table_name()?];
+        for join in self.get_joins()? {
+            result.push(join.table);
+        }
+        Ok(result)
+    }
+
+    pub(crate) fn get_joins(&self) -> ApiResult<Vec<JoinRef>> {
+        let select = self.select()?;
+        guard!(let Some(from) = select.from.first() else { return Ok(Vec::new()) });
+        let mut result = Vec::with_capacity(from.joins.len());
+        for join in from.joins.iter() {
+            let (kind, on) = match &join.join_operator {
+                ast::JoinOperator::Inner(ast::JoinConstraint::On(on)) => (JoinKind::Inner, on),
+                ast::JoinOperator::LeftOuter(ast::JoinConstraint::On(on)) => (JoinKind::Left, on),
+                op => return invalid_data_ae!("only INNER and LEFT JOIN ON supported: {:?}", op),
+            };
+            result.push(JoinRef {
+                table: table_ref(&join.relation)?,
+                kind,
+                on: on.clone(),
+            });
+        }
+        Ok(result)
+    }
 
-        let res = TableRef {
-            name: name.clone(),
-            alias: alias.as_ref().map(|a| a.name.value.clone()),
+    // `None` if query is ordinal select
+    pub(crate) fn get_set_operation(&self) -> Option<SetOperation> {
+        guard!(let ast::SetExpr::SetOperation { op, all, left, right } = &self.0.body else {
+            return None;
+        });
+        let op = match op {
+            ast::SetOperator::Union => SetOperator::Union,
+            ast::SetOperator::Intersect => SetOperator::Intersect,
+            ast::SetOperator::Except => SetOperator::Except,
         };
+        Some(SetOperation {
+            op,
+            all: *all,
+            left: Self::from_set_expr(left),
+            right: Self::from_set_expr(right),
+        })
+    }
+
+    fn from_set_expr(body: &ast::SetExpr) -> Self {
+        match body {
+            ast::SetExpr::Query(query) => Self(query.clone()),
+            body => Self(Box::new(ast::Query {
+                ctes: Vec::new(),
+                body: body.clone(),
+                order_by: Vec::new(),
+                limit: None,
+                offset: None,
+                fetch: None,
+            })),
+        }
+    }
+
+    pub(crate) fn has_order_by_or_limit(&self) -> bool {
+        !self.0.order_by.is_empty() || self.0.limit.is_some()
+    }
+
+    // Query over all columns of set operation result, with its ORDER BY and LIMIT
+    pub(crate) fn get_set_operation_result(&self) -> ApiResult<Query> {
+        let mut select = leftmost_select(&self.0.body)?.clone();
+        select.projection = vec![ast::SelectItem::Wildcard];
+        select.selection = None;
+        select.group_by = Vec::new();
+        select.having = None;
+        select.distinct = false;
+        Ok(Self(Box::new(ast::Query {
+            ctes: Vec::new(),
+            body: ast::SetExpr::Select(Box::new(select)),
+            order_by: self.0.order_by.clone(),
+            limit: self.0.limit.clone(),
+            offset: None,
+            fetch: None,
+        })))
+    }
+
+    // Query of aggregation stage under window functions, its ORDER BY and LIMIT are applied
+    // by window stage
+    pub(crate) fn with_projections(mut self, projection: Vec<ast::SelectItem>) -> ApiResult<Query> {
+        match &mut self.0.body {
+            ast::SetExpr::Select(select) => select.projection = projection,
+            _ => return invalid_data_ae!("cant't unwrap select"),
+        }
+        self.0.order_by = Vec::new();
+        self.0.limit = None;
+        Ok(self)
+    }
+
+    // Named subqueries of WITH in order of definition, they are removed from query
+    pub(crate) fn take_ctes(&mut self) -> Vec<Subquery> {
+        std::mem::take(&mut self.0.ctes)
+            .into_iter()
+            .map(|cte| Subquery {
+                name: cte.alias.name.value,
+                columns: cte.alias.columns.into_iter().map(|c| c.value).collect(),
+                query: Query(Box::new(cte.query)),
+            })
+            .collect()
+    }
+
+    // Replaces `IN (SELECT ...)` in WHERE with list of values returned by `f`
+    pub(crate) fn bind_in_subqueries(
+        &mut self,
+        f: &mut dyn FnMut(Query) -> ApiResult<Vec<ast::Expr>>,
+    ) -> ApiResult<()> {
+        guard!(let ast::SetExpr::Select(select) = &mut self.0.body else {
+            return invalid_data_ae!("cant't unwrap select");
+        });
+        guard!(let Some(selection) = &mut select.selection else { return Ok(()) });
+        bind_in_subqueries(selection, f)
+    }
 
-        Ok(res)
+    // Tables read from database by query and all its subqueries, CTEs are not included
+    pub fn get_stored_tables(&self) -> ApiResult<Vec<String>> {
+        let mut result = Vec::new();
+        collect_stored_tables(&self.0, &Vec::new(), &mut result)?;
+        Ok(result)
     }
 
-    pub(crate) fn get_if_group_by(&self) -> ApiResult<Option<GroupBy>> {
+    pub(crate) fn get_if_group_by(&self, headers: &Vec<String>) -> ApiResult<Option<GroupBy>> {
         let select = self.select()?;
         if select.group_by.is_empty() {
             return Ok(None);
         }
+        if let Some(grouping_sets) = expand_grouping_sets(&select.group_by)? {
+            let mut columns = Vec::with_capacity(grouping_sets.columns.len());
+            for column in grouping_sets.columns.iter() {
+                columns.push(group_by_item(column, headers)?);
+            }
+            return Ok(Some(GroupBy::Sets {
+                columns,
+                sets: grouping_sets.sets,
+            }));
+        }
         if select.group_by.len() > 1 {
             return invalid_data_ae!("only one group_by supported: {:?}", select.group_by);
         }
-        match select.group_by.first().unwrap() {
-            ast::Expr::Value(v) => {
-                guard!(let Some(num) = parse_ast_number(v) else {
-                    return invalid_data_ae!("only numeric group_by supported: {:?}", select.group_by);
-                });
-                return Ok(Some(GroupBy::ProjectionId(num)));
-            }
-            ast::Expr::Identifier(i) => return Ok(Some(GroupBy::Column(i.value.clone()))),
-            ast::Expr::CompoundIdentifier(vi) => {
-                return Ok(Some(GroupBy::Column(last_ident(vi)?.value.clone())))
-            }
-            _ => {}
-        }
-
-        return invalid_data_ae!("unsupported group_by expression: {:?}", select.group_by);
+        Ok(Some(group_by_item(
+            select.group_by.first().unwrap(),
+            headers,
+        )?))
     }
 
-    pub(crate) fn get_if_order_by(&self) -> ApiResult<Option<OrderBy>> {
+    pub(crate) fn get_if_order_by(&self, headers: &Vec<String>) -> ApiResult<Option<OrderBy>> {
         if self.0.order_by.is_empty() {
             return Ok(None);
         }
@@ -262,7 +469,7 @@ impl Query {
             }
             ast::Expr::CompoundIdentifier(vi) => {
                 return Ok(Some(OrderBy {
-                    id: OrderByIdType::Column(last_ident(vi)?.value.clone()),
+                    id: OrderByIdType::Column(compound_name(vi, headers)?),
                     asc: order_by.asc.unwrap_or(true),
                 }));
             }
@@ -271,6 +478,20 @@ impl Query {
         return invalid_data_ae!("unsupported order_by expression: {:?}", order_by);
     }
 
+    pub(crate) fn is_distinct(&self) -> ApiResult<bool> {
+        Ok(self.select()?.distinct)
+    }
+
+    pub(crate) fn get_if_having(
+        &self,
+        projections: &Vec<Projection>,
+        headers: &Vec<String>,
+    ) -> ApiResult<Option<Having>> {
+        let select = self.select()?;
+        guard!(let Some(having) = &select.having else { return Ok(None) });
+        Ok(Some(parse_having(having, projections, headers)?))
+    }
+
     pub(crate) fn get_if_limit(&self) -> ApiResult<Option<usize>> {
         if self.0.limit.is_none() {
             return Ok(None);
@@ -300,10 +521,31 @@ pub(crate) struct OrderBy {
     pub(crate) asc: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub(crate) enum GroupBy {
     ProjectionId(usize),
     Column(String),
+    Expression(Expression),
+    // ROLLUP, CUBE or GROUPING SETS, sets are indexes of columns
+    Sets {
+        columns: Vec<GroupBy>,
+        sets: Vec<Vec<usize>>,
+    },
+}
+
+fn group_by_item(expr: &ast::Expr, headers: &Vec<String>) -> ApiResult<GroupBy> {
+    let result = match expr {
+        ast::Expr::Value(v) => {
+            guard!(let Some(num) = parse_ast_number(v) else {
+                return invalid_data_ae!("only numeric group_by supported: {:?}", expr);
+            });
+            GroupBy::ProjectionId(num)
+        }
+        ast::Expr::Identifier(i) => GroupBy::Column(i.value.clone()),
+        ast::Expr::CompoundIdentifier(vi) => GroupBy::Column(compound_name(vi, headers)?),
+        e => GroupBy::Expression(Expression::parse(e, headers)?),
+    };
+    Ok(result)
 }
 
 pub(crate) fn parse_if_has_selection(
@@ -314,13 +556,21 @@ pub(crate) fn parse_if_has_selection(
     Ok(Some(parse_selection(selection, headers)?))
 }
 
+// Simple comparisons of column with value are checked without evaluation,
+// the rest is evaluated as boolean expression
 fn parse_selection(expr: &ast::Expr, headers: &Vec<String>) -> ApiResult<Selection> {
-    match expr {
-        ast::Expr::Nested(n) => parse_selection(n.as_ref(), headers),
+    let simple = match expr {
+        ast::Expr::Nested(n) => return parse_selection(n.as_ref(), headers),
         ast::Expr::BinaryOp { left, op, right } => {
             parse_binary_op(left, op.clone(), right, headers)
         }
-        _ => return invalid_data_ae!("Unsupported selection expression: {:?}", expr),
+        ast::Expr::IsNull(e) => parse_column(e.as_ref(), headers).map(Selection::IsNull),
+        ast::Expr::IsNotNull(e) => parse_column(e.as_ref(), headers).map(Selection::IsNotNull),
+        _ => return Ok(Selection::Expression(Expression::parse(expr, headers)?)),
+    };
+    match simple {
+        Ok(selection) => Ok(selection),
+        Err(_) => Ok(Selection::Expression(Expression::parse(expr, headers)?)),
     }
 }
 
@@ -330,6 +580,12 @@ fn parse_binary_op(
     right: &Box<ast::Expr>,
     headers: &Vec<String>,
 ) -> ApiResult<Selection> {
+    if let Some(spec) = PatternSpec::parse(&op, right.as_ref())? {
+        return Ok(Selection::Pattern(
+            parse_column(left.as_ref(), headers)?,
+            spec,
+        ));
+    }
     Ok(Selection::BinaryOp(
         parse_column(left.as_ref(), headers)?,
         parse_selection_op_type(op)?,
@@ -339,12 +595,12 @@ fn parse_binary_op(
 
 fn parse_column(expr: &ast::Expr, headers: &Vec<String>) -> ApiResult<usize> {
     let col_name = match expr {
-        ast::Expr::CompoundIdentifier(vi) => last_ident(vi)?,
+        ast::Expr::CompoundIdentifier(vi) => compound_name(vi, headers)?,
         _ => return invalid_data_ae!("unsupported column selection expr: {:?}", expr),
     };
 
     for (i, h) in headers.iter().enumerate() {
-        if col_name.value.eq(h) {
+        if col_name.eq(h) {
             return Ok(i);
         }
     }
@@ -365,6 +621,7 @@ fn parse_selection_value(expr: &ast::Expr) -> ApiResult<SelectionValue> {
         ast::Expr::Identifier(i) => SelectionValue::String(i.value.clone()),
         ast::Expr::Value(v) => match v {
             ast::Value::Number(num_str) => SelectionValue::Number(parse_number(num_str)?),
+            ast::Value::SingleQuotedString(s) => SelectionValue::String(s.clone()),
             _ => return invalid_data_ae!("unsupported selection value expr: {:?}", expr),
         },
         _ => return invalid_data_ae!("unsupported selection value expr: {:?}", expr),
@@ -375,9 +632,16 @@ fn parse_selection_value(expr: &ast::Expr) -> ApiResult<SelectionValue> {
 #[derive(Debug)]
 pub(crate) enum Selection {
     BinaryOp(usize, BinaryOpType, SelectionValue),
+    // Only stored NULLs match, empty strings are NULLs if table is imported with `empty_as_null`
+    IsNull(usize),
+    IsNotNull(usize),
+    // LIKE, ILIKE or REGEXP, pattern is compiled by filter
+    Pattern(usize, PatternSpec),
+    // Rows where expression is true are processed
+    Expression(Expression),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub(crate) enum SelectionValue {
     String(String),
     Number(Number),
@@ -392,9 +656,242 @@ impl SelectionValue {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub(crate) enum BinaryOpType {
     Eq,
+    NotEq,
+    Lt,
+    LtEq,
+    Gt,
+    GtEq,
+}
+
+impl BinaryOpType {
+    pub(crate) fn matches(&self, left: &ValueOrdRef, right: &ValueOrdRef) -> bool {
+        match self {
+            Self::Eq => left.eq(right),
+            Self::NotEq => !left.eq(right),
+            Self::Lt => left.cmp(right) == Ordering::Less,
+            Self::LtEq => left.cmp(right) != Ordering::Greater,
+            Self::Gt => left.cmp(right) == Ordering::Greater,
+            Self::GtEq => left.cmp(right) != Ordering::Less,
+        }
+    }
+}
+
+// HAVING is evaluated on aggregated groups, operands refer to projections of group record
+#[derive(Debug, Clone)]
+pub(crate) enum Having {
+    Compare(HavingOperand, BinaryOpType, HavingOperand),
+    IsNull(HavingOperand),
+    IsNotNull(HavingOperand),
+    And(Box<Having>, Box<Having>),
+    Or(Box<Having>, Box<Having>),
+    Not(Box<Having>),
+}
+
+#[derive(Debug, Clone)]
+pub(crate) enum HavingOperand {
+    Projection(usize),
+    // `count(*)` which is not projected, aggregator keeps it aside
+    Count,
+    Value(SelectionValue),
+}
+
+impl Having {
+    // Binds not projected `count(*)` to hidden aggregate at `idx`, returns `true` if it's used
+    pub(crate) fn bind_count(&mut self, idx: usize) -> bool {
+        match self {
+            Self::Compare(left, _, right) => {
+                let left = left.bind_count(idx);
+                right.bind_count(idx) || left
+            }
+            Self::IsNull(o) | Self::IsNotNull(o) => o.bind_count(idx),
+            Self::And(left, right) | Self::Or(left, right) => {
+                let left = left.bind_count(idx);
+                right.bind_count(idx) || left
+            }
+            Self::Not(h) => h.bind_count(idx),
+        }
+    }
+}
+
+impl HavingOperand {
+    fn bind_count(&mut self, idx: usize) -> bool {
+        if let Self::Count = self {
+            *self = Self::Projection(idx);
+            return true;
+        }
+        false
+    }
+}
+
+fn parse_having(
+    expr: &ast::Expr,
+    projections: &Vec<Projection>,
+    headers: &Vec<String>,
+) -> ApiResult<Having> {
+    let result = match expr {
+        ast::Expr::Nested(n) => parse_having(n.as_ref(), projections, headers)?,
+        ast::Expr::BinaryOp { left, op, right } => match op {
+            ast::BinaryOperator::And => Having::And(
+                Box::new(parse_having(left, projections, headers)?),
+                Box::new(parse_having(right, projections, headers)?),
+            ),
+            ast::BinaryOperator::Or => Having::Or(
+                Box::new(parse_having(left, projections, headers)?),
+                Box::new(parse_having(right, projections, headers)?),
+            ),
+            op => Having::Compare(
+                parse_having_operand(left, projections, headers, false)?,
+                parse_having_op_type(op)?,
+                parse_having_operand(right, projections, headers, true)?,
+            ),
+        },
+        ast::Expr::UnaryOp {
+            op: ast::UnaryOperator::Not,
+            expr,
+        } => Having::Not(Box::new(parse_having(expr, projections, headers)?)),
+        ast::Expr::IsNull(e) => {
+            Having::IsNull(parse_having_operand(e, projections, headers, false)?)
+        }
+        ast::Expr::IsNotNull(e) => {
+            Having::IsNotNull(parse_having_operand(e, projections, headers, false)?)
+        }
+        _ => return invalid_data_ae!("unsupported having expression: {:?}", expr),
+    };
+    Ok(result)
+}
+
+fn parse_having_op_type(op: &ast::BinaryOperator) -> ApiResult<BinaryOpType> {
+    let result = match op {
+        ast::BinaryOperator::Eq => BinaryOpType::Eq,
+        ast::BinaryOperator::NotEq => BinaryOpType::NotEq,
+        ast::BinaryOperator::Lt => BinaryOpType::Lt,
+        ast::BinaryOperator::LtEq => BinaryOpType::LtEq,
+        ast::BinaryOperator::Gt => BinaryOpType::Gt,
+        ast::BinaryOperator::GtEq => BinaryOpType::GtEq,
+        _ => return invalid_data_ae!("unsupported op in having: {:?}", op),
+    };
+    Ok(result)
+}
+
+// Names are resolved as aliases first, then as grouped columns,
+// unknown identifier on the right side is a string like in WHERE
+fn parse_having_operand(
+    expr: &ast::Expr,
+    projections: &Vec<Projection>,
+    headers: &Vec<String>,
+    is_right: bool,
+) -> ApiResult<HavingOperand> {
+    let name = match expr {
+        ast::Expr::Identifier(i) => i.value.clone(),
+        ast::Expr::CompoundIdentifier(vi) => compound_name(vi, headers)?,
+        ast::Expr::Function(f) => {
+            let (ptype, name) = parse_count_function(f, headers)?;
+            if let Some(idx) = projections.iter().position(|p| p.ptype == ptype) {
+                return Ok(HavingOperand::Projection(idx));
+            }
+            guard!(let ProjectionType::Count = ptype else {
+                return invalid_data_ae!("having {} should be projected", name);
+            });
+            return Ok(HavingOperand::Count);
+        }
+        ast::Expr::Value(_) => return Ok(HavingOperand::Value(parse_selection_value(expr)?)),
+        _ => return invalid_data_ae!("unsupported having operand: {:?}", expr),
+    };
+
+    if let Some(idx) = projections.iter().position(|p| p.name == name) {
+        return Ok(HavingOperand::Projection(idx));
+    }
+    if let Some(column) = headers.iter().position(|h| *h == name) {
+        let projection = projections.iter().position(|p| match p.ptype {
+            ProjectionType::Column(c) => c == column,
+            _ => false,
+        });
+        guard!(let Some(idx) = projection else {
+            return invalid_data_ae!("having column {} is not in group by projection", name);
+        });
+        return Ok(HavingOperand::Projection(idx));
+    }
+    match expr {
+        ast::Expr::Identifier(_) if is_right => {
+            Ok(HavingOperand::Value(parse_selection_value(expr)?))
+        }
+        _ => invalid_data_ae!("can't find having column or alias {}", name),
+    }
+}
+
+fn is_count_function(f: &ast::Function) -> bool {
+    f.name.0.len() == 1 && f.name.0.first().unwrap().value.to_lowercase() == "count"
+}
+
+fn is_approx_function(f: &ast::Function) -> bool {
+    f.name.0.len() == 1
+        && matches!(
+            f.name.0.first().unwrap().value.to_lowercase().as_str(),
+            "approx_count_distinct" | "approx_percentile" | "approx_median"
+        )
+}
+
+// Approximate aggregate over one column, percentile is a number between 0 and 1
+fn parse_approx_function(f: &ast::Function, headers: &Vec<String>) -> ApiResult<ProjectionType> {
+    if f.distinct || f.over.is_some() {
+        return invalid_data_ae!("wrong arguments of function: {}", f.name);
+    }
+    let name = f.name.0.first().unwrap().value.to_lowercase();
+    let (column, percentile) = match (name.as_str(), f.args.as_slice()) {
+        ("approx_count_distinct", [column]) => (column, None),
+        ("approx_median", [column]) => (column, Some(0.5)),
+        ("approx_percentile", [column, ast::Expr::Value(ast::Value::Number(p))]) => {
+            match p.parse::<f64>() {
+                Ok(p) if (0.0..=1.0).contains(&p) => (column, Some(p)),
+                _ => return invalid_data_ae!("percentile should be between 0 and 1: {}", p),
+            }
+        }
+        _ => return invalid_data_ae!("wrong arguments of function: {}", f.name),
+    };
+    let column = match column {
+        ast::Expr::Identifier(i) => i.value.clone(),
+        ast::Expr::CompoundIdentifier(vi) => compound_name(vi, headers)?,
+        _ => return invalid_data_ae!("{} expects one column: {}", name, column),
+    };
+    guard!(let Some(idx) = headers.iter().position(|h| *h == column) else {
+        return invalid_data_ae!("can't find column with name {}", column);
+    });
+    match percentile {
+        Some(p) => Ok(ProjectionType::ApproxPercentile(idx, p)),
+        None => Ok(ProjectionType::ApproxCountDistinct(idx)),
+    }
+}
+
+fn is_grouping_function(f: &ast::Function) -> bool {
+    f.name.0.len() == 1 && f.name.0.first().unwrap().value.to_lowercase() == "grouping"
+}
+
+// `count(*)` or `count(DISTINCT column)`, returns projection type and its default name
+fn parse_count_function(
+    f: &ast::Function,
+    headers: &Vec<String>,
+) -> ApiResult<(ProjectionType, String)> {
+    if !is_count_function(f) {
+        return invalid_data_ae!("unsupported function: {:?}", f);
+    }
+    if !f.distinct {
+        return Ok((ProjectionType::Count, "count(*)".to_owned()));
+    }
+    let column = match f.args.as_slice() {
+        [ast::Expr::Identifier(i)] => i.value.clone(),
+        [ast::Expr::CompoundIdentifier(vi)] => compound_name(vi, headers)?,
+        _ => return invalid_data_ae!("count(DISTINCT) expects one column: {:?}", f),
+    };
+    guard!(let Some(idx) = headers.iter().position(|h| *h == column) else {
+        return invalid_data_ae!("can't find column with name {}", column);
+    });
+    Ok((
+        ProjectionType::CountDistinct(idx),
+        format!("count(DISTINCT {})", column),
+    ))
 }
 
 fn parse_ast_number(value: &ast::Value) -> Option<usize> {
@@ -407,16 +904,334 @@ fn parse_ast_number(value: &ast::Value) -> Option<usize> {
     }
 }
 
-fn last_ident(idents: &Vec<sqlparser::ast::Ident>) -> ApiResult<&sqlparser::ast::Ident> {
-    guard!(let Some(i) = idents.last() else {
+// Columns with the same name in joined tables are qualified by table alias as `alias.column`,
+// other columns are found by the last identifier
+pub(crate) fn compound_name(
+    idents: &Vec<sqlparser::ast::Ident>,
+    headers: &Vec<String>,
+) -> ApiResult<String> {
+    guard!(let [.., last] = idents.as_slice() else {
         return invalid_data_ae!("wrong compound identifier: {:?}", idents)
     });
-    Ok(i)
+    if let [.., qualifier, _] = idents.as_slice() {
+        let qualified = format!("{}.{}", qualifier.value, last.value);
+        if headers.contains(&qualified) {
+            return Ok(qualified);
+        }
+    }
+    Ok(last.value.clone())
 }
 
 pub struct TableRef {
     pub name: String,
     pub alias: Option<String>,
+    // Subquery in FROM, its name is alias
+    pub(crate) subquery: Option<Subquery>,
+}
+
+// Query result which is read as table, CTE or subquery in FROM
+pub(crate) struct Subquery {
+    pub(crate) name: String,
+    // Column aliases, like `WITH t (a, b) AS (...)`
+    pub(crate) columns: Vec<String>,
+    pub(crate) query: Query,
+}
+
+impl TableRef {
+    // Columns of joined tables are qualified by alias or by table name
+    pub fn qualifier(&self) -> &str {
+        self.alias.as_ref().unwrap_or(&self.name)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum SetOperator {
+    Union,
+    Intersect,
+    Except,
+}
+
+// `left UNION [ALL] right`, branches are queries without ORDER BY and LIMIT of whole operation
+pub(crate) struct SetOperation {
+    pub(crate) op: SetOperator,
+    pub(crate) all: bool,
+    pub(crate) left: Query,
+    pub(crate) right: Query,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub(crate) enum JoinKind {
+    Inner,
+    // Rows of the left table without match are joined with NULLs
+    Left,
+}
+
+pub(crate) struct JoinRef {
+    pub(crate) table: TableRef,
+    pub(crate) kind: JoinKind,
+    pub(crate) on: ast::Expr,
+}
+
+fn table_ref(relation: &ast::TableFactor) -> ApiResult<TableRef> {
+    let (name_parts, alias) = match relation {
+        ast::TableFactor::Table {
+            name,
+            alias,
+            args: _,
+            with_hints: _,
+        } => (name, alias),
+        ast::TableFactor::Derived {
+            lateral: _,
+            subquery,
+            alias: Some(alias),
+        } => {
+            let name = alias.name.value.clone();
+            return Ok(TableRef {
+                name: name.clone(),
+                alias: Some(name.clone()),
+                subquery: Some(Subquery {
+                    name,
+                    columns: alias.columns.iter().map(|c| c.value.clone()).collect(),
+                    query: Query(subquery.clone()),
+                }),
+            });
+        }
+        _ => return invalid_data_ae!("no suitable table source"),
+    };
+
+    let name = match name_parts.0.last() {
+        Some(ident) => &ident.value,
+        _ => return invalid_data_ae!("no suitable table source"),
+    };
+
+    let res = TableRef {
+        name: name.clone(),
+        alias: alias.as_ref().map(|a| a.name.value.clone()),
+        subquery: None,
+    };
+
+    Ok(res)
+}
+
+fn collect_stored_tables(
+    query: &ast::Query,
+    ctes: &Vec<String>,
+    result: &mut Vec<String>,
+) -> ApiResult<()> {
+    // CTE is visible in the following CTEs and in query body
+    let mut ctes = ctes.clone();
+    for cte in query.ctes.iter() {
+        collect_stored_tables(&cte.query, &ctes, result)?;
+        ctes.push(cte.alias.name.value.clone());
+    }
+    collect_set_expr_tables(&query.body, &ctes, result)
+}
+
+fn collect_set_expr_tables(
+    body: &ast::SetExpr,
+    ctes: &Vec<String>,
+    result: &mut Vec<String>,
+) -> ApiResult<()> {
+    let select = match body {
+        ast::SetExpr::Select(select) => select,
+        ast::SetExpr::Query(query) => return collect_stored_tables(query, ctes, result),
+        ast::SetExpr::SetOperation { left, right, .. } => {
+            collect_set_expr_tables(left, ctes, result)?;
+            return collect_set_expr_tables(right, ctes, result);
+        }
+        _ => return invalid_data_ae!("only ordinal select supported"),
+    };
+    for from in select.from.iter() {
+        let relations =
+            std::iter::once(&from.relation).chain(from.joins.iter().map(|j| &j.relation));
+        for relation in relations {
+            let table = table_ref(relation)?;
+            match table.subquery {
+                Some(subquery) => collect_stored_tables(&subquery.query.0, ctes, result)?,
+                None if ctes.contains(&table.name) || result.contains(&table.name) => (),
+                None => result.push(table.name),
+            }
+        }
+    }
+    if let Some(selection) = &select.selection {
+        let mut subqueries = Vec::new();
+        collect_in_subqueries(selection, &mut subqueries)?;
+        for subquery in subqueries {
+            collect_stored_tables(subquery, ctes, result)?;
+        }
+    }
+    Ok(())
+}
+
+fn leftmost_select(body: &ast::SetExpr) -> ApiResult<&ast::Select> {
+    match body {
+        ast::SetExpr::Select(select) => Ok(select.as_ref()),
+        ast::SetExpr::Query(query) => leftmost_select(&query.body),
+        ast::SetExpr::SetOperation { left, .. } => leftmost_select(left),
+        _ => invalid_data_ae!("only ordinal select supported"),
+    }
+}
+
+// Only uncorrelated `IN (SELECT ...)` subqueries are supported in expressions
+fn collect_in_subqueries<'a>(
+    expr: &'a ast::Expr,
+    result: &mut Vec<&'a ast::Query>,
+) -> ApiResult<()> {
+    match expr {
+        ast::Expr::InSubquery { expr, subquery, .. } => {
+            collect_in_subqueries(expr, result)?;
+            result.push(subquery);
+        }
+        ast::Expr::Subquery(_) | ast::Expr::Exists(_) => {
+            return invalid_data_ae!("only IN (SELECT ...) subqueries supported: {}", expr)
+        }
+        ast::Expr::Nested(e)
+        | ast::Expr::IsNull(e)
+        | ast::Expr::IsNotNull(e)
+        | ast::Expr::UnaryOp { expr: e, .. }
+        | ast::Expr::Cast { expr: e, .. } => collect_in_subqueries(e, result)?,
+        ast::Expr::BinaryOp { left, right, .. } => {
+            collect_in_subqueries(left, result)?;
+            collect_in_subqueries(right, result)?;
+        }
+        ast::Expr::InList { expr, list, .. } => {
+            collect_in_subqueries(expr, result)?;
+            for e in list.iter() {
+                collect_in_subqueries(e, result)?;
+            }
+        }
+        ast::Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            let exprs = operand
+                .iter()
+                .chain(else_result.iter())
+                .map(|e| e.as_ref())
+                .chain(conditions.iter())
+                .chain(results.iter());
+            for e in exprs {
+                collect_in_subqueries(e, result)?;
+            }
+        }
+        ast::Expr::Function(f) => {
+            for e in f.args.iter() {
+                collect_in_subqueries(e, result)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+fn bind_in_subqueries(
+    expr: &mut ast::Expr,
+    f: &mut dyn FnMut(Query) -> ApiResult<Vec<ast::Expr>>,
+) -> ApiResult<()> {
+    if let ast::Expr::InSubquery {
+        expr: e,
+        subquery,
+        negated,
+    } = expr
+    {
+        bind_in_subqueries(e, f)?;
+        let list = f(Query(subquery.clone()))?;
+        *expr = ast::Expr::InList {
+            expr: e.clone(),
+            list,
+            negated: *negated,
+        };
+        return Ok(());
+    }
+    match expr {
+        ast::Expr::Nested(e)
+        | ast::Expr::IsNull(e)
+        | ast::Expr::IsNotNull(e)
+        | ast::Expr::UnaryOp { expr: e, .. }
+        | ast::Expr::Cast { expr: e, .. } => bind_in_subqueries(e, f)?,
+        ast::Expr::BinaryOp { left, right, .. } => {
+            bind_in_subqueries(left, f)?;
+            bind_in_subqueries(right, f)?;
+        }
+        ast::Expr::InList { expr, list, .. } => {
+            bind_in_subqueries(expr, f)?;
+            for e in list.iter_mut() {
+                bind_in_subqueries(e, f)?;
+            }
+        }
+        ast::Expr::Case {
+            operand,
+            conditions,
+            results,
+            else_result,
+        } => {
+            let exprs = operand
+                .iter_mut()
+                .chain(else_result.iter_mut())
+                .map(|e| e.as_mut())
+                .chain(conditions.iter_mut())
+                .chain(results.iter_mut());
+            for e in exprs {
+                bind_in_subqueries(e, f)?;
+            }
+        }
+        ast::Expr::Function(func) => {
+            for e in func.args.iter_mut() {
+                bind_in_subqueries(e, f)?;
+            }
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+// `ON a.x = b.y AND ...`, returns pairs of compared columns
+pub(crate) fn parse_join_on(
+    on: &ast::Expr,
+    headers: &Vec<String>,
+) -> ApiResult<Vec<(usize, usize)>> {
+    let mut result = Vec::new();
+    collect_join_keys(on, headers, &mut result)?;
+    Ok(result)
+}
+
+fn collect_join_keys(
+    expr: &ast::Expr,
+    headers: &Vec<String>,
+    keys: &mut Vec<(usize, usize)>,
+) -> ApiResult<()> {
+    match expr {
+        ast::Expr::Nested(e) => collect_join_keys(e, headers, keys)?,
+        ast::Expr::BinaryOp {
+            left,
+            op: ast::BinaryOperator::And,
+            right,
+        } => {
+            collect_join_keys(left, headers, keys)?;
+            collect_join_keys(right, headers, keys)?;
+        }
+        ast::Expr::BinaryOp {
+            left,
+            op: ast::BinaryOperator::Eq,
+            right,
+        } => keys.push((join_column(left, headers)?, join_column(right, headers)?)),
+        _ => return invalid_data_ae!("join condition should compare columns with AND: {}", expr),
+    }
+    Ok(())
+}
+
+fn join_column(expr: &ast::Expr, headers: &Vec<String>) -> ApiResult<usize> {
+    let name = match expr {
+        ast::Expr::Identifier(i) => i.value.clone(),
+        ast::Expr::CompoundIdentifier(vi) => compound_name(vi, headers)?,
+        _ => return invalid_data_ae!("unsupported join column: {}", expr),
+    };
+    guard!(let Some(idx) = headers.iter().position(|h| *h == name) else {
+        return invalid_data_ae!("can't find join column with name {}", name);
+    });
+    Ok(idx)
 }
 
 #[derive(Debug, Default)]
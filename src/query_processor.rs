@@ -3,6 +3,7 @@ use crate::errors::*;
 use crate::filter::*;
 use crate::query::*;
 use crate::record::*;
+use crate::window::split_window_query;
 
 pub struct QueryProcessor<'agg, 'de: 'agg> {
     projection_headers: Vec<String>,
@@ -12,17 +13,40 @@ pub struct QueryProcessor<'agg, 'de: 'agg> {
 
 impl<'agg, 'de: 'agg> QueryProcessor<'agg, 'de> {
     pub fn new(query: Query, headers: Vec<String>) -> ApiResult<Self> {
+        Self::with_options(query, headers, QueryOptions::default())
+    }
+
+    pub fn with_options(
+        query: Query,
+        headers: Vec<String>,
+        options: QueryOptions,
+    ) -> ApiResult<Self> {
+        let (query, window) = split_window_query(query, &headers)?;
         let select = query.select()?;
         let projections = Projection::compose_projections(select, &headers)?;
-        let group_by = query.get_if_group_by()?;
-        let order_by = query.get_if_order_by()?;
+        let group_by = query.get_if_group_by(&headers)?;
+        let order_by = query.get_if_order_by(&headers)?;
+        let having = query.get_if_having(&projections, &headers)?;
+        let distinct = query.is_distinct()?;
 
         let projection_headers = projections
             .iter()
             .map(|p| p.name.clone())
             .collect::<Vec<String>>();
 
-        let aggregator = create_aggregator(&headers, projections, group_by, order_by)?;
+        let aggregator = create_aggregator(
+            &headers,
+            projections,
+            group_by,
+            having,
+            order_by,
+            distinct,
+            &options,
+        )?;
+        let (projection_headers, aggregator) = match window {
+            Some(window) => window.wrap(projection_headers, aggregator)?,
+            None => (projection_headers, aggregator),
+        };
 
         let limit = query.get_if_limit()?;
         let selection = parse_if_has_selection(select, &headers)?;
@@ -36,7 +60,7 @@ impl<'agg, 'de: 'agg> QueryProcessor<'agg, 'de> {
         })
     }
 
-    pub fn process_record(&mut self, record: &flexbuffers::VectorReader<'de>) -> ApiResult<bool> {
+    pub fn process_record(&mut self, record: &dyn RecordRef<'de>) -> ApiResult<bool> {
         if let Some(filter) = self.filter.as_mut() {
             match filter.filter(record)? {
                 FilterRes::NeedStop => return Ok(false),
@@ -49,6 +73,10 @@ impl<'agg, 'de: 'agg> QueryProcessor<'agg, 'de> {
         Ok(true)
     }
 
+    pub fn headers(&self) -> &Vec<String> {
+        &self.projection_headers
+    }
+
     pub fn headers_csv(&self) -> String {
         self.projection_headers.join(",")
     }
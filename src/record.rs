@@ -1,11 +1,13 @@
 use crate::errors::*;
 
-use flexbuffers::{FlexBufferType, Reader};
+use flexbuffers::{FlexBufferType, Reader, VectorReader};
 use json::number::Number;
 use std::cmp::Ordering;
 use std::fmt::{self, Debug, Formatter, Write};
 use std::hash::Hash;
 use std::marker::PhantomData;
+use std::rc::Rc;
+use std::sync::Arc;
 
 pub(crate) type RecordIteratorItem<'a, 'de> = &'a (dyn RecordRef<'de> + 'a);
 
@@ -33,7 +35,11 @@ pub enum ValueRef<'a> {
     UInteger(u64),
     Integer(i64),
     Float(f64),
+    Bool(bool),
     Str(&'a str),
+    OwnedStr(Arc<str>),
+    // Nested map or vector, rendered as json
+    Json(Arc<str>),
     Null,
 }
 
@@ -44,7 +50,13 @@ impl<'a> ValueRef<'a> {
             FlexBufferType::UInt => Self::UInteger(reader.as_u64()),
             FlexBufferType::Int => Self::Integer(reader.as_i64()),
             FlexBufferType::Float => Self::Float(reader.as_f64()),
+            FlexBufferType::Bool => Self::Bool(reader.as_bool()),
             FlexBufferType::Null => Self::Null,
+            t if t == FlexBufferType::Map || t.is_vector() => {
+                let mut json = String::new();
+                write_json(reader, &mut json)?;
+                Self::Json(json.into())
+            }
             _ => {
                 return invalid_data_ae!(
                     "from_reader: unknown flexbuf type: {:?}",
@@ -63,8 +75,9 @@ impl<'a> ValueRef<'a> {
     }
 
     pub fn as_str(&self) -> ApiResult<&str> {
-        match *self {
+        match self {
             Self::Str(s) => Ok(s),
+            Self::OwnedStr(s) => Ok(s),
             _ => invalid_data_ae!("not str"),
         }
     }
@@ -87,33 +100,113 @@ impl<'a> ValueRef<'a> {
         }
     }
 
+    // Borrowed strings are copied, so value outlives record it's read from
+    pub(crate) fn into_owned(self) -> ValueRef<'static> {
+        match self {
+            Self::UInteger(x) => ValueRef::UInteger(x),
+            Self::Integer(x) => ValueRef::Integer(x),
+            Self::Float(x) => ValueRef::Float(x),
+            Self::Bool(x) => ValueRef::Bool(x),
+            Self::Str(x) => ValueRef::OwnedStr(x.into()),
+            Self::OwnedStr(x) => ValueRef::OwnedStr(x),
+            Self::Json(x) => ValueRef::Json(x),
+            Self::Null => ValueRef::Null,
+        }
+    }
+
     pub(crate) fn ord_ref(self) -> ValueOrdRef<'a> {
         match self {
             Self::UInteger(x) => ValueOrdRef::Number(json::number::Number::from(x)),
             Self::Integer(x) => ValueOrdRef::Number(json::number::Number::from(x)),
             Self::Float(x) => ValueOrdRef::Number(json::number::Number::from(x)),
+            Self::Bool(x) => ValueOrdRef::Bool(x),
             Self::Str(x) => ValueOrdRef::Str(x),
+            Self::OwnedStr(x) => ValueOrdRef::OwnedStr(x),
+            Self::Json(x) => ValueOrdRef::OwnedStr(x),
             Self::Null => ValueOrdRef::Null,
         }
     }
 }
 
+// Records imported before new column appeared (NDJSON import) are shorter than headers,
+// so missing trailing values are read as nulls
+#[inline]
+pub(crate) fn column_reader<'de>(record: &VectorReader<'de>, idx: usize) -> Reader<'de> {
+    record.idx(idx)
+}
+
+// Upsert key of encoded record. Both imported and already stored records are keyed
+// by formatted value, so `0123` and `123` are the same key, as they're stored as the same number
+pub(crate) fn record_key(record: &VectorReader, key_idx: usize) -> ApiResult<String> {
+    let value = ValueRef::from_reader(&column_reader(record, key_idx))?;
+    if value.is_null() {
+        return invalid_data_ae!("key column is NULL");
+    }
+    Ok(value.to_string())
+}
+
+// Renders flexbuffer value as json, used for nested maps and vectors
+fn write_json(reader: &Reader, out: &mut String) -> ApiResult<()> {
+    match reader.flexbuffer_type() {
+        FlexBufferType::Null => out.push_str("null"),
+        FlexBufferType::Bool => write!(out, "{}", reader.as_bool())?,
+        FlexBufferType::UInt | FlexBufferType::IndirectUInt => write!(out, "{}", reader.as_u64())?,
+        FlexBufferType::Int | FlexBufferType::IndirectInt => write!(out, "{}", reader.as_i64())?,
+        FlexBufferType::Float | FlexBufferType::IndirectFloat => {
+            write!(out, "{}", reader.as_f64())?
+        }
+        FlexBufferType::String | FlexBufferType::Key => {
+            out.push_str(&json::stringify(reader.as_str()))
+        }
+        FlexBufferType::Map => {
+            let map = reader.as_map();
+            out.push('{');
+            for (idx, (key, value)) in map.iter_keys().zip(map.iter_values()).enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                out.push_str(&json::stringify(key));
+                out.push(':');
+                write_json(&value, out)?;
+            }
+            out.push('}');
+        }
+        t if t.is_vector() => {
+            out.push('[');
+            for (idx, value) in reader.as_vector().iter().enumerate() {
+                if idx > 0 {
+                    out.push(',');
+                }
+                write_json(&value, out)?;
+            }
+            out.push(']');
+        }
+        t => return invalid_data_ae!("write_json: unsupported flexbuf type: {:?}", t),
+    }
+    Ok(())
+}
+
 impl<'a> fmt::Display for ValueRef<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::UInteger(x) => write!(f, "{}", x),
             Self::Integer(x) => write!(f, "{}", x),
             Self::Float(x) => write!(f, "{}", x),
+            Self::Bool(x) => write!(f, "{}", x),
             Self::Str(x) => write!(f, "{}", x),
+            Self::OwnedStr(x) => write!(f, "{}", x),
+            Self::Json(x) => write!(f, "{}", x),
             Self::Null => fmt::Result::Ok(()),
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone)]
 pub(crate) enum ValueOrdRef<'a> {
     Number(json::number::Number),
+    Bool(bool),
     Str(&'a str),
+    OwnedStr(Arc<str>),
     Null,
 }
 
@@ -131,17 +224,30 @@ impl<'a> ValueOrdRef<'a> {
                 }
                 ValueRef::Float(f64::from(n.clone()))
             }
+            Self::Bool(x) => ValueRef::Bool(*x),
             Self::Str(x) => ValueRef::Str(x),
+            Self::OwnedStr(x) => ValueRef::OwnedStr(x.clone()),
             Self::Null => ValueRef::Null,
         }
     }
+
+    #[inline]
+    fn str_value(&self) -> Option<&str> {
+        match self {
+            Self::Str(s) => Some(s),
+            Self::OwnedStr(s) => Some(s),
+            _ => None,
+        }
+    }
 }
 
 impl Hash for ValueOrdRef<'_> {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         match self {
             Self::Null => "".hash(state),
+            Self::Bool(b) => b.hash(state),
             Self::Str(s) => s.hash(state),
+            Self::OwnedStr(s) => s.as_ref().hash(state),
             Self::Number(n) => {
                 let (c, m, e) = n.as_parts();
                 c.hash(state);
@@ -152,6 +258,21 @@ impl Hash for ValueOrdRef<'_> {
     }
 }
 
+// Borrowed and owned strings are interchangeable in group by keys and ordering
+impl PartialEq for ValueOrdRef<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Self::Null, Self::Null) => true,
+            (Self::Bool(b1), Self::Bool(b2)) => b1 == b2,
+            (Self::Number(n1), Self::Number(n2)) => n1 == n2,
+            _ => match (self.str_value(), other.str_value()) {
+                (Some(s1), Some(s2)) => s1 == s2,
+                _ => false,
+            },
+        }
+    }
+}
+
 impl Eq for ValueOrdRef<'_> {}
 
 impl PartialOrd for ValueOrdRef<'_> {
@@ -162,14 +283,22 @@ impl PartialOrd for ValueOrdRef<'_> {
 
 impl Ord for ValueOrdRef<'_> {
     fn cmp(&self, other: &Self) -> Ordering {
+        if let (Some(s1), Some(s2)) = (self.str_value(), other.str_value()) {
+            return s1.cmp(s2);
+        }
         match (self, other) {
             (Self::Null, Self::Null) => Ordering::Equal,
             (_, Self::Null) => Ordering::Greater,
             (Self::Null, _) => Ordering::Less,
-            (Self::Str(s1), Self::Str(s2)) => s1.cmp(s2),
+            (Self::Bool(b1), Self::Bool(b2)) => b1.cmp(b2),
+            (_, Self::Bool(_)) => Ordering::Greater,
+            (Self::Bool(_), _) => Ordering::Less,
             (Self::Number(n1), Self::Number(n2)) => ord_number(n1, n2),
-            (Self::Str(s1), Self::Number(n2)) => ord_str_number(s1, n2),
-            (Self::Number(n1), Self::Str(s2)) => ord_str_number(s2, n1).reverse(),
+            (Self::Number(n1), s2) => {
+                ord_str_number(s2.str_value().unwrap_or_default(), n1).reverse()
+            }
+            (s1, Self::Number(n2)) => ord_str_number(s1.str_value().unwrap_or_default(), n2),
+            _ => Ordering::Equal,
         }
     }
 }
@@ -248,7 +377,9 @@ impl<'a> fmt::Display for ValueOrdRef<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Self::Number(x) => write!(f, "{}", x),
+            Self::Bool(x) => write!(f, "{}", x),
             Self::Str(x) => write!(f, "{}", x),
+            Self::OwnedStr(x) => write!(f, "{}", x),
             Self::Null => fmt::Result::Ok(()),
         }
     }
@@ -259,6 +390,17 @@ pub trait RecordRef<'a> {
     fn value_at(&self, idx: usize) -> ApiResult<ValueRef<'a>>;
 }
 
+// Stored record is input row of query
+impl<'a> RecordRef<'a> for VectorReader<'a> {
+    fn len(&self) -> usize {
+        VectorReader::len(self)
+    }
+
+    fn value_at(&self, idx: usize) -> ApiResult<ValueRef<'a>> {
+        ValueRef::from_reader(&column_reader(self, idx))
+    }
+}
+
 impl<'a> RecordRef<'a> for ValueRef<'a> {
     fn len(&self) -> usize {
         1
@@ -322,6 +464,39 @@ impl<'a, T: RecordRef<'a>> RecordIterator<'a> for OneRecordIterator<'a, T> {
     }
 }
 
+// Rows computed by aggregator when they are requested, error is returned by the first `next`.
+// Rows are shared, so aggregator could keep them for the next iteration
+pub(crate) struct OwnedRecordIterator<'de> {
+    error: Option<ApiError>,
+    rows: Rc<Vec<Vec<ValueRef<'de>>>>,
+    pos: usize,
+}
+
+impl<'de> OwnedRecordIterator<'de> {
+    pub(crate) fn new(rows: ApiResult<Rc<Vec<Vec<ValueRef<'de>>>>>) -> Self {
+        let (rows, error) = match rows {
+            Ok(rows) => (rows, None),
+            Err(e) => (Rc::new(Vec::new()), Some(e)),
+        };
+        Self {
+            error,
+            rows,
+            pos: 0,
+        }
+    }
+}
+
+impl<'de> RecordIterator<'de> for OwnedRecordIterator<'de> {
+    fn next(&mut self) -> ApiResult<Option<RecordIteratorItem<'_, 'de>>> {
+        if let Some(e) = self.error.take() {
+            return Err(e);
+        }
+        guard!(let Some(cur) = self.rows.get(self.pos) else { return Ok(None) });
+        self.pos += 1;
+        Ok(Some(cur))
+    }
+}
+
 #[test]
 fn test_value_ord_ref() {
     let values = vec![
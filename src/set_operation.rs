@@ -0,0 +1,208 @@
+use crate::errors::*;
+use crate::query::{Query, QueryOptions, SetOperator};
+use crate::query_processor::QueryProcessor;
+use crate::record::*;
+
+use std::collections::{HashMap, HashSet};
+
+// Row of query result which outlives query, strings are copied
+pub(crate) type OwnedRow = Vec<ValueRef<'static>>;
+
+pub(crate) fn owned_row(record: &dyn RecordRef) -> ApiResult<OwnedRow> {
+    let mut result = Vec::with_capacity(record.len());
+    for idx in 0..record.len() {
+        result.push(record.value_at(idx)?.into_owned());
+    }
+    Ok(result)
+}
+
+fn row_key(row: &OwnedRow) -> Vec<ValueOrdRef<'static>> {
+    row.iter().map(|v| v.clone().ord_ref()).collect()
+}
+
+// Receives rows of query, headers are passed once before rows
+pub(crate) trait RowSink {
+    fn headers(&mut self, headers: &Vec<String>) -> ApiResult<()>;
+    // `false` if no more rows are needed
+    fn row(&mut self, row: OwnedRow) -> ApiResult<bool>;
+}
+
+// Result of set operation is processed by query with its ORDER BY and LIMIT
+pub(crate) struct ResultSink {
+    query: Option<Query>,
+    options: QueryOptions,
+    pub(crate) processor: Option<QueryProcessor<'static, 'static>>,
+}
+
+impl ResultSink {
+    pub(crate) fn new(query: Query, options: QueryOptions) -> Self {
+        Self {
+            query: Some(query),
+            options,
+            processor: None,
+        }
+    }
+}
+
+impl RowSink for ResultSink {
+    fn headers(&mut self, headers: &Vec<String>) -> ApiResult<()> {
+        guard!(let Some(query) = self.query.take() else {
+            return invalid_data_ae!("headers of set operation are passed twice");
+        });
+        self.processor = Some(QueryProcessor::with_options(
+            query,
+            headers.clone(),
+            self.options,
+        )?);
+        Ok(())
+    }
+
+    fn row(&mut self, row: OwnedRow) -> ApiResult<bool> {
+        guard!(let Some(processor) = self.processor.as_mut() else {
+            return invalid_data_ae!("set operation row before headers");
+        });
+        processor.process_record(&row)
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum ValueKind {
+    Number,
+    Bool,
+    String,
+}
+
+impl ValueKind {
+    fn of(value: &ValueRef) -> Option<Self> {
+        match value {
+            ValueRef::Null => None,
+            ValueRef::UInteger(_) | ValueRef::Integer(_) | ValueRef::Float(_) => Some(Self::Number),
+            ValueRef::Bool(_) => Some(Self::Bool),
+            ValueRef::Str(_) | ValueRef::OwnedStr(_) | ValueRef::Json(_) => Some(Self::String),
+        }
+    }
+}
+
+// Rows of both branches go through it: UNION ALL rows are passed as is, UNION rows are deduplicated
+// by hash set, for INTERSECT and EXCEPT right branch is hashed first, then left one is probed
+pub(crate) struct SetOperationSink<'a> {
+    op: SetOperator,
+    all: bool,
+    out: &'a mut dyn RowSink,
+    // Columns count and kinds of columns of previous branches
+    columns: Option<usize>,
+    kinds: Vec<Option<ValueKind>>,
+    // Columns of current branch which kind is checked
+    checked: Vec<bool>,
+    building: bool,
+    headers_passed: bool,
+    hashed: HashMap<Vec<ValueOrdRef<'static>>, usize>,
+    seen: HashSet<Vec<ValueOrdRef<'static>>>,
+}
+
+impl<'a> SetOperationSink<'a> {
+    pub(crate) fn new(op: SetOperator, all: bool, out: &'a mut dyn RowSink) -> Self {
+        Self {
+            op,
+            all,
+            out,
+            columns: None,
+            kinds: Vec::new(),
+            checked: Vec::new(),
+            building: op != SetOperator::Union,
+            headers_passed: false,
+            hashed: HashMap::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    // Rows of the next branch are passed to result
+    pub(crate) fn start_probe(&mut self) {
+        self.building = false;
+    }
+
+    fn check_kinds(&mut self, row: &OwnedRow) -> ApiResult<()> {
+        for (idx, value) in row.iter().enumerate() {
+            if self.checked[idx] {
+                continue;
+            }
+            guard!(let Some(kind) = ValueKind::of(value) else { continue });
+            self.checked[idx] = true;
+            match self.kinds[idx] {
+                Some(k) if k != kind => {
+                    return invalid_data_ae!(
+                        "column {} of {:?} has incompatible types: {:?} and {:?}",
+                        idx + 1,
+                        self.op,
+                        k,
+                        kind
+                    )
+                }
+                _ => self.kinds[idx] = Some(kind),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl RowSink for SetOperationSink<'_> {
+    fn headers(&mut self, headers: &Vec<String>) -> ApiResult<()> {
+        match self.columns {
+            Some(columns) if columns != headers.len() => {
+                return invalid_data_ae!(
+                    "branches of {:?} have different columns count: {} and {}",
+                    self.op,
+                    columns,
+                    headers.len()
+                )
+            }
+            Some(_) => (),
+            None => {
+                self.columns = Some(headers.len());
+                self.kinds = vec![None; headers.len()];
+            }
+        }
+        self.checked = vec![false; headers.len()];
+        // Result has columns of the left branch
+        if !self.building && !self.headers_passed {
+            self.headers_passed = true;
+            self.out.headers(headers)?;
+        }
+        Ok(())
+    }
+
+    fn row(&mut self, row: OwnedRow) -> ApiResult<bool> {
+        self.check_kinds(&row)?;
+        if self.building {
+            *self.hashed.entry(row_key(&row)).or_insert(0) += 1;
+            return Ok(true);
+        }
+        let pass = match (self.op, self.all) {
+            (SetOperator::Union, true) => true,
+            (SetOperator::Union, false) => self.seen.insert(row_key(&row)),
+            // Distinct row is passed once, ALL row is passed as many times as it's in both
+            (SetOperator::Intersect, all) => match self.hashed.get_mut(&row_key(&row)) {
+                Some(count) if *count > 0 => {
+                    *count = if all { *count - 1 } else { 0 };
+                    true
+                }
+                _ => false,
+            },
+            (SetOperator::Except, true) => match self.hashed.get_mut(&row_key(&row)) {
+                Some(count) if *count > 0 => {
+                    *count -= 1;
+                    false
+                }
+                _ => true,
+            },
+            (SetOperator::Except, false) => {
+                let key = row_key(&row);
+                !self.hashed.contains_key(&key) && self.seen.insert(key)
+            }
+        };
+        if !pass {
+            return Ok(true);
+        }
+        self.out.row(row)
+    }
+}
@@ -0,0 +1,209 @@
+use crate::errors::*;
+use crate::misc_utils::*;
+
+use bytes::Bytes;
+use futures::stream::{Stream, StreamExt};
+use rocket::config::Config;
+use rocket::data::{Data, ToByteUnit};
+use rocket::{request, State};
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::io::Result;
+
+const DEFAULT_IMPORT_LIMIT: u64 = 256 * 1024 * 1024;
+
+pub struct Settings {
+    import_limit: u64,
+    table_import_limits: HashMap<String, u64>,
+    api_key_import_limits: HashMap<String, u64>,
+    // Server side imports are allowed only from this directory
+    import_dir: Option<PathBuf>,
+    admin_api_keys: HashSet<String>,
+}
+
+impl Settings {
+    pub fn from_config(config: &Config) -> ApiResult<Self> {
+        let import_limit = config
+            .limits
+            .get("import")
+            .map(|l| l.as_u64())
+            .unwrap_or(DEFAULT_IMPORT_LIMIT);
+
+        let import_dir = match config.get_str("import_dir") {
+            Ok(dir) => Some(Self::canonical_dir(dir)?),
+            Err(_) => None,
+        };
+
+        let mut admin_api_keys = HashSet::new();
+        if let Ok(keys) = config.get_slice("admin_api_keys") {
+            for key in keys.iter() {
+                guard!(let Some(key) = key.as_str() else {
+                    return invalid_data_ae!("wrong admin api key: {}", key);
+                });
+                admin_api_keys.insert(key.to_owned());
+            }
+        }
+
+        Ok(Self {
+            import_limit,
+            table_import_limits: Self::limits_table(config, "import_limits")?,
+            api_key_import_limits: Self::limits_table(config, "api_key_import_limits")?,
+            import_dir,
+            admin_api_keys,
+        })
+    }
+
+    fn canonical_dir(dir: &str) -> ApiResult<PathBuf> {
+        guard!(let Some(path) = expand_tilde(dir) else {
+            return other_e!("wrong import dir: {}", dir);
+        });
+        let path = path.canonicalize()?;
+        if !path.is_dir() {
+            return other_e!("import dir is not a directory: {}", dir);
+        }
+        Ok(path)
+    }
+
+    fn limits_table(config: &Config, name: &str) -> ApiResult<HashMap<String, u64>> {
+        let mut result = HashMap::new();
+        guard!(let Ok(table) = config.get_table(name) else { return Ok(result) });
+        for (key, value) in table.iter() {
+            guard!(let Some(limit) = value.as_integer() else {
+                return invalid_data_ae!("wrong limit for {} in {}: {}", key, name, value);
+            });
+            if limit < 0 {
+                return invalid_data_ae!("negative limit for {} in {}: {}", key, name, limit);
+            }
+            result.insert(key.clone(), limit as u64);
+        }
+        Ok(result)
+    }
+
+    // Limit for API key has priority over limit for table,
+    // and both of them have priority over global `limits.import`
+    pub fn import_limit(&self, table: &str, api_key: &ApiKey) -> u64 {
+        if let Some(limit) = api_key
+            .0
+            .as_ref()
+            .and_then(|k| self.api_key_import_limits.get(k))
+        {
+            return *limit;
+        }
+        if let Some(limit) = self.table_import_limits.get(table) {
+            return *limit;
+        }
+        self.import_limit
+    }
+
+    // Admin endpoints are closed if no admin keys configured
+    pub fn check_admin(&self, api_key: &ApiKey) -> ApiResult<()> {
+        if self.admin_api_keys.is_empty() {
+            return forbidden_ae!(
+                "admin endpoints are disabled, `admin_api_keys` is not configured"
+            );
+        }
+        match api_key.0.as_ref() {
+            Some(key) if self.admin_api_keys.contains(key) => Ok(()),
+            _ => forbidden_ae!("admin api key required"),
+        }
+    }
+
+    // Expands path or glob relative to import dir,
+    // every matched file should be inside of import dir after symlinks resolution
+    pub fn import_files(&self, pattern: &str) -> ApiResult<Vec<PathBuf>> {
+        guard!(let Some(import_dir) = self.import_dir.as_ref() else {
+            return forbidden_ae!("import from path is disabled, `import_dir` is not configured");
+        });
+
+        let pattern = import_dir.join(Path::new(pattern));
+        guard!(let Some(pattern) = pattern.to_str() else {
+            return invalid_data_ae!("wrong import path: {:?}", pattern);
+        });
+        let paths = match glob::glob(pattern) {
+            Ok(paths) => paths,
+            Err(e) => return invalid_data_ae!("wrong import path {}: {}", pattern, e),
+        };
+
+        let mut files = Vec::new();
+        for path in paths {
+            let path = match path {
+                Ok(path) => path.canonicalize()?,
+                Err(e) => return invalid_data_ae!("can't read import path: {}", e),
+            };
+            if !path.starts_with(import_dir) {
+                return forbidden_ae!("{:?} is outside of import dir", path);
+            }
+            if path.is_file() {
+                files.push(path);
+            }
+        }
+
+        if files.is_empty() {
+            return not_found_ae!("no files match {}", pattern);
+        }
+        files.sort();
+        Ok(files)
+    }
+}
+
+#[derive(Clone)]
+pub struct SettingsHandle(pub Arc<Settings>);
+
+impl std::ops::Deref for SettingsHandle {
+    type Target = Settings;
+
+    fn deref(&self) -> &Settings {
+        &*self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'a, 'r> request::FromRequest<'a, 'r> for SettingsHandle {
+    type Error = ();
+
+    async fn from_request(req: &'a request::Request<'r>) -> request::Outcome<SettingsHandle, ()> {
+        let settings = try_outcome!(req.guard::<State<SettingsHandle>>().await);
+        request::Outcome::Success(settings.clone())
+    }
+}
+
+// Value of `X-Api-Key` header, if any
+#[derive(Debug, Clone)]
+pub struct ApiKey(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'a, 'r> request::FromRequest<'a, 'r> for ApiKey {
+    type Error = ();
+
+    async fn from_request(req: &'a request::Request<'r>) -> request::Outcome<ApiKey, ()> {
+        let key = req.headers().get_one("X-Api-Key").map(|k| k.to_owned());
+        request::Outcome::Success(ApiKey(key))
+    }
+}
+
+// Opens body with one extra byte over the limit, so we could distinguish
+// body which exactly fits the limit from truncated one and fail instead of partial import
+pub fn limited_bytes_stream(data: Data, limit: u64) -> impl Stream<Item = Result<Bytes>> {
+    limit_stream(into_bytes_stream(data.open((limit + 1).bytes())), limit)
+}
+
+// Fails once stream yields more than `limit` bytes, import body is limited on the wire
+// and once more after decompression, so small compressed body can't expand over the limit
+pub fn limit_stream<S>(stream: S, limit: u64) -> impl Stream<Item = Result<Bytes>>
+where
+    S: Stream<Item = Result<Bytes>>,
+{
+    let mut bytes_read: u64 = 0;
+    stream.map(move |chunk| {
+        let chunk = chunk?;
+        bytes_read += chunk.len() as u64;
+        if bytes_read > limit {
+            return Err(payload_too_large!(
+                "request body exceeds import limit of {} bytes",
+                limit
+            ));
+        }
+        Ok(chunk)
+    })
+}
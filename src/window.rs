@@ -0,0 +1,539 @@
+use crate::aggregator::Aggregator;
+use crate::errors::*;
+use crate::expression::{as_f64, eval_arithmetic, ArithmeticOp, Expression};
+use crate::query::*;
+use crate::record::*;
+
+use sqlparser::ast;
+use std::cell::RefCell;
+use std::cmp::Ordering;
+use std::rc::Rc;
+
+// Window functions are computed over rows of aggregation stage, so their operands are passed
+// there as hidden projections after the query ones. ORDER BY and LIMIT of query are applied
+// after window functions
+pub(crate) struct WindowStage {
+    // Count of projections of aggregation stage
+    columns: usize,
+    outputs: Vec<WindowOutput>,
+    functions: Vec<WindowFunction>,
+    order_by: Option<OrderBy>,
+    limit: Option<usize>,
+}
+
+enum WindowOutput {
+    Projection(usize),
+    Function(usize),
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum WindowFunctionType {
+    RowNumber,
+    Rank,
+    DenseRank,
+    Lag,
+    Lead,
+    Sum,
+    Count,
+    Avg,
+}
+
+impl WindowFunctionType {
+    fn from_name(name: &str) -> Option<Self> {
+        let result = match name.to_lowercase().as_str() {
+            "row_number" => Self::RowNumber,
+            "rank" => Self::Rank,
+            "dense_rank" => Self::DenseRank,
+            "lag" => Self::Lag,
+            "lead" => Self::Lead,
+            "sum" => Self::Sum,
+            "count" => Self::Count,
+            "avg" => Self::Avg,
+            _ => return None,
+        };
+        Some(result)
+    }
+
+    fn check_args(&self, count: usize) -> bool {
+        match self {
+            Self::RowNumber | Self::Rank | Self::DenseRank => count == 0,
+            Self::Lag | Self::Lead => (1..=3).contains(&count),
+            Self::Sum | Self::Count | Self::Avg => count == 1,
+        }
+    }
+}
+
+struct WindowFunction {
+    name: String,
+    ftype: WindowFunctionType,
+    // Projection of argument, `None` for `count(*)` and ranking functions
+    arg: Option<usize>,
+    // Offset and default value of `lag` and `lead`
+    offset: usize,
+    default: ValueOrdRef<'static>,
+    partition_by: Vec<usize>,
+    order_by: Vec<(usize, bool)>,
+}
+
+// Query with window functions is split to query of aggregation stage and window stage
+pub(crate) fn split_window_query(
+    query: Query,
+    headers: &Vec<String>,
+) -> ApiResult<(Query, Option<WindowStage>)> {
+    let select = query.select()?;
+    if !select
+        .projection
+        .iter()
+        .any(|p| window_function(p).is_some())
+    {
+        return Ok((query, None));
+    }
+    if select.distinct {
+        return invalid_data_ae!("DISTINCT with window functions is not supported");
+    }
+
+    let mut items = Vec::with_capacity(select.projection.len());
+    let mut outputs = Vec::with_capacity(select.projection.len());
+    let mut windows = Vec::new();
+    for p in select.projection.iter() {
+        if let Some(window) = window_function(p) {
+            outputs.push(WindowOutput::Function(windows.len()));
+            windows.push(window);
+            continue;
+        }
+        if let ast::SelectItem::Wildcard = p {
+            return invalid_data_ae!("wildcard combined with window functions");
+        }
+        outputs.push(WindowOutput::Projection(items.len()));
+        items.push(p.clone());
+    }
+
+    let mut functions = Vec::with_capacity(windows.len());
+    for (name, f) in windows {
+        functions.push(WindowFunction::parse(name, f, &mut items, headers)?);
+    }
+
+    let stage = WindowStage {
+        columns: items.len(),
+        outputs,
+        functions,
+        order_by: query.get_if_order_by(headers)?,
+        limit: query.get_if_limit()?,
+    };
+    Ok((query.with_projections(items)?, Some(stage)))
+}
+
+fn window_function(item: &ast::SelectItem) -> Option<(String, &ast::Function)> {
+    let (expr, alias) = match item {
+        ast::SelectItem::UnnamedExpr(expr) => (expr, None),
+        ast::SelectItem::ExprWithAlias { expr, alias } => (expr, Some(alias)),
+        _ => return None,
+    };
+    match expr {
+        ast::Expr::Function(f) if f.over.is_some() => {
+            let name = alias.map_or_else(|| expr.to_string(), |a| a.value.clone());
+            Some((name, f))
+        }
+        _ => None,
+    }
+}
+
+// Column name of identifier, so `x` and `t.x` are the same operand
+fn operand_column(expr: &ast::Expr, headers: &Vec<String>) -> Option<String> {
+    match expr {
+        ast::Expr::Identifier(i) => Some(i.value.clone()),
+        ast::Expr::CompoundIdentifier(vi) => compound_name(vi, headers).ok(),
+        ast::Expr::Nested(e) => operand_column(e, headers),
+        _ => None,
+    }
+}
+
+// Projection of aggregation stage with the expression, input column shadows projection alias,
+// as window operands are evaluated on input rows
+fn operand_idx(expr: &ast::Expr, items: &mut Vec<ast::SelectItem>, headers: &Vec<String>) -> usize {
+    match expr {
+        ast::Expr::Identifier(i) if !headers.contains(&i.value) => {
+            let alias_idx = items.iter().position(|item| match item {
+                ast::SelectItem::ExprWithAlias { alias, .. } => alias.value == i.value,
+                _ => false,
+            });
+            if let Some(idx) = alias_idx {
+                return idx;
+            }
+        }
+        _ => (),
+    }
+    let column = operand_column(expr, headers);
+    let expr_idx = items.iter().position(|item| match item {
+        ast::SelectItem::UnnamedExpr(e) | ast::SelectItem::ExprWithAlias { expr: e, .. } => {
+            e == expr || (column.is_some() && operand_column(e, headers) == column)
+        }
+        _ => false,
+    });
+    if let Some(idx) = expr_idx {
+        return idx;
+    }
+    items.push(ast::SelectItem::UnnamedExpr(expr.clone()));
+    items.len() - 1
+}
+
+impl WindowFunction {
+    fn parse(
+        name: String,
+        f: &ast::Function,
+        items: &mut Vec<ast::SelectItem>,
+        headers: &Vec<String>,
+    ) -> ApiResult<Self> {
+        guard!(let Some(spec) = f.over.as_ref() else {
+            return invalid_data_ae!("{} is not a window function", name);
+        });
+        if spec.window_frame.is_some() {
+            return invalid_data_ae!("window frame is not supported: {}", name);
+        }
+        guard!(let Some(ftype) = WindowFunctionType::from_name(&f.name.to_string()) else {
+            return invalid_data_ae!("unsupported window function: {}", f.name);
+        });
+        if f.distinct || !ftype.check_args(f.args.len()) {
+            return invalid_data_ae!("wrong arguments of window function: {}", name);
+        }
+
+        let arg = match f.args.first() {
+            Some(ast::Expr::Wildcard) if ftype == WindowFunctionType::Count => None,
+            Some(ast::Expr::Wildcard) => {
+                return invalid_data_ae!("wrong arguments of window function: {}", name)
+            }
+            Some(expr) => Some(operand_idx(expr, items, headers)),
+            None => None,
+        };
+        let offset = match f.args.get(1) {
+            Some(ast::Expr::Value(ast::Value::Number(n))) => match n.parse::<usize>() {
+                Ok(offset) => offset,
+                Err(_) => return invalid_data_ae!("wrong offset of window function: {}", name),
+            },
+            Some(_) => return invalid_data_ae!("wrong offset of window function: {}", name),
+            None => 1,
+        };
+        let default = match f.args.get(2) {
+            Some(expr) => match Expression::parse(expr, &Vec::new()) {
+                Ok(Expression::Literal(v)) => v,
+                _ => {
+                    return invalid_data_ae!(
+                        "default of window function should be a value: {}",
+                        name
+                    )
+                }
+            },
+            None => ValueOrdRef::Null,
+        };
+
+        let mut partition_by = Vec::with_capacity(spec.partition_by.len());
+        for expr in spec.partition_by.iter() {
+            partition_by.push(operand_idx(expr, items, headers));
+        }
+        let mut order_by = Vec::with_capacity(spec.order_by.len());
+        for o in spec.order_by.iter() {
+            order_by.push((operand_idx(&o.expr, items, headers), o.asc.unwrap_or(true)));
+        }
+
+        Ok(Self {
+            name,
+            ftype,
+            arg,
+            offset,
+            default,
+            partition_by,
+            order_by,
+        })
+    }
+
+    // Rows are sorted by partition and order keys, then every partition is computed separately
+    fn compute<'de>(&self, rows: &Vec<Vec<ValueRef<'de>>>) -> ApiResult<Vec<ValueRef<'de>>> {
+        let mut ids = (0..rows.len()).collect::<Vec<usize>>();
+        ids.sort_by(|a, b| {
+            compare_by(
+                self.partition_by.iter().map(|c| (*c, true)),
+                &rows[*a],
+                &rows[*b],
+            )
+            .then_with(|| compare_by(self.order_by.iter().cloned(), &rows[*a], &rows[*b]))
+        });
+
+        let mut result = vec![ValueRef::Null; rows.len()];
+        let mut start = 0;
+        while start < ids.len() {
+            let mut end = start + 1;
+            while end < ids.len() && self.same_partition(&rows[ids[start]], &rows[ids[end]]) {
+                end += 1;
+            }
+            self.compute_partition(rows, &ids[start..end], &mut result)?;
+            start = end;
+        }
+        Ok(result)
+    }
+
+    fn same_partition(&self, a: &Vec<ValueRef>, b: &Vec<ValueRef>) -> bool {
+        compare_by(self.partition_by.iter().map(|c| (*c, true)), a, b) == Ordering::Equal
+    }
+
+    // Rows with equal order keys are peers, they have the same rank and running aggregate
+    fn is_peer(&self, a: &Vec<ValueRef>, b: &Vec<ValueRef>) -> bool {
+        compare_by(self.order_by.iter().cloned(), a, b) == Ordering::Equal
+    }
+
+    fn compute_partition<'de>(
+        &self,
+        rows: &Vec<Vec<ValueRef<'de>>>,
+        ids: &[usize],
+        result: &mut Vec<ValueRef<'de>>,
+    ) -> ApiResult<()> {
+        match self.ftype {
+            WindowFunctionType::RowNumber => {
+                for (n, id) in ids.iter().enumerate() {
+                    result[*id] = ValueRef::UInteger(n as u64 + 1);
+                }
+            }
+            WindowFunctionType::Rank | WindowFunctionType::DenseRank => {
+                let (mut rank, mut dense_rank) = (0, 0);
+                for (n, id) in ids.iter().enumerate() {
+                    if n == 0 || !self.is_peer(&rows[ids[n - 1]], &rows[*id]) {
+                        rank = n as u64 + 1;
+                        dense_rank += 1;
+                    }
+                    result[*id] = match self.ftype {
+                        WindowFunctionType::Rank => ValueRef::UInteger(rank),
+                        _ => ValueRef::UInteger(dense_rank),
+                    };
+                }
+            }
+            WindowFunctionType::Lag | WindowFunctionType::Lead => {
+                for (n, id) in ids.iter().enumerate() {
+                    let other = match self.ftype {
+                        WindowFunctionType::Lag => n.checked_sub(self.offset),
+                        _ => Some(n + self.offset).filter(|o| *o < ids.len()),
+                    };
+                    result[*id] = match other {
+                        Some(o) => self.arg_value(&rows[ids[o]])?,
+                        None => self.default.as_value_ref(),
+                    };
+                }
+            }
+            // Without ORDER BY all rows of partition are peers, so the whole one is aggregated
+            WindowFunctionType::Sum | WindowFunctionType::Count | WindowFunctionType::Avg => {
+                let mut aggregate = RunningAggregate::new();
+                let mut start = 0;
+                while start < ids.len() {
+                    let mut end = start;
+                    while end < ids.len() && self.is_peer(&rows[ids[start]], &rows[ids[end]]) {
+                        match self.arg {
+                            Some(_) => aggregate.add(self.arg_value(&rows[ids[end]])?)?,
+                            None => aggregate.count += 1,
+                        }
+                        end += 1;
+                    }
+                    let value = aggregate.value(self.ftype)?;
+                    for id in ids[start..end].iter() {
+                        result[*id] = value.clone();
+                    }
+                    start = end;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn arg_value<'de>(&self, row: &Vec<ValueRef<'de>>) -> ApiResult<ValueRef<'de>> {
+        guard!(let Some(arg) = self.arg else {
+            return invalid_data_ae!("no argument of window function {}", self.name);
+        });
+        row.value_at(arg)
+    }
+}
+
+fn compare_by(
+    columns: impl Iterator<Item = (usize, bool)>,
+    a: &Vec<ValueRef>,
+    b: &Vec<ValueRef>,
+) -> Ordering {
+    for (column, asc) in columns {
+        let result = a[column]
+            .clone()
+            .ord_ref()
+            .cmp(&b[column].clone().ord_ref());
+        let result = if asc { result } else { result.reverse() };
+        if result != Ordering::Equal {
+            return result;
+        }
+    }
+    Ordering::Equal
+}
+
+// NULLs are skipped like by aggregate functions
+struct RunningAggregate<'de> {
+    count: u64,
+    sum: ValueRef<'de>,
+}
+
+impl<'de> RunningAggregate<'de> {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            sum: ValueRef::Null,
+        }
+    }
+
+    fn add(&mut self, value: ValueRef<'de>) -> ApiResult<()> {
+        if value.is_null() {
+            return Ok(());
+        }
+        self.count += 1;
+        if let ValueRef::Null = self.sum {
+            self.sum = ValueRef::UInteger(0);
+        }
+        let sum = std::mem::replace(&mut self.sum, ValueRef::Null);
+        self.sum = eval_arithmetic(sum, ArithmeticOp::Plus, value)?;
+        Ok(())
+    }
+
+    fn value(&self, ftype: WindowFunctionType) -> ApiResult<ValueRef<'de>> {
+        let result = match ftype {
+            WindowFunctionType::Count => ValueRef::UInteger(self.count),
+            WindowFunctionType::Avg if self.count > 0 => {
+                ValueRef::Float(as_f64(&self.sum)? / self.count as f64)
+            }
+            _ => self.sum.clone(),
+        };
+        Ok(result)
+    }
+}
+
+impl WindowStage {
+    // Aggregator of query is wrapped, headers of its projections are replaced by query ones
+    pub(crate) fn wrap<'agg, 'de: 'agg>(
+        self,
+        headers: Vec<String>,
+        inner: Box<dyn Aggregator<'de> + 'agg>,
+    ) -> ApiResult<(Vec<String>, Box<dyn Aggregator<'de> + 'agg>)> {
+        if headers.len() != self.columns {
+            return invalid_data_ae!(
+                "window stage expects {} columns, got {}",
+                self.columns,
+                headers.len()
+            );
+        }
+        let output_headers = self
+            .outputs
+            .iter()
+            .map(|o| match o {
+                WindowOutput::Projection(idx) => headers[*idx].clone(),
+                WindowOutput::Function(idx) => self.functions[*idx].name.clone(),
+            })
+            .collect::<Vec<String>>();
+
+        let order_by = match self.order_by {
+            Some(OrderBy {
+                id: OrderByIdType::ProjectionId(pid),
+                asc,
+            }) => {
+                if pid == 0 || pid > output_headers.len() {
+                    return invalid_data_ae!(
+                        "can't order by {}, there is only {} projections",
+                        pid,
+                        output_headers.len()
+                    );
+                }
+                Some((pid - 1, asc))
+            }
+            Some(OrderBy {
+                id: OrderByIdType::Column(c),
+                asc,
+            }) => match output_headers.iter().position(|h| *h == c) {
+                Some(idx) => Some((idx, asc)),
+                None => return invalid_data_ae!("order_by column {} is not in projections", c),
+            },
+            None => None,
+        };
+
+        let aggregator = WindowAggregator {
+            inner,
+            columns: self.columns,
+            outputs: self.outputs,
+            functions: self.functions,
+            order_by,
+            limit: self.limit,
+            computed: RefCell::new(None),
+        };
+        Ok((output_headers, Box::new(aggregator)))
+    }
+}
+
+struct WindowAggregator<'agg, 'de: 'agg> {
+    inner: Box<dyn Aggregator<'de> + 'agg>,
+    columns: usize,
+    outputs: Vec<WindowOutput>,
+    functions: Vec<WindowFunction>,
+    order_by: Option<(usize, bool)>,
+    limit: Option<usize>,
+    // Rows computed by the first `iter`, reset once more input is aggregated
+    computed: RefCell<Option<Rc<Vec<Vec<ValueRef<'de>>>>>>,
+}
+
+impl<'agg, 'de: 'agg> WindowAggregator<'agg, 'de> {
+    fn rows(&self) -> ApiResult<Vec<Vec<ValueRef<'de>>>> {
+        let mut rows = Vec::new();
+        let mut iter = self.inner.iter();
+        while let Some(record) = iter.next()? {
+            let mut row = Vec::with_capacity(self.columns);
+            for idx in 0..self.columns {
+                row.push(record.value_at(idx)?);
+            }
+            rows.push(row);
+        }
+
+        let mut values = Vec::with_capacity(self.functions.len());
+        for function in self.functions.iter() {
+            values.push(function.compute(&rows)?);
+        }
+
+        let mut result = Vec::with_capacity(rows.len());
+        for (n, row) in rows.iter().enumerate() {
+            result.push(
+                self.outputs
+                    .iter()
+                    .map(|o| match o {
+                        WindowOutput::Projection(idx) => row[*idx].clone(),
+                        WindowOutput::Function(idx) => values[*idx][n].clone(),
+                    })
+                    .collect::<Vec<ValueRef<'de>>>(),
+            );
+        }
+
+        if let Some(order_by) = self.order_by {
+            result.sort_by(|a, b| compare_by(std::iter::once(order_by), a, b));
+        }
+        if let Some(limit) = self.limit {
+            result.truncate(limit);
+        }
+        Ok(result)
+    }
+}
+
+impl<'agg, 'de: 'agg> Aggregator<'de> for WindowAggregator<'agg, 'de> {
+    fn aggregate(&mut self, value: &dyn RecordRef<'de>) -> ApiResult<()> {
+        *self.computed.get_mut() = None;
+        self.inner.aggregate(value)
+    }
+
+    // Window functions need all rows, so they are computed when rows are requested
+    fn iter(&self) -> BoxedRecordIterator<'_, 'de> {
+        let cached = self.computed.borrow().clone();
+        let rows = match cached {
+            Some(rows) => Ok(rows),
+            None => self.rows().map(|rows| {
+                let rows = Rc::new(rows);
+                *self.computed.borrow_mut() = Some(rows.clone());
+                rows
+            }),
+        };
+        Box::new(OwnedRecordIterator::new(rows))
+    }
+}
@@ -1,9 +1,27 @@
+use test_db::arrow_utils::*;
+use test_db::charset::*;
+use test_db::compression::*;
 use test_db::csv_utils::*;
+use test_db::db::*;
+use test_db::errors::*;
+use test_db::executor::*;
+use test_db::export::*;
+use test_db::import::*;
+use test_db::jobs::*;
+use test_db::join::*;
+use test_db::mem_table::*;
+use test_db::misc_utils::TempFile;
+use test_db::multipart::*;
+use test_db::ndjson_utils::*;
+use test_db::parquet_utils::*;
+use test_db::preview::*;
 use test_db::query::*;
 use test_db::query_processor::*;
 use test_db::record::*;
+use test_db::settings::*;
 
-use flexbuffers::VectorReader;
+use flexbuffers::{FlexBufferType, VectorReader};
+use futures::stream::{self, StreamExt};
 use mimalloc::MiMalloc;
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt::Write;
@@ -309,9 +327,10 @@ fn parse_csv_to_flex(data: &'static [u8]) -> ParsedCsv {
     let data = bytes::Bytes::from(data);
     let mut reader = CSVImportReader::from_first_chunk(data).unwrap();
     let headers = reader.headers().clone();
+    let nulls = NullValues::default();
     let mut iter = reader.parse_records();
     while let Some(value) = iter.next() {
-        flex.push(value.map(|r| r.as_flexbuffer()).unwrap());
+        flex.push(value.map(|r| r.as_flexbuffer(&nulls)).unwrap());
     }
     ParsedCsv { headers, flex }
 }
@@ -663,6 +682,159 @@ fn test_first_query() {
     assert!(qres_iter.next().unwrap().is_none());
 }
 
+const TEST_QUERY_HAVING: &str = r#"
+SELECT
+  `donors`."Donor State" `donors__donor_state`,
+  count(*) `donors__count`FROM
+  test.donors AS `donors`GROUP BY
+  1
+HAVING count(*) > 5 AND `donors__donor_state` <> 'other'
+ORDER BY
+  2 DESC
+"#;
+
+const TEST_QUERY_HAVING_HIDDEN_COUNT: &str = r#"
+SELECT donors."Donor State"
+FROM donors AS donors
+GROUP BY 1
+HAVING count(*) >= 10 OR donors."Donor State" = 'Idaho'
+"#;
+
+#[test]
+fn test_having_query() {
+    let data = parse_csv_to_flex(&TEST_DATA);
+    let mut states_counts = HashMap::<String, u64>::new();
+    for r in flex_iter(&data.flex) {
+        let state = r.idx(2).as_str().to_owned();
+        *states_counts.entry(state).or_insert(0) += 1;
+    }
+
+    let processor = process_query(TEST_QUERY_HAVING, &data);
+    let expected: HashSet<&String> = states_counts
+        .iter()
+        .filter(|(state, count)| **count > 5 && state.as_str() != "other")
+        .map(|(state, _)| state)
+        .collect();
+    let mut prev_count = None;
+    let mut qres_iter = processor.iter();
+    for _ in 0..expected.len() {
+        let r = qres_iter.next().unwrap().unwrap();
+        let state = r.value_at(0).unwrap().as_str().unwrap().to_owned();
+        let count = r.value_at(1).unwrap().as_uint().unwrap();
+        if let Some(prev) = prev_count {
+            assert!(prev >= count);
+        }
+        prev_count = Some(count);
+        assert!(expected.contains(&state));
+    }
+    assert!(qres_iter.next().unwrap().is_none());
+
+    // Count used by HAVING only isn't projected
+    let processor = process_query(TEST_QUERY_HAVING_HIDDEN_COUNT, &data);
+    let expected: HashSet<&String> = states_counts
+        .iter()
+        .filter(|(state, count)| **count >= 10 || state.as_str() == "Idaho")
+        .map(|(state, _)| state)
+        .collect();
+    let mut qres_iter = processor.iter();
+    for _ in 0..expected.len() {
+        let r = qres_iter.next().unwrap().unwrap();
+        assert_eq!(r.len(), 1);
+        assert!(expected.contains(&r.value_at(0).unwrap().as_str().unwrap().to_owned()));
+    }
+    assert!(qres_iter.next().unwrap().is_none());
+
+    let errors = [
+        "SELECT count(*) FROM donors AS donors HAVING count(*) > 1",
+        "SELECT donors.\"Donor State\" FROM donors AS donors GROUP BY 1 HAVING donors.\"Donor City\" = 'Tampa'",
+        "SELECT donors.\"Donor State\" FROM donors AS donors GROUP BY 1 HAVING sum(1) > 1",
+    ];
+    for query in errors.iter() {
+        let query = Query::from_query_str(query).unwrap();
+        assert!(QueryProcessor::new(query, data.headers.clone()).is_err());
+    }
+}
+
+const SELECT_DISTINCT_QUERY: &str = r#"
+SELECT DISTINCT donors."Donor State", donors."Donor Is Teacher"
+FROM donors AS donors
+ORDER BY 1
+"#;
+
+const COUNT_DISTINCT_QUERY: &str = r#"
+SELECT count(DISTINCT donors."Donor City") cities
+FROM donors AS donors
+"#;
+
+const GROUP_BY_COUNT_DISTINCT_QUERY: &str = r#"
+SELECT donors."Donor State", count(*), count(DISTINCT donors."Donor City")
+FROM donors AS donors
+GROUP BY 1
+HAVING count(DISTINCT donors."Donor City") > 1
+"#;
+
+#[test]
+fn test_distinct_query() {
+    let data = parse_csv_to_flex(&TEST_DATA);
+    let pairs: HashSet<(String, String)> = flex_iter(&data.flex)
+        .map(|r| (r.idx(2).as_str().to_owned(), r.idx(3).as_str().to_owned()))
+        .collect();
+    let processor = process_query(SELECT_DISTINCT_QUERY, &data);
+    let mut qres_iter = processor.iter();
+    let mut prev_state: Option<String> = None;
+    for _ in 0..pairs.len() {
+        let r = qres_iter.next().unwrap().unwrap();
+        let state = r.value_at(0).unwrap().as_str().unwrap().to_owned();
+        let teacher = r.value_at(1).unwrap().as_str().unwrap().to_owned();
+        if let Some(prev) = prev_state {
+            assert!(prev <= state);
+        }
+        assert!(pairs.contains(&(state.clone(), teacher)));
+        prev_state = Some(state);
+    }
+    assert!(qres_iter.next().unwrap().is_none());
+
+    let mut cities = HashMap::<String, HashSet<String>>::new();
+    for r in flex_iter(&data.flex) {
+        let state_cities = cities.entry(r.idx(2).as_str().to_owned()).or_default();
+        if r.idx(1).flexbuffer_type() != FlexBufferType::Null {
+            state_cities.insert(r.idx(1).as_str().to_owned());
+        }
+    }
+    let all_cities: HashSet<&String> = cities.values().flatten().collect();
+
+    let processor = process_query(COUNT_DISTINCT_QUERY, &data);
+    assert_eq!(processor.headers_csv(), "cities");
+    let mut qres_iter = processor.iter();
+    let count = get_next_value_at(&mut qres_iter, 0).as_uint().unwrap();
+    assert_eq!(count, all_cities.len() as u64);
+
+    // Approximate count uses linear counting on small sets, so it's close to exact one
+    let query = Query::from_query_str(COUNT_DISTINCT_QUERY).unwrap();
+    let options = QueryOptions {
+        distinct: DistinctMode::Approx,
+    };
+    let mut processor = QueryProcessor::with_options(query, data.headers.clone(), options).unwrap();
+    for r in flex_iter(&data.flex) {
+        processor.process_record(&r).unwrap();
+    }
+    let mut qres_iter = processor.iter();
+    let approx = get_next_value_at(&mut qres_iter, 0).as_uint().unwrap();
+    assert!((approx as f64 - count as f64).abs() <= count as f64 * 0.05);
+
+    let processor = process_query(GROUP_BY_COUNT_DISTINCT_QUERY, &data);
+    let expected = cities.values().filter(|c| c.len() > 1).count();
+    let mut qres_iter = processor.iter();
+    for _ in 0..expected {
+        let r = qres_iter.next().unwrap().unwrap();
+        let state = r.value_at(0).unwrap().as_str().unwrap().to_owned();
+        let count = r.value_at(2).unwrap().as_uint().unwrap();
+        assert_eq!(cities[&state].len() as u64, count);
+        assert!(r.value_at(1).unwrap().as_uint().unwrap() >= count);
+    }
+    assert!(qres_iter.next().unwrap().is_none());
+}
+
 const TEST_QUERY_2: &str = r#"
 SELECT
   count(*) `donors__count`FROM
@@ -681,3 +853,1776 @@ fn test_second_query() {
     assert_eq!(count, 2);
     assert!(qres_iter.next().unwrap().is_none());
 }
+
+const TEST_QUERY_IS_NULL: &str = r#"
+SELECT
+  count(*) `donors__count`FROM
+  test.donors AS `donors`WHERE
+  (`donors`."Donor City" IS NULL)
+"#;
+
+const TEST_QUERY_IS_NOT_NULL: &str = r#"
+SELECT
+  count(*) `donors__count`FROM
+  test.donors AS `donors`WHERE
+  (`donors`."Donor City" IS NOT NULL)
+"#;
+
+#[test]
+fn test_is_null_query() {
+    let data = parse_csv_to_flex(&TEST_DATA);
+    let processor = process_query(TEST_QUERY_IS_NULL, &data);
+    let mut qres_iter = processor.iter();
+    assert_eq!(get_next_value_at(&mut qres_iter, 0).as_uint().unwrap(), 29);
+
+    let processor = process_query(TEST_QUERY_IS_NOT_NULL, &data);
+    let mut qres_iter = processor.iter();
+    assert_eq!(get_next_value_at(&mut qres_iter, 0).as_uint().unwrap(), 256);
+}
+
+const SELECT_EXPRESSIONS_QUERY: &str = r#"
+SELECT
+  upper(donors."Donor City") city,
+  donors."Donor Zip" * 10 + 1 zip,
+  CASE WHEN donors."Donor Is Teacher" = 'Yes' THEN 'teacher' ELSE 'donor' END kind,
+  coalesce(donors."Donor City", 'unknown') known_city,
+  concat(CAST(length(trim(donors."Donor State")) AS VARCHAR), '/', donors."Donor Is Teacher") info
+FROM donors AS donors
+"#;
+
+const WHERE_EXPRESSION_QUERY: &str = r#"
+SELECT count(*)
+FROM donors AS donors
+WHERE lower(donors."Donor State") = 'indiana' OR donors."Donor Zip" % 100 = 2
+"#;
+
+const GROUP_BY_EXPRESSION_QUERY: &str = r#"
+SELECT substr(donors."Donor State", 1, 1) letter, count(*)
+FROM donors AS donors
+GROUP BY substr(donors."Donor State", 1, 1)
+ORDER BY 1
+"#;
+
+#[test]
+fn test_expressions_query() {
+    let data = parse_csv_to_flex(&TEST_DATA);
+    let processor = process_query(SELECT_EXPRESSIONS_QUERY, &data);
+    assert_eq!(processor.headers_csv(), "city,zip,kind,known_city,info");
+    let mut qres_iter = processor.iter();
+    for row in flex_iter(&data.flex) {
+        let r = qres_iter.next().unwrap().unwrap();
+        let city = row.idx(1);
+        if city.flexbuffer_type() == FlexBufferType::Null {
+            assert!(r.value_at(0).unwrap().is_null());
+            assert_eq!(r.value_at(3).unwrap().as_str().unwrap(), "unknown");
+        } else {
+            let upper = city.as_str().to_uppercase();
+            assert_eq!(r.value_at(0).unwrap().as_str().unwrap(), upper);
+            assert_eq!(r.value_at(3).unwrap().as_str().unwrap(), city.as_str());
+        }
+        let zip = row.idx(4);
+        if zip.flexbuffer_type() == FlexBufferType::Null {
+            assert!(r.value_at(1).unwrap().is_null());
+        } else {
+            assert_eq!(
+                r.value_at(1).unwrap().as_uint().unwrap(),
+                zip.as_u64() * 10 + 1
+            );
+        }
+        let teacher = row.idx(3).as_str();
+        let kind = if teacher == "Yes" { "teacher" } else { "donor" };
+        assert_eq!(r.value_at(2).unwrap().as_str().unwrap(), kind);
+        let info = format!("{}/{}", row.idx(2).as_str().trim().chars().count(), teacher);
+        assert_eq!(r.value_at(4).unwrap().as_str().unwrap(), info);
+    }
+    assert!(qres_iter.next().unwrap().is_none());
+
+    let expected = flex_iter(&data.flex)
+        .filter(|r| {
+            let zip = r.idx(4);
+            r.idx(2).as_str().to_lowercase() == "indiana"
+                || (zip.flexbuffer_type() != FlexBufferType::Null && zip.as_u64() % 100 == 2)
+        })
+        .count();
+    let processor = process_query(WHERE_EXPRESSION_QUERY, &data);
+    let mut qres_iter = processor.iter();
+    let count = get_next_value_at(&mut qres_iter, 0).as_uint().unwrap();
+    assert_eq!(count, expected as u64);
+
+    let mut letters = BTreeMap::<String, u64>::new();
+    for r in flex_iter(&data.flex) {
+        let letter = r.idx(2).as_str().chars().take(1).collect::<String>();
+        *letters.entry(letter).or_default() += 1;
+    }
+    let processor = process_query(GROUP_BY_EXPRESSION_QUERY, &data);
+    let mut qres_iter = processor.iter();
+    for (letter, count) in letters.iter() {
+        let r = qres_iter.next().unwrap().unwrap();
+        assert_eq!(r.value_at(0).unwrap().as_str().unwrap(), letter);
+        assert_eq!(r.value_at(1).unwrap().as_uint().unwrap(), *count);
+    }
+    assert!(qres_iter.next().unwrap().is_none());
+
+    let errors = [
+        "SELECT donors.\"Donor City\", upper(donors.\"Donor State\"), count(*) FROM donors AS donors GROUP BY 2",
+        "SELECT unknown_fn(donors.\"Donor City\") FROM donors AS donors",
+        "SELECT substr(donors.\"Donor City\") FROM donors AS donors",
+    ];
+    for query in errors.iter() {
+        let query = Query::from_query_str(query).unwrap();
+        assert!(QueryProcessor::new(query, data.headers.clone()).is_err());
+    }
+}
+
+#[test]
+fn test_pattern_query() {
+    let data = parse_csv_to_flex(&TEST_DATA);
+    fn city(r: &VectorReader) -> Option<String> {
+        match r.idx(1).flexbuffer_type() {
+            FlexBufferType::Null => None,
+            _ => Some(r.idx(1).as_str().to_owned()),
+        }
+    }
+    fn state(r: &VectorReader) -> String {
+        r.idx(2).as_str().to_owned()
+    }
+    let cases: [(&str, fn(&VectorReader) -> bool); 7] = [
+        ("donors.\"Donor State\" LIKE 'New %'", |r| {
+            state(r).starts_with("New ")
+        }),
+        ("donors.\"Donor State\" NOT LIKE '%a'", |r| {
+            !state(r).ends_with('a')
+        }),
+        ("donors.\"Donor State\" LIKE 'I_____'", |r| {
+            state(r).starts_with('I') && state(r).chars().count() == 6
+        }),
+        ("donors.\"Donor City\" ILIKE 'SAN%'", |r| {
+            city(r).map_or(false, |c| c.to_lowercase().starts_with("san"))
+        }),
+        ("donors.\"Donor City\" NOT ILIKE 'san%'", |r| {
+            city(r).map_or(false, |c| !c.to_lowercase().starts_with("san"))
+        }),
+        ("donors.\"Donor State\" REGEXP '^(New|North) '", |r| {
+            state(r).starts_with("New ") || state(r).starts_with("North ")
+        }),
+        (
+            "donors.\"Donor City\" ~ 'ville$' AND donors.\"Donor Is Teacher\" = 'No'",
+            |r| city(r).map_or(false, |c| c.ends_with("ville")) && r.idx(3).as_str() == "No",
+        ),
+    ];
+    for (selection, predicate) in cases.iter() {
+        let query = format!("SELECT count(*) FROM donors AS donors WHERE {}", selection);
+        let expected = flex_iter(&data.flex).filter(|r| predicate(r)).count();
+        let processor = process_query(&query, &data);
+        let mut qres_iter = processor.iter();
+        let count = get_next_value_at(&mut qres_iter, 0).as_uint().unwrap();
+        assert_eq!(count, expected as u64, "{}", selection);
+    }
+
+    let errors = [
+        "SELECT count(*) FROM donors AS donors WHERE donors.\"Donor City\" LIKE donors.\"Donor State\"",
+        "SELECT count(*) FROM donors AS donors WHERE donors.\"Donor City\" ~ '('",
+    ];
+    for query in errors.iter() {
+        let query = Query::from_query_str(query).unwrap();
+        assert!(QueryProcessor::new(query, data.headers.clone()).is_err());
+    }
+}
+
+const TEST_DONATIONS: &'static [u8] = b"Donation ID,Donor ID,Donation Amount
+d1,00000ce845c00cbf0686c992fc369df4,25
+d2,00000ce845c00cbf0686c992fc369df4,10
+d3,00002d44003ed46b066607c5455a999a,100
+d4,ffffffffffffffffffffffffffffffff,5
+";
+
+fn process_join_query<'ret, 'de: 'ret>(
+    querystr: &str,
+    tables: &[&'de ParsedCsv],
+) -> QueryProcessor<'ret, 'de> {
+    let query = Query::from_query_str(querystr).unwrap();
+    let headers = tables.iter().map(|t| t.headers.clone()).collect();
+    let mut join = HashJoin::new(&query, headers).unwrap();
+    for (idx, table) in tables.iter().enumerate().skip(1) {
+        for r in flex_iter(&table.flex) {
+            join.add_build_row(idx, r).unwrap();
+        }
+    }
+    let mut processor = QueryProcessor::new(query, join.headers().clone()).unwrap();
+    for r in flex_iter(&tables[0].flex) {
+        if !join.probe(r, &mut processor).unwrap() {
+            break;
+        }
+    }
+    processor
+}
+
+const INNER_JOIN_QUERY: &str = r#"
+SELECT d."Donation ID", donors."Donor City", d."Donation Amount"
+FROM donors AS donors
+INNER JOIN donations AS d ON donors."Donor ID" = d."Donor ID"
+ORDER BY 1
+"#;
+
+const LEFT_JOIN_QUERY: &str = r#"
+SELECT count(*)
+FROM donors AS donors
+LEFT JOIN donations AS d ON donors."Donor ID" = d."Donor ID"
+"#;
+
+const LEFT_JOIN_NULLS_QUERY: &str = r#"
+SELECT count(*)
+FROM donors AS donors
+LEFT JOIN donations AS d ON d."Donor ID" = donors."Donor ID"
+WHERE d."Donation ID" IS NULL
+"#;
+
+#[test]
+fn test_join_query() {
+    let donors = parse_csv_to_flex(&TEST_DATA);
+    let donations = parse_csv_to_flex(&TEST_DONATIONS);
+
+    let processor = process_join_query(INNER_JOIN_QUERY, &[&donors, &donations]);
+    assert_eq!(
+        processor.headers_csv(),
+        "Donation ID,Donor City,Donation Amount"
+    );
+    let mut qres_iter = processor.iter();
+    for (id, city, amount) in [
+        ("d1", "Evanston", 25),
+        ("d2", "Evanston", 10),
+        ("d3", "Winton", 100),
+    ]
+    .iter()
+    {
+        let r = qres_iter.next().unwrap().unwrap();
+        assert_eq!(r.value_at(0).unwrap().as_str().unwrap(), *id);
+        assert_eq!(r.value_at(1).unwrap().as_str().unwrap(), *city);
+        assert_eq!(r.value_at(2).unwrap().as_uint().unwrap(), *amount);
+    }
+    assert!(qres_iter.next().unwrap().is_none());
+
+    let processor = process_join_query(LEFT_JOIN_QUERY, &[&donors, &donations]);
+    let mut qres_iter = processor.iter();
+    let count = get_next_value_at(&mut qres_iter, 0).as_uint().unwrap();
+    assert_eq!(count, donors.flex.len() as u64 + 1);
+
+    let processor = process_join_query(LEFT_JOIN_NULLS_QUERY, &[&donors, &donations]);
+    let mut qres_iter = processor.iter();
+    let count = get_next_value_at(&mut qres_iter, 0).as_uint().unwrap();
+    assert_eq!(count, donors.flex.len() as u64 - 2);
+
+    // Columns with the same name are qualified by table alias
+    let query = Query::from_query_str(
+        "SELECT * FROM donors AS donors JOIN donations AS d ON donors.\"Donor ID\" = d.\"Donor ID\"",
+    )
+    .unwrap();
+    let join = HashJoin::new(
+        &query,
+        vec![donors.headers.clone(), donations.headers.clone()],
+    )
+    .unwrap();
+    assert_eq!(
+        join.headers().join(","),
+        "donors.Donor ID,Donor City,Donor State,Donor Is Teacher,Donor Zip,Donation ID,d.Donor ID,Donation Amount"
+    );
+
+    let errors = [
+        "SELECT * FROM donors AS donors JOIN donations AS d ON donors.\"Donor City\" = donors.\"Donor State\"",
+        "SELECT * FROM donors AS donors JOIN donations AS d ON donors.\"Donor ID\" < d.\"Donor ID\"",
+        "SELECT * FROM donors AS donors JOIN donations AS d ON donors.\"Donor ID\" = d.unknown",
+    ];
+    for query in errors.iter() {
+        let query = Query::from_query_str(query).unwrap();
+        let headers = vec![donors.headers.clone(), donations.headers.clone()];
+        assert!(HashJoin::new(&query, headers).is_err());
+    }
+    assert!(Query::from_query_str(
+        "SELECT * FROM donors AS donors RIGHT JOIN donations AS d ON donors.\"Donor ID\" = d.\"Donor ID\""
+    )
+    .is_err());
+}
+
+fn mem_tables() -> HashMap<String, MemTable> {
+    let mut tables = HashMap::new();
+    for (name, data) in [("donors", TEST_DATA), ("donations", TEST_DONATIONS)].iter() {
+        let parsed = parse_csv_to_flex(data);
+        let mut table = MemTable::new(&parsed.headers);
+        parsed.flex.into_iter().for_each(|row| table.push_flex(row));
+        tables.insert(name.to_string(), table);
+    }
+    tables
+}
+
+fn execute_to_csv_str(tables: &HashMap<String, MemTable>, querystr: &str) -> ApiResult<String> {
+    let query = Query::from_query_str(querystr)?;
+    execute_query(tables, query, QueryOptions::default(), |processor| {
+        Ok(query_result_to_csv_str(processor))
+    })
+}
+
+const CTE_QUERY: &str = r#"
+WITH states AS (
+  SELECT donors."Donor State" state, count(*) donors_count
+  FROM donors AS donors
+  GROUP BY 1
+)
+SELECT s.state, s.donors_count
+FROM states AS s
+WHERE s.donors_count > 15
+ORDER BY 1
+"#;
+
+const FROM_SUBQUERY_QUERY: &str = r#"
+SELECT d."Donor City", t.total
+FROM donors AS d
+INNER JOIN (
+  SELECT donations."Donor ID", count(*) FROM donations AS donations GROUP BY 1
+) AS t (id, total) ON d."Donor ID" = t.id
+ORDER BY 1
+"#;
+
+const IN_SUBQUERY_QUERY: &str = r#"
+SELECT donors."Donor City"
+FROM donors AS donors
+WHERE donors."Donor ID" IN (
+  SELECT d."Donor ID" FROM donations AS d WHERE d."Donation Amount" >= 25
+)
+ORDER BY 1
+"#;
+
+const NESTED_CTE_QUERY: &str = r#"
+WITH big AS (SELECT d."Donor ID" FROM donations AS d WHERE d."Donation Amount" > 20),
+ids AS (SELECT * FROM big AS big)
+SELECT count(*)
+FROM donors AS donors
+WHERE donors."Donor ID" IN (SELECT ids."Donor ID" FROM ids AS ids)
+"#;
+
+#[test]
+fn test_subqueries() {
+    let tables = mem_tables();
+
+    assert_eq!(
+        execute_to_csv_str(&tables, CTE_QUERY).unwrap(),
+        "state,donors_count\nCalifornia,31\nFlorida,18\nIllinois,20\nTexas,18\nother,16\n"
+    );
+
+    assert_eq!(
+        execute_to_csv_str(&tables, FROM_SUBQUERY_QUERY).unwrap(),
+        "Donor City,total\nEvanston,2\nWinton,1\n"
+    );
+
+    assert_eq!(
+        execute_to_csv_str(&tables, IN_SUBQUERY_QUERY).unwrap(),
+        "Donor City\nEvanston\nWinton\n"
+    );
+
+    let query = Query::from_query_str(NESTED_CTE_QUERY).unwrap();
+    assert_eq!(
+        query.get_stored_tables().unwrap(),
+        vec!["donations", "donors"]
+    );
+    assert_eq!(
+        execute_to_csv_str(&tables, NESTED_CTE_QUERY).unwrap(),
+        "count(*)\n2\n"
+    );
+
+    let not_in = "SELECT count(*) FROM donors AS donors \
+        WHERE donors.\"Donor ID\" NOT IN (SELECT d.\"Donor ID\" FROM donations AS d)";
+    let donors_count = tables["donors"].len();
+    assert_eq!(
+        execute_to_csv_str(&tables, not_in).unwrap(),
+        format!("count(*)\n{}\n", donors_count - 2)
+    );
+
+    let invalid_queries = [
+        "SELECT * FROM (SELECT * FROM donors AS donors)",
+        "SELECT * FROM donors AS donors WHERE EXISTS (SELECT * FROM donations AS d)",
+    ];
+    for query in invalid_queries.iter() {
+        assert!(Query::from_query_str(query).is_err());
+    }
+    // Unused CTEs aren't materialized, so their tables aren't read
+    let unused_cte = "WITH u AS (SELECT * FROM unknown AS u), t AS (SELECT * FROM u AS u) \
+        SELECT count(*) FROM donors AS donors";
+    assert_eq!(
+        execute_to_csv_str(&tables, unused_cte).unwrap(),
+        format!("count(*)\n{}\n", donors_count)
+    );
+
+    let mut tables = tables;
+    let mut floats = MemTable::new(&vec!["v".to_owned()]);
+    for v in [1.5, std::f64::NAN].iter() {
+        let mut builder = flexbuffers::Builder::default();
+        let mut row = builder.start_vector();
+        row.push(*v);
+        row.end_vector();
+        floats.push_flex(builder.view().to_vec());
+    }
+    tables.insert("floats".to_owned(), floats);
+    let nan_in = "SELECT count(*) FROM donors AS donors \
+        WHERE donors.\"Donor Zip\" IN (SELECT f.v FROM floats AS f)";
+    let err = execute_to_csv_str(&tables, nan_in).err().unwrap();
+    assert_eq!(
+        err.0.to_string(),
+        "subquery in IN returned non-finite value NaN"
+    );
+
+    let failing_queries = [
+        "SELECT * FROM donors AS donors WHERE donors.\"Donor ID\" IN (SELECT * FROM donations AS d)",
+        "WITH t AS (SELECT * FROM unknown AS u) SELECT * FROM t AS t",
+        "SELECT * FROM (SELECT donors.\"Donor ID\" FROM donors AS donors) AS t (a, b)",
+    ];
+    for query in failing_queries.iter() {
+        assert!(execute_to_csv_str(&tables, query).is_err());
+    }
+}
+
+const UNION_QUERY: &str = r#"
+SELECT donors."Donor State" FROM donors AS donors WHERE donors."Donor City" = 'Evanston'
+UNION ALL
+SELECT donors."Donor State" FROM donors AS donors WHERE donors."Donor City" = 'Winton'
+UNION ALL
+SELECT donors."Donor State" FROM donors AS donors WHERE donors."Donor City" = 'Evanston'
+"#;
+
+#[test]
+fn test_set_operations() {
+    let tables = mem_tables();
+
+    assert_eq!(
+        execute_to_csv_str(&tables, UNION_QUERY).unwrap(),
+        "Donor State\nIllinois\nCalifornia\nIllinois\n"
+    );
+    let union = UNION_QUERY.replace("UNION ALL", "UNION") + " ORDER BY 1";
+    assert_eq!(
+        execute_to_csv_str(&tables, &union).unwrap(),
+        "Donor State\nCalifornia\nIllinois\n"
+    );
+    let union_limit = UNION_QUERY.to_owned() + " LIMIT 2";
+    assert_eq!(
+        execute_to_csv_str(&tables, &union_limit).unwrap(),
+        "Donor State\nIllinois\nCalifornia\n"
+    );
+
+    let cases = [
+        (
+            "SELECT \"Donor ID\" FROM donors INTERSECT SELECT \"Donor ID\" FROM donations ORDER BY 1",
+            "Donor ID\n00000ce845c00cbf0686c992fc369df4\n00002d44003ed46b066607c5455a999a\n",
+        ),
+        (
+            "SELECT \"Donor ID\" FROM donations INTERSECT ALL SELECT \"Donor ID\" FROM donors ORDER BY 1",
+            "Donor ID\n00000ce845c00cbf0686c992fc369df4\n00002d44003ed46b066607c5455a999a\n",
+        ),
+        (
+            "SELECT \"Donor ID\" FROM donations EXCEPT SELECT \"Donor ID\" FROM donors",
+            "Donor ID\nffffffffffffffffffffffffffffffff\n",
+        ),
+        (
+            "SELECT \"Donor ID\" FROM donations EXCEPT ALL SELECT \"Donor ID\" FROM donors ORDER BY 1",
+            "Donor ID\n00000ce845c00cbf0686c992fc369df4\nffffffffffffffffffffffffffffffff\n",
+        ),
+        (
+            "WITH ids AS (SELECT \"Donor ID\" id FROM donations) \
+             SELECT count(*) FROM ids UNION ALL SELECT count(*) FROM donors",
+            "count(*)\n4\n285\n",
+        ),
+    ];
+    for (query, expected) in cases.iter() {
+        assert_eq!(execute_to_csv_str(&tables, query).unwrap(), *expected);
+    }
+
+    let failing_queries = [
+        "SELECT \"Donor ID\", \"Donor City\" FROM donors UNION SELECT \"Donor ID\" FROM donations",
+        "SELECT \"Donor Zip\" FROM donors UNION ALL SELECT \"Donor City\" FROM donors",
+    ];
+    for query in failing_queries.iter() {
+        assert!(execute_to_csv_str(&tables, query).is_err());
+    }
+}
+
+const RUNNING_WINDOW_QUERY: &str = r#"
+SELECT "Donation ID",
+  row_number() OVER (PARTITION BY "Donor ID" ORDER BY "Donation Amount") rn,
+  sum("Donation Amount") OVER (PARTITION BY "Donor ID" ORDER BY "Donation Amount") total
+FROM donations
+ORDER BY 1
+"#;
+
+const RANKING_WINDOW_QUERY: &str = r#"
+SELECT "Donation ID",
+  rank() OVER (ORDER BY "Donor ID") r,
+  dense_rank() OVER (ORDER BY "Donor ID") dr,
+  lag("Donation Amount", 1, 0) OVER (ORDER BY "Donation Amount") prev,
+  lead("Donation Amount") OVER (ORDER BY "Donation Amount") next,
+  count(*) OVER (ORDER BY "Donor ID") running,
+  avg("Donation Amount") OVER (PARTITION BY "Donor ID") average
+FROM donations
+ORDER BY 1 DESC
+LIMIT 3
+"#;
+
+const GROUP_BY_WINDOW_QUERY: &str = r#"
+SELECT "Donor ID", count(*), rank() OVER (ORDER BY count(*) DESC) r
+FROM donations
+GROUP BY 1
+"#;
+
+#[test]
+fn test_window_functions() {
+    let tables = mem_tables();
+
+    assert_eq!(
+        execute_to_csv_str(&tables, RUNNING_WINDOW_QUERY).unwrap(),
+        "Donation ID,rn,total\nd1,2,35\nd2,1,10\nd3,1,100\nd4,1,5\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(&tables, RANKING_WINDOW_QUERY).unwrap(),
+        "Donation ID,r,dr,prev,next,running,average\n\
+         d4,4,3,0,10,4,5\n\
+         d3,3,2,25,,3,100\n\
+         d2,1,1,5,25,2,17.5\n"
+    );
+    // Qualified and unqualified column is the same operand
+    let qualified = "SELECT \"Donation ID\", \
+        row_number() OVER (PARTITION BY \"Donor ID\" ORDER BY d.\"Donation Amount\") rn, \
+        sum(d.\"Donation Amount\") OVER (PARTITION BY d.\"Donor ID\" ORDER BY \"Donation Amount\") total \
+        FROM donations AS d ORDER BY 1";
+    assert_eq!(
+        execute_to_csv_str(&tables, qualified).unwrap(),
+        "Donation ID,rn,total\nd1,2,35\nd2,1,10\nd3,1,100\nd4,1,5\n"
+    );
+    // Input column shadows projection alias with the same name
+    let shadowed = "SELECT \"Donation ID\" AS \"Donor ID\", \"Donor ID\", \
+        row_number() OVER (PARTITION BY \"Donor ID\" ORDER BY \"Donation ID\") rn \
+        FROM donations ORDER BY 1";
+    assert_eq!(
+        execute_to_csv_str(&tables, shadowed).unwrap(),
+        "Donor ID,Donor ID,rn\n\
+         d1,00000ce845c00cbf0686c992fc369df4,1\n\
+         d2,00000ce845c00cbf0686c992fc369df4,2\n\
+         d3,00002d44003ed46b066607c5455a999a,1\n\
+         d4,ffffffffffffffffffffffffffffffff,1\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(&tables, GROUP_BY_WINDOW_QUERY).unwrap(),
+        "Donor ID,count(*),r\n\
+         00000ce845c00cbf0686c992fc369df4,2,1\n\
+         00002d44003ed46b066607c5455a999a,1,2\n\
+         ffffffffffffffffffffffffffffffff,1,2\n"
+    );
+
+    let failing_queries = [
+        "SELECT ntile(2) OVER () FROM donations",
+        "SELECT *, row_number() OVER () FROM donations",
+        "SELECT row_number(\"Donor ID\") OVER () FROM donations",
+        "SELECT lag(\"Donor ID\", \"Donation ID\") OVER () FROM donations",
+        "SELECT row_number() OVER (ORDER BY \"Donation ID\" ROWS UNBOUNDED PRECEDING) FROM donations",
+        "SELECT \"Donor ID\", row_number() OVER (ORDER BY \"Donation Amount\") FROM donations GROUP BY 1",
+    ];
+    for query in failing_queries.iter() {
+        assert!(execute_to_csv_str(&tables, query).is_err());
+    }
+}
+
+const CUBE_QUERY: &str = r#"
+WITH t AS (
+  SELECT "Donation ID" id, substr("Donor ID", 1, 5) donor, "Donation Amount" > 20 big
+  FROM donations
+)
+SELECT donor, big, count(*) c, GROUPING(donor, big) g
+FROM t
+GROUP BY CUBE(donor, big)
+ORDER BY 4 DESC
+"#;
+
+#[test]
+fn test_grouping_sets() {
+    let tables = mem_tables();
+
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT \"Donor ID\", count(*), GROUPING(\"Donor ID\") g FROM donations GROUP BY ROLLUP(\"Donor ID\")"
+        )
+        .unwrap(),
+        "Donor ID,count(*),g\n\
+         00000ce845c00cbf0686c992fc369df4,2,0\n\
+         00002d44003ed46b066607c5455a999a,1,0\n\
+         ffffffffffffffffffffffffffffffff,1,0\n\
+         ,4,1\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(&tables, CUBE_QUERY).unwrap(),
+        "donor,big,c,g\n\
+         ,,4,3\n\
+         ,true,2,2\n\
+         ,false,2,2\n\
+         00000,,2,1\n\
+         00002,,1,1\n\
+         fffff,,1,1\n\
+         00000,true,1,0\n\
+         00000,false,1,0\n\
+         00002,true,1,0\n\
+         fffff,false,1,0\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT \"Donor ID\", count(*) FROM donations \
+             GROUP BY GROUPING SETS ((\"Donor ID\"), ()) HAVING count(*) > 1"
+        )
+        .unwrap(),
+        "Donor ID,count(*)\n00000ce845c00cbf0686c992fc369df4,2\n,4\n"
+    );
+
+    let failing_queries = [
+        "SELECT \"Donor ID\", GROUPING(\"Donation ID\") FROM donations GROUP BY ROLLUP(\"Donor ID\")",
+        "SELECT GROUPING(\"Donor ID\") FROM donations",
+        "SELECT \"Donation ID\", count(*) FROM donations GROUP BY CUBE(\"Donor ID\")",
+    ];
+    for query in failing_queries.iter() {
+        assert!(execute_to_csv_str(&tables, query).is_err());
+    }
+
+    // Count of sets is checked before cross product is built
+    let cube = vec!["\"Donor ID\""; 12].join(", ");
+    let too_many = format!(
+        "SELECT count(*) FROM donations GROUP BY CUBE({}), ROLLUP(\"Donation ID\")",
+        cube
+    );
+    let err = execute_to_csv_str(&tables, &too_many).err().unwrap();
+    assert_eq!(err.0.to_string(), "more than 4096 grouping sets");
+}
+
+#[test]
+fn test_distinct_group_by() {
+    let tables = mem_tables();
+
+    // Group key isn't projected, so grouped rows could repeat
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT DISTINCT count(*) c FROM donations GROUP BY \"Donor ID\""
+        )
+        .unwrap(),
+        "c\n2\n1\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT DISTINCT count(*) c FROM donations GROUP BY \"Donor ID\" ORDER BY 1"
+        )
+        .unwrap(),
+        "c\n1\n2\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT DISTINCT \"Donor ID\" FROM donations \
+             GROUP BY GROUPING SETS ((\"Donor ID\", \"Donation ID\"))"
+        )
+        .unwrap(),
+        "Donor ID\n\
+         00000ce845c00cbf0686c992fc369df4\n\
+         00002d44003ed46b066607c5455a999a\n\
+         ffffffffffffffffffffffffffffffff\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT DISTINCT count(*) c FROM donations GROUP BY ROLLUP(\"Donor ID\")"
+        )
+        .unwrap(),
+        "c\n2\n1\n4\n"
+    );
+}
+
+#[test]
+fn test_approx_aggregates() {
+    let tables = mem_tables();
+
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT approx_median(\"Donation Amount\"), \
+             approx_percentile(\"Donation Amount\", 0.25) p FROM donations"
+        )
+        .unwrap(),
+        "approx_median(\"Donation Amount\"),p\n17.5,7.5\n"
+    );
+    // Aggregates without group by give one row, even for empty input
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT approx_count_distinct(\"Donor ID\") d, \
+             approx_median(\"Donation Amount\") m, count(*) c FROM donations"
+        )
+        .unwrap(),
+        "d,m,c\n3,17.5,4\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT approx_count_distinct(\"Donor ID\") d, \
+             approx_median(\"Donation Amount\") m, count(*) c FROM donations \
+             WHERE \"Donation Amount\" > 1000"
+        )
+        .unwrap(),
+        "d,m,c\n0,,0\n"
+    );
+    assert_eq!(
+        execute_to_csv_str(
+            &tables,
+            "SELECT \"Donor ID\", approx_count_distinct(\"Donation ID\") d, \
+             approx_median(\"Donation Amount\") m FROM donations GROUP BY 1"
+        )
+        .unwrap(),
+        "Donor ID,d,m\n\
+         00000ce845c00cbf0686c992fc369df4,2,17.5\n\
+         00002d44003ed46b066607c5455a999a,1,100\n\
+         ffffffffffffffffffffffffffffffff,1,5\n"
+    );
+
+    let failing_queries = [
+        "SELECT approx_percentile(\"Donation Amount\", 2) FROM donations",
+        "SELECT approx_percentile(\"Donation Amount\") FROM donations",
+        "SELECT approx_median(\"Donation Amount\" + 1) FROM donations",
+    ];
+    for query in failing_queries.iter() {
+        assert!(execute_to_csv_str(&tables, query).is_err());
+    }
+}
+
+#[test]
+fn test_null_values() {
+    let data = bytes::Bytes::from(&b"a,b,c,d\nNA,,\\N,x\n"[..]);
+    let mut reader = CSVImportReader::from_first_chunk(data).unwrap();
+    let nulls = NullValues::new(Some("NA,\\N"), false);
+    let mut iter = reader.parse_records();
+    let flex = iter.next().unwrap().unwrap().as_flexbuffer(&nulls);
+    let record = flexbuffers::Reader::get_root(flex.as_slice())
+        .unwrap()
+        .as_vector();
+    let is_null = |idx| matches!(record.idx(idx).flexbuffer_type(), FlexBufferType::Null);
+    assert!(is_null(0));
+    assert!(!is_null(1));
+    assert_eq!(record.idx(1).as_str(), "");
+    assert!(is_null(2));
+    assert_eq!(record.idx(3).as_str(), "x");
+
+    assert_eq!(NullValues::from_json(&nulls.to_json()), nulls);
+    assert_eq!(
+        NullValues::from_json(&json::JsonValue::new_object()),
+        NullValues::default()
+    );
+}
+
+fn parse_csv_batches(data: &[u8], chunk_size: usize, min_batch_size: usize) -> ParsedCsv {
+    let nulls = NullValues::default();
+    let mut splitter = CSVBatchSplitter::new();
+    let mut headers = None;
+    let mut flex = Vec::new();
+    for chunk in data.chunks(chunk_size) {
+        splitter.add_chunk(bytes::Bytes::copy_from_slice(chunk));
+        if headers.is_none() {
+            headers = splitter.take_line();
+        }
+        while let Some(batch) = splitter.next_batch(min_batch_size) {
+            flex.extend(parse_csv_batch(batch, &nulls, 5, None).unwrap().records);
+        }
+    }
+    if let Some(batch) = splitter.finish() {
+        flex.extend(parse_csv_batch(batch, &nulls, 5, None).unwrap().records);
+    }
+    let headers = CSVImportReader::from_first_chunk(headers.unwrap())
+        .unwrap()
+        .headers()
+        .clone();
+    ParsedCsv { headers, flex }
+}
+
+#[test]
+fn test_csv_batches() {
+    let expected = parse_csv_to_flex(&TEST_DATA);
+    for (chunk_size, min_batch_size) in &[(7, 1), (100, 500), (4096, 1), (10000, 100000)] {
+        let parsed = parse_csv_batches(TEST_DATA, *chunk_size, *min_batch_size);
+        assert_eq!(parsed.headers, expected.headers);
+        assert_eq!(parsed.flex, expected.flex);
+    }
+
+    // New lines in quotes don't split records, last record could be not terminated
+    let data = b"a,b,c,d,e\n1,\"x\ny\",,,\n2,\"\"\"\n\",,,3";
+    let parsed = parse_csv_batches(data, 3, 1);
+    assert_eq!(parsed.headers, vec!["a", "b", "c", "d", "e"]);
+    assert_eq!(parsed.flex.len(), 2);
+    let records: Vec<VectorReader> = flex_iter(&parsed.flex).collect();
+    assert_eq!(records[0].idx(1).as_str(), "x\ny");
+    assert_eq!(records[1].idx(1).as_str(), "\"\n");
+    assert_eq!(records[1].idx(4).as_u64(), 3);
+
+    // CR and CRLF line endings, CRLF could be split between chunks
+    let cr_data: Vec<u8> = TEST_DATA
+        .iter()
+        .map(|b| if *b == b'\n' { b'\r' } else { *b })
+        .collect();
+    let crlf_data = String::from_utf8_lossy(TEST_DATA).replace("\n", "\r\n");
+    for data in [cr_data.as_slice(), crlf_data.as_bytes()].iter() {
+        for (chunk_size, min_batch_size) in &[(7, 1), (100, 500), (10000, 100000)] {
+            let parsed = parse_csv_batches(data, *chunk_size, *min_batch_size);
+            assert_eq!(parsed.headers, expected.headers);
+            assert_eq!(parsed.flex, expected.flex);
+        }
+    }
+
+    // Lone CR ends record, so CR terminated input is split into batches too
+    let mut splitter = CSVBatchSplitter::new();
+    splitter.add_chunk(bytes::Bytes::from_static(b"a,b\r1,\"x\ry\"\r2,z"));
+    assert_eq!(splitter.take_line().unwrap().as_ref(), b"a,b\r");
+    assert_eq!(splitter.next_batch(1).unwrap().as_ref(), b"1,\"x\ry\"\r");
+    assert!(splitter.next_batch(1).is_none());
+    assert_eq!(splitter.finish().unwrap().as_ref(), b"2,z\n");
+}
+
+#[test]
+fn test_import_mode() {
+    let mut options = ImportOptions::default();
+    assert_eq!(
+        ImportMode::from_options(&options).unwrap(),
+        ImportMode::Replace
+    );
+
+    options.mode = Some("upsert".to_owned());
+    assert!(ImportMode::from_options(&options).is_err());
+
+    options.key = Some("Donor ID".to_owned());
+    assert_eq!(
+        ImportMode::from_options(&options).unwrap(),
+        ImportMode::Upsert("Donor ID".to_owned())
+    );
+
+    options.mode = Some("merge".to_owned());
+    assert!(ImportMode::from_options(&options).is_err());
+}
+
+#[test]
+fn test_content_range() {
+    assert_eq!(
+        ContentRange::parse_start("bytes 1024-2047/4096"),
+        Some(1024)
+    );
+    assert_eq!(ContentRange::parse_start("bytes 0-*/*"), Some(0));
+    assert_eq!(ContentRange::parse_start("bytes */4096"), None);
+    assert_eq!(ContentRange::parse_start("items 1-2/3"), None);
+    assert_eq!(ContentRange::parse_start("bytes 100"), None);
+}
+
+#[test]
+fn test_export_values() {
+    let to_csv = |value: ValueRef| {
+        let mut out = Vec::new();
+        write_csv_value(&value, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(to_csv(ValueRef::Str("Green Bay")), "Green Bay");
+    assert_eq!(to_csv(ValueRef::Str("a,\"b\"")), "\"a,\"\"b\"\"\"");
+    assert_eq!(to_csv(ValueRef::Str("a\nb")), "\"a\nb\"");
+    assert_eq!(to_csv(ValueRef::Integer(-5)), "-5");
+    assert_eq!(to_csv(ValueRef::Null), "");
+
+    let to_json = |value: ValueRef| {
+        let mut out = Vec::new();
+        write_json_value(&value, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    assert_eq!(to_json(ValueRef::Str("a\"b")), "\"a\\\"b\"");
+    assert_eq!(to_json(ValueRef::UInteger(602)), "602");
+    assert_eq!(to_json(ValueRef::Float(1.5)), "1.5");
+    assert_eq!(to_json(ValueRef::Float(std::f64::NAN)), "null");
+    assert_eq!(to_json(ValueRef::Bool(true)), "true");
+    assert_eq!(to_json(ValueRef::Json("[1,\"x\"]".into())), "[1,\"x\"]");
+    assert_eq!(to_json(ValueRef::Null), "null");
+
+    let options = ExportOptions {
+        format: Some("ndjson".to_owned()),
+        sql: None,
+    };
+    let format = ExportFormat::from_options(&options).unwrap();
+    let mut writer = format.writer(vec!["Donor Zip".to_owned()]);
+    let mut out = Vec::new();
+    writer.write_headers(&mut out).unwrap();
+    writer
+        .write_row(&ValueRef::UInteger(602), &mut out)
+        .unwrap();
+    assert_eq!(out, b"{\"Donor Zip\":602}\n");
+
+    let mut writer = ExportFormat::CSV.writer(vec!["Donor, City".to_owned()]);
+    let mut out = Vec::new();
+    writer.write_headers(&mut out).unwrap();
+    writer.write_row(&ValueRef::Null, &mut out).unwrap();
+    assert_eq!(out, b"\"Donor, City\"\n\n");
+
+    let options = ExportOptions {
+        format: Some("xlsx".to_owned()),
+        sql: None,
+    };
+    assert!(ExportFormat::from_options(&options).is_err());
+}
+
+#[test]
+fn test_arrow_writer() {
+    use arrow::datatypes::DataType;
+
+    assert_eq!(
+        ArrowColumnType::UInt.merge(ArrowColumnType::Int),
+        ArrowColumnType::Int
+    );
+    assert_eq!(
+        ArrowColumnType::Int.merge(ArrowColumnType::Float),
+        ArrowColumnType::Float
+    );
+    assert_eq!(
+        ArrowColumnType::Null.merge(ArrowColumnType::Bool),
+        ArrowColumnType::Bool
+    );
+    assert_eq!(
+        ArrowColumnType::Float.merge(ArrowColumnType::Utf8),
+        ArrowColumnType::Utf8
+    );
+    assert_eq!(
+        ArrowColumnType::Bool.merge(ArrowColumnType::UInt),
+        ArrowColumnType::Utf8
+    );
+
+    let values = vec![
+        ValueRef::UInteger(602),
+        ValueRef::Null,
+        ValueRef::Integer(-5),
+    ];
+    let mut writer = ArrowWriter::new(vec!["Donor Zip".to_owned()]);
+    let mut out = Vec::new();
+    writer.write_headers(&mut out).unwrap();
+    for value in values.iter() {
+        writer.write_row(value, &mut out).unwrap();
+    }
+    // Rows are buffered until batch is full or export is finished, types are taken from them
+    assert!(out.is_empty());
+    let schema = writer.schema();
+    assert_eq!(schema.field(0).name(), "Donor Zip");
+    assert_eq!(schema.field(0).data_type(), &DataType::Int64);
+    assert!(schema.field(0).is_nullable());
+    writer.finish(&mut out).unwrap();
+    assert!(!out.is_empty());
+
+    // Schema is fixed by the first batch, later values are converted if they fit
+    let mut writer = ArrowWriter::new(vec!["Donor Zip".to_owned()]);
+    let mut out = Vec::new();
+    for _ in 0..8192 {
+        writer
+            .write_row(&ValueRef::UInteger(602), &mut out)
+            .unwrap();
+    }
+    let first_batch = out.len();
+    assert!(first_batch > 0);
+    writer.write_row(&ValueRef::Integer(5), &mut out).unwrap();
+    assert!(writer.write_row(&ValueRef::Integer(-5), &mut out).is_err());
+    assert_eq!(writer.schema().field(0).data_type(), &DataType::UInt64);
+
+    // Empty result has schema only
+    let mut writer = ArrowWriter::new(vec!["Donor Zip".to_owned()]);
+    let mut out = Vec::new();
+    writer.finish(&mut out).unwrap();
+    assert!(!out.is_empty());
+}
+
+#[test]
+fn test_parquet_round_trip() {
+    assert_eq!(format_date(0), "1970-01-01");
+    assert_eq!(format_date(18_321), "2020-02-29");
+    assert_eq!(format_date(-1), "1969-12-31");
+    assert_eq!(
+        format_timestamp(1_590_000_000_123_456),
+        "2020-05-20 18:40:00.123456"
+    );
+    assert_eq!(format_timestamp(-1), "1969-12-31 23:59:59.999999");
+
+    let values = vec![
+        ValueRef::UInteger(602),
+        ValueRef::Null,
+        ValueRef::Integer(-5),
+    ];
+    let mut writer = ExportFormat::Parquet.writer(vec!["Donor Zip".to_owned()]);
+    let mut out = Vec::new();
+    writer.write_headers(&mut out).unwrap();
+    for value in values.iter() {
+        writer.write_row(value, &mut out).unwrap();
+    }
+    writer.finish(&mut out).unwrap();
+    // Whole file is sent after finish
+    assert!(out.is_empty());
+    while writer.write_tail(&mut out).unwrap() {}
+    assert!(out.starts_with(b"PAR1") && out.ends_with(b"PAR1"));
+
+    let temp = TempFile::new("parquet");
+    std::fs::write(&temp.path, &out).unwrap();
+    let reader = ParquetImportReader::open(&temp.path).unwrap();
+    assert_eq!(reader.headers(), &vec!["Donor Zip".to_owned()]);
+    let records: Vec<Vec<u8>> = reader.records().unwrap().collect();
+    assert_eq!(records.len(), 3);
+    let first = flexbuffers::Reader::get_root(&records[0]).unwrap();
+    assert_eq!(first.as_vector().idx(0).as_i64(), 602);
+    let second = flexbuffers::Reader::get_root(&records[1]).unwrap();
+    assert_eq!(
+        second.as_vector().idx(0).flexbuffer_type(),
+        FlexBufferType::Null
+    );
+}
+
+#[test]
+fn test_encoding_magic() {
+    assert_eq!(
+        ContentEncoding::from_magic(&[0x1f, 0x8b, 0x08]),
+        ContentEncoding::Gzip
+    );
+    assert_eq!(
+        ContentEncoding::from_magic(&[0x28, 0xb5, 0x2f, 0xfd]),
+        ContentEncoding::Zstd
+    );
+    assert_eq!(
+        ContentEncoding::from_magic(&TEST_DATA),
+        ContentEncoding::Identity
+    );
+}
+
+async fn decode_to_vec(encoding: Option<ContentEncoding>, data: Vec<u8>) -> Vec<u8> {
+    // Split input into small chunks to check decoder buffering
+    let chunks: Vec<tokio::io::Result<bytes::Bytes>> = data
+        .chunks(100)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    let mut decoded = decode_stream(encoding, stream::iter(chunks)).await;
+    let mut result = Vec::new();
+    while let Some(bytes) = decoded.next().await {
+        result.extend_from_slice(bytes.unwrap().as_ref());
+    }
+    result
+}
+
+#[tokio::test]
+async fn test_decode_gzip_stream() {
+    use async_compression::tokio_02::bufread::GzipEncoder;
+    use tokio::io::AsyncReadExt;
+
+    let mut compressed = Vec::new();
+    GzipEncoder::new(&TEST_DATA[..])
+        .read_to_end(&mut compressed)
+        .await
+        .unwrap();
+
+    assert_eq!(decode_to_vec(None, compressed.clone()).await, TEST_DATA);
+    assert_eq!(
+        decode_to_vec(Some(ContentEncoding::Gzip), compressed.clone()).await,
+        TEST_DATA
+    );
+    assert_eq!(decode_to_vec(None, TEST_DATA.to_vec()).await, TEST_DATA);
+
+    // Magic is detected when it's split between chunks
+    let chunks: Vec<tokio::io::Result<bytes::Bytes>> = compressed
+        .chunks(1)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    let (encoding, _) = detect_encoding(None, stream::iter(chunks)).await;
+    assert_eq!(encoding, ContentEncoding::Gzip);
+}
+
+// Import request for `donors` with body sent in small chunks
+fn body_request(data: &[u8], limit: u64, options: ImportOptions) -> ImportRequest {
+    let chunks: Vec<tokio::io::Result<bytes::Bytes>> = data
+        .chunks(100)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    ImportRequest {
+        table: "donors".to_owned(),
+        options,
+        source: ImportSource::Body {
+            body: Box::pin(stream::iter(chunks)),
+            limit,
+            encoding: RequestEncoding(None),
+            format: ImportFormat::CSV,
+            range: ContentRange(None),
+            multipart: MultipartBoundary(None),
+        },
+    }
+}
+
+fn is_payload_too_large(e: &std::io::Error) -> bool {
+    e.get_ref()
+        .map_or(false, |inner| inner.is::<PayloadTooLarge>())
+}
+
+async fn limited_len(data: &[u8], limit: u64) -> tokio::io::Result<usize> {
+    let chunks: Vec<tokio::io::Result<bytes::Bytes>> = data
+        .chunks(100)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    let mut limited = Box::pin(limit_stream(stream::iter(chunks), limit));
+    let mut len = 0;
+    while let Some(bytes) = limited.next().await {
+        len += bytes?.len();
+    }
+    Ok(len)
+}
+
+#[tokio::test]
+async fn test_import_limit() {
+    use async_compression::tokio_02::bufread::GzipEncoder;
+    use tokio::io::AsyncReadExt;
+
+    let len = TEST_DATA.len() as u64;
+    assert_eq!(limited_len(TEST_DATA, len).await.unwrap(), TEST_DATA.len());
+    let err = limited_len(TEST_DATA, len - 1).await.unwrap_err();
+    assert!(is_payload_too_large(&err));
+    assert_eq!(
+        err.to_string(),
+        format!("request body exceeds import limit of {} bytes", len - 1)
+    );
+
+    // Compressed body is much smaller than the limit, but decompressed one isn't
+    let mut data = b"a,b\n".to_vec();
+    data.resize(200_000, b'x');
+    let mut compressed = Vec::new();
+    GzipEncoder::new(data.as_slice())
+        .read_to_end(&mut compressed)
+        .await
+        .unwrap();
+    assert!(compressed.len() < 10_000);
+    let request = body_request(&compressed, 10_000, ImportOptions::default());
+    let err = run_preview(request).await.unwrap_err();
+    assert!(is_payload_too_large(&err.0));
+    let request = body_request(&compressed, 200_000, ImportOptions::default());
+    assert!(run_preview(request).await.is_ok());
+}
+
+#[test]
+fn test_import_limit_priority() {
+    use rocket::config::{Config, Environment, Limits};
+    use rocket::data::ToByteUnit;
+
+    let mut table_limits = BTreeMap::new();
+    table_limits.insert("donors", 200);
+    let mut api_key_limits = BTreeMap::new();
+    api_key_limits.insert("key", 300);
+    let config = Config::build(Environment::Development)
+        .limits(Limits::new().limit("import", 100.bytes()))
+        .extra("import_limits", table_limits)
+        .extra("api_key_import_limits", api_key_limits)
+        .finalize()
+        .unwrap();
+    let settings = Settings::from_config(&config).unwrap();
+
+    let no_key = ApiKey(None);
+    let key = ApiKey(Some("key".to_owned()));
+    let other_key = ApiKey(Some("other".to_owned()));
+    assert_eq!(settings.import_limit("projects", &no_key), 100);
+    assert_eq!(settings.import_limit("donors", &no_key), 200);
+    assert_eq!(settings.import_limit("donors", &other_key), 200);
+    assert_eq!(settings.import_limit("donors", &key), 300);
+    assert_eq!(settings.import_limit("projects", &key), 300);
+
+    let mut negative_limits = BTreeMap::new();
+    negative_limits.insert("donors", -1);
+    let config = Config::build(Environment::Development)
+        .extra("import_limits", negative_limits)
+        .finalize()
+        .unwrap();
+    assert!(Settings::from_config(&config).is_err());
+}
+
+#[test]
+fn test_job_status() {
+    let progress = ImportProgress::new("donors".to_owned());
+    assert_eq!(progress.status().unwrap(), JobStatus::Running);
+    progress.add_bytes(10);
+    progress.add_rows(2);
+    let json = progress.to_json(7).unwrap();
+    assert_eq!(json["id"], 7);
+    assert_eq!(json["table"], "donors");
+    assert_eq!(json["status"], "running");
+    assert_eq!(json["bytes_read"], 10);
+    assert_eq!(json["rows_imported"], 2);
+
+    progress.finish(&Ok(2)).unwrap();
+    assert_eq!(progress.status().unwrap(), JobStatus::Done);
+    assert_eq!(progress.to_json(7).unwrap()["status"], "done");
+
+    let progress = ImportProgress::new("donors".to_owned());
+    let error = std::io::Error::new(std::io::ErrorKind::InvalidData, "broken row");
+    let result: ApiResult<usize> = Err(ApiError(error));
+    progress.finish(&result).unwrap();
+    let json = progress.to_json(8).unwrap();
+    assert_eq!(json["status"], "failed");
+    assert_eq!(json["errors"][0], "broken row");
+}
+
+#[test]
+fn test_jobs_lookup() {
+    let jobs = Jobs::new();
+    assert_eq!(
+        jobs.get(1).err().unwrap().0.kind(),
+        std::io::ErrorKind::NotFound
+    );
+
+    let running = std::sync::Arc::new(ImportProgress::new("donors".to_owned()));
+    let running_id = jobs.register(running.clone()).unwrap();
+    let mut finished_ids = Vec::new();
+    for _ in 0..MAX_FINISHED_JOBS {
+        let progress = ImportProgress::new("donors".to_owned());
+        progress.finish(&Ok(0)).unwrap();
+        finished_ids.push(jobs.register(std::sync::Arc::new(progress)).unwrap());
+    }
+    // Running job isn't counted, so all finished jobs are kept
+    assert!(jobs.get(finished_ids[0]).is_ok());
+
+    let last_id = jobs
+        .register(std::sync::Arc::new(ImportProgress::new(
+            "donors".to_owned(),
+        )))
+        .unwrap();
+    assert!(jobs.get(finished_ids[0]).is_err());
+    assert!(jobs.get(finished_ids[1]).is_ok());
+    assert!(jobs.get(running_id).is_ok());
+    assert!(jobs.get(last_id).is_ok());
+
+    running.finish(&Ok(0)).unwrap();
+    assert_eq!(
+        jobs.get(running_id).unwrap().status().unwrap(),
+        JobStatus::Done
+    );
+}
+
+// DB in its own temp directory, removed after test
+struct TestDB {
+    db: DBHandle,
+    dir: std::path::PathBuf,
+}
+
+impl TestDB {
+    fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("test_db-{}-{}", std::process::id(), name));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let db = DB::new(dir.to_str().unwrap()).unwrap();
+        Self {
+            db: DBHandle(std::sync::Arc::new(db)),
+            dir,
+        }
+    }
+
+    fn query(&self, sql: &str) -> ApiResult<String> {
+        let query = Query::from_query_str(sql)?;
+        let tables = query
+            .get_stored_tables()?
+            .into_iter()
+            .map(|name| (name.clone(), self.db.table(name)))
+            .collect::<Vec<_>>();
+        let txns = tables
+            .iter()
+            .map(|(name, t)| t.open_transaction().map(|txn| (name.clone(), txn)))
+            .collect::<ApiResult<ReadTransactions>>()?;
+        execute_query(&txns, query, QueryOptions::default(), |processor| {
+            Ok(query_result_to_csv_str(processor))
+        })
+    }
+}
+
+impl Drop for TestDB {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn jobs_handle() -> JobsHandle {
+    JobsHandle(std::sync::Arc::new(Jobs::new()))
+}
+
+#[test]
+fn test_async_import() {
+    let test_db = TestDB::new("async_import");
+    let jobs = jobs_handle();
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(async {
+        let options = ImportOptions {
+            is_async: Some(true),
+            ..Default::default()
+        };
+        let request = body_request(TEST_DATA, u64::MAX, options);
+        let response = run_import(test_db.db.clone(), jobs.clone(), request)
+            .await
+            .unwrap();
+        let id = json::parse(&response).unwrap()["id"].as_u64().unwrap();
+
+        let job = jobs.get(id).unwrap();
+        while job.status().unwrap() == JobStatus::Running {
+            tokio::time::delay_for(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(job.status().unwrap(), JobStatus::Done);
+        assert_eq!(job.rows_imported(), 285);
+    });
+    assert_eq!(
+        test_db.query("SELECT count(*) FROM donors").unwrap(),
+        "count(*)\n285\n"
+    );
+}
+
+#[test]
+fn test_import_files() {
+    use rocket::config::{Config, Environment};
+    use std::io::ErrorKind;
+
+    let root = std::env::temp_dir().join(format!("test_db-{}-import_files", std::process::id()));
+    let import_dir = root.join("import");
+    std::fs::create_dir_all(import_dir.join("sub")).unwrap();
+    for path in ["a.csv", "b.csv", "c.txt", "sub/d.csv"].iter() {
+        std::fs::write(import_dir.join(path), TEST_DATA).unwrap();
+    }
+    std::fs::write(root.join("outside.csv"), TEST_DATA).unwrap();
+
+    let config = Config::build(Environment::Development)
+        .extra("import_dir", import_dir.to_str().unwrap())
+        .extra("admin_api_keys", vec!["admin"])
+        .finalize()
+        .unwrap();
+    let settings = Settings::from_config(&config).unwrap();
+    let names = |pattern: &str| -> ApiResult<Vec<String>> {
+        Ok(settings
+            .import_files(pattern)?
+            .iter()
+            .map(|p| p.file_name().unwrap().to_str().unwrap().to_owned())
+            .collect())
+    };
+    let kind = |result: ApiResult<Vec<String>>| result.unwrap_err().0.kind();
+
+    assert_eq!(names("*.csv").unwrap(), vec!["a.csv", "b.csv"]);
+    assert_eq!(names("**/*.csv").unwrap(), vec!["a.csv", "b.csv", "d.csv"]);
+    assert_eq!(names("sub/d.csv").unwrap(), vec!["d.csv"]);
+    assert_eq!(kind(names("../outside.csv")), ErrorKind::PermissionDenied);
+    assert_eq!(kind(names("../*.csv")), ErrorKind::PermissionDenied);
+    let outside = root.join("outside.csv");
+    assert_eq!(
+        kind(names(outside.to_str().unwrap())),
+        ErrorKind::PermissionDenied
+    );
+    assert_eq!(kind(names("e.csv")), ErrorKind::NotFound);
+
+    assert!(settings
+        .check_admin(&ApiKey(Some("admin".to_owned())))
+        .is_ok());
+    for key in [None, Some("other".to_owned())].iter() {
+        let err = settings.check_admin(&ApiKey(key.clone())).unwrap_err();
+        assert_eq!(err.0.kind(), ErrorKind::PermissionDenied);
+    }
+
+    // Without admin keys and import dir server side imports are disabled
+    let config = Config::build(Environment::Development).finalize().unwrap();
+    let settings = Settings::from_config(&config).unwrap();
+    let err = settings.check_admin(&ApiKey(None)).unwrap_err();
+    assert_eq!(err.0.kind(), ErrorKind::PermissionDenied);
+    let err = settings
+        .check_admin(&ApiKey(Some("admin".to_owned())))
+        .unwrap_err();
+    assert_eq!(err.0.kind(), ErrorKind::PermissionDenied);
+    let err = settings.import_files("*.csv").unwrap_err();
+    assert_eq!(err.0.kind(), ErrorKind::PermissionDenied);
+
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+fn import_body(test_db: &TestDB, data: &[u8], options: ImportOptions) -> ApiResult<String> {
+    let request = body_request(data, u64::MAX, options);
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(run_import(test_db.db.clone(), jobs_handle(), request))
+}
+
+fn upsert_options(key: &str) -> ImportOptions {
+    ImportOptions {
+        mode: Some("upsert".to_owned()),
+        key: Some(key.to_owned()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_upsert_import() {
+    let test_db = TestDB::new("upsert_import");
+    let select_all = "SELECT * FROM donors";
+
+    // Keys are formatted values, `0123` and `123` is the same key, as both are stored as number
+    import_body(
+        &test_db,
+        b"id,name\n1,a\n0123,b\n123,c\n",
+        upsert_options("id"),
+    )
+    .unwrap();
+    assert_eq!(test_db.query(select_all).unwrap(), "id,name\n1,a\n123,c\n");
+
+    // Existing key is replaced in place, new one is appended
+    import_body(&test_db, b"id,name\n123,x\n7,y\n", upsert_options("id")).unwrap();
+    assert_eq!(
+        test_db.query(select_all).unwrap(),
+        "id,name\n1,a\n123,x\n7,y\n"
+    );
+
+    // Index is rebuilt from stored rows for other key, and once more when key is back
+    import_body(&test_db, b"id,name\n9,x\n", upsert_options("name")).unwrap();
+    import_body(&test_db, b"id,name\n1,z\n", upsert_options("id")).unwrap();
+    assert_eq!(
+        test_db.query(select_all).unwrap(),
+        "id,name\n1,z\n9,x\n7,y\n"
+    );
+
+    assert!(import_body(&test_db, b"id,name\n,w\n", upsert_options("id")).is_err());
+    assert!(import_body(&test_db, b"id,name\n2,w\n", upsert_options("no_such")).is_err());
+    assert!(import_body(&test_db, b"code,name\n2,w\n", upsert_options("id")).is_err());
+
+    // Replace mode erases table and key index
+    import_body(&test_db, b"id,name\n5,v\n", ImportOptions::default()).unwrap();
+    import_body(&test_db, b"id,name\n5,w\n", upsert_options("id")).unwrap();
+    assert_eq!(test_db.query(select_all).unwrap(), "id,name\n5,w\n");
+
+    // Rebuilt index matches imported keys, which aren't kept as raw fields
+    import_body(
+        &test_db,
+        b"id,name\n0123,a\n1.50,b\n",
+        ImportOptions::default(),
+    )
+    .unwrap();
+    import_body(&test_db, b"id,name\n0123,c\n1.50,d\n", upsert_options("id")).unwrap();
+    assert_eq!(
+        test_db.query(select_all).unwrap(),
+        "id,name\n123,c\n1.5,d\n"
+    );
+}
+
+// Import of `data` sent in 64KB chunks, body fails after `data` if `interrupted`
+fn import_part(
+    test_db: &TestDB,
+    data: &[u8],
+    interrupted: bool,
+    range: Option<u64>,
+    options: ImportOptions,
+) -> ApiResult<String> {
+    let mut chunks: Vec<tokio::io::Result<bytes::Bytes>> = data
+        .chunks(64 * 1024)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    if interrupted {
+        chunks.push(Err(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "connection reset",
+        )));
+    }
+    let request = ImportRequest {
+        table: "donors".to_owned(),
+        options,
+        source: ImportSource::Body {
+            body: Box::pin(stream::iter(chunks)),
+            limit: u64::MAX,
+            encoding: RequestEncoding(None),
+            format: ImportFormat::CSV,
+            range: ContentRange(range),
+            multipart: MultipartBoundary(None),
+        },
+    };
+    let mut runtime = tokio::runtime::Runtime::new().unwrap();
+    runtime.block_on(run_import(test_db.db.clone(), jobs_handle(), request))
+}
+
+#[test]
+fn test_resume_import() {
+    let test_db = TestDB::new("resume_import");
+    let checkpoint_options = || ImportOptions {
+        commit_every: Some(1000),
+        ..Default::default()
+    };
+    // UTF-8 BOM is a part of file, so it's counted in offset
+    let mut data = b"\xef\xbb\xbfid,name\n".to_vec();
+    for i in 0..200_000 {
+        data.extend_from_slice(format!("{},name_{}\n", i, i).as_bytes());
+    }
+    let expected = String::from_utf8(data[3..].to_vec()).unwrap();
+
+    // Connection is lost in the middle of file, committed batches are kept
+    let sent = data.len() * 2 / 3;
+    assert!(import_part(&test_db, &data[..sent], true, None, checkpoint_options()).is_err());
+    let checkpoint = json::parse(&import_checkpoint(&test_db.db, "donors".to_owned()).unwrap());
+    let offset = checkpoint.unwrap()["offset"].as_u64().unwrap() as usize;
+    assert!(offset > 0 && offset <= sent);
+    assert_eq!(data[offset - 1], b'\n');
+    let committed = test_db.query("SELECT * FROM donors").unwrap();
+    assert_eq!(committed, expected[..offset - 3]);
+
+    // Offset should match checkpoint
+    let wrong = Some(offset as u64 + 1);
+    assert!(import_part(
+        &test_db,
+        &data[offset + 1..],
+        false,
+        wrong,
+        checkpoint_options()
+    )
+    .is_err());
+
+    // The rest of file is sent from checkpoint, checkpoint is removed once import is complete
+    let range = Some(offset as u64);
+    import_part(
+        &test_db,
+        &data[offset..],
+        false,
+        range,
+        checkpoint_options(),
+    )
+    .unwrap();
+    assert_eq!(test_db.query("SELECT * FROM donors").unwrap(), expected);
+    assert!(import_checkpoint(&test_db.db, "donors".to_owned()).is_err());
+
+    // Range from the start is a normal import, nothing to resume is needed
+    let fresh_db = TestDB::new("resume_import_fresh");
+    import_part(&fresh_db, &data, false, Some(0), checkpoint_options()).unwrap();
+    assert_eq!(fresh_db.query("SELECT * FROM donors").unwrap(), expected);
+    assert!(import_part(&fresh_db, b"x", false, Some(1), checkpoint_options()).is_err());
+
+    // Offset in transcoded input wouldn't match file
+    let latin1 = ImportOptions {
+        encoding: Some("windows-1252".to_owned()),
+        ..checkpoint_options()
+    };
+    assert!(import_part(&test_db, b"id\n1\n", false, None, latin1).is_err());
+    let utf16 = b"\xff\xfei\x00d\x00\n\x001\x00\n\x00";
+    assert!(import_part(&test_db, utf16, false, None, checkpoint_options()).is_err());
+    import_part(&test_db, utf16, false, None, ImportOptions::default()).unwrap();
+    assert_eq!(test_db.query("SELECT * FROM donors").unwrap(), "id\n1\n");
+}
+
+#[test]
+fn test_export_query_table() {
+    let test_db = TestDB::new("export_query_table");
+    import_body(&test_db, b"id,name\n1,a\n", ImportOptions::default()).unwrap();
+    let export = |sql: &str, table: Option<&str>| {
+        let source = ExportSource::Query(
+            sql.to_owned(),
+            QueryOptions::default(),
+            table.map(str::to_owned),
+        );
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(run_export(test_db.db.clone(), source, ExportFormat::CSV))
+    };
+    assert!(export("SELECT name FROM donors", Some("donors")).is_ok());
+    assert!(export("SELECT name FROM donors", None).is_ok());
+    // `/export/<table>?sql=` should query the table of path
+    let err = export("SELECT name FROM donors", Some("projects"))
+        .err()
+        .unwrap();
+    assert_eq!(
+        err.0.to_string(),
+        "query doesn't read from exported table projects"
+    );
+}
+
+async fn transcode_to_vec(charset: Charset, data: &[u8]) -> tokio::io::Result<Vec<u8>> {
+    // One byte chunks to check multibyte sequences split between chunks
+    let chunks: Vec<tokio::io::Result<bytes::Bytes>> = data
+        .chunks(1)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    let mut transcoded = transcode_stream(charset, Box::pin(stream::iter(chunks))).await;
+    let mut result = Vec::new();
+    while let Some(bytes) = transcoded.next().await {
+        result.extend_from_slice(bytes?.as_ref());
+    }
+    Ok(result)
+}
+
+#[tokio::test]
+async fn test_transcode_stream() {
+    let latin1 = Charset::new(Some("windows-1252"), false).unwrap();
+    let result = transcode_to_vec(latin1, b"Donor City\nM\xfcnchen").await;
+    assert_eq!(result.unwrap(), "Donor City\nMünchen".as_bytes());
+
+    // BOM is removed and has priority over requested encoding
+    let result = transcode_to_vec(latin1, b"\xef\xbb\xbfDonor City\nM\xc3\xbcnchen").await;
+    assert_eq!(result.unwrap(), "Donor City\nMünchen".as_bytes());
+    let result = transcode_to_vec(Charset::default(), b"\xff\xfeA\x00,\x00B\x00").await;
+    assert_eq!(result.unwrap(), b"A,B");
+    // Input shorter than BOM
+    let result = transcode_to_vec(Charset::default(), b"A").await;
+    assert_eq!(result.unwrap(), b"A");
+
+    // Not strict UTF-8 input is passed as is
+    let result = transcode_to_vec(Charset::default(), b"M\xfcnchen").await;
+    assert_eq!(result.unwrap(), b"M\xfcnchen");
+
+    let strict = Charset::new(None, true).unwrap();
+    let result = transcode_to_vec(strict, "Donor City\nMünchen".as_bytes()).await;
+    assert_eq!(result.unwrap(), "Donor City\nMünchen".as_bytes());
+    let err = transcode_to_vec(strict, b"Donor City\nM\xfcnchen")
+        .await
+        .unwrap_err();
+    assert_eq!(err.to_string(), "invalid UTF-8 input at byte offset 12");
+
+    assert!(Charset::new(Some("no-such-charset"), false).is_err());
+}
+
+#[tokio::test]
+async fn test_preview_csv() {
+    let chunks: Vec<tokio::io::Result<bytes::Bytes>> = TEST_DATA
+        .chunks(7)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    let mut bs: BytesStream = Box::pin(stream::iter(chunks));
+    let preview = preview_csv(&mut bs, &NullValues::default(), 10)
+        .await
+        .unwrap();
+    assert_eq!(preview.headers[0], "Donor ID");
+    assert_eq!(preview.rows, 10);
+    assert!(preview.errors.is_empty());
+    let city = &preview.columns[1];
+    assert_eq!(city.column_type, ColumnType::String);
+    assert_eq!(city.nulls, 1);
+    assert_eq!(
+        city.samples,
+        vec![
+            "Evanston",
+            "Appomattox",
+            "Winton",
+            "Indianapolis",
+            "Paterson"
+        ]
+    );
+    let zip = &preview.columns[4];
+    assert_eq!(zip.column_type, ColumnType::UInt);
+    assert_eq!(zip.nulls, 1);
+
+    // Broken rows are reported, last row has no new line
+    let data = b"a,b\n1,x\n-2\n1.5,\n";
+    let mut bs: BytesStream = Box::pin(stream::iter(vec![Ok(bytes::Bytes::from_static(
+        &data[..data.len() - 1],
+    ))]));
+    let preview = preview_csv(&mut bs, &NullValues::new(Some("x"), false), 100)
+        .await
+        .unwrap();
+    assert_eq!(preview.rows, 3);
+    assert_eq!(preview.columns[0].column_type, ColumnType::Float);
+    assert_eq!(preview.columns[1].column_type, ColumnType::String);
+    assert_eq!(preview.columns[1].nulls, 1);
+    assert_eq!(preview.errors.len(), 1);
+    assert_eq!(preview.errors[0].0, 2);
+}
+
+async fn multipart_file(boundary: &str, data: &[u8]) -> tokio::io::Result<(PartHeaders, Vec<u8>)> {
+    // Small chunks to check delimiter split between chunks
+    let chunks: Vec<tokio::io::Result<bytes::Bytes>> = data
+        .chunks(3)
+        .map(|c| Ok(bytes::Bytes::copy_from_slice(c)))
+        .collect();
+    let (headers, mut part) = file_part(boundary, Box::pin(stream::iter(chunks)))
+        .await
+        .map_err(|e| e.0)?;
+    let mut result = Vec::new();
+    while let Some(bytes) = part.next().await {
+        result.extend_from_slice(bytes?.as_ref());
+    }
+    Ok((headers, result))
+}
+
+#[tokio::test]
+async fn test_multipart_file_part() {
+    let mut body =
+        b"--XyZ\r\nContent-Disposition: form-data; name=\"table\"\r\n\r\ndonors\r\n".to_vec();
+    body.extend_from_slice(
+        b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"Donors.csv\"\r\n",
+    );
+    body.extend_from_slice(b"Content-Type: text/csv\r\n\r\n");
+    body.extend_from_slice(TEST_DATA);
+    body.extend_from_slice(b"\r\n--XyZ--\r\n");
+
+    let (headers, data) = multipart_file("XyZ", &body).await.unwrap();
+    assert_eq!(headers.name.as_deref(), Some("file"));
+    assert_eq!(headers.filename.as_deref(), Some("Donors.csv"));
+    assert_eq!(headers.content_type.as_deref(), Some("text/csv"));
+    assert_eq!(data, TEST_DATA);
+
+    let no_file =
+        b"--XyZ\r\nContent-Disposition: form-data; name=\"table\"\r\n\r\ndonors\r\n--XyZ--\r\n";
+    let err = multipart_file("XyZ", no_file).await.unwrap_err();
+    assert_eq!(err.to_string(), "no file in multipart body");
+
+    let unterminated = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"a.csv\"\r\n\r\na,b\n1,2\n";
+    let err = multipart_file("XyZ", unterminated).await.unwrap_err();
+    assert_eq!(err.to_string(), "unterminated multipart body");
+}
+
+const TEST_NDJSON_DATA: &'static [u8] =
+    br#"{"Donor ID": "a1", "Donor City": "San Francisco", "Donor Zip": 941}
+{"Donor ID": "a2", "Donor City": "Chicago", "Donor Zip": 606, "Donor State": "Illinois"}
+
+{"Donor ID": "a3", "Donor City": "San Francisco", "Tags": ["x", 1], "Meta": {"teacher": true}}
+{"Donor ID": "a4", "Donor State": "Texas", "Donor Zip": -5}"#;
+
+fn parse_ndjson_to_flex(data: &'static [u8]) -> ParsedCsv {
+    let mut flex = Vec::new();
+    let mut reader = NDJSONImportReader::new();
+    // Feed input by small chunks to check lines split between chunks
+    for chunk in data.chunks(7) {
+        reader.add_chunk(bytes::Bytes::from(chunk));
+        while let Some(record) = reader.next_record().unwrap() {
+            flex.push(record);
+        }
+    }
+    if let Some(record) = reader.finish().unwrap() {
+        flex.push(record);
+    }
+    ParsedCsv {
+        headers: reader.headers(),
+        flex,
+    }
+}
+
+const SELECT_ALL_NDJSON_QUERY: &str = r#"
+SELECT *
+FROM donors AS donors
+"#;
+
+#[test]
+fn test_ndjson_import() {
+    let data = parse_ndjson_to_flex(&TEST_NDJSON_DATA);
+    assert_eq!(
+        data.headers,
+        vec![
+            "Donor ID",
+            "Donor City",
+            "Donor Zip",
+            "Donor State",
+            "Tags",
+            "Meta"
+        ]
+    );
+    assert_eq!(data.flex.len(), 4);
+
+    let processor = process_query(SELECT_ALL_NDJSON_QUERY, &data);
+    let result = query_result_to_csv_str(&processor);
+    assert_eq!(
+        result,
+        r#"Donor ID,Donor City,Donor Zip,Donor State,Tags,Meta
+a1,San Francisco,941,,,
+a2,Chicago,606,Illinois,,
+a3,San Francisco,,,["x",1],{"teacher":true}
+a4,,-5,Texas,,
+"#
+    );
+
+    let processor = process_query(TEST_QUERY_2, &data);
+    let mut qres_iter = processor.iter();
+    let count = get_next_value_at(&mut qres_iter, 0).as_uint().unwrap();
+    assert_eq!(count, 2);
+}